@@ -0,0 +1,237 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Logging setup. Logs go to a file when running in the background and to the terminal in the
+//! foreground, unless another target is chosen, as text or JSON lines. `--verbosity` sets the
+//! level of everything that `--log-filter` doesn't name.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    process::exit,
+};
+
+use clap::{Arg, ArgMatches, value_parser};
+use tracing::{Level, Metadata};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt::MakeWriter, layer::SubscriberExt as _,
+    util::SubscriberInitExt as _,
+};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+pub fn args() -> [Arg; 7] {
+    [
+        Arg::new("verbosity")
+            .long("verbosity")
+            .short('v')
+            .env("WG_LOG_LEVEL")
+            .value_parser(["error", "info", "debug", "trace"])
+            .help("Log verbosity")
+            .default_value("error"),
+        Arg::new("log-filter")
+            .long("log-filter")
+            .env("WG_LOG_FILTER")
+            .help("Log levels by module, such as defguard_boringtun::noise=debug"),
+        Arg::new("log-target")
+            .long("log-target")
+            .env("WG_LOG_TARGET")
+            .value_parser([
+                "file",
+                "terminal",
+                "syslog",
+                #[cfg(target_os = "linux")]
+                "journald",
+            ])
+            .help("Where to log, instead of the log file in the background or the terminal"),
+        Arg::new("log-format")
+            .long("log-format")
+            .env("WG_LOG_FORMAT")
+            .value_parser(["text", "json"])
+            .help("Log as text or as JSON lines")
+            .default_value("text"),
+        Arg::new("log")
+            .long("log")
+            .short('l')
+            .env("WG_LOG_FILE")
+            .help("Log file")
+            .default_value("/tmp/boringtun.out"),
+        Arg::new("log-rotation")
+            .long("log-rotation")
+            .env("WG_LOG_ROTATION")
+            .value_parser(["never", "minutely", "hourly", "daily"])
+            .help("Start a new log file, named after the log file and the time, this often")
+            .default_value("never"),
+        Arg::new("log-max-files")
+            .long("log-max-files")
+            .env("WG_LOG_MAX_FILES")
+            .value_parser(value_parser!(usize))
+            .help("Remove the oldest rotated log files beyond this many"),
+    ]
+}
+
+/// Install the logger, exiting on invalid settings. Logs written to files are flushed until
+/// the returned guard is dropped.
+pub fn init(matches: &ArgMatches, background: bool) -> Option<WorkerGuard> {
+    let arg = |name| matches.get_one::<String>(name).map(String::as_str);
+    // The verbosity comes first, so the filter overrides it for the modules it names
+    let mut directives = arg("verbosity").unwrap().to_owned();
+    if let Some(filter) = arg("log-filter") {
+        directives = format!("{directives},{filter}");
+    }
+    let filter = EnvFilter::builder()
+        .parse(&directives)
+        .unwrap_or_else(|e| fail(&format!("Invalid log filter: {e}")));
+    let json = arg("log-format") == Some("json");
+    let target = arg("log-target").unwrap_or(if background { "file" } else { "terminal" });
+
+    let mut guard = None;
+    let layer = match target {
+        "terminal" => fmt_layer(io::stdout, json, Style::Terminal),
+        "syslog" => {
+            // The identity must outlive the process, as syslog keeps a pointer to it
+            unsafe { libc::openlog(c"boringtun".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+            fmt_layer(Syslog, json, Style::Syslog)
+        }
+        #[cfg(target_os = "linux")]
+        "journald" => match tracing_journald::layer() {
+            Ok(layer) => Box::new(layer.with_syslog_identifier("boringtun".to_owned())),
+            Err(e) => fail(&format!("Could not connect to journald: {e}")),
+        },
+        _ => {
+            let (writer, file_guard) = tracing_appender::non_blocking(log_file(matches));
+            guard = Some(file_guard);
+            fmt_layer(writer, json, Style::File)
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(filter)
+        .init();
+    guard
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    exit(1);
+}
+
+/// The log file, or the rotating set of log files named after it
+fn log_file(matches: &ArgMatches) -> Box<dyn Write + Send> {
+    let log = matches.get_one::<String>("log").unwrap();
+    let rotation = match matches.get_one::<String>("log-rotation").unwrap().as_str() {
+        "minutely" => Rotation::MINUTELY,
+        "hourly" => Rotation::HOURLY,
+        "daily" => Rotation::DAILY,
+        _ => {
+            return Box::new(
+                File::create(log).unwrap_or_else(|_| panic!("Could not create log file {log}")),
+            );
+        }
+    };
+
+    let path = Path::new(log);
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default(),
+        );
+    if let Some(max) = matches.get_one::<usize>("log-max-files") {
+        builder = builder.max_log_files(*max);
+    }
+    match builder.build(path.parent().unwrap_or(Path::new("."))) {
+        Ok(appender) => Box::new(appender),
+        Err(e) => fail(&format!("Could not create log file {log}: {e}")),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Style {
+    /// Multi-line text with colors
+    Terminal,
+    File,
+    /// Without timestamps, as syslog adds them
+    Syslog,
+}
+
+fn fmt_layer<W>(writer: W, json: bool, style: Style) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match (json, style) {
+        (true, Style::Syslog) => layer.json().without_time().boxed(),
+        (true, _) => layer.json().boxed(),
+        (false, Style::Terminal) => layer.pretty().boxed(),
+        (false, Style::File) => layer.with_ansi(false).boxed(),
+        (false, Style::Syslog) => layer.with_ansi(false).without_time().boxed(),
+    }
+}
+
+/// Sends every log line to syslog, with the priority of its level
+struct Syslog;
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine;
+
+    fn make_writer(&'a self) -> SyslogLine {
+        SyslogLine {
+            priority: libc::LOG_INFO,
+            line: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> SyslogLine {
+        let priority = match *meta.level() {
+            Level::ERROR => libc::LOG_ERR,
+            Level::WARN => libc::LOG_WARNING,
+            Level::INFO => libc::LOG_INFO,
+            Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+        };
+        SyslogLine {
+            priority,
+            line: Vec::new(),
+        }
+    }
+}
+
+/// A log line, sent to syslog when it is dropped
+struct SyslogLine {
+    priority: libc::c_int,
+    line: Vec<u8>,
+}
+
+impl Write for SyslogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogLine {
+    fn drop(&mut self) {
+        let mut line = std::mem::take(&mut self.line);
+        line.retain(|&b| b != 0);
+        while line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        if line.is_empty() {
+            return;
+        }
+        let line = CString::new(line).unwrap_or_default();
+        unsafe { libc::syslog(self.priority, c"%s".as_ptr(), line.as_ptr()) };
+    }
+}
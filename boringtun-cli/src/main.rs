@@ -1,12 +1,26 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{fs::File, os::unix::net::UnixDatagram, process::exit, str::FromStr};
+use std::{
+    fs::read_to_string,
+    os::unix::net::UnixDatagram,
+    path::{PathBuf, absolute},
+    process::exit,
+};
 
 use clap::{Arg, ArgAction, Command, value_parser};
 use daemonize::Daemonize;
-use defguard_boringtun::device::{DeviceConfig, DeviceHandle, drop_privileges::drop_privileges};
-use tracing::Level;
+use defguard_boringtun::{
+    device::{
+        DeviceConfig, DeviceHandle, api::ApiAccess, drop_privileges::drop_privileges,
+        http::HttpListen, http_api::HttpApiConfig, icmp::Unreachable, metrics::MetricsConfig,
+        systemd,
+    },
+    uapi::config::Config,
+};
+
+mod log;
+mod wg;
 
 fn check_tun_name<'a>(v: &str) -> Result<String, &'a str> {
     #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
@@ -29,6 +43,10 @@ fn main() {
     let matches = Command::new("boringtun")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Vlad Krasnov <vlad@cloudflare.com>")
+        // The `wg` compatible subcommands replace running a tunnel
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommands(wg::subcommands())
         .args(&[
             Arg::new("INTERFACE_NAME")
                 .required(true)
@@ -46,13 +64,6 @@ fn main() {
                 .help("Number of OS threads to use")
                 .value_parser(value_parser!(usize))
                 .default_value("4"),
-            Arg::new("verbosity")
-                .long("verbosity")
-                .short('v')
-                .env("WG_LOG_LEVEL")
-                .value_parser(["error", "info", "debug", "trace"])
-                .help("Log verbosity")
-                .default_value("error"),
             Arg::new("uapi-fd")
                 .long("uapi-fd")
                 .env("WG_UAPI_FD")
@@ -65,12 +76,17 @@ fn main() {
                 .help("File descriptor for an already-existing TUN device")
                 .value_parser(value_parser!(isize))
                 .default_value("-1"),
-            Arg::new("log")
-                .long("log")
-                .short('l')
-                .env("WG_LOG_FILE")
-                .help("Log file")
-                .default_value("/tmp/boringtun.out"),
+            Arg::new("config")
+                .long("config")
+                .short('c')
+                .env("WG_CONFIG_FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("WireGuard configuration file to apply at startup, and reload on SIGHUP"),
+            Arg::new("save-config")
+                .long("save-config")
+                .env("WG_SAVE_CONFIG_FILE")
+                .value_parser(value_parser!(PathBuf))
+                .help("Save the configuration to this file after every change and on shutdown"),
             Arg::new("disable-drop-privileges")
                 .long("disable-drop-privileges")
                 .action(ArgAction::SetTrue)
@@ -85,9 +101,83 @@ fn main() {
                 .long("disable-multi-queue")
                 .action(ArgAction::SetTrue)
                 .help("Disable using multiple queues for the tunnel interface"),
+            Arg::new("tun-offload")
+                .long("tun-offload")
+                .action(ArgAction::SetTrue)
+                .help("Enable TCP segmentation and receive offloads on the tunnel interface"),
+            Arg::new("io-uring")
+                .long("io-uring")
+                .action(ArgAction::SetTrue)
+                .help("Use io_uring instead of epoll for the event loop"),
+            Arg::new("icmp-too-big")
+                .long("icmp-too-big")
+                .action(ArgAction::SetTrue)
+                .help("Reply with ICMP to packets that don't fit the path MTU of their peer"),
+            Arg::new("clamp-mss")
+                .long("clamp-mss")
+                .action(ArgAction::SetTrue)
+                .help("Clamp the MSS of TCP SYNs to the MTU of the tunnel and the peer"),
+            Arg::new("icmp-unreachable")
+                .long("icmp-unreachable")
+                .value_parser(value_parser!(Unreachable))
+                .help("Reply with ICMP unreachable to packets without a peer, with code net or prohibited"),
+            Arg::new("hub-mode")
+                .long("hub-mode")
+                .action(ArgAction::SetTrue)
+                .help("Forward packets between peers inside the device, without the host routing them"),
+            Arg::new("uapi-allow-uid")
+                .long("uapi-allow-uid")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(value_parser!(u32))
+                .help("Allow the user to configure the interface through the api socket"),
+            Arg::new("uapi-allow-gid")
+                .long("uapi-allow-gid")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(value_parser!(u32))
+                .help("Allow users with this primary group to configure the interface"),
+            Arg::new("uapi-read-only-uid")
+                .long("uapi-read-only-uid")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(value_parser!(u32))
+                .help("Allow the user to read the interface state through the api socket"),
+            Arg::new("uapi-read-only-gid")
+                .long("uapi-read-only-gid")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(value_parser!(u32))
+                .help("Allow users with this primary group to read the interface state"),
+            Arg::new("http-listen")
+                .long("http-listen")
+                .env("WG_HTTP_LISTEN")
+                .value_parser(value_parser!(HttpListen))
+                .help("Serve the HTTP/JSON API on a socket path or a local ip:port"),
+            Arg::new("http-token")
+                .long("http-token")
+                .env("WG_HTTP_TOKEN")
+                .requires("http-listen")
+                .help("Bearer token required by the HTTP/JSON API"),
+            Arg::new("metrics-listen")
+                .long("metrics-listen")
+                .env("WG_METRICS_LISTEN")
+                .value_parser(value_parser!(HttpListen))
+                .help("Serve Prometheus metrics on a socket path or a local ip:port"),
+            Arg::new("metrics-max-peers")
+                .long("metrics-max-peers")
+                .env("WG_METRICS_MAX_PEERS")
+                .requires("metrics-listen")
+                .value_parser(value_parser!(usize))
+                .help("Limit per peer metrics to the peers with the most traffic"),
         ])
+        .args(log::args())
         .get_matches();
 
+    if let Some((name, matches)) = matches.subcommand() {
+        exit(wg::run(name, matches));
+    }
+
     let background = !matches.get_flag("foreground");
     #[cfg(target_os = "linux")]
     let uapi_fd = matches.get_one::<i32>("uapi-fd").unwrap();
@@ -97,31 +187,53 @@ fn main() {
         tun_name = matches.get_one::<String>("tun-fd").unwrap();
     }
     let n_threads = matches.get_one::<usize>("threads").unwrap();
-    let log_level = matches.get_one::<String>("verbosity").unwrap();
-    let log_level = Level::from_str(log_level).unwrap();
 
-    // Create a socketpair to communicate between forked processes
-    let (sock1, sock2) = UnixDatagram::pair().unwrap();
-    let _ = sock1.set_nonblocking(true);
-
-    let _guard;
+    // Access control is only enabled when at least one user or group is listed
+    let ids = |name| -> Vec<u32> {
+        matches
+            .get_many::<u32>(name)
+            .map(|ids| ids.copied().collect())
+            .unwrap_or_default()
+    };
+    let api_access = ApiAccess {
+        uids: ids("uapi-allow-uid"),
+        gids: ids("uapi-allow-gid"),
+        read_only_uids: ids("uapi-read-only-uid"),
+        read_only_gids: ids("uapi-read-only-gid"),
+    };
+    let api_access = (api_access != ApiAccess::default()).then_some(api_access);
 
-    if background {
-        let log = matches.get_one::<String>("log").unwrap();
+    // Paths are resolved now, because the daemon changes its working directory
+    let path = |name| {
+        matches
+            .get_one::<PathBuf>(name)
+            .and_then(|p| absolute(p).ok())
+    };
+    let config_file = path("config");
+    let save_config = path("save-config");
 
-        let log_file =
-            File::create(log).unwrap_or_else(|_| panic!("Could not create log file {log}"));
+    // The device reads the configuration file itself, so it can be reloaded on SIGHUP. Check
+    // it before daemonizing as well, so errors are reported on the terminal.
+    if let Some(ref path) = config_file {
+        let config = read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| s.parse::<Config>().map_err(|e| e.to_string()));
+        if let Err(e) = config {
+            eprintln!("{}: {e}", path.display());
+            exit(1);
+        }
+    }
 
-        let (non_blocking, guard) = tracing_appender::non_blocking(log_file);
+    // Sockets passed by systemd socket activation are only meant for the process it started
+    let api_listen_fds = systemd::listen_fds();
 
-        _guard = guard;
+    // Create a socketpair to communicate between forked processes
+    let (sock1, sock2) = UnixDatagram::pair().unwrap();
+    let _ = sock1.set_nonblocking(true);
 
-        tracing_subscriber::fmt()
-            .with_max_level(log_level)
-            .with_writer(non_blocking)
-            .with_ansi(false)
-            .init();
+    let _guard = log::init(&matches, background);
 
+    if background {
         let daemonize = Daemonize::new()
             .working_directory("/tmp")
             .privileged_action(move || {
@@ -141,11 +253,6 @@ fn main() {
                 exit(1);
             }
         }
-    } else {
-        tracing_subscriber::fmt()
-            .pretty()
-            .with_max_level(log_level)
-            .init();
     }
 
     let config = DeviceConfig {
@@ -155,6 +262,32 @@ fn main() {
         use_connected_socket: !matches.get_flag("disable-connected-udp"),
         #[cfg(target_os = "linux")]
         use_multi_queue: !matches.get_flag("disable-multi-queue"),
+        #[cfg(target_os = "linux")]
+        use_tun_offload: matches.get_flag("tun-offload"),
+        #[cfg(target_os = "linux")]
+        use_io_uring: matches.get_flag("io-uring"),
+        icmp_too_big: matches.get_flag("icmp-too-big"),
+        clamp_mss: matches.get_flag("clamp-mss"),
+        hub_mode: matches.get_flag("hub-mode"),
+        icmp_unreachable: matches.get_one::<Unreachable>("icmp-unreachable").copied(),
+        api_access,
+        http_api: matches
+            .get_one::<HttpListen>("http-listen")
+            .map(|listen| HttpApiConfig {
+                listen: listen.clone(),
+                token: matches.get_one::<String>("http-token").cloned(),
+            }),
+        metrics: matches
+            .get_one::<HttpListen>("metrics-listen")
+            .map(|listen| MetricsConfig {
+                listen: listen.clone(),
+                max_peers: matches.get_one::<usize>("metrics-max-peers").copied(),
+            }),
+        config_file,
+        save_config,
+        api_listen_fds,
+        systemd_notify: systemd::notify_enabled(),
+        handle_signals: true,
     };
 
     let mut device_handle: DeviceHandle = match DeviceHandle::new(tun_name, config) {
@@ -180,6 +313,14 @@ fn main() {
     drop(sock1);
 
     tracing::info!("BoringTun started successfully");
+    notify_systemd("READY=1\nSTATUS=Started");
 
     device_handle.wait();
+    notify_systemd("STOPPING=1");
+}
+
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        tracing::warn!(message = "Failed to notify the service manager", error = ?e);
+    }
 }
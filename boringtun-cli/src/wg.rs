@@ -0,0 +1,772 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Subcommands that mirror the `wg` tool from wireguard-tools, so a single binary can both run
+//! and configure tunnels. `show` and `set` talk to the UAPI socket of the interface, and their
+//! output matches `wg` byte for byte when it is not writing to a terminal.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    env,
+    fmt::Write as _,
+    fs::{read_dir, read_to_string},
+    io::{self, Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Arg, ArgMatches, Command};
+use defguard_boringtun::{
+    serialization::KeyBytes,
+    uapi::{
+        SOCK_DIR,
+        client::{self, Client, Interface, PeerUpdate, SetRequest},
+        config::{self, Config},
+    },
+    x25519,
+};
+
+pub fn subcommands() -> [Command; 7] {
+    let interface = || Arg::new("INTERFACE").required(true);
+    [
+        Command::new("show")
+            .about("Shows the current configuration and device information")
+            .args([
+                Arg::new("INTERFACE").help("An interface name, `all' or `interfaces'"),
+                Arg::new("FIELD").help(
+                    "public-key, private-key, listen-port, fwmark, peers, preshared-keys, \
+                     endpoints, allowed-ips, latest-handshakes, transfer, persistent-keepalive \
+                     or dump",
+                ),
+            ]),
+        Command::new("showconf")
+            .about("Shows the current configuration of a given WireGuard interface")
+            .arg(interface()),
+        Command::new("set")
+            .about("Change the current configuration, add peers, remove peers, or change peers")
+            .args([
+                interface(),
+                Arg::new("ARGS")
+                    .required(true)
+                    .num_args(1..)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true)
+                    .help(
+                        "[listen-port <port>] [fwmark <mark>] [private-key <file path>] \
+                         [peer <base64 public key> [remove] [preshared-key <file path>] \
+                         [endpoint <ip>:<port>] [persistent-keepalive <interval seconds>] \
+                         [allowed-ips <ip1>/<cidr1>[,<ip2>/<cidr2>]...] ]...",
+                    ),
+            ]),
+        Command::new("syncconf")
+            .about("Synchronizes a configuration file to a WireGuard interface")
+            .args([interface(), Arg::new("FILE").required(true)]),
+        Command::new("genkey").about("Generates a new private key and writes it to stdout"),
+        Command::new("genpsk").about("Generates a new preshared key and writes it to stdout"),
+        Command::new("pubkey")
+            .about("Reads a private key from stdin and writes a public key to stdout"),
+    ]
+}
+
+/// Run a subcommand, returning the exit code
+pub fn run(name: &str, matches: &ArgMatches) -> i32 {
+    let arg = |name| matches.get_one::<String>(name).map(String::as_str);
+    match name {
+        "show" => show(arg("INTERFACE"), arg("FIELD")),
+        "showconf" => showconf(arg("INTERFACE").unwrap()),
+        "set" => {
+            let args: Vec<&str> = matches
+                .get_many::<String>("ARGS")
+                .unwrap()
+                .map(String::as_str)
+                .collect();
+            set(arg("INTERFACE").unwrap(), &args)
+        }
+        "syncconf" => syncconf(arg("INTERFACE").unwrap(), arg("FILE").unwrap()),
+        "genkey" => {
+            let mut key = KeyBytes::secret().raw_bytes();
+            // Clamp the key the same way `wg genkey` does
+            key[0] &= 248;
+            key[31] &= 127;
+            key[31] |= 64;
+            output(&format!("{}\n", encode_key(&key)));
+            0
+        }
+        "genpsk" => {
+            output(&format!("{}\n", KeyBytes::secret().to_base64()));
+            0
+        }
+        "pubkey" => pubkey(),
+        _ => unreachable!("unknown subcommand {name}"),
+    }
+}
+
+/// Write to stdout, ignoring errors such as a closed pipe like `wg` does
+fn output(s: &str) {
+    let _ = io::stdout().lock().write_all(s.as_bytes());
+}
+
+fn pubkey() -> i32 {
+    let mut input = String::new();
+    let key = io::stdin()
+        .read_to_string(&mut input)
+        .ok()
+        .and_then(|_| input.trim().parse::<KeyBytes>().ok());
+    match key {
+        Some(key) => {
+            output(&format!("{}\n", key.public_key().to_base64()));
+            0
+        }
+        None => {
+            eprintln!("boringtun: Key is not the correct length or format");
+            1
+        }
+    }
+}
+
+/// The names of the interfaces that have an API socket, sorted
+fn list_interfaces() -> io::Result<Vec<String>> {
+    let entries = match read_dir(SOCK_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str().and_then(|n| n.strip_suffix(".sock")) {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn show(interface: Option<&str>, field: Option<&str>) -> i32 {
+    match interface {
+        None | Some("all") => {
+            let names = match list_interfaces() {
+                Ok(names) => names,
+                Err(e) => {
+                    eprintln!("Unable to list interfaces: {}", io_error_message(&e));
+                    return 1;
+                }
+            };
+            // Only fail if no interface at all could be shown
+            let mut ret = i32::from(!names.is_empty());
+            for (i, name) in names.iter().enumerate() {
+                let device = match Client::for_interface(name).get() {
+                    Ok(device) => device,
+                    Err(e) => {
+                        eprintln!("Unable to access interface {name}: {}", error_message(&e));
+                        continue;
+                    }
+                };
+                match field {
+                    Some(field) => match ugly(name, &device, field, true) {
+                        Some(out) => output(&out),
+                        None => return invalid_field(field),
+                    },
+                    None => {
+                        let mut out = pretty(name, &device, now());
+                        if i + 1 < names.len() {
+                            out.push('\n');
+                        }
+                        output(&out);
+                    }
+                }
+                ret = 0;
+            }
+            ret
+        }
+        Some("interfaces") => {
+            if field.is_some() {
+                eprintln!("Usage: boringtun show {{ <interface> | all | interfaces }} [FIELD]");
+                return 1;
+            }
+            match list_interfaces() {
+                Ok(names) if names.is_empty() => 0,
+                Ok(names) => {
+                    output(&format!("{}\n", names.join(" ")));
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Unable to list interfaces: {}", io_error_message(&e));
+                    1
+                }
+            }
+        }
+        Some(name) => {
+            let device = match Client::for_interface(name).get() {
+                Ok(device) => device,
+                Err(e) => {
+                    eprintln!("Unable to access interface: {}", error_message(&e));
+                    return 1;
+                }
+            };
+            match field {
+                Some(field) => match ugly(name, &device, field, false) {
+                    Some(out) => {
+                        output(&out);
+                        0
+                    }
+                    None => invalid_field(field),
+                },
+                None => {
+                    output(&pretty(name, &device, now()));
+                    0
+                }
+            }
+        }
+    }
+}
+
+fn invalid_field(field: &str) -> i32 {
+    eprintln!("Invalid parameter: `{field}'");
+    1
+}
+
+fn showconf(name: &str) -> i32 {
+    match Client::for_interface(name).get() {
+        Ok(device) => {
+            output(&Config::from(&device).to_string());
+            0
+        }
+        Err(e) => {
+            eprintln!("Unable to access interface: {}", error_message(&e));
+            1
+        }
+    }
+}
+
+fn syncconf(name: &str, path: &str) -> i32 {
+    let config = read_to_string(path)
+        .map_err(|e| io_error_message(&e))
+        .and_then(|s| s.parse::<Config>().map_err(|e| e.to_string()));
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{path}: {e}");
+            return 1;
+        }
+    };
+
+    let mut client = Client::for_interface(name);
+    let result = client
+        .get()
+        .and_then(|current| client.set(&config.to_sync_request(&current)));
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Unable to modify interface: {}", error_message(&e));
+            1
+        }
+    }
+}
+
+fn set(name: &str, args: &[&str]) -> i32 {
+    let request = match parse_set(args) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    match Client::for_interface(name).set(&request) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Unable to modify interface: {}", error_message(&e));
+            1
+        }
+    }
+}
+
+/// Parse the arguments of `set` into a single transaction
+fn parse_set(args: &[&str]) -> Result<SetRequest, String> {
+    let mut request = SetRequest::default();
+    let mut args = args.iter().copied();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for argument: {arg}"))
+        };
+
+        match (arg, request.peers.last_mut()) {
+            ("listen-port", None) => {
+                let val = value()?;
+                let port = val.parse().map_err(|_| format!("Invalid port: {val}"))?;
+                request.listen_port = Some(port);
+            }
+            ("fwmark", None) => request.fwmark = Some(config::parse_fwmark(value()?)?),
+            ("private-key", None) => {
+                request.private_key = Some(x25519::StaticSecret::from(read_key_file(value()?)?));
+            }
+            ("peer", _) => {
+                let val = value()?;
+                let key = val
+                    .parse::<KeyBytes>()
+                    .map_err(|_| format!("Key is not the correct length or format: `{val}'"))?;
+                let key: [u8; 32] = key.raw_bytes().try_into().unwrap();
+                request
+                    .peers
+                    .push(PeerUpdate::new(x25519::PublicKey::from(key)));
+            }
+            ("remove", Some(peer)) => peer.remove = true,
+            ("endpoint", Some(peer)) => peer.endpoint = Some(config::parse_endpoint(value()?)?),
+            ("allowed-ips", Some(peer)) => {
+                let val = value()?;
+                peer.replace_allowed_ips = true;
+                peer.allowed_ips = val
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|ip| !ip.is_empty())
+                    .map(config::parse_allowed_ip)
+                    .collect::<Result<_, _>>()?;
+            }
+            ("persistent-keepalive", Some(peer)) => {
+                let interval = config::parse_persistent_keepalive(value()?)?;
+                peer.persistent_keepalive_interval = Some(interval);
+            }
+            ("preshared-key", Some(peer)) => peer.preshared_key = Some(read_key_file(value()?)?),
+            _ => return Err(format!("Invalid argument: {arg}")),
+        }
+    }
+
+    Ok(request)
+}
+
+/// Read a base64 key from a file. An empty file, such as `/dev/null`, is an all-zero key,
+/// which removes a preshared key.
+fn read_key_file(path: &str) -> Result<[u8; 32], String> {
+    let contents = read_to_string(path).map_err(|e| format!("{path}: {}", io_error_message(&e)))?;
+    let contents = contents.trim();
+    if contents.is_empty() {
+        return Ok([0u8; 32]);
+    }
+    let key = contents
+        .parse::<KeyBytes>()
+        .map_err(|_| format!("Key is not the correct length or format: `{path}'"))?;
+    Ok(key.raw_bytes().try_into().unwrap())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A key in base64, or `(none)` when it is all zeros or not set
+fn key(bytes: Option<&[u8; 32]>) -> String {
+    match bytes {
+        Some(bytes) if *bytes != [0u8; 32] => encode_key(bytes),
+        _ => "(none)".to_owned(),
+    }
+}
+
+fn encode_key(bytes: &[u8]) -> String {
+    KeyBytes::from_bytes(bytes).unwrap().to_base64()
+}
+
+fn masked_key(bytes: &[u8; 32]) -> String {
+    if env::var("WG_HIDE_KEYS").is_ok_and(|v| v == "never") {
+        key(Some(bytes))
+    } else {
+        "(hidden)".to_owned()
+    }
+}
+
+fn private_key(device: &Interface) -> Option<[u8; 32]> {
+    device
+        .private_key
+        .as_ref()
+        .map(x25519::StaticSecret::to_bytes)
+}
+
+fn public_key(device: &Interface) -> Option<[u8; 32]> {
+    device.public_key().map(|k| k.to_bytes())
+}
+
+fn allowed_ips(peer: &client::Peer, separator: &str) -> Option<String> {
+    let ips: Vec<_> = peer
+        .allowed_ips
+        .iter()
+        .map(|ip| format!("{}/{}", ip.addr, ip.cidr))
+        .collect();
+    (!ips.is_empty()).then(|| ips.join(separator))
+}
+
+fn handshake_secs(peer: &client::Peer) -> u64 {
+    peer.last_handshake_time.map_or(0, |t| t.as_secs())
+}
+
+/// The human readable output of `show`
+fn pretty(name: &str, device: &Interface, now: u64) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "interface: {name}");
+    if let Some(public_key) = public_key(device) {
+        let _ = writeln!(out, "  public key: {}", key(Some(&public_key)));
+    }
+    if let Some(private_key) = private_key(device) {
+        let _ = writeln!(out, "  private key: {}", masked_key(&private_key));
+    }
+    if let Some(port) = device.listen_port.filter(|&p| p != 0) {
+        let _ = writeln!(out, "  listening port: {port}");
+    }
+    if let Some(fwmark) = device.fwmark.filter(|&m| m != 0) {
+        let _ = writeln!(out, "  fwmark: {fwmark:#x}");
+    }
+
+    // The most recently active peers come first, and those that never connected last
+    let mut peers: Vec<_> = device.peers.iter().collect();
+    peers.sort_by_key(|p| Reverse(p.last_handshake_time));
+    if !peers.is_empty() {
+        out.push('\n');
+    }
+
+    for (i, peer) in peers.iter().enumerate() {
+        let _ = writeln!(out, "peer: {}", key(Some(peer.public_key.as_bytes())));
+        if let Some(ref preshared_key) = peer.preshared_key {
+            let _ = writeln!(out, "  preshared key: {}", masked_key(preshared_key));
+        }
+        if let Some(endpoint) = peer.endpoint {
+            let _ = writeln!(out, "  endpoint: {endpoint}");
+        }
+        let ips = allowed_ips(peer, ", ");
+        let _ = writeln!(out, "  allowed ips: {}", ips.as_deref().unwrap_or("(none)"));
+        let handshake = handshake_secs(peer);
+        if handshake != 0 {
+            let _ = writeln!(out, "  latest handshake: {}", ago(handshake, now));
+        }
+        if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+            let _ = writeln!(
+                out,
+                "  transfer: {} received, {} sent",
+                bytes(peer.rx_bytes),
+                bytes(peer.tx_bytes)
+            );
+        }
+        if let Some(interval) = peer.persistent_keepalive_interval.filter(|&i| i != 0) {
+            let _ = writeln!(
+                out,
+                "  persistent keepalive: every {}",
+                pretty_time(interval.into())
+            );
+        }
+        if i + 1 < peers.len() {
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+/// The tab separated output of `show` for a single field, `None` for an unknown field
+fn ugly(name: &str, device: &Interface, field: &str, with_interface: bool) -> Option<String> {
+    let prefix = if with_interface {
+        format!("{name}\t")
+    } else {
+        String::new()
+    };
+    let mut out = String::new();
+    let peer_lines = |out: &mut String, f: &dyn Fn(&client::Peer) -> String| {
+        for peer in &device.peers {
+            let _ = writeln!(
+                out,
+                "{prefix}{}\t{}",
+                key(Some(peer.public_key.as_bytes())),
+                f(peer)
+            );
+        }
+    };
+
+    match field {
+        "public-key" => {
+            let _ = writeln!(out, "{prefix}{}", key(public_key(device).as_ref()));
+        }
+        "private-key" => {
+            let _ = writeln!(out, "{prefix}{}", key(private_key(device).as_ref()));
+        }
+        "listen-port" => {
+            let _ = writeln!(out, "{prefix}{}", device.listen_port.unwrap_or(0));
+        }
+        "fwmark" => {
+            let _ = writeln!(out, "{prefix}{}", fwmark(device));
+        }
+        "endpoints" => peer_lines(&mut out, &|peer| endpoint(peer)),
+        "allowed-ips" => peer_lines(&mut out, &|peer| {
+            allowed_ips(peer, " ").unwrap_or_else(|| "(none)".to_owned())
+        }),
+        "latest-handshakes" => peer_lines(&mut out, &|peer| handshake_secs(peer).to_string()),
+        "transfer" => peer_lines(&mut out, &|peer| {
+            format!("{}\t{}", peer.rx_bytes, peer.tx_bytes)
+        }),
+        "persistent-keepalive" => peer_lines(&mut out, &keepalive),
+        "preshared-keys" => peer_lines(&mut out, &|peer| key(peer.preshared_key.as_ref())),
+        "peers" => {
+            for peer in &device.peers {
+                let _ = writeln!(out, "{prefix}{}", key(Some(peer.public_key.as_bytes())));
+            }
+        }
+        "dump" => {
+            let _ = writeln!(
+                out,
+                "{prefix}{}\t{}\t{}\t{}",
+                key(private_key(device).as_ref()),
+                key(public_key(device).as_ref()),
+                device.listen_port.unwrap_or(0),
+                fwmark(device)
+            );
+            for peer in &device.peers {
+                let _ = writeln!(
+                    out,
+                    "{prefix}{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    key(Some(peer.public_key.as_bytes())),
+                    key(peer.preshared_key.as_ref()),
+                    endpoint(peer),
+                    allowed_ips(peer, ",").as_deref().unwrap_or("(none)"),
+                    handshake_secs(peer),
+                    peer.rx_bytes,
+                    peer.tx_bytes,
+                    keepalive(peer)
+                );
+            }
+        }
+        _ => return None,
+    }
+
+    Some(out)
+}
+
+fn fwmark(device: &Interface) -> String {
+    match device.fwmark.filter(|&m| m != 0) {
+        Some(fwmark) => format!("{fwmark:#x}"),
+        None => "off".to_owned(),
+    }
+}
+
+fn endpoint(peer: &client::Peer) -> String {
+    peer.endpoint
+        .map_or_else(|| "(none)".to_owned(), |e| e.to_string())
+}
+
+fn keepalive(peer: &client::Peer) -> String {
+    match peer.persistent_keepalive_interval.filter(|&i| i != 0) {
+        Some(interval) => interval.to_string(),
+        None => "off".to_owned(),
+    }
+}
+
+fn ago(secs: u64, now: u64) -> String {
+    match now.cmp(&secs) {
+        Ordering::Equal => "Now".to_owned(),
+        Ordering::Less => {
+            "(System clock wound backward; connection problems may ensue.)".to_owned()
+        }
+        Ordering::Greater => format!("{} ago", pretty_time(now - secs)),
+    }
+}
+
+/// A duration like "1 day, 2 hours, 5 seconds", leaving out the units that are zero
+fn pretty_time(mut left: u64) -> String {
+    let mut parts = Vec::new();
+    for (unit, secs) in [
+        ("year", 365 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ] {
+        let n = left / secs;
+        left %= secs;
+        if n != 0 {
+            parts.push(format!("{n} {unit}{}", if n == 1 { "" } else { "s" }));
+        }
+    }
+    parts.join(", ")
+}
+
+fn bytes(b: u64) -> String {
+    const KIB: u64 = 1024;
+    #[allow(clippy::cast_precision_loss)]
+    let scaled = |unit: u64| b as f64 / unit as f64;
+    if b < KIB {
+        format!("{b} B")
+    } else if b < KIB.pow(2) {
+        format!("{:.2} KiB", scaled(KIB))
+    } else if b < KIB.pow(3) {
+        format!("{:.2} MiB", scaled(KIB.pow(2)))
+    } else if b < KIB.pow(4) {
+        format!("{:.2} GiB", scaled(KIB.pow(3)))
+    } else {
+        format!("{:.2} TiB", scaled(KIB.pow(4)))
+    }
+}
+
+/// The error as `perror` would print it
+fn error_message(e: &client::Error) -> String {
+    match e {
+        client::Error::Io(e) => io_error_message(e),
+        client::Error::Errno(errno) => io_error_message(&io::Error::from_raw_os_error(*errno)),
+        client::Error::InvalidArgument => "Invalid argument".to_owned(),
+        client::Error::Protocol => "Protocol error".to_owned(),
+        client::Error::AddressInUse => "Address already in use".to_owned(),
+        client::Error::PermissionDenied => "Permission denied".to_owned(),
+        client::Error::NotFound => "No such file or directory".to_owned(),
+        client::Error::Busy => "Device or resource busy".to_owned(),
+        client::Error::RemoteIo => "Input/output error".to_owned(),
+        e => e.to_string(),
+    }
+}
+
+/// An i/o error without the " (os error N)" suffix that Rust adds
+fn io_error_message(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.rsplit_once(" (os error ") {
+        Some((message, _)) => message.to_owned(),
+        None => message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const KEY_A: &str = "YFjESZYbWRxQVnHsOVYM3zM6rr7f6yyKA7cUp+ElWHk=";
+    const KEY_B: &str = "GNnzFVwRLzi/WFcJ4/hwZjJCd0Q6tqYdNQbTyzZKPUo=";
+
+    fn public_key(key: &str) -> x25519::PublicKey {
+        let key: [u8; 32] = key
+            .parse::<KeyBytes>()
+            .unwrap()
+            .raw_bytes()
+            .try_into()
+            .unwrap();
+        x25519::PublicKey::from(key)
+    }
+
+    fn device() -> Interface {
+        let mut active = client::Peer::new(public_key(KEY_B));
+        active.endpoint = Some("[fd00::1]:51820".parse().unwrap());
+        active.allowed_ips = vec!["10.0.0.2/32".parse().unwrap(), "fd00::/64".parse().unwrap()];
+        active.last_handshake_time = Some(Duration::from_secs(10_000 - 3725));
+        active.rx_bytes = 1152;
+        active.tx_bytes = 92;
+        active.persistent_keepalive_interval = Some(25);
+        Interface {
+            private_key: Some(x25519::StaticSecret::from([1u8; 32])),
+            listen_port: Some(51820),
+            fwmark: Some(0x10),
+            // The idle peer is listed first, but shown last
+            peers: vec![client::Peer::new(public_key(KEY_A)), active],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn formats_numbers_like_wg() {
+        assert_eq!(pretty_time(1), "1 second");
+        assert_eq!(pretty_time(3725), "1 hour, 2 minutes, 5 seconds");
+        assert_eq!(
+            pretty_time(366 * 24 * 60 * 60 + 60),
+            "1 year, 1 day, 1 minute"
+        );
+        assert_eq!(ago(10, 10), "Now");
+        assert_eq!(bytes(1023), "1023 B");
+        assert_eq!(bytes(1152), "1.12 KiB");
+        assert_eq!(bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+    }
+
+    #[test]
+    fn pretty_output() {
+        let public = encode_key(device().public_key().unwrap().as_bytes());
+        assert_eq!(
+            pretty("wg0", &device(), 10_000),
+            format!(
+                "interface: wg0\n  \
+                 public key: {public}\n  \
+                 private key: (hidden)\n  \
+                 listening port: 51820\n  \
+                 fwmark: 0x10\n\
+                 \n\
+                 peer: {KEY_B}\n  \
+                 endpoint: [fd00::1]:51820\n  \
+                 allowed ips: 10.0.0.2/32, fd00::/64\n  \
+                 latest handshake: 1 hour, 2 minutes, 5 seconds ago\n  \
+                 transfer: 1.12 KiB received, 92 B sent\n  \
+                 persistent keepalive: every 25 seconds\n\
+                 \n\
+                 peer: {KEY_A}\n  \
+                 allowed ips: (none)\n"
+            )
+        );
+    }
+
+    #[test]
+    fn field_output() {
+        let device = device();
+        let private = encode_key(&[1u8; 32]);
+        let public = encode_key(device.public_key().unwrap().as_bytes());
+
+        assert_eq!(
+            ugly("wg0", &device, "dump", false).unwrap(),
+            format!(
+                "{private}\t{public}\t51820\t0x10\n\
+                 {KEY_A}\t(none)\t(none)\t(none)\t0\t0\t0\toff\n\
+                 {KEY_B}\t(none)\t[fd00::1]:51820\t10.0.0.2/32,fd00::/64\t6275\t1152\t92\t25\n"
+            )
+        );
+        assert_eq!(
+            ugly("wg0", &device, "allowed-ips", true).unwrap(),
+            format!(
+                "wg0\t{KEY_A}\t(none)\n\
+                 wg0\t{KEY_B}\t10.0.0.2/32 fd00::/64\n"
+            )
+        );
+        assert_eq!(
+            ugly("wg0", &device, "peers", true).unwrap(),
+            format!("wg0\t{KEY_A}\nwg0\t{KEY_B}\n")
+        );
+        assert_eq!(
+            ugly("wg0", &Interface::default(), "fwmark", false).unwrap(),
+            "off\n"
+        );
+        assert_eq!(ugly("wg0", &device, "bogus", false), None);
+    }
+
+    #[test]
+    fn parse_set_arguments() {
+        let request = parse_set(&[
+            "listen-port",
+            "51821",
+            "fwmark",
+            "off",
+            "peer",
+            KEY_A,
+            "remove",
+            "peer",
+            KEY_B,
+            "endpoint",
+            "127.0.0.1:1",
+            "persistent-keepalive",
+            "off",
+            "allowed-ips",
+            "10.0.0.2, fd00::/64",
+        ])
+        .unwrap();
+        assert_eq!(request.listen_port, Some(51821));
+        assert_eq!(request.fwmark, Some(0));
+        assert!(request.peers[0].remove);
+        let peer = &request.peers[1];
+        assert_eq!(peer.public_key, public_key(KEY_B));
+        assert_eq!(peer.persistent_keepalive_interval, Some(0));
+        assert!(peer.replace_allowed_ips);
+        assert_eq!(peer.allowed_ips.len(), 2);
+
+        assert!(parse_set(&["remove"]).is_err());
+        assert!(parse_set(&["peer", KEY_A, "listen-port", "1"]).is_err());
+        assert!(parse_set(&["listen-port"]).is_err());
+    }
+}
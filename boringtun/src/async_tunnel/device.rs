@@ -0,0 +1,270 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc};
+
+use parking_lot::RwLock;
+use tokio::net::UdpSocket;
+
+use super::{Inner, MAX_PACKET_SIZE, MAX_UDP_SIZE, Peer, TIMER_INTERVAL, receive_loop};
+use crate::device::{IndexLfsr, allowed_ips::AllowedIps, peer::AllowedIP};
+use crate::noise::{Packet, Tunn, handshake::parse_handshake_anon, rate_limiter::RateLimiter};
+use crate::x25519;
+
+/// The number of handshakes per second tolerated before replying with cookies
+const HANDSHAKE_RATE_LIMIT: u64 = 100;
+
+/// A peer of an [`AsyncDevice`]
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub public_key: x25519::PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<AllowedIP>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+struct Peers {
+    by_key: HashMap<x25519::PublicKey, (u32, Arc<Peer>)>,
+    by_idx: HashMap<u32, Arc<Peer>>,
+    allowed_ips: AllowedIps<Arc<Peer>>,
+    next_index: IndexLfsr,
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Peers {
+            by_key: HashMap::new(),
+            by_idx: HashMap::new(),
+            allowed_ips: AllowedIps::new(),
+            next_index: IndexLfsr::default(),
+        }
+    }
+}
+
+impl Peers {
+    fn remove(&mut self, public_key: &x25519::PublicKey) -> bool {
+        let Some((index, peer)) = self.by_key.remove(public_key) else {
+            return false;
+        };
+        self.by_idx.remove(&index);
+        self.allowed_ips.remove(&|p| Arc::ptr_eq(p, &peer));
+        true
+    }
+}
+
+/// A WireGuard device with many peers on one UDP socket, routing the packets of an [`Inner`]
+/// side to the peer whose allowed IPs contain their destination
+pub struct AsyncDevice<I> {
+    private_key: x25519::StaticSecret,
+    public_key: x25519::PublicKey,
+    rate_limiter: Arc<RateLimiter>,
+    udp: UdpSocket,
+    inner: I,
+    peers: RwLock<Peers>,
+}
+
+impl<I: Inner> AsyncDevice<I> {
+    /// Create a device without peers
+    pub fn new(private_key: x25519::StaticSecret, udp: UdpSocket, inner: I) -> Self {
+        let public_key = x25519::PublicKey::from(&private_key);
+        AsyncDevice {
+            rate_limiter: Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT)),
+            private_key,
+            public_key,
+            udp,
+            inner,
+            peers: RwLock::default(),
+        }
+    }
+
+    /// The public key of the device
+    pub fn public_key(&self) -> x25519::PublicKey {
+        self.public_key
+    }
+
+    /// The UDP socket of the device
+    pub fn socket(&self) -> &UdpSocket {
+        &self.udp
+    }
+
+    /// Add a peer, replacing any peer with the same public key
+    pub fn add_peer(&self, config: PeerConfig) {
+        let mut peers = self.peers.write();
+        peers.remove(&config.public_key);
+
+        let index = peers.next_index.next();
+        let tunn = Tunn::new(
+            self.private_key.clone(),
+            config.public_key,
+            config.preshared_key,
+            config.persistent_keepalive,
+            index,
+            Some(Arc::clone(&self.rate_limiter)),
+        );
+        let peer = Arc::new(Peer::new(tunn, config.endpoint));
+        for AllowedIP { addr, cidr } in config.allowed_ips {
+            peers
+                .allowed_ips
+                .insert(addr, u32::from(cidr), Arc::clone(&peer));
+        }
+        peers.by_idx.insert(index, Arc::clone(&peer));
+        peers.by_key.insert(config.public_key, (index, peer));
+    }
+
+    /// Remove a peer, returns false if there was none with the public key
+    pub fn remove_peer(&self, public_key: &x25519::PublicKey) -> bool {
+        self.peers.write().remove(public_key)
+    }
+
+    /// The endpoint of a peer, see [`super::AsyncTunnel::endpoint`]
+    pub fn peer_endpoint(&self, public_key: &x25519::PublicKey) -> Option<SocketAddr> {
+        let peers = self.peers.read();
+        peers.by_key.get(public_key)?.1.endpoint()
+    }
+
+    /// Run the device, until receiving from the socket or the inner side fails
+    pub async fn run(&self) -> io::Result<()> {
+        tokio::try_join!(self.run_network(), self.run_inner(), self.run_timers()).map(drop)
+    }
+
+    fn find_peer(&self, packet: &Packet<'_>) -> Option<Arc<Peer>> {
+        let receiver_idx = match packet {
+            Packet::HandshakeInit(p) => {
+                let half = parse_handshake_anon(&self.private_key, &self.public_key, p).ok()?;
+                let public_key = x25519::PublicKey::from(half.peer_static_public);
+                return self
+                    .peers
+                    .read()
+                    .by_key
+                    .get(&public_key)
+                    .map(|p| Arc::clone(&p.1));
+            }
+            Packet::HandshakeResponse(p) => p.receiver_idx,
+            Packet::PacketCookieReply(p) => p.receiver_idx,
+            Packet::PacketData(p) => p.receiver_idx,
+        };
+        self.peers.read().by_idx.get(&(receiver_idx >> 8)).cloned()
+    }
+
+    async fn run_network(&self) -> io::Result<()> {
+        receive_loop(
+            &self.udp,
+            &self.inner,
+            &self.rate_limiter,
+            |packet| self.find_peer(packet),
+            |peer, ip| {
+                let peers = self.peers.read();
+                peers
+                    .allowed_ips
+                    .find(ip)
+                    .is_some_and(|p| Arc::ptr_eq(p, peer))
+            },
+        )
+        .await
+    }
+
+    async fn run_inner(&self) -> io::Result<()> {
+        let mut src = vec![0u8; MAX_PACKET_SIZE];
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        loop {
+            let len = self.inner.recv(&mut src).await?;
+            let packet = &src[..len];
+            let peer = Tunn::dst_address(packet)
+                .and_then(|addr| self.peers.read().allowed_ips.find(addr).cloned());
+            match peer {
+                Some(peer) => peer.send_packet(&self.udp, packet, &mut dst).await,
+                None => tracing::trace!(message = "No peer for packet"),
+            }
+        }
+    }
+
+    async fn run_timers(&self) -> io::Result<()> {
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        let mut interval = tokio::time::interval(TIMER_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.rate_limiter.reset_count();
+            let peers: Vec<_> = self.peers.read().by_idx.values().cloned().collect();
+            for peer in peers {
+                peer.update_timers(&self.udp, &mut dst).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use aead::rand_core::OsRng;
+
+    use super::super::{AsyncTunnel, PacketChannel, tests::ipv4_packet};
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_between_peers() {
+        let server_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let server_udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = server_udp.local_addr().unwrap();
+        let (server_inner, server_app) = PacketChannel::pair(16);
+        let server = Arc::new(AsyncDevice::new(server_key, server_udp, server_inner));
+
+        // Two clients, each allowed its own address
+        let mut clients = Vec::new();
+        for i in 1..=2u8 {
+            let key = x25519::StaticSecret::random_from_rng(OsRng);
+            let ip = Ipv4Addr::new(10, 0, 0, i);
+            server.add_peer(PeerConfig {
+                public_key: x25519::PublicKey::from(&key),
+                preshared_key: None,
+                endpoint: None,
+                allowed_ips: vec![AllowedIP {
+                    addr: ip.into(),
+                    cidr: 32,
+                }],
+                persistent_keepalive: None,
+            });
+            let (inner, app) = PacketChannel::pair(16);
+            let tunnel = AsyncTunnel::new(
+                Tunn::new(key, server.public_key(), None, None, u32::from(i), None),
+                UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap(),
+                Some(server_addr),
+                inner,
+            );
+            tokio::spawn(async move { tunnel.run().await });
+            clients.push((ip, app));
+        }
+        let running = Arc::clone(&server);
+        let server_task = tokio::spawn(async move { running.run().await });
+
+        let mut buf = [0u8; 100];
+        let gateway = Ipv4Addr::new(10, 0, 0, 254);
+        for (ip, app) in &clients {
+            let packet = ipv4_packet(*ip, gateway);
+            app.send(&packet).await.unwrap();
+            let len = server_app.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], packet);
+        }
+
+        // Replies are routed by destination
+        for (ip, app) in clients.iter().rev() {
+            let packet = ipv4_packet(gateway, *ip);
+            server_app.send(&packet).await.unwrap();
+            let len = app.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], packet);
+        }
+
+        // A source IP that is not allowed for the peer is dropped
+        let (_, app) = &clients[0];
+        app.send(&ipv4_packet(Ipv4Addr::new(10, 0, 0, 2), gateway))
+            .await
+            .unwrap();
+        let allowed = ipv4_packet(Ipv4Addr::new(10, 0, 0, 1), gateway);
+        app.send(&allowed).await.unwrap();
+        let len = server_app.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], allowed);
+
+        server_task.abort();
+    }
+}
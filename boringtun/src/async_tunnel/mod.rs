@@ -0,0 +1,367 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! WireGuard tunnels on tokio, enabled with the `tokio` feature.
+//!
+//! [`AsyncTunnel`] runs a single [`Tunn`] over a UDP socket, and [`AsyncDevice`] routes between
+//! many peers by their allowed IPs, like [`crate::device`] does. Both exchange IP packets with
+//! an [`Inner`] side, such as a [`PacketChannel`], and handle timers and the packets queued
+//! during handshakes themselves, so `run` is all there is to drive.
+
+mod device;
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::noise::{Packet, Tunn, TunnResult, errors::WireGuardError, rate_limiter::RateLimiter};
+
+pub use device::{AsyncDevice, PeerConfig};
+
+const MAX_UDP_SIZE: usize = (1 << 16) - 1;
+/// Room for a packet of the inner side, with the data message overhead
+const MAX_PACKET_SIZE: usize = MAX_UDP_SIZE - 32;
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The inner side of a tunnel, where IP packets come from and go to
+pub trait Inner: Send + Sync {
+    /// Receive the next packet to send through the tunnel into `buf`, and return its length
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Deliver a packet received through the tunnel
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// An [`Inner`] side made of channels, for packets produced and consumed by other tasks
+pub struct PacketChannel {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl PacketChannel {
+    /// Create two connected ends, packets sent on one are received on the other. One end is
+    /// given to the tunnel, and the other is used by the application.
+    #[must_use]
+    pub fn pair(capacity: usize) -> (PacketChannel, PacketChannel) {
+        let (a_tx, a_rx) = mpsc::channel(capacity);
+        let (b_tx, b_rx) = mpsc::channel(capacity);
+        (
+            PacketChannel {
+                tx: a_tx,
+                rx: tokio::sync::Mutex::new(b_rx),
+            },
+            PacketChannel {
+                tx: b_tx,
+                rx: tokio::sync::Mutex::new(a_rx),
+            },
+        )
+    }
+}
+
+impl Inner for PacketChannel {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::BrokenPipe)?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// A [`Tunn`] with the endpoint of its peer, shared by [`AsyncTunnel`] and [`AsyncDevice`]
+struct Peer {
+    tunn: Mutex<Tunn>,
+    endpoint: Mutex<Option<SocketAddr>>,
+}
+
+impl Peer {
+    fn new(tunn: Tunn, endpoint: Option<SocketAddr>) -> Peer {
+        Peer {
+            tunn: Mutex::new(tunn),
+            endpoint: Mutex::new(endpoint),
+        }
+    }
+
+    fn endpoint(&self) -> Option<SocketAddr> {
+        *self.endpoint.lock()
+    }
+
+    /// Encapsulate a packet of the inner side, and send it to the endpoint
+    async fn send_packet(&self, udp: &UdpSocket, packet: &[u8], dst: &mut [u8]) {
+        let result = self.tunn.lock().encapsulate(packet, dst);
+        match result {
+            TunnResult::WriteToNetwork(packet) => self.send_to_endpoint(udp, packet).await,
+            TunnResult::Err(e) => tracing::debug!(message = "Encapsulate error", error = ?e),
+            _ => {}
+        }
+    }
+
+    /// Decapsulate a datagram from `addr` verified by the rate limiter, and deliver the packet
+    /// to the inner side if its source IP is allowed
+    async fn receive(
+        &self,
+        udp: &UdpSocket,
+        inner: &impl Inner,
+        addr: SocketAddr,
+        packet: Packet<'_>,
+        dst: &mut [u8],
+        is_allowed: impl Fn(IpAddr) -> bool,
+    ) -> io::Result<()> {
+        let result = self.tunn.lock().handle_verified_packet(packet, dst);
+        let packet = match result {
+            TunnResult::Done => None,
+            TunnResult::Err(e) => {
+                tracing::debug!(message = "Decapsulate error", error = ?e);
+                return Ok(());
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                *self.endpoint.lock() = Some(addr);
+                send_to(udp, packet, addr).await;
+                // A handshake completed, send the packets queued in the meantime
+                self.send_queued(udp, dst).await;
+                return Ok(());
+            }
+            TunnResult::WriteToTunnelV4(packet, src) => Some((packet, IpAddr::from(src))),
+            TunnResult::WriteToTunnelV6(packet, src) => Some((packet, IpAddr::from(src))),
+        };
+
+        // The packet is authenticated, so the peer roamed to the address it came from
+        *self.endpoint.lock() = Some(addr);
+        match packet {
+            Some((packet, src)) if is_allowed(src) => inner.send(packet).await,
+            Some((_, src)) => {
+                tracing::debug!(message = "Source IP not allowed", ?src);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Send the packets queued while a handshake was in progress
+    async fn send_queued(&self, udp: &UdpSocket, dst: &mut [u8]) {
+        loop {
+            let result = self.tunn.lock().decapsulate(None, &[], dst);
+            match result {
+                TunnResult::WriteToNetwork(packet) => self.send_to_endpoint(udp, packet).await,
+                _ => break,
+            }
+        }
+    }
+
+    /// Send handshakes and keepalives when they are due
+    async fn update_timers(&self, udp: &UdpSocket, dst: &mut [u8]) {
+        let result = self.tunn.lock().update_timers(dst);
+        match result {
+            TunnResult::WriteToNetwork(packet) => self.send_to_endpoint(udp, packet).await,
+            TunnResult::Err(WireGuardError::ConnectionExpired) => {}
+            TunnResult::Err(e) => tracing::debug!(message = "Timer error", error = ?e),
+            _ => {}
+        }
+    }
+
+    async fn send_to_endpoint(&self, udp: &UdpSocket, packet: &[u8]) {
+        match self.endpoint() {
+            Some(addr) => send_to(udp, packet, addr).await,
+            None => tracing::trace!(message = "No endpoint, dropping packet"),
+        }
+    }
+}
+
+/// Receive datagrams from the socket, verify them with the rate limiter, and pass them to the
+/// peer `find_peer` returns. `is_allowed` checks the source IPs of the peer.
+async fn receive_loop<P: Deref<Target = Peer>>(
+    udp: &UdpSocket,
+    inner: &impl Inner,
+    rate_limiter: &RateLimiter,
+    find_peer: impl Fn(&Packet<'_>) -> Option<P>,
+    is_allowed: impl Fn(&P, IpAddr) -> bool,
+) -> io::Result<()> {
+    let mut src = vec![0u8; MAX_UDP_SIZE];
+    let mut dst = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let (len, addr) = udp.recv_from(&mut src).await?;
+        let packet = match rate_limiter.verify_packet(Some(addr.ip()), &src[..len], &mut dst) {
+            Ok(packet) => packet,
+            Err(TunnResult::WriteToNetwork(cookie)) => {
+                send_to(udp, cookie, addr).await;
+                continue;
+            }
+            Err(TunnResult::Err(e)) => {
+                tracing::debug!(message = "Invalid datagram", ?addr, error = ?e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let Some(peer) = find_peer(&packet) else {
+            tracing::trace!(message = "Unknown peer", ?addr);
+            continue;
+        };
+        peer.receive(udp, inner, addr, packet, &mut dst, |ip| {
+            is_allowed(&peer, ip)
+        })
+        .await?;
+    }
+}
+
+/// Send a datagram, errors are not fatal to the tunnel so they are only logged
+async fn send_to(udp: &UdpSocket, packet: &[u8], addr: SocketAddr) {
+    if let Err(e) = udp.send_to(packet, addr).await {
+        tracing::debug!(message = "Send error", ?addr, error = ?e);
+    }
+}
+
+/// A single WireGuard tunnel, carrying the packets of an [`Inner`] side to one peer
+pub struct AsyncTunnel<I> {
+    peer: Peer,
+    rate_limiter: Arc<RateLimiter>,
+    udp: UdpSocket,
+    inner: I,
+}
+
+impl<I: Inner> AsyncTunnel<I> {
+    /// Create a tunnel to the peer of `tunn` over the socket, sending to `endpoint` until the
+    /// peer is heard from at another address
+    pub fn new(tunn: Tunn, udp: UdpSocket, endpoint: Option<SocketAddr>, inner: I) -> Self {
+        AsyncTunnel {
+            rate_limiter: tunn.rate_limiter(),
+            peer: Peer::new(tunn, endpoint),
+            udp,
+            inner,
+        }
+    }
+
+    /// The address the peer is sent to
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.peer.endpoint()
+    }
+
+    /// The UDP socket of the tunnel
+    pub fn socket(&self) -> &UdpSocket {
+        &self.udp
+    }
+
+    /// See [`Tunn::stats`]
+    pub fn stats(&self) -> (Option<Duration>, usize, usize, f32, Option<u32>) {
+        self.peer.tunn.lock().stats()
+    }
+
+    /// Run the tunnel, until receiving from the socket or the inner side fails
+    pub async fn run(&self) -> io::Result<()> {
+        tokio::try_join!(self.run_network(), self.run_inner(), self.run_timers()).map(drop)
+    }
+
+    async fn run_network(&self) -> io::Result<()> {
+        receive_loop(
+            &self.udp,
+            &self.inner,
+            &self.rate_limiter,
+            |_| Some(&self.peer),
+            |_, _| true,
+        )
+        .await
+    }
+
+    async fn run_inner(&self) -> io::Result<()> {
+        let mut src = vec![0u8; MAX_PACKET_SIZE];
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        loop {
+            let len = self.inner.recv(&mut src).await?;
+            self.peer
+                .send_packet(&self.udp, &src[..len], &mut dst)
+                .await;
+        }
+    }
+
+    async fn run_timers(&self) -> io::Result<()> {
+        let mut dst = vec![0u8; MAX_UDP_SIZE];
+        let mut interval = tokio::time::interval(TIMER_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.peer.update_timers(&self.udp, &mut dst).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use aead::rand_core::OsRng;
+
+    use super::*;
+    use crate::x25519::{PublicKey, StaticSecret};
+
+    /// An IPv4 header without payload, from `src`
+    pub(super) fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet
+    }
+
+    #[tokio::test]
+    async fn tunnels_packets() {
+        let (key_a, key_b) = (
+            StaticSecret::random_from_rng(OsRng),
+            StaticSecret::random_from_rng(OsRng),
+        );
+        let (public_a, public_b) = (PublicKey::from(&key_a), PublicKey::from(&key_b));
+        let udp_a = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let udp_b = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr_b = udp_b.local_addr().unwrap();
+
+        let (inner_a, app_a) = PacketChannel::pair(16);
+        let (inner_b, app_b) = PacketChannel::pair(16);
+        let tunnel_a = AsyncTunnel::new(
+            Tunn::new(key_a, public_b, None, None, 1, None),
+            udp_a,
+            Some(addr_b),
+            inner_a,
+        );
+        let tunnel_b = AsyncTunnel::new(
+            Tunn::new(key_b, public_a, None, None, 2, None),
+            udp_b,
+            None,
+            inner_b,
+        );
+        let a = tokio::spawn(async move { tunnel_a.run().await });
+        let b = tokio::spawn(async move { tunnel_b.run().await });
+
+        // The first packet is queued until the handshake completes
+        let (ip_a, ip_b) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let packet = ipv4_packet(ip_a, ip_b);
+        app_a.send(&packet).await.unwrap();
+        let mut buf = [0u8; 100];
+        let len = app_b.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], packet);
+
+        // The responder learned the endpoint of the initiator
+        let reply = ipv4_packet(ip_b, ip_a);
+        app_b.send(&reply).await.unwrap();
+        let len = app_a.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], reply);
+
+        a.abort();
+        b.abort();
+    }
+}
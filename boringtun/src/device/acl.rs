@@ -0,0 +1,580 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Per-peer firewall rules for the traffic inside the tunnel.
+//!
+//! Every peer has an [`Acl`]: a list of rules, and a policy for packets that no rule matches.
+//! Packets sent to the peer and packets received from it are checked against the rules in
+//! order, and the first rule that matches decides. Rules match on direction, protocol, source
+//! and destination networks, and TCP or UDP port ranges, and count the packets they match.
+//!
+//! Rules are written as an action followed by the conditions, for example
+//! `allow out proto tcp to 10.0.0.0/24 dport 22` or `deny in from fd00::/64`. They are set
+//! with the `acl_rule`, `replace_acl_rules` and `acl_policy` keys of a peer over the api socket,
+//! and the `AclRule` and `AclPolicy` keys of a `[Peer]` section in a configuration file.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::peer::AllowedIP;
+
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_DSTOPTS: u8 = 60;
+
+/// What to do with a packet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            _ => Err(format!("Invalid action: {s}, expected allow or deny")),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Allow => write!(f, "allow"),
+            Action::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// The direction of a packet, as seen from the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the peer, and written to the tunnel interface
+    In,
+    /// Read from the tunnel interface, and sent to the peer
+    Out,
+}
+
+/// An inclusive range of TCP or UDP ports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    fn contains(self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid port range: {s}");
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.parse().map_err(|_| invalid())?;
+        let end = end.parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// A rule, the conditions left as `None` match any packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclRule {
+    pub action: Action,
+    pub direction: Option<Direction>,
+    /// The IP protocol number, such as 6 for TCP
+    pub protocol: Option<u8>,
+    pub src: Option<AllowedIP>,
+    pub dst: Option<AllowedIP>,
+    /// Only for TCP and UDP
+    pub src_ports: Option<PortRange>,
+    /// Only for TCP and UDP
+    pub dst_ports: Option<PortRange>,
+}
+
+impl AclRule {
+    fn matches(&self, flow: &Flow, direction: Direction) -> bool {
+        let ports = |range: Option<PortRange>, port: fn(&(u16, u16)) -> u16| {
+            range.is_none_or(|range| {
+                flow.ports
+                    .as_ref()
+                    .map(port)
+                    .is_some_and(|p| range.contains(p))
+            })
+        };
+        self.direction.is_none_or(|d| d == direction)
+            && self.protocol.is_none_or(|p| p == flow.protocol)
+            && self.src.is_none_or(|net| contains(net, flow.src))
+            && self.dst.is_none_or(|net| contains(net, flow.dst))
+            && ports(self.src_ports, |p| p.0)
+            && ports(self.dst_ports, |p| p.1)
+    }
+}
+
+fn parse_protocol(s: &str) -> Result<u8, String> {
+    match s {
+        "icmp" => Ok(IPPROTO_ICMP),
+        "tcp" => Ok(IPPROTO_TCP),
+        "udp" => Ok(IPPROTO_UDP),
+        "icmpv6" => Ok(IPPROTO_ICMPV6),
+        _ => s.parse().map_err(|_| format!("Invalid protocol: {s}")),
+    }
+}
+
+fn parse_network(s: &str) -> Result<AllowedIP, String> {
+    // A bare address is a single host
+    match s.parse::<IpAddr>() {
+        Ok(addr @ IpAddr::V4(_)) => Ok(AllowedIP { addr, cidr: 32 }),
+        Ok(addr @ IpAddr::V6(_)) => Ok(AllowedIP { addr, cidr: 128 }),
+        Err(_) => s.parse().map_err(|_| format!("Invalid network: {s}")),
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let mut rule = AclRule {
+            action: words.next().ok_or("Empty rule")?.parse()?,
+            direction: None,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_ports: None,
+            dst_ports: None,
+        };
+
+        while let Some(word) = words.next() {
+            match word {
+                "in" => rule.direction = Some(Direction::In),
+                "out" => rule.direction = Some(Direction::Out),
+                _ => {
+                    let val = words
+                        .next()
+                        .ok_or_else(|| format!("Missing value for {word}"))?;
+                    match word {
+                        "proto" => rule.protocol = Some(parse_protocol(val)?),
+                        "from" => rule.src = Some(parse_network(val)?),
+                        "to" => rule.dst = Some(parse_network(val)?),
+                        "sport" => rule.src_ports = Some(val.parse()?),
+                        "dport" => rule.dst_ports = Some(val.parse()?),
+                        _ => return Err(format!("Unknown condition: {word}")),
+                    }
+                }
+            }
+        }
+
+        let has_ports = rule.src_ports.is_some() || rule.dst_ports.is_some();
+        if has_ports && !matches!(rule.protocol, Some(IPPROTO_TCP | IPPROTO_UDP)) {
+            return Err("Ports need proto tcp or udp".to_owned());
+        }
+        if let (Some(src), Some(dst)) = (rule.src, rule.dst)
+            && src.addr.is_ipv4() != dst.addr.is_ipv4()
+        {
+            return Err("Source and destination of different address families".to_owned());
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for AclRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        match self.direction {
+            Some(Direction::In) => write!(f, " in")?,
+            Some(Direction::Out) => write!(f, " out")?,
+            None => {}
+        }
+        match self.protocol {
+            Some(IPPROTO_ICMP) => write!(f, " proto icmp")?,
+            Some(IPPROTO_TCP) => write!(f, " proto tcp")?,
+            Some(IPPROTO_UDP) => write!(f, " proto udp")?,
+            Some(IPPROTO_ICMPV6) => write!(f, " proto icmpv6")?,
+            Some(protocol) => write!(f, " proto {protocol}")?,
+            None => {}
+        }
+        if let Some(AllowedIP { addr, cidr }) = self.src {
+            write!(f, " from {addr}/{cidr}")?;
+        }
+        if let Some(AllowedIP { addr, cidr }) = self.dst {
+            write!(f, " to {addr}/{cidr}")?;
+        }
+        if let Some(ports) = self.src_ports {
+            write!(f, " sport {ports}")?;
+        }
+        if let Some(ports) = self.dst_ports {
+            write!(f, " dport {ports}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The rules of a peer. The default allows everything, like a peer without rules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    /// The action for packets that no rule matches
+    pub policy: Action,
+    pub rules: Vec<AclRule>,
+}
+
+impl Acl {
+    /// Does the ACL let every packet through
+    #[must_use]
+    pub fn is_open(&self) -> bool {
+        self.policy == Action::Allow && self.rules.is_empty()
+    }
+
+    pub fn apply(&mut self, update: &AclUpdate) {
+        if let Some(policy) = update.policy {
+            self.policy = policy;
+        }
+        if update.replace_rules {
+            self.rules.clear();
+        }
+        self.rules.extend_from_slice(&update.rules);
+    }
+}
+
+/// Changes to the rules of a peer, sent as the `acl_policy`, `replace_acl_rules` and `acl_rule`
+/// keys of a `set=1` transaction. New rules are added after the existing ones, unless
+/// `replace_rules` is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AclUpdate {
+    pub policy: Option<Action>,
+    pub replace_rules: bool,
+    pub rules: Vec<AclRule>,
+}
+
+impl AclUpdate {
+    /// The changes that turn the `current` rules into the `wanted` ones. Unchanged rules are
+    /// left alone, so their hit counters keep counting.
+    #[must_use]
+    pub fn between(current: &Acl, wanted: &Acl) -> AclUpdate {
+        let replace_rules = current.rules != wanted.rules;
+        AclUpdate {
+            policy: (current.policy != wanted.policy).then_some(wanted.policy),
+            replace_rules,
+            rules: if replace_rules {
+                wanted.rules.clone()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// The packets matched by the rules of a peer, and by its policy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AclHits {
+    /// In the order of the rules
+    pub rules: Vec<u64>,
+    pub policy: u64,
+}
+
+/// The rules of a peer, with their hit counters
+#[derive(Debug, Default)]
+pub(crate) struct AclFilter {
+    acl: Acl,
+    hits: Vec<AtomicU64>,
+    policy_hits: AtomicU64,
+}
+
+impl AclFilter {
+    pub(crate) fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    /// Change the rules, the counters of rules that are replaced start over
+    pub(crate) fn apply(&mut self, update: &AclUpdate) {
+        self.acl.apply(update);
+        if update.replace_rules {
+            self.hits.clear();
+        }
+        self.hits
+            .resize_with(self.acl.rules.len(), AtomicU64::default);
+    }
+
+    pub(crate) fn hits(&self) -> AclHits {
+        AclHits {
+            rules: self
+                .hits
+                .iter()
+                .map(|hits| hits.load(Ordering::Relaxed))
+                .collect(),
+            policy: self.policy_hits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Is the packet allowed in the direction, counting the rule that decided
+    pub(crate) fn allows(&self, packet: &[u8], direction: Direction) -> bool {
+        if self.acl.is_open() {
+            return true;
+        }
+        // Packets that can't be parsed are dropped once there are rules
+        let Some(flow) = Flow::parse(packet) else {
+            self.policy_hits.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+        for (rule, hits) in self.acl.rules.iter().zip(&self.hits) {
+            if rule.matches(&flow, direction) {
+                hits.fetch_add(1, Ordering::Relaxed);
+                return rule.action == Action::Allow;
+            }
+        }
+        self.policy_hits.fetch_add(1, Ordering::Relaxed);
+        self.acl.policy == Action::Allow
+    }
+}
+
+/// The fields of a packet that rules match on
+struct Flow {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    /// The source and destination ports of TCP and UDP, except in later fragments
+    ports: Option<(u16, u16)>,
+}
+
+impl Flow {
+    fn parse(packet: &[u8]) -> Option<Flow> {
+        let (src, dst, mut protocol, mut offset, mut first_fragment) = match packet.first()? >> 4 {
+            4 => {
+                let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+                let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+                let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+                let ihl = usize::from(packet[0] & 0xf) * 4;
+                if ihl < 20 {
+                    return None;
+                }
+                (
+                    IpAddr::from(src),
+                    IpAddr::from(dst),
+                    packet[9],
+                    ihl,
+                    fragment_offset == 0,
+                )
+            }
+            6 => {
+                let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+                let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+                (IpAddr::from(src), IpAddr::from(dst), packet[6], 40, true)
+            }
+            _ => return None,
+        };
+
+        // Skip the IPv6 extension headers to the protocol
+        if src.is_ipv6() {
+            loop {
+                match protocol {
+                    IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                        protocol = *packet.get(offset)?;
+                        offset += (usize::from(*packet.get(offset + 1)?) + 1) * 8;
+                    }
+                    IPPROTO_FRAGMENT => {
+                        let header = packet.get(offset..offset + 8)?;
+                        protocol = header[0];
+                        first_fragment = u16::from_be_bytes([header[2], header[3]]) >> 3 == 0;
+                        offset += 8;
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        let ports = match protocol {
+            IPPROTO_TCP | IPPROTO_UDP if first_fragment => {
+                let header = packet.get(offset..offset + 4)?;
+                Some((
+                    u16::from_be_bytes([header[0], header[1]]),
+                    u16::from_be_bytes([header[2], header[3]]),
+                ))
+            }
+            _ => None,
+        };
+
+        Some(Flow {
+            src,
+            dst,
+            protocol,
+            ports,
+        })
+    }
+}
+
+/// Is the address in the network
+fn contains(net: AllowedIP, addr: IpAddr) -> bool {
+    match (net.addr, addr) {
+        (IpAddr::V4(net_addr), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(net.cidr)).unwrap_or(0);
+            u32::from(net_addr) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(net_addr), IpAddr::V6(addr)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(net.cidr))
+                .unwrap_or(0);
+            u128::from(net_addr) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp4(src: [u8; 4], dst: [u8; 4], dst_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[9] = IPPROTO_UDP;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        packet
+    }
+
+    fn tcp6_fragment(dst_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40 + 8 + 20];
+        packet[0] = 0x60;
+        packet[6] = IPPROTO_FRAGMENT;
+        packet[8] = 0xfd;
+        packet[24] = 0xfd;
+        packet[39] = 2;
+        packet[40] = IPPROTO_TCP;
+        packet[50..52].copy_from_slice(&dst_port.to_be_bytes());
+        packet
+    }
+
+    fn filter(policy: Action, rules: &[&str]) -> AclFilter {
+        let mut filter = AclFilter::default();
+        filter.apply(&AclUpdate {
+            policy: Some(policy),
+            replace_rules: true,
+            rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+        });
+        filter
+    }
+
+    #[test]
+    fn parses_rules() {
+        for rule in [
+            "allow",
+            "deny in proto 47",
+            "allow out proto tcp from 10.0.0.0/8 to 10.1.2.3/32 sport 1024-65535 dport 22",
+            "deny proto icmpv6 to fd00::/64",
+        ] {
+            assert_eq!(rule.parse::<AclRule>().unwrap().to_string(), rule);
+        }
+        assert_eq!(
+            "allow to 10.0.0.1 proto udp dport 53"
+                .parse::<AclRule>()
+                .unwrap()
+                .to_string(),
+            "allow proto udp to 10.0.0.1/32 dport 53"
+        );
+
+        for rule in [
+            "",
+            "permit",
+            "allow dport 22",
+            "allow proto tcp dport 22-21",
+            "allow from 10.0.0.0/8 to fd00::/8",
+            "allow to",
+            "allow via 10.0.0.1",
+        ] {
+            assert!(rule.parse::<AclRule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let filter = filter(
+            Action::Deny,
+            &[
+                "deny out proto udp to 10.0.0.9/32",
+                "allow out proto udp to 10.0.0.0/24 dport 53",
+                "allow in from 10.0.0.0/24",
+            ],
+        );
+
+        assert!(filter.allows(&udp4([10, 9, 0, 1], [10, 0, 0, 2], 53), Direction::Out));
+        assert!(!filter.allows(&udp4([10, 9, 0, 1], [10, 0, 0, 9], 53), Direction::Out));
+        assert!(!filter.allows(&udp4([10, 9, 0, 1], [10, 0, 0, 2], 80), Direction::Out));
+        assert!(filter.allows(&udp4([10, 0, 0, 2], [10, 9, 0, 1], 80), Direction::In));
+        assert!(!filter.allows(&udp4([10, 0, 0, 2], [10, 9, 0, 1], 80), Direction::Out));
+        assert!(!filter.allows(&[0x45], Direction::In));
+
+        assert_eq!(
+            filter.hits(),
+            AclHits {
+                rules: vec![1, 1, 1],
+                policy: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn matches_ipv6_past_extension_headers() {
+        let filter = filter(Action::Allow, &["deny proto tcp dport 22"]);
+        assert!(!filter.allows(&tcp6_fragment(22), Direction::In));
+        assert!(filter.allows(&tcp6_fragment(80), Direction::In));
+
+        // Later fragments have no ports to match
+        let mut fragment = tcp6_fragment(22);
+        fragment[43] = 1 << 3;
+        assert!(filter.allows(&fragment, Direction::In));
+    }
+
+    #[test]
+    fn updates_between_acls() {
+        let current = Acl::default();
+        assert_eq!(AclUpdate::between(&current, &current), AclUpdate::default());
+
+        let wanted = Acl {
+            policy: Action::Deny,
+            rules: vec!["allow proto tcp dport 443".parse().unwrap()],
+        };
+        let update = AclUpdate::between(&current, &wanted);
+        assert_eq!(update.policy, Some(Action::Deny));
+        assert!(update.replace_rules);
+
+        let mut filter = AclFilter::default();
+        filter.apply(&update);
+        assert_eq!(filter.acl(), &wanted);
+        assert_eq!(filter.hits().rules, vec![0]);
+        filter.apply(&AclUpdate::between(&wanted, &current));
+        assert!(filter.acl().is_open());
+        assert!(filter.hits().rules.is_empty());
+    }
+}
@@ -2,10 +2,12 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use std::{
+    collections::HashSet,
     ffi::CString,
-    fs::{create_dir, remove_file},
+    fs::{Permissions, create_dir, remove_file, set_permissions},
     io::{BufRead, BufReader, BufWriter, Write},
     os::unix::{
+        fs::PermissionsExt,
         io::{AsRawFd, FromRawFd},
         net::{UnixListener, UnixStream},
     },
@@ -16,11 +18,91 @@ use hex::encode as encode_hex;
 use libc::*;
 
 use super::{
-    AllowedIP, Device, Error, SocketAddr, dev_lock::LockReadGuard, drop_privileges::get_saved_ids,
+    AllowedIP, Device, Error, SocketAddr,
+    acl::{self, AclRule, AclUpdate},
+    capture::{CaptureOptions, CaptureSink},
+    dev_lock::LockReadGuard,
+    drop_privileges::get_saved_ids,
+    peer::Peer,
+    pmtu::{PmtuUpdate, Toggle},
 };
-use crate::{device::Action, serialization::KeyBytes, x25519};
+use crate::{
+    device::Action,
+    serialization::KeyBytes,
+    uapi::{Field, GetRequest, SOCK_DIR},
+    x25519,
+};
+
+/// Access control for the api socket, based on the credentials of the connecting process.
+///
+/// Root and the user the device runs as always have full access. Other users need to be
+/// listed, either by uid or by primary gid. Read-only users may run `get=1` and `subscribe=1`,
+/// but not `set=1`, and are not told the private key and preshared keys. When access control
+/// is enabled the socket itself is made accessible to all local users, and connections that
+/// don't match are refused.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApiAccess {
+    pub uids: Vec<uid_t>,
+    pub gids: Vec<gid_t>,
+    pub read_only_uids: Vec<uid_t>,
+    pub read_only_gids: Vec<gid_t>,
+}
 
-const SOCK_DIR: &str = "/var/run/wireguard/";
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiRole {
+    ReadOnly,
+    Admin,
+}
+
+impl ApiAccess {
+    /// The role of a process with the given credentials, or `None` if it has no access
+    #[must_use]
+    pub fn role(&self, uid: uid_t, gid: gid_t) -> Option<ApiRole> {
+        if uid == 0 || uid == unsafe { geteuid() } || self.uids.contains(&uid) {
+            return Some(ApiRole::Admin);
+        }
+        if self.gids.contains(&gid) {
+            return Some(ApiRole::Admin);
+        }
+        if self.read_only_uids.contains(&uid) || self.read_only_gids.contains(&gid) {
+            return Some(ApiRole::ReadOnly);
+        }
+        None
+    }
+}
+
+/// Get the effective uid and gid of the process on the other end of the connection
+#[cfg(target_os = "linux")]
+fn peer_credentials(conn: &UnixStream) -> std::io::Result<(uid_t, gid_t)> {
+    let mut cred = ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<ucred>() as socklen_t;
+    match unsafe {
+        getsockopt(
+            conn.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEERCRED,
+            (&raw mut cred).cast(),
+            &raw mut len,
+        )
+    } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok((cred.uid, cred.gid)),
+    }
+}
+
+/// Get the effective uid and gid of the process on the other end of the connection
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(conn: &UnixStream) -> std::io::Result<(uid_t, gid_t)> {
+    let (mut uid, mut gid) = (0, 0);
+    match unsafe { getpeereid(conn.as_raw_fd(), &raw mut uid, &raw mut gid) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok((uid, gid)),
+    }
+}
 
 fn create_sock_dir() {
     let _ = create_dir(SOCK_DIR); // Create the directory if it does not exist
@@ -53,6 +135,46 @@ impl Device {
 
         self.cleanup_paths.push(path.clone());
 
+        if self.config.api_access.is_some() {
+            // Let every local user connect, the peer credentials decide what they may do
+            set_permissions(&path, Permissions::from_mode(0o666)).map_err(Error::ApiSocket)?;
+        }
+
+        self.register_api_listener(api_listener)?;
+        self.register_monitor(Some(path))?;
+        self.register_api_signal_handlers()
+    }
+
+    /// Accept api connections on listening sockets passed by systemd socket activation. The
+    /// sockets belong to systemd, so they are neither removed on exit nor monitored.
+    pub fn register_api_listeners(&mut self, fds: &[i32]) -> Result<(), Error> {
+        for &fd in fds {
+            let mut listening: c_int = 0;
+            let mut len = size_of::<c_int>() as socklen_t;
+            let ret = unsafe {
+                getsockopt(
+                    fd,
+                    SOL_SOCKET,
+                    SO_ACCEPTCONN,
+                    (&raw mut listening).cast(),
+                    &raw mut len,
+                )
+            };
+            if ret != 0 || listening == 0 {
+                return Err(Error::ApiSocket(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("File descriptor {fd} is not a listening socket"),
+                )));
+            }
+            self.register_api_listener(unsafe { UnixListener::from_raw_fd(fd) })?;
+        }
+
+        self.register_monitor(None)?;
+        self.register_api_signal_handlers()
+    }
+
+    fn register_api_listener(&self, api_listener: UnixListener) -> Result<(), Error> {
+        let api_access = self.config.api_access.clone();
         self.queue.new_event(
             api_listener.as_raw_fd(),
             Box::new(move |d, _| {
@@ -61,26 +183,62 @@ impl Device {
                     return Action::Continue;
                 };
 
+                let (role, cred) = match &api_access {
+                    None => (ApiRole::Admin, None),
+                    Some(access) => match peer_credentials(&api_conn) {
+                        Ok((uid, gid)) => match access.role(uid, gid) {
+                            Some(role) => (role, Some((uid, gid))),
+                            None => {
+                                tracing::warn!(message = "Denied api connection", uid, gid);
+                                writeln!(&api_conn, "errno={EACCES}\n").ok();
+                                return Action::Continue;
+                            }
+                        },
+                        Err(e) => {
+                            tracing::warn!(message = "Failed to get api peer credentials", error = ?e);
+                            return Action::Continue;
+                        }
+                    },
+                };
+
                 let mut reader = BufReader::new(&api_conn);
                 let mut writer = BufWriter::new(&api_conn);
                 let mut cmd = String::new();
+                let mut subscribe = false;
                 if reader.read_line(&mut cmd).is_ok() {
                     cmd.pop(); // pop the new line character
                     let status = match cmd.as_ref() {
                         // Only two commands are legal according to the protocol, get=1 and set=1.
-                        "get=1" => api_get(&mut writer, d),
+                        "get=1" => match read_get_request(&api_conn, &mut reader) {
+                            Ok(req) => api_get(&req, &mut writer, d, role),
+                            Err(errno) => errno,
+                        },
+                        "set=1" if role == ApiRole::ReadOnly => {
+                            tracing::warn!(message = "Denied set from read-only api user", ?cred);
+                            EACCES
+                        }
                         "set=1" => api_set(&mut reader, d),
+                        // Extension: keep the connection open and stream device events over it
+                        "subscribe=1" if d.events.is_full() => EBUSY,
+                        "subscribe=1" => {
+                            subscribe = true;
+                            0
+                        }
                         _ => EIO,
                     };
                     // The protocol requires to return an error code as the response, or zero on success
                     writeln!(writer, "errno={status}\n").ok();
                 }
+
+                if subscribe && writer.flush().is_ok() {
+                    drop((reader, writer));
+                    d.events.subscribe(api_conn);
+                }
                 Action::Continue // Indicates the worker thread should continue as normal
             }),
         )?;
 
-        self.register_monitor(path)?;
-        self.register_api_signal_handlers()
+        Ok(())
     }
 
     pub fn register_api_fd(&mut self, fd: i32) -> Result<(), Error> {
@@ -98,7 +256,10 @@ impl Device {
                     cmd.pop(); // pop the new line character
                     let status = match cmd.as_ref() {
                         // Only two commands are legal according to the protocol, get=1 and set=1.
-                        "get=1" => api_get(&mut writer, d),
+                        "get=1" => match read_get_request(&io_file, &mut reader) {
+                            Ok(req) => api_get(&req, &mut writer, d, ApiRole::Admin),
+                            Err(errno) => errno,
+                        },
                         "set=1" => api_set(&mut reader, d),
                         _ => EIO,
                     };
@@ -117,7 +278,7 @@ impl Device {
         Ok(())
     }
 
-    fn register_monitor(&self, path: String) -> Result<(), Error> {
+    fn register_monitor(&self, path: Option<String>) -> Result<(), Error> {
         self.queue.new_periodic_event(
             Box::new(move |d, _| {
                 // This is not a very nice hack to detect if the control socket was removed
@@ -127,8 +288,9 @@ impl Device {
                 // deletion, and kqueue EVFILT_VNODE can be used for the same purpose, but that
                 // will require introducing new events, for no measurable benefit.
                 // TODO: Could this be an issue if we restart the service too quickly?
-                let path = std::path::Path::new(&path);
-                if !path.exists() {
+                if let Some(path) = &path
+                    && !std::path::Path::new(path).exists()
+                {
                     d.trigger_exit();
                     return Action::Exit;
                 }
@@ -147,137 +309,349 @@ impl Device {
     }
 
     fn register_api_signal_handlers(&self) -> Result<(), Error> {
+        if !self.config.handle_signals {
+            return Ok(());
+        }
+
         self.queue
             .new_signal_event(SIGINT, Box::new(move |_, _| Action::Exit))?;
 
         self.queue
             .new_signal_event(SIGTERM, Box::new(move |_, _| Action::Exit))?;
 
+        if let Some(path) = self.config.config_file.clone() {
+            self.queue.new_signal_event(
+                SIGHUP,
+                Box::new(move |d, _| {
+                    Device::reload_config(d, &path);
+                    Action::Continue
+                }),
+            )?;
+        }
+
         Ok(())
     }
 }
 
+/// Handle a complete get or set request held in memory, and return the response including the
+/// terminating errno line. This lets other front ends share the exact semantics of the socket.
+pub(super) fn api_request(request: &[u8], d: &mut LockReadGuard<Device>) -> Vec<u8> {
+    let mut reader = request;
+    let mut response = Vec::new();
+    let mut cmd = String::new();
+    let status = match reader.read_line(&mut cmd) {
+        Ok(_) => match cmd.trim_end_matches('\n') {
+            "get=1" => match GetRequest::read_from(&mut reader) {
+                Ok(req) => api_get(&req, &mut response, d, ApiRole::Admin),
+                Err(errno) => errno,
+            },
+            "set=1" => api_set(&mut reader, d),
+            _ => EIO,
+        },
+        Err(_) => EIO,
+    };
+    writeln!(response, "errno={status}\n").ok();
+    response
+}
+
+/// Read the body of a get request from a connection, without waiting for the empty line that
+/// ends it. The handler runs on a device thread, and clients may send a bare `get=1`.
+fn read_get_request<R: BufRead>(conn: &UnixStream, reader: &mut R) -> Result<GetRequest, i32> {
+    conn.set_nonblocking(true).map_err(|_| EIO)?;
+    let req = GetRequest::read_from(reader);
+    // The response is written blocking
+    conn.set_nonblocking(false).map_err(|_| EIO)?;
+    req
+}
+
+/// Respond to a get request. Read-only users are not told the private key and preshared keys.
 #[allow(unused_must_use)]
-fn api_get(writer: &mut BufWriter<&UnixStream>, d: &Device) -> i32 {
-    // get command requires an empty line, but there is no reason to be religious about it
-    if let Some(ref k) = d.key_pair {
+fn api_get<W: Write>(req: &GetRequest, writer: &mut W, d: &Device, role: ApiRole) -> i32 {
+    let secrets = role == ApiRole::Admin;
+    if secrets
+        && req.wants(Field::PrivateKey)
+        && let Some(ref k) = d.key_pair
+    {
         writeln!(writer, "private_key={}", encode_hex(k.0.as_bytes()));
     }
 
-    if d.listen_port != 0 {
+    if req.wants(Field::ListenPort) && d.listen_port != 0 {
         writeln!(writer, "listen_port={}", d.listen_port);
     }
 
-    if let Some(fwmark) = d.fwmark {
+    if req.wants(Field::Fwmark)
+        && let Some(fwmark) = d.fwmark
+    {
         writeln!(writer, "fwmark={fwmark}");
     }
 
-    for (k, p) in &d.peers {
-        let p = p.lock();
-        writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+    if req.wants(Field::Capture)
+        && let Some(ref capture) = d.capture
+    {
+        let stats = capture.stats();
+        writeln!(writer, "capture={}", capture.sink());
+        writeln!(writer, "capture_packets={}", stats.packets);
+        writeln!(writer, "capture_bytes={}", stats.bytes);
+        writeln!(writer, "capture_lost={}", stats.lost);
+    }
 
-        if let Some(ref key) = p.preshared_key() {
-            writeln!(writer, "preshared_key={}", encode_hex(key));
+    if req.drops {
+        for (reason, count) in d.drops.stats().iter() {
+            writeln!(writer, "drop_{}={count}", reason.name());
+        }
+        for (error, count) in d.drops.decapsulation_errors() {
+            if count > 0 {
+                writeln!(writer, "decapsulation_error_{error}={count}");
+            }
         }
+    }
 
-        if let Some(keepalive) = p.persistent_keepalive() {
-            writeln!(writer, "persistent_keepalive_interval={keepalive}");
+    // A plain request reports all peers in map order, so only collect them when filtering
+    if req.public_keys.is_empty() && !req.is_paged() {
+        for (k, p) in &d.peers {
+            api_get_peer(writer, req, secrets, k, &p.lock());
         }
+        return 0;
+    }
 
-        if let Some(ref addr) = p.endpoint().addr {
-            writeln!(writer, "endpoint={addr}");
+    let mut peers: Vec<_> = if req.public_keys.is_empty() {
+        d.peers.iter().collect()
+    } else {
+        // Report a peer listed more than once only the first time
+        let mut listed = HashSet::with_capacity(req.public_keys.len());
+        req.public_keys
+            .iter()
+            .filter(|k| listed.insert(*k))
+            .filter_map(|k| d.peers.get_key_value(k))
+            .collect()
+    };
+
+    if req.is_paged() {
+        if let Some(ref cursor) = req.cursor {
+            peers.retain(|(k, _)| k.as_bytes() > cursor.as_bytes());
+        }
+        if let Some(limit) = req.limit
+            && limit < peers.len()
+        {
+            // Avoid sorting all peers just to report the first few
+            peers.select_nth_unstable_by_key(limit, |(k, _)| *k.as_bytes());
+            peers.truncate(limit);
         }
+        peers.sort_unstable_by_key(|(k, _)| *k.as_bytes());
+    }
 
+    for (k, p) in peers {
+        api_get_peer(writer, req, secrets, k, &p.lock());
+    }
+    0
+}
+
+#[allow(unused_must_use)]
+fn api_get_peer<W: Write>(
+    writer: &mut W,
+    req: &GetRequest,
+    secrets: bool,
+    k: &x25519::PublicKey,
+    p: &Peer,
+) {
+    writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+
+    if secrets
+        && req.wants(Field::PresharedKey)
+        && let Some(ref key) = p.preshared_key()
+    {
+        writeln!(writer, "preshared_key={}", encode_hex(key));
+    }
+
+    if req.wants(Field::PersistentKeepaliveInterval)
+        && let Some(keepalive) = p.persistent_keepalive()
+    {
+        writeln!(writer, "persistent_keepalive_interval={keepalive}");
+    }
+
+    if req.wants(Field::Endpoint)
+        && let Some(ref addr) = p.endpoint().addr
+    {
+        writeln!(writer, "endpoint={addr}");
+    }
+
+    if req.wants(Field::AllowedIp) {
         for (ip, cidr) in p.allowed_ips() {
             writeln!(writer, "allowed_ip={ip}/{cidr}");
         }
+    }
 
-        if let Some(time) = p.last_handshake_time() {
-            writeln!(writer, "last_handshake_time_sec={}", time.as_secs());
-            writeln!(writer, "last_handshake_time_nsec={}", time.subsec_nanos());
+    if req.wants(Field::LastHandshakeTime)
+        && let Some(time) = p.last_handshake_time()
+    {
+        writeln!(writer, "last_handshake_time_sec={}", time.as_secs());
+        writeln!(writer, "last_handshake_time_nsec={}", time.subsec_nanos());
+    }
+
+    // Only reported when set, as other implementations don't know these keys
+    let pmtu = p.pmtu();
+    if req.wants(Field::Mtu)
+        && let Some(mtu) = pmtu.mtu
+    {
+        writeln!(writer, "mtu={mtu}");
+    }
+    if req.wants(Field::IcmpTooBig) && pmtu.icmp_too_big != Toggle::Auto {
+        writeln!(writer, "icmp_too_big={}", pmtu.icmp_too_big);
+    }
+    if req.wants(Field::ClampMss) && pmtu.clamp_mss != Toggle::Auto {
+        writeln!(writer, "clamp_mss={}", pmtu.clamp_mss);
+    }
+    let acl = p.acl();
+    if req.wants(Field::AclPolicy) && acl.policy != acl::Action::Allow {
+        writeln!(writer, "acl_policy={}", acl.policy);
+    }
+    if req.wants(Field::AclRule) && !acl.is_open() {
+        let hits = p.acl_hits();
+        for (rule, hits) in acl.rules.iter().zip(hits.rules) {
+            writeln!(writer, "acl_rule={rule}");
+            writeln!(writer, "acl_rule_hits={hits}");
         }
+        writeln!(writer, "acl_policy_hits={}", hits.policy);
+    }
 
-        let (_, tx_bytes, rx_bytes, ..) = p.tunnel.stats();
+    let (_, tx_bytes, rx_bytes, ..) = p.tunnel.stats();
 
+    if req.wants(Field::RxBytes) {
         writeln!(writer, "rx_bytes={rx_bytes}");
+    }
+    if req.wants(Field::TxBytes) {
         writeln!(writer, "tx_bytes={tx_bytes}");
     }
-    0
+
+    if req.drops {
+        for (reason, count) in p.drop_stats().iter() {
+            if count > 0 {
+                writeln!(writer, "drop_{}={count}", reason.name());
+            }
+        }
+    }
+
+    if req.flight_record {
+        for record in p.flight_record() {
+            writeln!(writer, "flight_record={record}");
+        }
+    }
 }
 
-fn api_set(reader: &mut BufReader<&UnixStream>, d: &mut LockReadGuard<Device>) -> i32 {
+fn api_set<R: BufRead>(reader: &mut R, d: &mut LockReadGuard<Device>) -> i32 {
+    let status = apply_set(reader, d);
+    if status == 0 {
+        d.save_config();
+    }
+    status
+}
+
+fn apply_set<R: BufRead>(reader: &mut R, d: &mut LockReadGuard<Device>) -> i32 {
     d.try_writeable(super::Device::trigger_yield, |device| {
         device.cancel_yield();
+        let status = apply_set_commands(reader, device);
+        // Also after a failed request, as the peers before the failure were changed
+        #[cfg(target_os = "linux")]
+        if let Err(e) = device.sync_routes() {
+            tracing::warn!(message = "Failed to update routes", error = ?e);
+        }
+        status
+    })
+    .unwrap_or(EIO)
+}
 
-        let mut cmd = String::new();
+fn apply_set_commands<R: BufRead>(reader: &mut R, device: &mut Device) -> i32 {
+    let mut cmd = String::new();
+    // The capture options apply to the next capture key
+    let mut capture = CaptureOptions::default();
 
-        while reader.read_line(&mut cmd).is_ok() {
-            cmd.pop(); // remove newline if any
-            if cmd.is_empty() {
-                return 0; // Done
+    while reader.read_line(&mut cmd).is_ok() {
+        cmd.pop(); // remove newline if any
+        if cmd.is_empty() {
+            return 0; // Done
+        }
+        {
+            let parsed_cmd: Vec<&str> = cmd.split('=').collect();
+            if parsed_cmd.len() != 2 {
+                return EPROTO;
             }
-            {
-                let parsed_cmd: Vec<&str> = cmd.split('=').collect();
-                if parsed_cmd.len() != 2 {
-                    return EPROTO;
-                }
 
-                let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
+            let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
 
-                match key {
-                    "private_key" => match val.parse::<KeyBytes>() {
-                        Ok(key_bytes) => {
-                            device.set_key(&x25519::StaticSecret::from(key_bytes.0));
-                        }
-                        Err(_) => return EINVAL,
-                    },
-                    "listen_port" => match val.parse::<u16>() {
-                        Ok(port) => match device.open_listen_socket(port) {
-                            Ok(()) => {}
-                            Err(_) => return EADDRINUSE,
-                        },
-                        Err(_) => return EINVAL,
-                    },
-                    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-                    "fwmark" => match val.parse::<u32>() {
-                        Ok(mark) => match device.set_fwmark(mark) {
-                            Ok(()) => {}
-                            Err(_) => return EADDRINUSE,
-                        },
-                        Err(_) => return EINVAL,
+            match key {
+                "private_key" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => {
+                        device.set_key(&x25519::StaticSecret::from(key_bytes.0));
+                    }
+                    Err(_) => return EINVAL,
+                },
+                "listen_port" => match val.parse::<u16>() {
+                    Ok(port) => match device.open_listen_socket(port) {
+                        Ok(()) => {}
+                        Err(_) => return EADDRINUSE,
                     },
-                    "replace_peers" => match val.parse::<bool>() {
-                        Ok(true) => device.clear_peers(),
-                        Ok(false) => {}
-                        Err(_) => return EINVAL,
+                    Err(_) => return EINVAL,
+                },
+                #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                "fwmark" => match val.parse::<u32>() {
+                    Ok(mark) => match device.set_fwmark(mark) {
+                        Ok(()) => {}
+                        Err(_) => return EADDRINUSE,
                     },
-                    "public_key" => match val.parse::<KeyBytes>() {
-                        // Indicates a new peer section
-                        Ok(key_bytes) => {
-                            return api_set_peer(
-                                reader,
-                                device,
-                                x25519::PublicKey::from(key_bytes.0),
-                            );
+                    Err(_) => return EINVAL,
+                },
+                "capture_outer" => match val.parse::<bool>() {
+                    Ok(outer) => capture.outer = outer,
+                    Err(_) => return EINVAL,
+                },
+                "capture_peer" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => capture.peers.push(x25519::PublicKey::from(key_bytes.0)),
+                    Err(_) => return EINVAL,
+                },
+                "capture_max_packets" => match val.parse::<u64>() {
+                    Ok(max) => capture.max_packets = Some(max),
+                    Err(_) => return EINVAL,
+                },
+                "capture_max_bytes" => match val.parse::<u64>() {
+                    Ok(max) => capture.max_bytes = Some(max),
+                    Err(_) => return EINVAL,
+                },
+                "capture_snaplen" => match val.parse::<u32>() {
+                    Ok(snaplen) => capture.snaplen = Some(snaplen),
+                    Err(_) => return EINVAL,
+                },
+                "capture" if val == "off" => device.capture = None,
+                "capture" => match val.parse::<CaptureSink>() {
+                    Ok(sink) => {
+                        if let Err(e) = device.start_capture(sink, std::mem::take(&mut capture)) {
+                            tracing::warn!(message = "Failed to start capture", error = ?e);
+                            return e.raw_os_error().unwrap_or(EIO);
                         }
-                        Err(_) => return EINVAL,
-                    },
-                    _ => return EINVAL,
-                }
+                    }
+                    Err(_) => return EINVAL,
+                },
+                "replace_peers" => match val.parse::<bool>() {
+                    Ok(true) => device.clear_peers(),
+                    Ok(false) => {}
+                    Err(_) => return EINVAL,
+                },
+                "public_key" => match val.parse::<KeyBytes>() {
+                    // Indicates a new peer section
+                    Ok(key_bytes) => {
+                        return api_set_peer(reader, device, x25519::PublicKey::from(key_bytes.0));
+                    }
+                    Err(_) => return EINVAL,
+                },
+                _ => return EINVAL,
             }
-            cmd.clear();
         }
+        cmd.clear();
+    }
 
-        0
-    })
-    .unwrap_or(EIO)
+    0
 }
 
-fn api_set_peer(
-    reader: &mut BufReader<&UnixStream>,
-    d: &mut Device,
-    pub_key: x25519::PublicKey,
-) -> i32 {
+fn api_set_peer<R: BufRead>(reader: &mut R, d: &mut Device, pub_key: x25519::PublicKey) -> i32 {
     let mut cmd = String::new();
 
     let mut remove = false;
@@ -287,6 +661,8 @@ fn api_set_peer(
     let mut public_key = pub_key;
     let mut preshared_key = None;
     let mut allowed_ips: Vec<AllowedIP> = Vec::new();
+    let mut pmtu = PmtuUpdate::default();
+    let mut acl = AclUpdate::default();
     while reader.read_line(&mut cmd).is_ok() {
         cmd.pop(); // remove newline if any
         if cmd.is_empty() {
@@ -298,6 +674,8 @@ fn api_set_peer(
                 allowed_ips.as_slice(),
                 keepalive,
                 preshared_key,
+                &pmtu,
+                &acl,
             );
             allowed_ips.clear(); //clear the vector content after update
             return 0; // Done
@@ -335,6 +713,30 @@ fn api_set_peer(
                     Ok(ip) => allowed_ips.push(ip),
                     Err(_) => return EINVAL,
                 },
+                "mtu" => match val.parse::<u16>() {
+                    Ok(mtu) => pmtu.mtu = Some(mtu),
+                    Err(_) => return EINVAL,
+                },
+                "icmp_too_big" => match val.parse::<Toggle>() {
+                    Ok(toggle) => pmtu.icmp_too_big = Some(toggle),
+                    Err(_) => return EINVAL,
+                },
+                "clamp_mss" => match val.parse::<Toggle>() {
+                    Ok(toggle) => pmtu.clamp_mss = Some(toggle),
+                    Err(_) => return EINVAL,
+                },
+                "acl_policy" => match val.parse::<acl::Action>() {
+                    Ok(policy) => acl.policy = Some(policy),
+                    Err(_) => return EINVAL,
+                },
+                "replace_acl_rules" => match val.parse::<bool>() {
+                    Ok(replace) => acl.replace_rules = replace,
+                    Err(_) => return EINVAL,
+                },
+                "acl_rule" => match val.parse::<AclRule>() {
+                    Ok(rule) => acl.rules.push(rule),
+                    Err(_) => return EINVAL,
+                },
                 "public_key" => {
                     // Indicates a new peer section. Commit changes for current peer, and continue to next peer
                     d.update_peer(
@@ -345,7 +747,17 @@ fn api_set_peer(
                         allowed_ips.as_slice(),
                         keepalive,
                         preshared_key,
+                        &pmtu,
+                        &acl,
                     );
+                    // The settings of the previous section don't carry over to the next peer
+                    remove = false;
+                    replace_ips = false;
+                    endpoint = None;
+                    keepalive = None;
+                    preshared_key = None;
+                    pmtu = PmtuUpdate::default();
+                    acl = AclUpdate::default();
                     allowed_ips.clear(); //clear the vector content after update
                     match val.parse::<KeyBytes>() {
                         Ok(key_bytes) => public_key = key_bytes.0.into(),
@@ -363,3 +775,168 @@ fn api_set_peer(
     }
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::device::{DeviceConfig, DeviceHandle};
+
+    const PRIVATE_KEY: &str = "a8dbc8b5e5e5b5e5d5c5b5a5958575655545352515050f0e0d0c0b0a09080748";
+    const PEER_KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    /// Creating the tunnel interface requires privileges
+    fn test_device(name: &str) -> DeviceHandle {
+        let config = DeviceConfig {
+            n_threads: 1,
+            ..DeviceConfig::default()
+        };
+        DeviceHandle::new(name, config).unwrap()
+    }
+
+    fn request(device: &DeviceHandle, request: &str) -> String {
+        let response = api_request(request.as_bytes(), &mut device.device.read());
+        String::from_utf8(response).unwrap()
+    }
+
+    /// Set the private key of the device and add the peer with the given keys
+    fn set_peer(device: &DeviceHandle, keys: &str) {
+        let set = format!("set=1\nprivate_key={PRIVATE_KEY}\npublic_key={PEER_KEY}\n{keys}\n\n");
+        assert_eq!(request(device, &set), "errno=0\n\n");
+    }
+
+    #[test]
+    fn api_access_roles() {
+        let access = ApiAccess {
+            uids: vec![4000000],
+            gids: vec![10],
+            read_only_uids: vec![4000001],
+            read_only_gids: vec![20],
+        };
+        let own_uid = unsafe { geteuid() };
+
+        assert_eq!(access.role(0, 0), Some(ApiRole::Admin));
+        assert_eq!(access.role(own_uid, 4000099), Some(ApiRole::Admin));
+        assert_eq!(access.role(4000000, 4000099), Some(ApiRole::Admin));
+        assert_eq!(access.role(4000002, 10), Some(ApiRole::Admin));
+        assert_eq!(access.role(4000001, 4000099), Some(ApiRole::ReadOnly));
+        assert_eq!(access.role(4000002, 20), Some(ApiRole::ReadOnly));
+        // Only root and the device user pass an empty list
+        assert_eq!(ApiAccess::default().role(4000002, 30), None);
+        assert_eq!(access.role(4000002, 30), None);
+    }
+
+    #[test]
+    #[ignore]
+    fn get_reports_duplicate_peers_once() {
+        let device = test_device("utun90");
+        set_peer(&device, "allowed_ip=10.0.0.1/32");
+
+        let get = format!("get=1\npublic_key={PEER_KEY}\npublic_key={PEER_KEY}\n\n");
+        let response = request(&device, &get);
+        assert_eq!(response.matches("public_key=").count(), 1, "{response}");
+    }
+
+    #[test]
+    #[ignore]
+    fn read_only_get_hides_secrets() {
+        let device = test_device("utun91");
+        set_peer(&device, &format!("preshared_key={PEER_KEY}"));
+
+        let get = |role| {
+            let mut response = Vec::new();
+            let req = GetRequest::default();
+            api_get(&req, &mut response, &device.device.read(), role);
+            String::from_utf8(response).unwrap()
+        };
+        let admin = get(ApiRole::Admin);
+        assert!(admin.contains("private_key=") && admin.contains("preshared_key="));
+        let read_only = get(ApiRole::ReadOnly);
+        assert!(read_only.contains("public_key="), "{read_only}");
+        assert!(!read_only.contains("private_key="), "{read_only}");
+        assert!(!read_only.contains("preshared_key="), "{read_only}");
+    }
+
+    #[test]
+    #[ignore]
+    fn get_without_the_empty_line_is_answered() {
+        let device = test_device("utun99");
+        set_peer(&device, "allowed_ip=10.0.0.1/32");
+
+        let mut conn = UnixStream::connect(format!("{SOCK_DIR}/utun99.sock")).unwrap();
+        conn.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        conn.write_all(b"get=1\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.contains("allowed_ip=10.0.0.1/32\n"), "{response}");
+        assert!(response.ends_with("errno=0\n\n"), "{response}");
+
+        // The keys that were sent still apply
+        let mut conn = UnixStream::connect(format!("{SOCK_DIR}/utun99.sock")).unwrap();
+        conn.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        conn.write_all(b"get=1\nfields=rx_bytes\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("errno=0\n\n"), "{response}");
+        assert!(!response.contains("allowed_ip"), "{response}");
+    }
+
+    #[test]
+    #[ignore]
+    fn extensions_are_only_reported_when_selected() {
+        let device = test_device("utun98");
+        set_peer(
+            &device,
+            "mtu=1280\nacl_policy=deny\nacl_rule=allow proto tcp dport 22",
+        );
+
+        let plain = request(&device, "get=1\n\n");
+        assert!(plain.contains("rx_bytes="), "{plain}");
+        assert!(
+            !plain.contains("mtu=") && !plain.contains("acl_"),
+            "{plain}"
+        );
+
+        let selected = request(&device, "get=1\nfields=mtu,acl_policy,acl_rule\n\n");
+        assert!(selected.contains("mtu=1280\n"), "{selected}");
+        assert!(selected.contains("acl_policy=deny\n"), "{selected}");
+        assert!(
+            selected.contains("acl_rule=allow proto tcp dport 22\n"),
+            "{selected}"
+        );
+        assert!(!selected.contains("rx_bytes="), "{selected}");
+    }
+
+    #[test]
+    #[ignore]
+    fn set_settings_do_not_carry_over_to_the_next_peer() {
+        let device = test_device("utun92");
+        let other_key = "02".repeat(32);
+        let set = format!(
+            "set=1\nprivate_key={PRIVATE_KEY}\n\
+             public_key={PEER_KEY}\npreshared_key={PEER_KEY}\nendpoint=192.0.2.1:51820\n\
+             persistent_keepalive_interval=25\nallowed_ip=10.0.0.1/32\n\
+             public_key={other_key}\nallowed_ip=10.0.0.2/32\n\n"
+        );
+        assert_eq!(request(&device, &set), "errno=0\n\n");
+
+        let get = format!("get=1\npublic_key={other_key}\n\n");
+        let response = request(&device, &get);
+        let expected =
+            format!("public_key={other_key}\nallowed_ip=10.0.0.2/32\nrx_bytes=0\ntx_bytes=0\n");
+        assert!(
+            response.ends_with(&format!("{expected}errno=0\n\n")),
+            "{response}"
+        );
+
+        // A removal only applies to its own section
+        let set = format!("set=1\npublic_key={PEER_KEY}\nremove=true\npublic_key={other_key}\n\n");
+        assert_eq!(request(&device, &set), "errno=0\n\n");
+        let response = request(&device, "get=1\n\n");
+        assert!(!response.contains(PEER_KEY), "{response}");
+        assert!(response.contains(&other_key), "{response}");
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Batched I/O on the UDP sockets that face the network.
+//!
+//! On Linux, the kernel coalesces datagrams from the same sender with `UDP_GRO`, so they are
+//! read with a single syscall. Packets sent during a handler call are queued in a [`SendBatch`]
+//! and sent together with `sendmmsg`, and runs of equal sized packets to the same endpoint go out
+//! as a single `UDP_SEGMENT` message. Where the kernel lacks any of these, one datagram is read or
+//! sent per syscall, which is also what other platforms do.
+
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+#[cfg(not(target_os = "linux"))]
+use std::mem::MaybeUninit;
+
+use socket2::{SockAddr, Socket};
+
+#[cfg(target_os = "linux")]
+const UDP_SEGMENT: libc::c_int = 103;
+#[cfg(target_os = "linux")]
+const UDP_GRO: libc::c_int = 104;
+
+/// The number of packets queued before the batch must be sent
+const MAX_BATCH: usize = 64;
+/// Older kernels accept at most 64 segments in one message
+const MAX_SEGMENTS: usize = 64;
+/// The total size of a segmented message, below the IP packet size limit with any headers
+const MAX_SEGMENTED_SIZE: usize = 65000;
+
+/// Room for a single control message with an integer
+#[cfg(target_os = "linux")]
+type Control = [u64; 4];
+
+/// Ask the kernel to coalesce received datagrams, where supported
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn enable_gro(socket: &Socket) {
+    #[cfg(target_os = "linux")]
+    {
+        let on: libc::c_int = 1;
+        // Before Linux 5.0 this fails, and datagrams are received one at a time
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                UDP_GRO,
+                (&raw const on).cast(),
+                size_of::<libc::c_int>() as _,
+            )
+        };
+    }
+}
+
+/// Receive one or more coalesced datagrams from the same sender. Returns the total length, the
+/// sender, and the size of each datagram, except for the last one that may be shorter.
+pub(crate) fn recv_from(socket: &Socket, buf: &mut [u8]) -> io::Result<(usize, SockAddr, usize)> {
+    #[cfg(target_os = "linux")]
+    let ((len, segment), addr) = {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let mut control = Control::default();
+        // Safety: the message header points to buffers that outlive the call
+        unsafe {
+            SockAddr::try_init(|storage, addr_len| {
+                let mut msg: libc::msghdr = std::mem::zeroed();
+                msg.msg_name = storage.cast();
+                msg.msg_namelen = *addr_len;
+                msg.msg_iov = &mut iov;
+                msg.msg_iovlen = 1;
+                msg.msg_control = control.as_mut_ptr().cast();
+                msg.msg_controllen = size_of::<Control>() as _;
+
+                let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                *addr_len = msg.msg_namelen;
+                Ok((len as usize, gro_segment(&msg)))
+            })?
+        }
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let ((len, segment), addr) = {
+        // Safety: `recv_from` promises not to write uninitialised bytes to the buffer
+        let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        let (len, addr) = socket.recv_from(buf)?;
+        ((len, None), addr)
+    };
+
+    Ok((len, addr, segment.unwrap_or(len).max(1)))
+}
+
+/// The size of the coalesced datagrams, when the message has more than one
+#[cfg(target_os = "linux")]
+pub(super) fn gro_segment(msg: &libc::msghdr) -> Option<usize> {
+    // Safety: the control messages were filled in by the kernel, within `msg_controllen`
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                let segment = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>());
+                return usize::try_from(segment).ok().filter(|&segment| segment > 0);
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    None
+}
+
+/// Send one datagram
+fn send_to(fd: RawFd, addr: &SockAddr, packet: &[u8]) -> io::Result<()> {
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            packet.as_ptr().cast(),
+            packet.len(),
+            0,
+            addr.as_ptr().cast(),
+            addr.len(),
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// One or more packets of the same size, to the same endpoint
+struct Message<T> {
+    fd: RawFd,
+    addr: SockAddr,
+    start: usize,
+    len: usize,
+    segment: usize,
+    tag: T,
+}
+
+impl<T> Message<T> {
+    fn segments(&self) -> usize {
+        self.len.div_ceil(self.segment.max(1))
+    }
+
+    fn can_append(&self, fd: RawFd, addr: &SockAddr, len: usize) -> bool {
+        self.fd == fd
+            && self.addr == *addr
+            && self.segment > 0
+            // Only the last segment may be shorter
+            && self.len.is_multiple_of(self.segment)
+            && len <= self.segment
+            && self.segments() < MAX_SEGMENTS
+            && self.len + len <= MAX_SEGMENTED_SIZE
+    }
+}
+
+/// What the kernel supports, found out as packets are sent
+struct Support {
+    /// `UDP_SEGMENT`, probed on first use
+    gso: Option<bool>,
+    /// `sendmmsg`
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    mmsg: bool,
+}
+
+/// Packets to send on the network, tagged with the peer to charge for errors
+pub(crate) struct SendBatch<T> {
+    buf: Vec<u8>,
+    messages: Vec<Message<T>>,
+    packets: usize,
+    support: Support,
+}
+
+impl<T> Default for SendBatch<T> {
+    fn default() -> Self {
+        SendBatch {
+            buf: Vec::new(),
+            messages: Vec::new(),
+            packets: 0,
+            support: Support {
+                gso: None,
+                mmsg: cfg!(target_os = "linux"),
+            },
+        }
+    }
+}
+
+impl<T> SendBatch<T> {
+    pub(crate) fn is_full(&self) -> bool {
+        self.packets >= MAX_BATCH
+    }
+
+    /// Queue a packet to be sent from the socket to the address
+    pub(crate) fn push(&mut self, socket: &Socket, addr: SocketAddr, packet: &[u8], tag: T) {
+        let fd = socket.as_raw_fd();
+        let addr = SockAddr::from(addr);
+
+        let gso = *self.support.gso.get_or_insert_with(|| gso_supported(fd));
+        match self.messages.last_mut() {
+            Some(last) if gso && last.can_append(fd, &addr, packet.len()) => {
+                last.len += packet.len();
+            }
+            _ => self.messages.push(Message {
+                fd,
+                addr,
+                start: self.buf.len(),
+                len: packet.len(),
+                segment: packet.len(),
+                tag,
+            }),
+        }
+        self.buf.extend_from_slice(packet);
+        self.packets += 1;
+    }
+
+    /// Send the queued packets, and call `failed` with the tag of each message that could not be
+    /// sent and the number of packets lost
+    pub(crate) fn flush(&mut self, mut failed: impl FnMut(&T, usize)) {
+        let mut start = 0;
+        while start < self.messages.len() {
+            // A single sendmmsg call sends on one socket
+            let fd = self.messages[start].fd;
+            let run = self.messages[start..]
+                .iter()
+                .take_while(|message| message.fd == fd)
+                .count();
+            let messages = &self.messages[start..start + run];
+            send_messages(&mut self.support, fd, messages, &self.buf, &mut failed);
+            start += run;
+        }
+
+        self.buf.clear();
+        self.messages.clear();
+        self.packets = 0;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn gso_supported(fd: RawFd) -> bool {
+    let mut segment: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            UDP_SEGMENT,
+            (&raw mut segment).cast(),
+            &mut len,
+        ) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gso_supported(_fd: RawFd) -> bool {
+    false
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn send_messages<T>(
+    support: &mut Support,
+    fd: RawFd,
+    messages: &[Message<T>],
+    buf: &[u8],
+    failed: &mut impl FnMut(&T, usize),
+) {
+    let mut sent = 0;
+    while sent < messages.len() {
+        #[cfg(target_os = "linux")]
+        if support.mmsg {
+            match sendmmsg(fd, &messages[sent..], buf) {
+                Ok(n) => {
+                    sent += n;
+                    continue;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => support.mmsg = false,
+                // Send the first message on its own, to tell what to do about the error
+                Err(_) => {}
+            }
+        }
+
+        let message = &messages[sent];
+        let packets = &buf[message.start..message.start + message.len];
+        sent += 1;
+
+        if message.segments() == 1 {
+            if send_to(fd, &message.addr, packets).is_err() {
+                failed(&message.tag, 1);
+            }
+            continue;
+        }
+
+        #[cfg(target_os = "linux")]
+        match sendmsg(fd, message, buf) {
+            Ok(()) => continue,
+            // The device can't checksum segmented packets
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => support.gso = Some(false),
+            // Otherwise, e.g. the path MTU is below the segment size, send them one by one
+            Err(_) => {}
+        }
+
+        let lost = packets
+            .chunks(message.segment)
+            .filter(|packet| send_to(fd, &message.addr, packet).is_err())
+            .count();
+        if lost > 0 {
+            failed(&message.tag, lost);
+        }
+    }
+}
+
+/// Fill in a message header for the message, segmented if it holds more than one packet
+#[cfg(target_os = "linux")]
+fn message_header<T>(
+    message: &Message<T>,
+    iov: &mut libc::iovec,
+    control: &mut Control,
+) -> libc::msghdr {
+    // Safety: all zeroes is a valid empty message header
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = message.addr.as_ptr() as *mut _;
+    msg.msg_namelen = message.addr.len();
+    msg.msg_iov = iov;
+    msg.msg_iovlen = 1;
+
+    if message.segments() > 1 {
+        msg.msg_control = control.as_mut_ptr().cast();
+        // Safety: the control buffer has room for one control message with a u16
+        unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(size_of::<u16>() as _) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), message.segment as u16);
+        }
+    }
+
+    msg
+}
+
+#[cfg(target_os = "linux")]
+fn iovec<T>(message: &Message<T>, buf: &[u8]) -> libc::iovec {
+    libc::iovec {
+        iov_base: buf[message.start..].as_ptr() as *mut _,
+        iov_len: message.len,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg<T>(fd: RawFd, message: &Message<T>, buf: &[u8]) -> io::Result<()> {
+    let mut iov = iovec(message, buf);
+    let mut control = Control::default();
+    let msg = message_header(message, &mut iov, &mut control);
+    if unsafe { libc::sendmsg(fd, &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send as many of the messages as the kernel accepts, and return how many it did
+#[cfg(target_os = "linux")]
+fn sendmmsg<T>(fd: RawFd, messages: &[Message<T>], buf: &[u8]) -> io::Result<usize> {
+    let mut iovs: Vec<_> = messages.iter().map(|m| iovec(m, buf)).collect();
+    let mut controls = vec![Control::default(); messages.len()];
+    let mut headers: Vec<_> = messages
+        .iter()
+        .zip(iovs.iter_mut().zip(controls.iter_mut()))
+        .map(|(message, (iov, control))| libc::mmsghdr {
+            msg_hdr: message_header(message, iov, control),
+            msg_len: 0,
+        })
+        .collect();
+
+    match unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as _, 0) } {
+        n if n > 0 => Ok(n as usize),
+        0 => Err(io::ErrorKind::WouldBlock.into()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use super::*;
+
+    fn socket() -> Socket {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        Socket::from(socket)
+    }
+
+    #[test]
+    fn sends_and_receives_batches() {
+        let (sender, receiver) = (socket(), socket());
+        enable_gro(&receiver);
+        let dst = receiver.local_addr().unwrap().as_socket().unwrap();
+
+        let mut batch = SendBatch::default();
+        let packets: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100]).collect();
+        for packet in &packets {
+            batch.push(&sender, dst, packet, ());
+        }
+        batch.push(&sender, dst, &[10; 40], ());
+        batch.flush(|_, _| panic!("Send failed"));
+        assert!(!batch.is_full());
+
+        // However the kernel coalesced them, the same datagrams must come out
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 65535];
+        while let Ok((len, addr, segment)) = recv_from(&receiver, &mut buf) {
+            assert_eq!(addr.as_socket(), sender.local_addr().unwrap().as_socket());
+            received.extend(buf[..len].chunks(segment).map(<[u8]>::to_vec));
+        }
+        assert_eq!(received.len(), 11);
+        assert_eq!(received[..10], packets[..]);
+        assert_eq!(received[10], [10; 40]);
+    }
+
+    #[test]
+    fn coalesces_equal_sized_packets() {
+        let socket = socket();
+        let dst = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let other = SocketAddr::from((Ipv4Addr::LOCALHOST, 10));
+
+        let mut batch = SendBatch::default();
+        batch.support.gso = Some(true);
+        batch.push(&socket, dst, &[0; 100], 1);
+        batch.push(&socket, dst, &[0; 100], 1);
+        batch.push(&socket, dst, &[0; 50], 1);
+        // After a short packet a new message starts
+        batch.push(&socket, dst, &[0; 50], 2);
+        batch.push(&socket, other, &[0; 50], 3);
+        batch.push(&socket, other, &[0; 60], 4);
+
+        let messages: Vec<_> = batch
+            .messages
+            .iter()
+            .map(|m| (m.tag, m.segments()))
+            .collect();
+        assert_eq!(messages, [(1, 3), (2, 1), (3, 1), (4, 1)]);
+        assert_eq!(batch.packets, 6);
+    }
+}
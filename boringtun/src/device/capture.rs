@@ -0,0 +1,563 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Packet capture to pcapng, for debugging a tunnel without tcpdump on the interface.
+//!
+//! A capture records the decrypted packets sent to and received from peers, and optionally the
+//! encrypted datagrams exchanged with their endpoints. Every packet is tagged with its
+//! direction and a comment naming the peer. The inner packets are the first interface of the
+//! capture, and the outer datagrams the second, with IP and UDP headers made up from the
+//! endpoint and the listen port, since the local address is not known.
+//!
+//! Captures are started and stopped with the `capture` keys of a `set=1` transaction, see
+//! [`super::api`], or with [`super::DeviceHandle::start_capture`]. The data path never waits
+//! for the capture: packets are handed to a writer thread, and counted as lost when it falls
+//! behind.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::{fs::OpenOptionsExt, net::UnixStream},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::prelude::*;
+
+use super::{
+    acl::Direction,
+    icmp::{IPV4_HEADER_LEN, IPV6_HEADER_LEN},
+    offload::{fold, set_u16, sum_words},
+};
+use crate::x25519;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Raw IPv4 and IPv6 packets, without a link layer header
+const LINKTYPE_RAW: u16 = 101;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+const OPT_SHB_USERAPPL: u16 = 4;
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+
+const INTERFACE_INNER: u32 = 0;
+const INTERFACE_OUTER: u32 = 1;
+
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+/// The packets the writer thread may fall behind by
+const QUEUE_LEN: usize = 4096;
+/// A consumer that doesn't read for this long ends the capture
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where a capture is written
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureSink {
+    /// A new file, which must not exist yet
+    File(PathBuf),
+    /// A listening Unix stream socket, such as one made by `socat UNIX-LISTEN:<path> -`
+    Unix(PathBuf),
+}
+
+impl FromStr for CaptureSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(CaptureSink::File(path.into())),
+            Some(("unix", path)) if !path.is_empty() => Ok(CaptureSink::Unix(path.into())),
+            _ => Err(format!(
+                "Invalid capture sink: {s}, expected file:<path> or unix:<path>"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CaptureSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureSink::File(path) => write!(f, "file:{}", path.display()),
+            CaptureSink::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// What a capture records, and when it stops recording
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// Also record the encrypted datagrams exchanged with the endpoints
+    pub outer: bool,
+    /// Only record the traffic of these peers, or of every peer when empty
+    pub peers: Vec<x25519::PublicKey>,
+    /// Stop recording after this many packets
+    pub max_packets: Option<u64>,
+    /// Stop recording before the capture grows beyond this many bytes
+    pub max_bytes: Option<u64>,
+    /// Record at most this many bytes of every packet
+    pub snaplen: Option<u32>,
+}
+
+/// The progress of a capture
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CaptureStats {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets that were not recorded because the writer fell behind
+    pub lost: u64,
+}
+
+/// A running capture
+pub(crate) struct Capture {
+    sink: CaptureSink,
+    options: CaptureOptions,
+    listen_port: u16,
+    queue: SyncSender<Vec<u8>>,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    lost: AtomicU64,
+}
+
+impl Capture {
+    /// Open the sink, write the capture header, and start the writer thread. The capture ends
+    /// when it is dropped.
+    pub(crate) fn start(
+        sink: CaptureSink,
+        options: CaptureOptions,
+        listen_port: u16,
+    ) -> io::Result<Capture> {
+        let out: Box<dyn Write + Send> = match &sink {
+            CaptureSink::File(path) => Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?,
+            ),
+            CaptureSink::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Box::new(stream)
+            }
+        };
+        let mut out = BufWriter::new(out);
+        out.write_all(&header(options.snaplen))?;
+        out.flush()?;
+
+        let (queue, received) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("capture".to_owned())
+            .spawn(move || write_blocks(&received, out))?;
+
+        let bytes = header(options.snaplen).len() as u64;
+        Ok(Capture {
+            sink,
+            options,
+            listen_port,
+            queue,
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(bytes),
+            lost: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn sink(&self) -> &CaptureSink {
+        &self.sink
+    }
+
+    pub(crate) fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            lost: self.lost.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record a decrypted packet sent to or received from a peer
+    pub(crate) fn inner(&self, peer: &x25519::PublicKey, packet: &[u8], direction: Direction) {
+        if self.wants(peer) {
+            self.record(INTERFACE_INNER, peer, packet, direction);
+        }
+    }
+
+    /// Record an encrypted datagram sent to or received from the endpoint of a peer
+    pub(crate) fn outer(
+        &self,
+        peer: &x25519::PublicKey,
+        datagram: &[u8],
+        endpoint: SocketAddr,
+        direction: Direction,
+    ) {
+        if !self.options.outer || !self.wants(peer) {
+            return;
+        }
+        let packet = udp_packet(datagram, endpoint, self.listen_port, direction);
+        self.record(INTERFACE_OUTER, peer, &packet, direction);
+    }
+
+    fn wants(&self, peer: &x25519::PublicKey) -> bool {
+        let max_packets = self.options.max_packets.unwrap_or(u64::MAX);
+        (self.options.peers.is_empty() || self.options.peers.contains(peer))
+            && self.packets.load(Ordering::Relaxed) < max_packets
+    }
+
+    fn record(
+        &self,
+        interface: u32,
+        peer: &x25519::PublicKey,
+        packet: &[u8],
+        direction: Direction,
+    ) {
+        let len = packet.len();
+        let snaplen = self.options.snaplen.map_or(usize::MAX, |s| s as usize);
+        let block = enhanced_packet(
+            interface,
+            SystemTime::now(),
+            &packet[..len.min(snaplen)],
+            len,
+            direction,
+            &format!("peer {}", BASE64_STANDARD.encode(peer.as_bytes())),
+        );
+        let size = block.len() as u64;
+        if self
+            .options
+            .max_bytes
+            .is_some_and(|max| self.bytes.load(Ordering::Relaxed) + size > max)
+        {
+            return;
+        }
+        match self.queue.try_send(block) {
+            Ok(()) => {
+                self.packets.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(size, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.lost.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Write the queued blocks until the capture is dropped, flushing whenever the queue is empty
+fn write_blocks(queue: &Receiver<Vec<u8>>, mut out: impl Write) {
+    while let Ok(block) = queue.recv() {
+        let mut written = out.write_all(&block);
+        while written.is_ok()
+            && let Ok(block) = queue.try_recv()
+        {
+            written = out.write_all(&block);
+        }
+        if let Err(e) = written.and_then(|()| out.flush()) {
+            tracing::warn!(message = "Capture stopped", error = ?e);
+            return;
+        }
+    }
+}
+
+/// Append a block of the type, with the body padded to 32 bits
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let padded = body.len().next_multiple_of(4);
+    let len = (12 + padded) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(8 + padded, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+/// Append an option, padded to 32 bits
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// The section header, and the descriptions of the inner and outer interfaces
+fn header(snaplen: Option<u32>) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // The length of the section is not known up front
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    option(&mut body, OPT_SHB_USERAPPL, b"boringtun");
+    option(&mut body, OPT_END, &[]);
+    let mut header = block(BLOCK_SECTION_HEADER, &body);
+
+    for name in ["inner", "outer"] {
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&snaplen.unwrap_or(0).to_le_bytes());
+        option(&mut body, OPT_IF_NAME, name.as_bytes());
+        option(&mut body, OPT_END, &[]);
+        header.extend(block(BLOCK_INTERFACE, &body));
+    }
+    header
+}
+
+/// A packet of the interface, with the default resolution of microseconds
+fn enhanced_packet(
+    interface: u32,
+    time: SystemTime,
+    data: &[u8],
+    len: usize,
+    direction: Direction,
+    comment: &str,
+) -> Vec<u8> {
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let flags = match direction {
+        Direction::In => FLAG_INBOUND,
+        Direction::Out => FLAG_OUTBOUND,
+    };
+
+    let mut body = Vec::with_capacity(data.len() + comment.len() + 48);
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(len as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    body.resize(body.len().next_multiple_of(4), 0);
+    option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    option(&mut body, OPT_COMMENT, comment.as_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(BLOCK_ENHANCED_PACKET, &body)
+}
+
+/// Wrap a datagram in IP and UDP headers between the endpoint and the unspecified address
+fn udp_packet(
+    datagram: &[u8],
+    endpoint: SocketAddr,
+    listen_port: u16,
+    direction: Direction,
+) -> Vec<u8> {
+    let (local, ip_header) = match endpoint.ip() {
+        IpAddr::V4(_) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), IPV4_HEADER_LEN),
+        IpAddr::V6(_) => (IpAddr::V6(Ipv6Addr::UNSPECIFIED), IPV6_HEADER_LEN),
+    };
+    let local = SocketAddr::new(local, listen_port);
+    let (src, dst) = match direction {
+        Direction::In => (endpoint, local),
+        Direction::Out => (local, endpoint),
+    };
+
+    let udp_len = UDP_HEADER_LEN + datagram.len();
+    let mut packet = vec![0u8; ip_header + udp_len];
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet[0] = 0x45;
+            set_u16(&mut packet, 2, (ip_header + udp_len) as u16);
+            packet[8] = 64;
+            packet[9] = IPPROTO_UDP;
+            packet[12..16].copy_from_slice(&src.octets());
+            packet[16..20].copy_from_slice(&dst.octets());
+            let sum = sum_words(&packet[..ip_header], 0);
+            set_u16(&mut packet, 10, !fold(sum));
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            packet[0] = 0x60;
+            set_u16(&mut packet, 4, udp_len as u16);
+            packet[6] = IPPROTO_UDP;
+            packet[7] = 64;
+            packet[8..24].copy_from_slice(&src.octets());
+            packet[24..40].copy_from_slice(&dst.octets());
+        }
+        _ => unreachable!(),
+    }
+
+    // The UDP checksum is left out, as the local address it covers is not known
+    let udp = &mut packet[ip_header..];
+    set_u16(udp, 0, src.port());
+    set_u16(udp, 2, dst.port());
+    set_u16(udp, 4, udp_len as u16);
+    udp[UDP_HEADER_LEN..].copy_from_slice(datagram);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, os::unix::net::UnixListener};
+
+    use super::*;
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    /// Split a capture into its blocks, checking the lengths at both ends of every block
+    fn blocks(mut capture: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        while !capture.is_empty() {
+            let len = u32_at(capture, 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(capture, len - 4) as usize, len);
+            blocks.push((u32_at(capture, 0), &capture[8..len - 4]));
+            capture = &capture[len..];
+        }
+        blocks
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("boringtun-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn writes_pcapng_blocks() {
+        let packet = enhanced_packet(
+            INTERFACE_OUTER,
+            UNIX_EPOCH + Duration::from_micros(0x1_0000_0002),
+            &[0x45; 5],
+            9,
+            Direction::In,
+            "peer",
+        );
+        let header = header(Some(96));
+        let capture = [header.as_slice(), packet.as_slice()].concat();
+
+        let blocks = blocks(&capture);
+        let types: Vec<u32> = blocks.iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            types,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE,
+                BLOCK_INTERFACE,
+                BLOCK_ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(u32_at(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_RAW.to_le_bytes());
+        assert_eq!(u32_at(blocks[1].1, 4), 96);
+
+        let epb = blocks[3].1;
+        assert_eq!(u32_at(epb, 0), INTERFACE_OUTER);
+        assert_eq!((u32_at(epb, 4), u32_at(epb, 8)), (1, 2));
+        assert_eq!((u32_at(epb, 12), u32_at(epb, 16)), (5, 9));
+        // The data is padded, and followed by the flags option
+        assert_eq!(&epb[20..28], &[0x45, 0x45, 0x45, 0x45, 0x45, 0, 0, 0]);
+        assert_eq!(&epb[28..32], &[2, 0, 4, 0]);
+        assert_eq!(u32_at(epb, 32), FLAG_INBOUND);
+    }
+
+    #[test]
+    fn wraps_outer_datagrams() {
+        let endpoint: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let packet = udp_packet(&[4, 0, 0, 0], endpoint, 51821, Direction::Out);
+        assert_eq!(packet.len(), 20 + 8 + 4);
+        assert_eq!(fold(sum_words(&packet[..20], 0)), 0xffff);
+        assert_eq!(&packet[16..20], &[192, 0, 2, 1]);
+        assert_eq!(&packet[20..24], &[0xca, 0x6d, 0xca, 0x6c]);
+
+        let endpoint: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let packet = udp_packet(&[4, 0, 0, 0], endpoint, 51821, Direction::In);
+        assert_eq!(packet.len(), 40 + 8 + 4);
+        assert_eq!(packet[6], IPPROTO_UDP);
+        assert_eq!(&packet[8..10], &[0x20, 0x01]);
+        assert_eq!(&packet[40..44], &[0xca, 0x6c, 0xca, 0x6d]);
+    }
+
+    #[test]
+    fn captures_to_a_new_file() {
+        let path = temp_path("capture.pcapng");
+        let _ = fs::remove_file(&path);
+        let peer = x25519::PublicKey::from([1u8; 32]);
+        let other = x25519::PublicKey::from([2u8; 32]);
+        let options = CaptureOptions {
+            peers: vec![peer],
+            max_packets: Some(2),
+            ..Default::default()
+        };
+
+        let capture = Capture::start(CaptureSink::File(path.clone()), options, 51820).unwrap();
+        for _ in 0..3 {
+            capture.inner(&peer, &[0x45; 20], Direction::Out);
+        }
+        capture.inner(&other, &[0x45; 20], Direction::Out);
+        // Outer datagrams were not asked for
+        capture.outer(
+            &peer,
+            &[4; 32],
+            "192.0.2.1:1".parse().unwrap(),
+            Direction::In,
+        );
+        assert_eq!(capture.stats().packets, 2);
+        drop(capture);
+
+        // Wait for the writer thread to catch up
+        let mut written = Vec::new();
+        for _ in 0..100 {
+            written = fs::read(&path).unwrap();
+            if blocks(&written).len() == 5 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(blocks(&written).len(), 5);
+
+        // Existing files are never overwritten
+        let existing = Capture::start(CaptureSink::File(path.clone()), Default::default(), 0);
+        assert!(existing.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn captures_to_a_unix_socket() {
+        let path = temp_path("capture.sock");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let options = CaptureOptions {
+            outer: true,
+            snaplen: Some(16),
+            ..Default::default()
+        };
+
+        let capture = Capture::start(CaptureSink::Unix(path.clone()), options, 51820).unwrap();
+        let peer = x25519::PublicKey::from([1u8; 32]);
+        capture.outer(
+            &peer,
+            &[4; 32],
+            "192.0.2.1:1".parse().unwrap(),
+            Direction::In,
+        );
+        drop(capture);
+
+        let mut received = Vec::new();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_to_end(&mut received).unwrap();
+        let blocks = blocks(&received);
+        assert_eq!(blocks.len(), 4);
+        let epb = blocks[3].1;
+        assert_eq!((u32_at(epb, 12), u32_at(epb, 16)), (16, 20 + 8 + 32));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_sinks() {
+        for sink in ["file:/tmp/a.pcapng", "unix:/run/capture.sock"] {
+            assert_eq!(sink.parse::<CaptureSink>().unwrap().to_string(), sink);
+        }
+        assert!("/tmp/a.pcapng".parse::<CaptureSink>().is_err());
+        assert!("file:".parse::<CaptureSink>().is_err());
+    }
+}
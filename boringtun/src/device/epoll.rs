@@ -7,6 +7,8 @@ use libc::*;
 use parking_lot::Mutex;
 
 use super::Error;
+use super::uring::{Completion, Uring};
+pub use super::uring::{ReadKind, Received};
 
 /// A return type for the EventPoll::wait() function
 pub enum WaitResult<'a, H> {
@@ -22,6 +24,7 @@ pub enum WaitResult<'a, H> {
 pub struct EventPoll<H: Sized> {
     events: Mutex<Vec<Option<Box<Event<H>>>>>,
     epoll: RawFd, // The OS epoll
+    uring: Option<Uring>,
 }
 
 /// A type that hold a reference to a triggered Event
@@ -31,6 +34,8 @@ pub struct EventGuard<'a, H> {
     epoll: RawFd,
     event: &'a mut Event<H>,
     poll: &'a EventPoll<H>,
+    completions: Vec<Completion>,
+    received: Option<Vec<Received>>,
 }
 
 /// A reference to a single event in an EventPoll
@@ -39,11 +44,14 @@ pub struct EventRef {
 }
 
 struct Event<H> {
-    event: epoll_event, // The epoll event description
-    fd: RawFd,          // The associated fd
-    handler: H,         // The associated data
-    notifier: bool,     // Is a notification event
-    needs_read: bool,   // This event needs to be read to be cleared
+    event: epoll_event,          // The epoll event description
+    fd: RawFd,                   // The associated fd
+    handler: H,                  // The associated data
+    notifier: bool,              // Is a notification event
+    needs_read: bool,            // This event needs to be read to be cleared
+    read_kind: Option<ReadKind>, // Receives data with io_uring
+    key: u64,                    // The io_uring user data
+    periodic: bool,              // Is a timer
 }
 
 impl<H> Drop for EventPoll<H> {
@@ -63,9 +71,45 @@ impl<H: Sync + Send> EventPoll<H> {
         Ok(EventPoll {
             events: Mutex::new(Vec::new()),
             epoll,
+            uring: None,
         })
     }
 
+    /// Create a new event registry backed by io_uring instead of epoll, see
+    /// [`super::DeviceConfig::use_io_uring`]
+    pub fn new_with_io_uring() -> Result<EventPoll<H>, Error> {
+        let mut poll = Self::new()?;
+        poll.uring = Some(Uring::new().map_err(Error::EventQueue)?);
+        Ok(poll)
+    }
+
+    /// Add and enable a new event like [`EventPoll::new_event`], for a UDP socket or a tunnel
+    /// interface. With io_uring the data is received before the event triggers, and the
+    /// handler gets it from [`EventGuard::take_received`] instead of reading it.
+    pub fn new_read_event(
+        &self,
+        trigger: RawFd,
+        handler: H,
+        kind: ReadKind,
+    ) -> Result<EventRef, Error> {
+        let flags = EPOLLIN | EPOLLONESHOT;
+        let ev = Event {
+            event: epoll_event {
+                events: flags.cast_unsigned(),
+                u64: 0,
+            },
+            fd: trigger,
+            handler,
+            notifier: false,
+            needs_read: false,
+            read_kind: Some(kind),
+            key: 0,
+            periodic: false,
+        };
+
+        self.register_event(ev)
+    }
+
     /// Add and enable a new event with the factory.
     /// The event is triggered when a Read operation on the provided trigger becomes available
     /// If the trigger fd is closed, the event won't be triggered anymore, but it's data won't be
@@ -90,6 +134,9 @@ impl<H: Sync + Send> EventPoll<H> {
             handler,
             notifier: false,
             needs_read: false,
+            read_kind: None,
+            key: 0,
+            periodic: false,
         };
 
         self.register_event(ev)
@@ -111,6 +158,9 @@ impl<H: Sync + Send> EventPoll<H> {
             handler,
             notifier: false,
             needs_read: false,
+            read_kind: None,
+            key: 0,
+            periodic: false,
         };
 
         self.register_event(ev)
@@ -154,6 +204,9 @@ impl<H: Sync + Send> EventPoll<H> {
             handler,
             notifier: false,
             needs_read: true,
+            read_kind: None,
+            key: 0,
+            periodic: true,
         };
 
         self.register_event(ev)
@@ -183,6 +236,9 @@ impl<H: Sync + Send> EventPoll<H> {
             handler,
             notifier: true,
             needs_read: false,
+            read_kind: None,
+            key: 0,
+            periodic: false,
         };
 
         self.register_event(ev)
@@ -210,6 +266,9 @@ impl<H: Sync + Send> EventPoll<H> {
             handler,
             notifier: false,
             needs_read: true,
+            read_kind: None,
+            key: 0,
+            periodic: false,
         };
 
         self.register_event(ev)
@@ -220,6 +279,10 @@ impl<H: Sync + Send> EventPoll<H> {
     /// In case a notifier is triggered, all waiting threads will receive the same
     /// handler.
     pub fn wait(&self) -> WaitResult<'_, H> {
+        if let Some(ref uring) = self.uring {
+            return self.wait_uring(uring);
+        }
+
         let mut event = epoll_event { events: 0, u64: 0 };
         match unsafe { epoll_wait(self.epoll, &raw mut event, 1, -1) } {
             -1 => return WaitResult::Error(io::Error::last_os_error().to_string()),
@@ -233,6 +296,8 @@ impl<H: Sync + Send> EventPoll<H> {
             epoll: self.epoll,
             event: event_data,
             poll: self,
+            completions: Vec::new(),
+            received: None,
         };
 
         if event.events & EPOLLHUP as u32 != 0 {
@@ -243,6 +308,119 @@ impl<H: Sync + Send> EventPoll<H> {
         }
     }
 
+    /// The fds of the registered events, except timers, to wait for them with another event
+    /// loop. When one is readable, [`EventPoll::triggered`] returns its event.
+    pub fn fds(&self) -> Vec<RawFd> {
+        let events = self.events.lock();
+        events
+            .iter()
+            .flatten()
+            .filter(|event| !event.periodic)
+            .map(|event| event.fd)
+            .collect()
+    }
+
+    /// The time until the next timer expires, zero if one already did
+    pub fn timeout(&self) -> Option<Duration> {
+        let events = self.events.lock();
+        events
+            .iter()
+            .flatten()
+            .filter(|event| event.periodic)
+            .map(|event| {
+                if readable(event.fd) {
+                    return Duration::ZERO;
+                }
+                let mut spec: itimerspec = unsafe { std::mem::zeroed() };
+                unsafe { timerfd_gettime(event.fd, &raw mut spec) };
+                Duration::new(spec.it_value.tv_sec as u64, spec.it_value.tv_nsec as u32)
+            })
+            .min()
+    }
+
+    /// The fds of the timers that expired, to pass to [`EventPoll::triggered`]
+    pub fn due_timers(&self) -> Vec<RawFd> {
+        let events = self.events.lock();
+        events
+            .iter()
+            .flatten()
+            .filter(|event| event.periodic && readable(event.fd))
+            .map(|event| event.fd)
+            .collect()
+    }
+
+    /// The event of an fd that another event loop found readable, as [`EventPoll::wait`] would
+    /// return it, or `None` if no event is registered for it. Only valid without io_uring.
+    pub fn triggered(&self, fd: RawFd) -> Option<WaitResult<'_, H>> {
+        debug_assert!(self.uring.is_none());
+        let mut events = self.events.lock();
+        let event = events.get_mut(usize::try_from(fd).ok()?)?.as_mut()?;
+        let event_data = unsafe { (event.as_mut() as *mut Event<H>).as_mut().unwrap() };
+        drop(events);
+
+        let mut pollfd = pollfd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        unsafe { poll(&raw mut pollfd, 1, 0) };
+        let guard = EventGuard {
+            epoll: self.epoll,
+            event: event_data,
+            poll: self,
+            completions: Vec::new(),
+            received: None,
+        };
+
+        Some(if pollfd.revents & POLLHUP != 0 {
+            WaitResult::EoF(guard)
+        } else {
+            WaitResult::Ok(guard)
+        })
+    }
+
+    fn wait_uring<'a>(&'a self, uring: &'a Uring) -> WaitResult<'a, H> {
+        loop {
+            let ready = match uring.wait() {
+                Ok(ready) => ready,
+                Err(e) => return WaitResult::Error(e.to_string()),
+            };
+            let receives = ready.completions.is_some();
+            let completions = ready.completions.unwrap_or_default();
+
+            let mut events = self.events.lock();
+            let Some(event) = events
+                .get_mut(ready.fd as usize)
+                .and_then(Option::as_mut)
+                .filter(|event| event.key == ready.key)
+            else {
+                // The event was replaced in the meantime
+                uring.release(ready.key, 0, &completions);
+                continue;
+            };
+            let event_data = unsafe { (event.as_mut() as *mut Event<H>).as_mut().unwrap() };
+            drop(events);
+
+            let received = match event_data.read_kind {
+                Some(kind) if receives => Some(uring.received(kind, &completions)),
+                _ => None,
+            };
+            let guard = EventGuard {
+                epoll: self.epoll,
+                event: event_data,
+                poll: self,
+                completions,
+                received,
+            };
+
+            return if ready.revents & POLLHUP as u32 != 0 {
+                WaitResult::EoF(guard)
+            } else {
+                WaitResult::Ok(guard)
+            };
+        }
+    }
+
     // Register an event with this poll.
     fn register_event(&self, ev: Event<H>) -> Result<EventRef, Error> {
         // To register an event we
@@ -255,6 +433,14 @@ impl<H: Sync + Send> EventPoll<H> {
         // The inner event points back to the wrapper
         ev.event.u64 = ev.as_mut() as *mut Event<H> as _;
         let mut event_desc = ev.event;
+        if let Some(ref uring) = self.uring {
+            // The event is armed while holding the lock, so it is found once triggered
+            let mut events = self.events.lock();
+            let mask = event_desc.events & (EPOLLIN | EPOLLOUT) as u32;
+            ev.key = uring.add(trigger, mask, ev.notifier, ev.read_kind);
+            Self::insert_locked(&mut events, trigger as _, ev);
+            return Ok(EventRef { trigger });
+        }
         // Now add the pointer to the events vector, this is a place from which we can drop the event
         self.insert_at(trigger as _, ev);
         // Add the event to epoll
@@ -268,20 +454,27 @@ impl<H: Sync + Send> EventPoll<H> {
     // Insert an event into the events vector
     fn insert_at(&self, index: usize, data: Box<Event<H>>) {
         let mut events = self.events.lock();
-        while events.len() <= index {
-            // Resize the vector to be able to fit the new index
-            // We trust the OS to allocate file descriptors in a sane order
-            events.push(None); // resize doesn't work because Clone is not satisfied
-        }
-
-        if events[index].take().is_some() {
+        if Self::insert_locked(&mut events, index, data) {
             // Properly remove the previous event first
             unsafe {
                 epoll_ctl(self.epoll, EPOLL_CTL_DEL, index as _, null_mut());
             };
         }
+    }
+
+    // Insert an event into the locked events vector, returns true if it replaced one
+    fn insert_locked(
+        events: &mut Vec<Option<Box<Event<H>>>>,
+        index: usize,
+        data: Box<Event<H>>,
+    ) -> bool {
+        while events.len() <= index {
+            // Resize the vector to be able to fit the new index
+            // We trust the OS to allocate file descriptors in a sane order
+            events.push(None); // resize doesn't work because Clone is not satisfied
+        }
 
-        events[index] = Some(data);
+        events[index].replace(data).is_some()
     }
 
     /// Trigger a notification
@@ -339,7 +532,12 @@ impl<H> EventPoll<H> {
         let mut events = self.events.lock();
         assert!(index >= 0);
         if events[index as usize].take().is_some() {
-            unsafe { epoll_ctl(self.epoll, EPOLL_CTL_DEL, index, null_mut()) };
+            match self.uring {
+                Some(ref uring) => uring.remove(index),
+                None => unsafe {
+                    epoll_ctl(self.epoll, EPOLL_CTL_DEL, index, null_mut());
+                },
+            }
         }
     }
 }
@@ -360,6 +558,12 @@ impl<H> Drop for EventGuard<'_, H> {
             while unsafe { read(self.event.fd, buf.as_mut_ptr().cast(), buf.len() as _) } != -1 {}
         }
 
+        if let Some(ref uring) = self.poll.uring {
+            let mask = self.event.event.events & (EPOLLIN | EPOLLOUT) as u32;
+            uring.release(self.event.key, mask, &self.completions);
+            return;
+        }
+
         unsafe {
             epoll_ctl(
                 self.epoll,
@@ -379,11 +583,21 @@ impl<H> EventGuard<'_, H> {
     }
 
     /// Cancel and remove the event referenced by this guard
-    pub fn cancel(self) {
+    pub fn cancel(mut self) {
         unsafe { self.poll.clear_event_by_fd(self.event.fd) };
+        if let Some(ref uring) = self.poll.uring {
+            // The event is gone, so this only returns the buffers
+            uring.release(self.event.key, 0, &std::mem::take(&mut self.completions));
+        }
         std::mem::forget(self); // Don't call the regular drop that would enable the event
     }
 
+    /// Take the data received for a read event, when the handler must not read it itself. It
+    /// is only valid while the guard is alive.
+    pub fn take_received(&mut self) -> Option<Vec<Received>> {
+        self.received.take()
+    }
+
     #[must_use]
     pub fn fd(&self) -> i32 {
         self.event.fd
@@ -400,6 +614,16 @@ impl<H> EventGuard<'_, H> {
     }
 }
 
+/// Check without blocking whether a fd is readable
+fn readable(fd: RawFd) -> bool {
+    let mut pollfd = pollfd {
+        fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { poll(&raw mut pollfd, 1, 0) == 1 }
+}
+
 pub fn block_signal(signal: c_int) -> Result<sigset_t, String> {
     unsafe {
         let mut sigset = std::mem::zeroed();
@@ -413,3 +637,39 @@ pub fn block_signal(signal: c_int) -> Result<sigset_t, String> {
         Ok(sigset)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_wait() {
+        let poll = EventPoll::<u32>::new().unwrap();
+        let notifier = poll.new_notifier(1).unwrap();
+        poll.new_periodic_event(2, Duration::from_millis(20))
+            .unwrap();
+
+        // Only the notifier is waited on, the timer is reported through its timeout
+        assert_eq!(poll.fds(), vec![notifier.trigger]);
+        let timeout = poll.timeout().unwrap();
+        assert!(timeout > Duration::ZERO && timeout <= Duration::from_millis(20));
+        assert!(poll.due_timers().is_empty());
+
+        poll.trigger_notification(&notifier);
+        match poll.triggered(notifier.trigger) {
+            Some(WaitResult::Ok(mut guard)) => assert_eq!(*guard.get_mut(), 1),
+            _ => panic!("notifier not triggered"),
+        }
+        poll.stop_notification(&notifier);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(poll.timeout(), Some(Duration::ZERO));
+        let due = poll.due_timers();
+        assert_eq!(due.len(), 1);
+        match poll.triggered(due[0]) {
+            Some(WaitResult::Ok(mut guard)) => assert_eq!(*guard.get_mut(), 2),
+            _ => panic!("timer not triggered"),
+        }
+        assert!(poll.triggered(-1).is_none());
+    }
+}
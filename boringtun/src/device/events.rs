@@ -0,0 +1,87 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::{
+    io,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use parking_lot::Mutex;
+
+use crate::uapi::event::Event;
+
+/// The maximum number of concurrent subscribers, further requests are refused with EBUSY
+const MAX_SUBSCRIBERS: usize = 64;
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+const SEND_FLAGS: libc::c_int = libc::MSG_DONTWAIT;
+
+/// The set of API connections that subscribed to the device event stream
+#[derive(Default)]
+pub(crate) struct EventSubscribers {
+    streams: Mutex<Vec<UnixStream>>,
+    count: AtomicUsize,
+}
+
+impl EventSubscribers {
+    pub fn is_full(&self) -> bool {
+        self.count.load(Ordering::Relaxed) >= MAX_SUBSCRIBERS
+    }
+
+    /// Add a new subscriber
+    pub fn subscribe(&self, stream: UnixStream) {
+        let mut streams = self.streams.lock();
+
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        unsafe {
+            // There is no MSG_NOSIGNAL on BSD derived systems, it has to be set on the socket
+            let on: libc::c_int = 1;
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_NOSIGPIPE,
+                (&raw const on).cast(),
+                std::mem::size_of_val(&on) as _,
+            );
+        }
+
+        streams.push(stream);
+        self.count.store(streams.len(), Ordering::Relaxed);
+    }
+
+    /// Send an event line to every subscriber. The sends never block: a subscriber that
+    /// can't accept the whole line right away is too slow to keep up, and is dropped.
+    pub fn emit(&self, event: Event) {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let line = format!("{event}\n");
+        let mut streams = self.streams.lock();
+        streams.retain(|stream| {
+            match unsafe {
+                libc::send(
+                    stream.as_raw_fd(),
+                    line.as_ptr().cast(),
+                    line.len(),
+                    SEND_FLAGS,
+                )
+            } {
+                n if n >= 0 && n.cast_unsigned() == line.len() => true,
+                n => {
+                    let e = if n < 0 {
+                        io::Error::last_os_error()
+                    } else {
+                        io::ErrorKind::WouldBlock.into()
+                    };
+                    tracing::info!(message = "Dropping event subscriber", error = ?e);
+                    false
+                }
+            }
+        });
+        self.count.store(streams.len(), Ordering::Relaxed);
+    }
+}
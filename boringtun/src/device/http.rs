@@ -0,0 +1,520 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A minimal HTTP/1.1 server for the local management endpoints. Every listener is served on
+//! a thread of its own, and every connection carries a single request, read on a thread of its
+//! own. The device lock is only taken while the request is handled, so slow clients never hold
+//! up the packet threads.
+
+use std::{
+    fs::remove_file,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::{
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    Device, Error,
+    dev_lock::{Lock, LockReadGuard},
+};
+
+/// Requests with larger headers or bodies are rejected
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+/// The whole request must arrive within this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Writing the response must make progress within this time
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections beyond this many in flight are closed right away
+const MAX_CONNECTIONS: usize = 16;
+/// How often the listener threads check whether the device is gone
+const ACCEPT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where to serve an HTTP endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpListen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for HttpListen {
+    type Err = String;
+
+    /// Absolute paths are Unix sockets, anything else must be an `ip:port` pair
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(HttpListen::Unix(s.into()));
+        }
+        s.parse()
+            .map(HttpListen::Tcp)
+            .map_err(|_| format!("Expected a socket path or an ip:port pair: {s}"))
+    }
+}
+
+pub(super) struct Request {
+    pub method: String,
+    /// The request target without the query
+    pub path: String,
+    pub accept: Option<String>,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+pub(super) struct Response {
+    pub status: u16,
+    pub content_type: Option<&'static str>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            content_type: Some(content_type),
+            body: body.into(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        };
+
+        write!(w, "HTTP/1.1 {} {reason}\r\n", self.status)?;
+        if self.status == 401 {
+            write!(w, "WWW-Authenticate: Bearer\r\n")?;
+        }
+        if let Some(content_type) = self.content_type {
+            write!(w, "Content-Type: {content_type}\r\n")?;
+        }
+        write!(
+            w,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        )?;
+        w.write_all(&self.body)?;
+        w.flush()
+    }
+}
+
+/// An endpoint served by [`Device::register_http_service`]
+pub(super) trait HttpService: Send + Sync + 'static {
+    fn handle(&self, request: &Request, d: &mut LockReadGuard<Device>) -> Response;
+
+    /// The response to a request that could not be parsed
+    fn error(&self, status: u16, message: &str) -> Response;
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Wait up to the timeout for a connection
+    fn accept(&self, timeout: Duration) -> io::Result<Option<Connection>> {
+        let mut pollfd = libc::pollfd {
+            fd: match self {
+                Listener::Tcp(listener) => listener.as_raw_fd(),
+                Listener::Unix(listener) => listener.as_raw_fd(),
+            },
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        if unsafe { libc::poll(&raw mut pollfd, 1, timeout) } <= 0 {
+            return Ok(None);
+        }
+
+        // Connections accepted on a non-blocking listener may inherit the flag
+        let conn = match self {
+            Listener::Tcp(listener) => listener.accept().map(|(conn, _)| {
+                conn.set_nonblocking(false)?;
+                Ok(Connection::Tcp(conn))
+            }),
+            Listener::Unix(listener) => listener.accept().map(|(conn, _)| {
+                conn.set_nonblocking(false)?;
+                Ok(Connection::Unix(conn))
+            }),
+        };
+        match conn {
+            Ok(conn) => conn.map(Some),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Connection {
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.set_read_timeout(Some(timeout)),
+            Connection::Unix(conn) => conn.set_read_timeout(Some(timeout)),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.set_write_timeout(Some(timeout)),
+            Connection::Unix(conn) => conn.set_write_timeout(Some(timeout)),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(conn) => conn.read(buf),
+            Connection::Unix(conn) => conn.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(conn) => conn.write(buf),
+            Connection::Unix(conn) => conn.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(conn) => conn.flush(),
+            Connection::Unix(conn) => conn.flush(),
+        }
+    }
+}
+
+/// Reads from a connection until the deadline, however slowly the data trickles in
+struct Deadline {
+    conn: Connection,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.conn.set_read_timeout(remaining)?;
+        self.conn.read(buf)
+    }
+}
+
+/// A bound listener, served once the device is started
+pub(super) struct HttpServer {
+    listener: Listener,
+    service: Arc<dyn HttpService>,
+}
+
+impl HttpServer {
+    /// Serve on a thread of its own, until the device is dropped
+    pub(super) fn spawn(self, device: Weak<Lock<Device>>) -> io::Result<()> {
+        thread::Builder::new()
+            .name("http".to_owned())
+            .spawn(move || self.run(&device))?;
+        Ok(())
+    }
+
+    fn run(self, device: &Weak<Lock<Device>>) {
+        let active = Arc::new(AtomicUsize::new(0));
+        while device.strong_count() > 0 {
+            let conn = match self.listener.accept(ACCEPT_INTERVAL) {
+                Ok(Some(conn)) => conn,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::debug!(message = "Failed to accept HTTP connection", error = ?e);
+                    continue;
+                }
+            };
+            if active.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::Relaxed);
+                tracing::warn!("Too many HTTP connections, closing a new one");
+                continue;
+            }
+
+            let service = Arc::clone(&self.service);
+            let device = Weak::clone(device);
+            let finished = Arc::clone(&active);
+            let spawned = thread::Builder::new()
+                .name("http-conn".to_owned())
+                .spawn(move || {
+                    serve(conn, &*service, &device);
+                    finished.fetch_sub(1, Ordering::Relaxed);
+                });
+            if let Err(e) = spawned {
+                tracing::warn!(message = "Failed to spawn HTTP connection thread", error = ?e);
+                active.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Device {
+    /// Bind the listener of an endpoint. It is served by [`HttpServer::spawn`] once the device
+    /// is started.
+    pub(super) fn register_http_service(
+        &mut self,
+        listen: &HttpListen,
+        service: impl HttpService,
+    ) -> Result<(), Error> {
+        let listener = match listen {
+            HttpListen::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).map_err(Error::Http)?;
+                listener.set_nonblocking(true).map_err(Error::Http)?;
+                Listener::Tcp(listener)
+            }
+            HttpListen::Unix(path) => {
+                let _ = remove_file(path); // Attempt to remove the socket if already exists
+                let listener = UnixListener::bind(path).map_err(Error::Http)?;
+                self.cleanup_paths.push(path.to_string_lossy().into_owned());
+                listener.set_nonblocking(true).map_err(Error::Http)?;
+                Listener::Unix(listener)
+            }
+        };
+
+        self.http_servers.push(HttpServer {
+            listener,
+            service: Arc::new(service),
+        });
+        Ok(())
+    }
+}
+
+/// Serve a single request on the connection, then close it
+fn serve(conn: Connection, service: &dyn HttpService, device: &Weak<Lock<Device>>) {
+    let mut reader = BufReader::new(Deadline {
+        conn,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    });
+    let response = match read_request(&mut reader) {
+        Ok(request) => {
+            let Some(device) = device.upgrade() else {
+                return;
+            };
+            service.handle(&request, &mut device.read())
+        }
+        Err((status, message)) => service.error(status, message),
+    };
+
+    let conn = &mut reader.get_mut().conn;
+    let written = conn
+        .set_write_timeout(WRITE_TIMEOUT)
+        .and_then(|()| response.write_to(conn));
+    if let Err(e) = written {
+        tracing::debug!(message = "Failed to write HTTP response", error = ?e);
+    }
+}
+
+fn read_request<R: BufRead>(reader: R) -> Result<Request, (u16, &'static str)> {
+    let mut reader = reader.take(MAX_REQUEST_SIZE);
+    let mut line = String::new();
+    let mut read_line = |line: &mut String| {
+        line.clear();
+        match reader.read_line(line) {
+            Ok(0) => Err((400, "Incomplete request")),
+            Ok(_) if !line.ends_with('\n') => Err((413, "Request too large")),
+            Ok(_) => {
+                line.truncate(line.trim_end().len());
+                Ok(())
+            }
+            Err(_) => Err((400, "Failed to read request")),
+        }
+    };
+
+    read_line(&mut line)?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err((400, "Malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err((400, "Unsupported HTTP version"));
+    }
+    let mut request = Request {
+        method: method.to_owned(),
+        path: target.split('?').next().unwrap_or_default().to_owned(),
+        accept: None,
+        authorization: None,
+        body: Vec::new(),
+    };
+
+    let mut content_length = 0;
+    loop {
+        read_line(&mut line)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err((400, "Malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse::<u64>()
+                .map_err(|_| (400, "Invalid Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("accept") {
+            request.accept = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err((400, "Chunked requests are not supported"));
+        }
+    }
+
+    if content_length > reader.limit() {
+        return Err((413, "Request too large"));
+    }
+    reader
+        .take(content_length)
+        .read_to_end(&mut request.body)
+        .map_err(|_| (400, "Failed to read request body"))?;
+    if request.body.len() as u64 != content_length {
+        return Err((400, "Incomplete request body"));
+    }
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceConfig, DeviceHandle, metrics::MetricsConfig};
+
+    #[test]
+    fn parse_request() {
+        let raw = "PUT /peers/abc%2Bd?x=1 HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   authorization: Bearer secret\r\n\
+                   Content-Length: 2\r\n\r\n{}";
+        let request = read_request(raw.as_bytes()).ok().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/peers/abc%2Bd");
+        assert_eq!(request.authorization.as_deref(), Some("Bearer secret"));
+        assert_eq!(request.body, b"{}");
+
+        let status = |raw: &str| read_request(raw.as_bytes()).err().map(|(s, _)| s);
+        assert_eq!(status("GET /health\r\n\r\n"), Some(400));
+        assert_eq!(status("GET /health HTTP/1.1\r\n"), Some(400));
+        assert_eq!(
+            status("PUT /peers HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"),
+            Some(400)
+        );
+        assert_eq!(
+            status("PUT /peers HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n"),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn response_format() {
+        let mut buf = Vec::new();
+        let response = Response {
+            status: 204,
+            content_type: None,
+            body: Vec::new(),
+        };
+        response.write_to(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        let mut buf = Vec::new();
+        Response::new(200, "text/plain", "ok\n")
+            .write_to(&mut buf)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\
+             Connection: close\r\n\r\nok\n"
+        );
+    }
+
+    #[test]
+    fn reads_stop_at_the_deadline() {
+        let (conn, mut client) = UnixStream::pair().unwrap();
+        client.write_all(b"GET").unwrap();
+        let mut reader = Deadline {
+            conn: Connection::Unix(conn),
+            deadline: Instant::now() + Duration::from_millis(50),
+        };
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert!(reader.read(&mut buf).is_err());
+        let e = reader.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    #[ignore]
+    /// Creating the tunnel interface requires privileges
+    fn slow_clients_do_not_stall_the_device() {
+        let addr: SocketAddr = "127.0.0.1:61090".parse().unwrap();
+        let config = DeviceConfig {
+            n_threads: 1,
+            metrics: Some(MetricsConfig {
+                listen: HttpListen::Tcp(addr),
+                max_peers: None,
+            }),
+            ..DeviceConfig::default()
+        };
+        let device = DeviceHandle::new("utun94", config).unwrap();
+        let started = Instant::now();
+
+        // Requests that never complete
+        let _slow: Vec<_> = (0..4)
+            .map(|_| {
+                let mut conn = TcpStream::connect(addr).unwrap();
+                conn.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+                conn
+            })
+            .collect();
+
+        let mut api = UnixStream::connect("/var/run/wireguard/utun94.sock").unwrap();
+        api.write_all(b"get=1\n\n").unwrap();
+        let mut response = String::new();
+        api.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("errno=0\n\n"), "{response}");
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The listener is closed once the device is gone
+        drop(device);
+        thread::sleep(ACCEPT_INTERVAL * 2);
+        TcpListener::bind(addr).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An optional management API that serves JSON over HTTP on a local listener.
+//!
+//! | Method   | Path               | Description                                    |
+//! |----------|--------------------|------------------------------------------------|
+//! | `GET`    | `/health`          | Always succeeds while the device is running    |
+//! | `GET`    | `/interface`       | Public key, listen port, fwmark and peer count |
+//! | `GET`    | `/peers`           | All peers                                      |
+//! | `GET`    | `/peers/{key}`     | A single peer                                  |
+//! | `PUT`    | `/peers/{key}`     | Create or replace a peer                       |
+//! | `DELETE` | `/peers/{key}`     | Remove a peer                                  |
+//! | `GET`    | `/stats`           | Transfer totals and per peer counters          |
+//!
+//! Keys in paths may be hex or base64, with `/` and `+` percent encoded. Keys in responses are
+//! base64, as used by `wg`. Every request is executed as a UAPI `get=1` or `set=1`
+//! transaction, so both APIs share the same validation and semantics.
+//!
+//! When a token is configured, every request except `/health` must carry an
+//! `Authorization: Bearer <token>` header.
+
+use std::{io::BufRead, net::SocketAddr};
+
+use base64::prelude::*;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use super::{
+    Device, Error,
+    api::api_request,
+    dev_lock::LockReadGuard,
+    http::{HttpListen, HttpService, Request, Response},
+    peer::AllowedIP,
+};
+use crate::{
+    serialization::KeyBytes,
+    uapi::{
+        Field, GetRequest,
+        client::{self, Interface, PeerUpdate, SetRequest},
+    },
+    x25519,
+};
+
+#[derive(Debug, Clone)]
+pub struct HttpApiConfig {
+    pub listen: HttpListen,
+    /// The bearer token clients must present, if any. A token is required to listen on a
+    /// non-loopback address.
+    pub token: Option<String>,
+}
+
+/// The body of a `PUT /peers/{key}` request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerConfig {
+    preshared_key: Option<String>,
+    endpoint: Option<SocketAddr>,
+    persistent_keepalive_interval: Option<u16>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+}
+
+struct JsonApi {
+    token: Option<String>,
+}
+
+impl HttpService for JsonApi {
+    fn handle(&self, request: &Request, d: &mut LockReadGuard<Device>) -> Response {
+        handle(request, self.token.as_deref(), d)
+    }
+
+    fn error(&self, status: u16, message: &str) -> Response {
+        error(status, message)
+    }
+}
+
+impl Device {
+    pub(super) fn register_http_api(&mut self, config: &HttpApiConfig) -> Result<(), Error> {
+        if let HttpListen::Tcp(addr) = config.listen
+            && !addr.ip().is_loopback()
+            && config.token.is_none()
+        {
+            return Err(Error::Http(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a token is required to serve the HTTP API on a non-loopback address",
+            )));
+        }
+
+        self.register_http_service(
+            &config.listen,
+            JsonApi {
+                token: config.token.clone(),
+            },
+        )
+    }
+}
+
+fn ok(body: Value) -> Response {
+    Response::new(200, "application/json", format!("{body}\n"))
+}
+
+fn no_content() -> Response {
+    Response {
+        status: 204,
+        content_type: None,
+        body: Vec::new(),
+    }
+}
+
+fn error(status: u16, message: impl ToString) -> Response {
+    let body = json!({ "error": message.to_string() });
+    Response::new(status, "application/json", format!("{body}\n"))
+}
+
+fn handle(request: &Request, token: Option<&str>, d: &mut LockReadGuard<Device>) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let method = request.method.as_str();
+
+    if let Some(token) = token
+        && segments != ["health"]
+    {
+        let presented = request
+            .authorization
+            .as_deref()
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !presented.is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())) {
+            tracing::warn!(
+                message = "Denied unauthenticated HTTP API request",
+                method,
+                path = request.path
+            );
+            return error(401, "Missing or invalid bearer token");
+        }
+    }
+
+    match (method, segments.as_slice()) {
+        ("GET", ["health"]) => ok(json!({ "status": "ok" })),
+        ("GET", ["interface"]) => get_interface(d),
+        ("GET", ["peers"]) => get_peers(d),
+        ("GET", ["peers", key]) => with_key(key, |key| get_peer(d, key)),
+        ("PUT", ["peers", key]) => with_key(key, |key| put_peer(d, key, &request.body)),
+        ("DELETE", ["peers", key]) => with_key(key, |key| delete_peer(d, key)),
+        ("GET", ["stats"]) => get_stats(d),
+        (_, ["health" | "interface" | "peers" | "stats"] | ["peers", _]) => {
+            error(405, "Method not allowed")
+        }
+        _ => error(404, "Not found"),
+    }
+}
+
+fn with_key(segment: &str, f: impl FnOnce(x25519::PublicKey) -> Response) -> Response {
+    match percent_decode(segment).and_then(|s| s.parse::<KeyBytes>().ok()) {
+        Some(key) => f(x25519::PublicKey::from(key.0)),
+        None => error(400, "Invalid public key"),
+    }
+}
+
+fn get(d: &mut LockReadGuard<Device>, request: &GetRequest) -> Result<Interface, Response> {
+    let mut buf = Vec::new();
+    request.write_to(&mut buf).map_err(|e| error(500, e))?;
+    let response = api_request(&buf, d);
+    client::parse_get_response(&mut response.lines()).map_err(uapi_error)
+}
+
+fn set(d: &mut LockReadGuard<Device>, request: &SetRequest) -> Result<(), Response> {
+    let mut buf = Vec::new();
+    request.write_to(&mut buf).map_err(|e| error(500, e))?;
+    let response = api_request(&buf, d);
+    client::parse_response(&mut response.lines(), |_| Ok(())).map_err(uapi_error)
+}
+
+fn uapi_error(e: client::Error) -> Response {
+    match e {
+        client::Error::InvalidArgument | client::Error::Protocol => error(400, e),
+        client::Error::PermissionDenied => error(403, e),
+        client::Error::AddressInUse | client::Error::Busy => error(409, e),
+        e => error(500, e),
+    }
+}
+
+fn get_interface(d: &mut LockReadGuard<Device>) -> Response {
+    // Only the interface fields are needed, but the peers still have to be counted
+    let request = GetRequest {
+        fields: Some(vec![Field::PrivateKey, Field::ListenPort, Field::Fwmark]),
+        ..Default::default()
+    };
+    match get(d, &request) {
+        Ok(interface) => ok(json!({
+            "public_key": interface.public_key().map(|k| encode_key(k.as_bytes())),
+            "listen_port": interface.listen_port,
+            "fwmark": interface.fwmark,
+            "peers": interface.peers.len(),
+        })),
+        Err(response) => response,
+    }
+}
+
+fn get_peers(d: &mut LockReadGuard<Device>) -> Response {
+    match get(d, &GetRequest::default()) {
+        Ok(interface) => ok(interface.peers.iter().map(peer_json).collect()),
+        Err(response) => response,
+    }
+}
+
+fn get_peer(d: &mut LockReadGuard<Device>, key: x25519::PublicKey) -> Response {
+    let request = GetRequest {
+        public_keys: vec![key],
+        ..Default::default()
+    };
+    match get(d, &request) {
+        Ok(interface) => match interface.peers.first() {
+            Some(peer) => ok(peer_json(peer)),
+            None => error(404, "No such peer"),
+        },
+        Err(response) => response,
+    }
+}
+
+/// Create the peer, or replace its preshared key, keepalive and allowed IPs. An existing peer is
+/// updated in place, so its sessions, counters, path MTU settings and firewall rules are kept,
+/// and an endpoint that is left out keeps the endpoint the peer was last seen at.
+fn put_peer(d: &mut LockReadGuard<Device>, key: x25519::PublicKey, body: &[u8]) -> Response {
+    let config: PeerConfig = match serde_json::from_slice(body) {
+        Ok(config) => config,
+        Err(e) => return error(400, e),
+    };
+
+    let preshared_key = match config.preshared_key.map(|k| k.parse::<KeyBytes>()) {
+        Some(Ok(key)) => Some(key.0),
+        Some(Err(e)) => return error(400, e),
+        None => None,
+    };
+    let allowed_ips = match config
+        .allowed_ips
+        .iter()
+        .map(|ip| ip.parse::<AllowedIP>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(allowed_ips) => allowed_ips,
+        Err(e) => return error(400, e),
+    };
+
+    if d.key_pair.is_none() {
+        return error(409, "The interface private key is not set");
+    }
+
+    let request = SetRequest {
+        peers: vec![PeerUpdate {
+            // An all zero key removes the preshared key, and a zero interval the keepalive
+            preshared_key: Some(preshared_key.unwrap_or_default()),
+            endpoint: config.endpoint,
+            persistent_keepalive_interval: Some(config.persistent_keepalive_interval.unwrap_or(0)),
+            replace_allowed_ips: true,
+            allowed_ips,
+            ..PeerUpdate::new(key)
+        }],
+        ..Default::default()
+    };
+    if let Err(response) = set(d, &request) {
+        return response;
+    }
+
+    get_peer(d, key)
+}
+
+fn delete_peer(d: &mut LockReadGuard<Device>, key: x25519::PublicKey) -> Response {
+    if !d.peers.contains_key(&key) {
+        return error(404, "No such peer");
+    }
+
+    let request = SetRequest {
+        peers: vec![PeerUpdate::remove(key)],
+        ..Default::default()
+    };
+    match set(d, &request) {
+        Ok(()) => no_content(),
+        Err(response) => response,
+    }
+}
+
+fn get_stats(d: &mut LockReadGuard<Device>) -> Response {
+    let request = GetRequest {
+        fields: Some(vec![
+            Field::LastHandshakeTime,
+            Field::RxBytes,
+            Field::TxBytes,
+        ]),
+        ..Default::default()
+    };
+    match get(d, &request) {
+        Ok(interface) => ok(json!({
+            "peers": interface.peers.len(),
+            "rx_bytes": interface.peers.iter().map(|p| p.rx_bytes).sum::<u64>(),
+            "tx_bytes": interface.peers.iter().map(|p| p.tx_bytes).sum::<u64>(),
+            "peer_stats": interface.peers.iter().map(|p| json!({
+                "public_key": encode_key(p.public_key.as_bytes()),
+                "last_handshake_time": p.last_handshake_time.map(|t| t.as_secs()),
+                "rx_bytes": p.rx_bytes,
+                "tx_bytes": p.tx_bytes,
+            })).collect::<Vec<_>>(),
+        })),
+        Err(response) => response,
+    }
+}
+
+fn peer_json(peer: &client::Peer) -> Value {
+    json!({
+        "public_key": encode_key(peer.public_key.as_bytes()),
+        // Like `wg`, never reveal the preshared key itself
+        "has_preshared_key": peer.preshared_key.is_some(),
+        "endpoint": peer.endpoint,
+        "persistent_keepalive_interval": peer.persistent_keepalive_interval,
+        "allowed_ips": peer
+            .allowed_ips
+            .iter()
+            .map(|ip| format!("{}/{}", ip.addr, ip.cidr))
+            .collect::<Vec<_>>(),
+        "last_handshake_time": peer.last_handshake_time.map(|t| t.as_secs()),
+        "rx_bytes": peer.rx_bytes,
+        "tx_bytes": peer.tx_bytes,
+    })
+}
+
+fn encode_key(key: &[u8; 32]) -> String {
+    BASE64_STANDARD.encode(key)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceConfig, DeviceHandle, acl};
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%2Fb%2bc").as_deref(), Some("a/b+c"));
+        assert_eq!(percent_decode("abc").as_deref(), Some("abc"));
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+    }
+
+    #[test]
+    #[ignore]
+    /// Creating the tunnel interface requires privileges
+    fn put_updates_the_peer_in_place() {
+        let config = DeviceConfig {
+            n_threads: 1,
+            ..DeviceConfig::default()
+        };
+        let device = DeviceHandle::new("utun93", config).unwrap();
+        let key = x25519::PublicKey::from([1u8; 32]);
+        let set = format!(
+            "set=1\nprivate_key={}\npublic_key={}\nacl_policy=deny\nacl_rule=allow proto tcp dport 22\n\n",
+            "11".repeat(32),
+            "01".repeat(32)
+        );
+        assert_eq!(
+            api_request(set.as_bytes(), &mut device.device.read()),
+            b"errno=0\n\n"
+        );
+        let index = device.device.read().peers[&key].lock().index();
+
+        let body = br#"{"persistent_keepalive_interval": 25, "allowed_ips": ["10.0.0.2/32"]}"#;
+        let response = put_peer(&mut device.device.read(), key, body);
+        assert_eq!(response.status, 200);
+
+        let d = device.device.read();
+        let peer = d.peers[&key].lock();
+        assert_eq!(peer.index(), index);
+        assert_eq!(peer.acl().policy, acl::Action::Deny);
+        assert_eq!(peer.acl().rules.len(), 1);
+        assert_eq!(peer.persistent_keepalive(), Some(25));
+        assert_eq!(peer.allowed_ips().count(), 1);
+    }
+}
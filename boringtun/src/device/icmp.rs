@@ -0,0 +1,403 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! ICMP errors written back to the tunnel interface, for packets from it that can't be sent.
+//!
+//! Packets that don't fit the path MTU of their peer are answered with "fragmentation needed"
+//! or "packet too big", see [`super::DeviceConfig::icmp_too_big`], and packets without a peer
+//! or endpoint to send them to with "destination unreachable", see
+//! [`super::DeviceConfig::icmp_unreachable`]. The errors appear to come from the destination of
+//! the packet, and every source gets a limited number of them per second.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use parking_lot::Mutex;
+
+use super::offload::{fold, set_u16, sum_words};
+
+pub(crate) const IPV4_HEADER_LEN: usize = 20;
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+const ICMP_HEADER_LEN: usize = 8;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_NET_UNREACH: u8 = 0;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP_PKT_FILTERED: u8 = 13;
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_NOROUTE: u8 = 0;
+const ICMPV6_ADM_PROHIBITED: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+/// ICMPv6 types below this are errors
+const ICMPV6_INFO_MIN: u8 = 128;
+
+/// The largest ICMP message built, the minimum MTU of IPv6
+pub(crate) const MAX_ICMP_LEN: usize = 1280;
+/// ICMP errors for IPv4 fit the smallest datagram every host accepts
+const MAX_ICMP4_LEN: usize = 576;
+/// Hosts ignore IPv6 path MTUs below the minimum MTU
+const MIN_IPV6_MTU: usize = 1280;
+
+/// The errors sent to every source address per second
+const ERRORS_PER_SOURCE: u32 = 10;
+/// The source addresses tracked per second, sources beyond these get no errors
+const MAX_SOURCES: usize = 4096;
+
+/// The "destination unreachable" code sent for packets without a peer or endpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unreachable {
+    /// "Network unreachable" for IPv4 and "no route to destination" for IPv6
+    Net,
+    /// "Communication administratively prohibited"
+    Prohibited,
+}
+
+impl FromStr for Unreachable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "net" => Ok(Unreachable::Net),
+            "prohibited" => Ok(Unreachable::Prohibited),
+            _ => Err(format!("Invalid code: {s}, expected net or prohibited")),
+        }
+    }
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unreachable::Net => write!(f, "net"),
+            Unreachable::Prohibited => write!(f, "prohibited"),
+        }
+    }
+}
+
+/// An ICMP error to reply to a packet from the tunnel interface with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IcmpError {
+    /// "Fragmentation needed" for IPv4 or "packet too big" for IPv6, with the MTU that fits
+    TooBig(usize),
+    /// "Destination unreachable", with the code to send
+    Unreachable(Unreachable),
+}
+
+/// Counts the errors sent to every source address, so a flood of packets that can't be sent
+/// isn't answered with a flood of errors
+#[derive(Default)]
+pub(crate) struct IcmpLimiter {
+    sent: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl IcmpLimiter {
+    /// May another error be sent to the address
+    pub(crate) fn allow(&self, addr: IpAddr) -> bool {
+        let mut sent = self.sent.lock();
+        if sent.len() >= MAX_SOURCES && !sent.contains_key(&addr) {
+            return false;
+        }
+        let count = sent.entry(addr).or_default();
+        *count += 1;
+        *count <= ERRORS_PER_SOURCE
+    }
+
+    /// Start counting again, called every second
+    pub(crate) fn reset(&self) {
+        self.sent.lock().clear();
+    }
+}
+
+/// The source address of a packet, that errors are sent to
+pub(crate) fn src_address(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            Some(IpAddr::from(src))
+        }
+        6 => {
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            Some(IpAddr::from(src))
+        }
+        _ => None,
+    }
+}
+
+/// Build the ICMP error for a packet, sent from its destination back to its source. Returns the
+/// length of the message in `out`, or `None` if the packet must not be answered with an error.
+pub(crate) fn icmp_error(packet: &[u8], error: IcmpError, out: &mut [u8]) -> Option<usize> {
+    match packet.first()? >> 4 {
+        4 => icmp4_error(packet, error, out),
+        6 => icmp6_error(packet, error, out),
+        _ => None,
+    }
+}
+
+fn icmp4_error(packet: &[u8], error: IcmpError, out: &mut [u8]) -> Option<usize> {
+    if packet.len() < IPV4_HEADER_LEN {
+        return None;
+    }
+    let ihl = usize::from(packet[0] & 0xf) * 4;
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+
+    // No errors about fragments other than the first, or to and from broadcast and multicast
+    // addresses, as required by RFC 1122
+    if ihl < IPV4_HEADER_LEN
+        || fragment_offset != 0
+        || src.is_unspecified()
+        || src.is_broadcast()
+        || src.is_multicast()
+        || dst.is_broadcast()
+        || dst.is_multicast()
+    {
+        return None;
+    }
+    // Nor about other ICMP errors
+    if packet[9] == IPPROTO_ICMP
+        && packet
+            .get(ihl)
+            .is_none_or(|t| matches!(t, 3 | 4 | 5 | 11 | 12))
+    {
+        return None;
+    }
+
+    let (icmp_type, code, rest) = match error {
+        IcmpError::TooBig(mtu) => {
+            // Packets without the DF flag are fragmented by the outer IP layer instead
+            if packet[6] & 0x40 == 0 {
+                return None;
+            }
+            let mtu = u16::try_from(mtu).unwrap_or(u16::MAX).to_be_bytes();
+            (ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED, [0, 0, mtu[0], mtu[1]])
+        }
+        IcmpError::Unreachable(Unreachable::Net) => (ICMP_DEST_UNREACH, ICMP_NET_UNREACH, [0; 4]),
+        IcmpError::Unreachable(Unreachable::Prohibited) => {
+            (ICMP_DEST_UNREACH, ICMP_PKT_FILTERED, [0; 4])
+        }
+    };
+
+    let quoted = packet
+        .len()
+        .min(MAX_ICMP4_LEN - IPV4_HEADER_LEN - ICMP_HEADER_LEN);
+    let len = IPV4_HEADER_LEN + ICMP_HEADER_LEN + quoted;
+    let out = out.get_mut(..len)?;
+    out.fill(0);
+
+    out[0] = 0x45;
+    set_u16(out, 2, len as u16);
+    out[8] = 64;
+    out[9] = IPPROTO_ICMP;
+    out[12..16].copy_from_slice(&dst.octets());
+    out[16..20].copy_from_slice(&src.octets());
+    let sum = sum_words(&out[..IPV4_HEADER_LEN], 0);
+    set_u16(out, 10, !fold(sum));
+
+    let icmp = &mut out[IPV4_HEADER_LEN..];
+    icmp[0] = icmp_type;
+    icmp[1] = code;
+    icmp[4..8].copy_from_slice(&rest);
+    icmp[ICMP_HEADER_LEN..].copy_from_slice(&packet[..quoted]);
+    let sum = sum_words(icmp, 0);
+    set_u16(icmp, 2, !fold(sum));
+
+    Some(len)
+}
+
+fn icmp6_error(packet: &[u8], error: IcmpError, out: &mut [u8]) -> Option<usize> {
+    if packet.len() < IPV6_HEADER_LEN {
+        return None;
+    }
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+
+    if src.is_unspecified() || src.is_multicast() {
+        return None;
+    }
+    // No errors about other ICMPv6 errors, as required by RFC 4443
+    if packet[6] == IPPROTO_ICMPV6
+        && packet
+            .get(IPV6_HEADER_LEN)
+            .is_none_or(|&t| t < ICMPV6_INFO_MIN)
+    {
+        return None;
+    }
+
+    let (icmp_type, code, rest) = match error {
+        IcmpError::TooBig(mtu) => {
+            // Packet too big is the one error also sent for multicast destinations, but a
+            // path MTU below the minimum would be ignored
+            if mtu < MIN_IPV6_MTU {
+                return None;
+            }
+            let mtu = u32::try_from(mtu).unwrap_or(u32::MAX);
+            (ICMPV6_PACKET_TOO_BIG, 0, mtu.to_be_bytes())
+        }
+        IcmpError::Unreachable(_) if dst.is_multicast() => return None,
+        IcmpError::Unreachable(Unreachable::Net) => (ICMPV6_DEST_UNREACH, ICMPV6_NOROUTE, [0; 4]),
+        IcmpError::Unreachable(Unreachable::Prohibited) => {
+            (ICMPV6_DEST_UNREACH, ICMPV6_ADM_PROHIBITED, [0; 4])
+        }
+    };
+
+    let quoted = packet
+        .len()
+        .min(MAX_ICMP_LEN - IPV6_HEADER_LEN - ICMP_HEADER_LEN);
+    let icmp_len = ICMP_HEADER_LEN + quoted;
+    let len = IPV6_HEADER_LEN + icmp_len;
+    let out = out.get_mut(..len)?;
+    out.fill(0);
+
+    out[0] = 0x60;
+    set_u16(out, 4, icmp_len as u16);
+    out[6] = IPPROTO_ICMPV6;
+    out[7] = 64;
+    out[8..24].copy_from_slice(&dst.octets());
+    out[24..40].copy_from_slice(&src.octets());
+
+    let (header, icmp) = out.split_at_mut(IPV6_HEADER_LEN);
+    icmp[0] = icmp_type;
+    icmp[1] = code;
+    icmp[4..8].copy_from_slice(&rest);
+    icmp[ICMP_HEADER_LEN..].copy_from_slice(&packet[..quoted]);
+    let pseudo = sum_words(&header[8..40], u64::from(IPPROTO_ICMPV6) + icmp_len as u64);
+    let sum = sum_words(icmp, pseudo);
+    set_u16(icmp, 2, !fold(sum));
+
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPPROTO_UDP: u8 = 17;
+
+    fn ipv4(len: usize, dst: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        set_u16(&mut packet, 2, len as u16);
+        packet[6] = 0x40;
+        packet[8] = 64;
+        packet[9] = IPPROTO_UDP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&dst.octets());
+        let sum = sum_words(&packet[..20], 0);
+        set_u16(&mut packet, 10, !fold(sum));
+        packet
+    }
+
+    fn ipv6(len: usize, dst: Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x60;
+        set_u16(&mut packet, 4, (len - 40) as u16);
+        packet[6] = IPPROTO_UDP;
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&dst.octets());
+        packet
+    }
+
+    fn valid_icmp6_checksum(reply: &[u8]) -> bool {
+        let pseudo = sum_words(
+            &reply[8..40],
+            u64::from(IPPROTO_ICMPV6) + (reply.len() - 40) as u64,
+        );
+        fold(sum_words(&reply[40..], pseudo)) == 0xffff
+    }
+
+    #[test]
+    fn replies_too_big() {
+        let mut packet = ipv4(1500, Ipv4Addr::new(10, 0, 0, 2));
+        let mut out = [0u8; MAX_ICMP_LEN];
+
+        let len = icmp_error(&packet, IcmpError::TooBig(1420), &mut out).unwrap();
+        let reply = &out[..len];
+        assert_eq!(len, MAX_ICMP4_LEN);
+        assert_eq!(reply[9], IPPROTO_ICMP);
+        assert_eq!(&reply[12..16], &packet[16..20]);
+        assert_eq!(&reply[16..20], &packet[12..16]);
+        assert_eq!(fold(sum_words(&reply[..20], 0)), 0xffff);
+        assert_eq!(&reply[20..22], &[ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED]);
+        assert_eq!(u16::from_be_bytes([reply[26], reply[27]]), 1420);
+        assert_eq!(fold(sum_words(&reply[20..], 0)), 0xffff);
+        assert_eq!(&reply[28..48], &packet[..20]);
+
+        // Without DF the packet is fragmented instead
+        packet[6] = 0;
+        assert_eq!(icmp_error(&packet, IcmpError::TooBig(1420), &mut out), None);
+
+        // ICMP errors are not answered with errors
+        packet[6] = 0x40;
+        packet[9] = IPPROTO_ICMP;
+        packet[20] = ICMP_DEST_UNREACH;
+        assert_eq!(icmp_error(&packet, IcmpError::TooBig(1420), &mut out), None);
+    }
+
+    #[test]
+    fn replies_packet_too_big() {
+        let packet = ipv6(1500, "fd00::2".parse().unwrap());
+        let mut out = [0u8; MAX_ICMP_LEN];
+
+        let len = icmp_error(&packet, IcmpError::TooBig(1400), &mut out).unwrap();
+        let reply = &out[..len];
+        assert_eq!(len, MAX_ICMP_LEN);
+        assert_eq!(&reply[8..24], &packet[24..40]);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+        assert_eq!(reply[40], ICMPV6_PACKET_TOO_BIG);
+        assert_eq!(&reply[44..48], &1400u32.to_be_bytes());
+        assert!(valid_icmp6_checksum(reply));
+
+        // Hosts don't go below the minimum MTU
+        assert_eq!(icmp_error(&packet, IcmpError::TooBig(1200), &mut out), None);
+    }
+
+    #[test]
+    fn replies_unreachable() {
+        let prohibited = IcmpError::Unreachable(Unreachable::Prohibited);
+        let net = IcmpError::Unreachable(Unreachable::Net);
+        let mut out = [0u8; MAX_ICMP_LEN];
+
+        let packet = ipv4(60, Ipv4Addr::new(10, 0, 0, 2));
+        let len = icmp_error(&packet, prohibited, &mut out).unwrap();
+        assert_eq!(len, 20 + 8 + 60);
+        assert_eq!(&out[20..22], &[ICMP_DEST_UNREACH, ICMP_PKT_FILTERED]);
+        assert_eq!(fold(sum_words(&out[20..len], 0)), 0xffff);
+        assert_eq!(&out[28..len], &packet[..]);
+
+        let packet = ipv6(60, "fd00::2".parse().unwrap());
+        let len = icmp_error(&packet, net, &mut out).unwrap();
+        assert_eq!(&out[40..42], &[ICMPV6_DEST_UNREACH, ICMPV6_NOROUTE]);
+        assert!(valid_icmp6_checksum(&out[..len]));
+
+        // Nor to multicast destinations
+        let packet = ipv4(60, Ipv4Addr::new(224, 0, 0, 251));
+        assert_eq!(icmp_error(&packet, net, &mut out), None);
+        let packet = ipv6(60, "ff02::fb".parse().unwrap());
+        assert_eq!(icmp_error(&packet, net, &mut out), None);
+    }
+
+    #[test]
+    fn limits_errors_per_source() {
+        let limiter = IcmpLimiter::default();
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+
+        for _ in 0..ERRORS_PER_SOURCE {
+            assert!(limiter.allow(a));
+        }
+        assert!(!limiter.allow(a));
+        assert!(limiter.allow(b));
+
+        limiter.reset();
+        assert!(limiter.allow(a));
+        assert_eq!(src_address(&ipv4(20, Ipv4Addr::LOCALHOST)), Some(a));
+    }
+}
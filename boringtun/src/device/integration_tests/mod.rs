@@ -9,9 +9,9 @@ mod tests {
         device::{DeviceConfig, DeviceHandle},
         x25519::{PublicKey, StaticSecret},
     };
-    use base64::encode as base64encode;
+    use aead::rand_core::OsRng;
+    use base64::prelude::*;
     use hex::encode;
-    use rand_core::OsRng;
     use ring::rand::{SecureRandom, SystemRandom};
     use std::{
         fmt::Write as _,
@@ -21,7 +21,7 @@ mod tests {
         process::Command,
         sync::{
             Arc,
-            sync::atomic::{AtomicUsize, Ordering},
+            atomic::{AtomicUsize, Ordering},
         },
         thread,
     };
@@ -116,11 +116,19 @@ mod tests {
             // The local endpoint port is the remote listen port
             let _ = writeln!(conf, "ListenPort = {}", self.endpoint.port());
             // HACK: this should consume the key so it can't be reused instead of cloning and serializing
-            let _ = writeln!(conf, "PrivateKey = {}", base64encode(self.key.to_bytes()));
+            let _ = writeln!(
+                conf,
+                "PrivateKey = {}",
+                BASE64_STANDARD.encode(self.key.to_bytes())
+            );
 
             // We are the peer
             let _ = writeln!(conf, "[Peer]");
-            let _ = writeln!(conf, "PublicKey = {}", base64encode(local_key.as_bytes()));
+            let _ = writeln!(
+                conf,
+                "PublicKey = {}",
+                BASE64_STANDARD.encode(local_key.as_bytes())
+            );
             let _ = writeln!(conf, "AllowedIPs = {}", local_addr);
             let _ = write!(conf, "Endpoint = 127.0.0.1:{}", local_port);
 
@@ -271,7 +279,24 @@ mod tests {
                     #[cfg(target_os = "linux")]
                     use_multi_queue: true,
                     #[cfg(target_os = "linux")]
+                    use_tun_offload: false,
+                    #[cfg(target_os = "linux")]
+                    use_io_uring: false,
+                    icmp_too_big: false,
+                    clamp_mss: false,
+                    icmp_unreachable: None,
+                    hub_mode: false,
+                    #[cfg(target_os = "linux")]
                     uapi_fd: -1,
+                    api_access: None,
+                    #[cfg(feature = "http-api")]
+                    http_api: None,
+                    metrics: None,
+                    config_file: None,
+                    save_config: None,
+                    api_listen_fds: Vec::new(),
+                    systemd_notify: false,
+                    handle_signals: true,
                 },
             )
         }
@@ -488,7 +513,7 @@ mod tests {
         let wg = WGHandle::init("192.0.2.0".parse().unwrap(), "::2".parse().unwrap());
         assert!(wg.wg_get().ends_with("errno=0\n\n"));
         assert_eq!(wg.wg_set_port(port), "errno=0\n\n");
-        assert_eq!(wg.wg_set_key(private_key), "errno=0\n\n");
+        assert_eq!(wg.wg_set_key(private_key.clone()), "errno=0\n\n");
 
         // Check that the response matches what we expect
         assert_eq!(
@@ -563,7 +588,24 @@ mod tests {
                 #[cfg(target_os = "linux")]
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
+                use_tun_offload: false,
+                #[cfg(target_os = "linux")]
+                use_io_uring: false,
+                icmp_too_big: false,
+                clamp_mss: false,
+                icmp_unreachable: None,
+                hub_mode: false,
+                #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                api_access: None,
+                #[cfg(feature = "http-api")]
+                http_api: None,
+                metrics: None,
+                config_file: None,
+                save_config: None,
+                api_listen_fds: Vec::new(),
+                systemd_notify: false,
+                handle_signals: true,
             },
         );
 
@@ -721,7 +763,24 @@ mod tests {
                 #[cfg(target_os = "linux")]
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
+                use_tun_offload: false,
+                #[cfg(target_os = "linux")]
+                use_io_uring: false,
+                icmp_too_big: false,
+                clamp_mss: false,
+                icmp_unreachable: None,
+                hub_mode: false,
+                #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                api_access: None,
+                #[cfg(feature = "http-api")]
+                http_api: None,
+                metrics: None,
+                config_file: None,
+                save_config: None,
+                api_listen_fds: Vec::new(),
+                systemd_notify: false,
+                handle_signals: true,
             },
         );
 
@@ -11,6 +11,7 @@ use std::{
 
 use libc::*;
 use parking_lot::Mutex;
+use socket2::SockAddr;
 
 use super::Error;
 
@@ -46,6 +47,29 @@ pub struct EventRef {
     trigger: RawFd,
 }
 
+/// Stub: what data a read event receives, only used with io_uring on Linux.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadKind {
+    Datagram,
+    Packet,
+}
+
+/// Stub: data received for a read event, only used with io_uring on Linux.
+#[derive(Clone)]
+pub struct Received {
+    pub addr: Option<SockAddr>,
+    pub segment: usize,
+}
+
+impl Received {
+    /// # Safety
+    ///
+    /// Always safe, nothing is ever received.
+    pub unsafe fn data(&self) -> &[u8] {
+        &[]
+    }
+}
+
 #[derive(PartialEq)]
 enum EventKind {
     FD,
@@ -120,6 +144,16 @@ impl<H: Send + Sync> EventPoll<H> {
         self.register_event(ev)
     }
 
+    /// Same as `new_event`, the data is always read by the handler
+    pub fn new_read_event(
+        &self,
+        trigger: RawFd,
+        handler: H,
+        _kind: ReadKind,
+    ) -> Result<EventRef, Error> {
+        self.new_event(trigger, handler)
+    }
+
     pub fn new_periodic_event(&self, handler: H, period: Duration) -> Result<EventRef, Error> {
         // The periodic event in BSD uses EVFILT_TIMER
         let ev = Event {
@@ -190,6 +224,38 @@ impl<H: Send + Sync> EventPoll<H> {
     /// In case a notifier is triggered, all waiting threads will receive the same
     /// handler.
     pub fn wait(&'_ self) -> WaitResult<'_, H> {
+        self.wait_timeout(null())
+            .unwrap_or_else(|| WaitResult::Error("no event returned".to_string()))
+    }
+
+    /// The kqueue itself, since timers and notifiers have no fds. When it is readable,
+    /// [`EventPoll::triggered`] returns the next event.
+    pub fn fds(&self) -> Vec<RawFd> {
+        vec![self.kqueue]
+    }
+
+    /// Timers trigger the kqueue
+    pub fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Timers trigger the kqueue
+    pub fn due_timers(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// The next event if the kqueue is readable, as [`EventPoll::wait`] would return it
+    pub fn triggered(&self, fd: RawFd) -> Option<WaitResult<'_, H>> {
+        let timeout = timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        (fd == self.kqueue)
+            .then(|| self.wait_timeout(&raw const timeout))
+            .flatten()
+    }
+
+    fn wait_timeout(&self, timeout: *const timespec) -> Option<WaitResult<'_, H>> {
         let mut event = kevent {
             ident: 0,
             filter: 0,
@@ -201,8 +267,10 @@ impl<H: Send + Sync> EventPoll<H> {
             ext: [0u64; 4],
         };
 
-        if unsafe { kevent(self.kqueue, null(), 0, &raw mut event, 1, null()) } == -1 {
-            return WaitResult::Error(io::Error::last_os_error().to_string());
+        match unsafe { kevent(self.kqueue, null(), 0, &raw mut event, 1, timeout) } {
+            -1 => return Some(WaitResult::Error(io::Error::last_os_error().to_string())),
+            0 => return None,
+            _ => {}
         }
 
         let event_data = unsafe { event.udata.cast::<Event<H>>().as_ref().unwrap() };
@@ -213,11 +281,11 @@ impl<H: Send + Sync> EventPoll<H> {
             poll: self,
         };
 
-        if event.flags & EV_EOF != 0 {
+        Some(if event.flags & EV_EOF != 0 {
             WaitResult::EoF(guard)
         } else {
             WaitResult::Ok(guard)
-        }
+        })
     }
 
     // Register an event with this poll.
@@ -373,6 +441,11 @@ impl<H> EventGuard<'_, H> {
         std::mem::forget(self); // Don't call the regular drop that would enable the event
     }
 
+    /// Stub: only used for Linux-specific features.
+    pub fn take_received(&mut self) -> Option<Vec<Received>> {
+        None
+    }
+
     /// Stub: only used for Linux-specific features.
     #[must_use]
     pub fn fd(&self) -> i32 {
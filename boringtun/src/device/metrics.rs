@@ -0,0 +1,439 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Prometheus metrics for the device and its peers, served on `GET /metrics`.
+//!
+//! The response uses the OpenMetrics format when the scraper asks for it in the `Accept`
+//! header, and the Prometheus text format otherwise. Device wide families always cover every
+//! peer, including the peers that were removed, so their counters never go down. Per peer
+//! families carry a `public_key` label, and can be limited to the busiest peers with
+//! [`MetricsConfig::max_peers`] to bound the number of series on large servers.
+
+use std::{
+    cmp::Reverse,
+    fmt::{Display, Write as _},
+    time::Duration,
+};
+
+use base64::prelude::*;
+use parking_lot::Mutex;
+
+use super::{
+    Device,
+    dev_lock::LockReadGuard,
+    http::{HttpListen, HttpService, Request, Response},
+    peer::Peer,
+    stats::DropStats,
+};
+use crate::{noise::rate_limiter::RateLimiter, x25519};
+
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub listen: HttpListen,
+    /// Export per peer series for at most this many peers, those with the most traffic.
+    /// `None` exports all peers, `Some(0)` only exports device wide series.
+    pub max_peers: Option<usize>,
+}
+
+struct Metrics {
+    max_peers: Option<usize>,
+}
+
+impl HttpService for Metrics {
+    fn handle(&self, request: &Request, d: &mut LockReadGuard<Device>) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => {
+                let openmetrics = request
+                    .accept
+                    .as_deref()
+                    .is_some_and(|a| a.contains("application/openmetrics-text"));
+                let body = render(d, self.max_peers, openmetrics);
+                let content_type = if openmetrics { OPENMETRICS } else { PROMETHEUS };
+                Response::new(200, content_type, body)
+            }
+            (_, "/metrics") => self.error(405, "Method not allowed"),
+            _ => self.error(404, "Not found"),
+        }
+    }
+
+    fn error(&self, status: u16, message: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", format!("{message}\n"))
+    }
+}
+
+impl Device {
+    pub(super) fn register_metrics(&mut self, config: &MetricsConfig) -> Result<(), super::Error> {
+        self.register_http_service(
+            &config.listen,
+            Metrics {
+                max_peers: config.max_peers,
+            },
+        )
+    }
+}
+
+/// A point in time copy of the counters of one peer
+pub(super) struct PeerSample {
+    public_key: x25519::PublicKey,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    rx_packets: u64,
+    tx_packets: u64,
+    handshakes: u64,
+    last_handshake_time: Option<Duration>,
+    loss: f32,
+    rtt: Option<u32>,
+    forwarded_packets: u64,
+    drops: DropStats,
+}
+
+impl PeerSample {
+    pub(super) fn new(public_key: &x25519::PublicKey, p: &Peer) -> PeerSample {
+        let (_, tx_bytes, rx_bytes, loss, rtt) = p.tunnel.stats();
+        let (tx_packets, rx_packets) = p.tunnel.packets();
+        PeerSample {
+            public_key: *public_key,
+            rx_bytes: rx_bytes as u64,
+            tx_bytes: tx_bytes as u64,
+            rx_packets,
+            tx_packets,
+            handshakes: p.tunnel.handshakes(),
+            last_handshake_time: p.last_handshake_time(),
+            loss,
+            rtt,
+            forwarded_packets: p.forwarded_packets(),
+            drops: p.drop_stats(),
+        }
+    }
+}
+
+/// The device wide counters of the peers and rate limiters that are gone
+#[derive(Default)]
+pub(super) struct RetiredCounters {
+    inner: Mutex<Retired>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Retired {
+    /// In the order of [`COUNTERS`]
+    peers: [u64; COUNTERS.len()],
+    under_load: u64,
+    cookie_replies: u64,
+}
+
+impl RetiredCounters {
+    /// Keep the counters of a peer that is being removed
+    pub(super) fn retire_peer(&self, sample: &PeerSample) {
+        let mut retired = self.inner.lock();
+        for (total, counter) in retired.peers.iter_mut().zip(&COUNTERS) {
+            *total += (counter.value)(sample);
+        }
+    }
+
+    /// Keep the counters of a rate limiter that is being replaced
+    pub(super) fn retire_rate_limiter(&self, rate_limiter: &RateLimiter) {
+        let mut retired = self.inner.lock();
+        retired.under_load += rate_limiter.under_load_count();
+        retired.cookie_replies += rate_limiter.cookie_reply_count();
+    }
+
+    fn get(&self) -> Retired {
+        *self.inner.lock()
+    }
+}
+
+/// A counter that is exported both as a device total and per peer
+struct Counter {
+    name: &'static str,
+    total_help: &'static str,
+    peer_help: &'static str,
+    value: fn(&PeerSample) -> u64,
+}
+
+const COUNTERS: [Counter; 6] = [
+    Counter {
+        name: "rx_bytes",
+        total_help: "Data bytes received from all peers",
+        peer_help: "Data bytes received from the peer",
+        value: |p| p.rx_bytes,
+    },
+    Counter {
+        name: "tx_bytes",
+        total_help: "Data bytes sent to all peers",
+        peer_help: "Data bytes sent to the peer",
+        value: |p| p.tx_bytes,
+    },
+    Counter {
+        name: "rx_packets",
+        total_help: "Data packets received from all peers",
+        peer_help: "Data packets received from the peer",
+        value: |p| p.rx_packets,
+    },
+    Counter {
+        name: "tx_packets",
+        total_help: "Data packets sent to all peers",
+        peer_help: "Data packets sent to the peer",
+        value: |p| p.tx_packets,
+    },
+    Counter {
+        name: "handshakes",
+        total_help: "Handshakes completed with all peers",
+        peer_help: "Handshakes completed with the peer",
+        value: |p| p.handshakes,
+    },
+    Counter {
+        name: "forwarded_packets",
+        total_help: "Data packets forwarded between peers in hub mode",
+        peer_help: "Data packets from the peer forwarded to other peers in hub mode",
+        value: |p| p.forwarded_packets,
+    },
+];
+
+fn render(d: &Device, max_peers: Option<usize>, openmetrics: bool) -> String {
+    let mut peers: Vec<PeerSample> = d
+        .peers
+        .iter()
+        .map(|(public_key, peer)| PeerSample::new(public_key, &peer.lock()))
+        .collect();
+    let retired = d.retired.get();
+
+    let mut w = Writer {
+        out: String::new(),
+        openmetrics,
+    };
+
+    w.family("boringtun_peers", "gauge", "Number of configured peers");
+    w.sample("boringtun_peers", &[], peers.len());
+
+    for (counter, retired) in COUNTERS.iter().zip(retired.peers) {
+        let name = format!("boringtun_{}", counter.name);
+        w.family(&name, "counter", counter.total_help);
+        let total = peers.iter().map(counter.value).sum::<u64>();
+        w.counter(&name, &[], retired + total);
+    }
+
+    let (under_load, cookie_replies) =
+        d.rate_limiter
+            .as_ref()
+            .map_or((retired.under_load, retired.cookie_replies), |r| {
+                (
+                    retired.under_load + r.under_load_count(),
+                    retired.cookie_replies + r.cookie_reply_count(),
+                )
+            });
+    w.family(
+        "boringtun_under_load_handshakes",
+        "counter",
+        "Handshake messages received while the handshake rate limit was exceeded",
+    );
+    w.counter("boringtun_under_load_handshakes", &[], under_load);
+    w.family(
+        "boringtun_cookie_replies",
+        "counter",
+        "Cookie replies sent to handshakes received under load",
+    );
+    w.counter("boringtun_cookie_replies", &[], cookie_replies);
+
+    w.family(
+        "boringtun_dropped_packets",
+        "counter",
+        "Packets dropped by the device, by reason",
+    );
+    for (reason, count) in d.drops.stats().iter() {
+        w.counter(
+            "boringtun_dropped_packets",
+            &[("reason", reason.name())],
+            count,
+        );
+    }
+
+    w.family(
+        "boringtun_decapsulation_errors",
+        "counter",
+        "Packets from the network that failed verification or decapsulation, by error",
+    );
+    for (error, count) in d.drops.decapsulation_errors() {
+        w.counter("boringtun_decapsulation_errors", &[("error", error)], count);
+    }
+
+    if let Some(max_peers) = max_peers
+        && max_peers < peers.len()
+    {
+        let traffic = |p: &PeerSample| Reverse(p.rx_bytes + p.tx_bytes);
+        peers.select_nth_unstable_by_key(max_peers, traffic);
+        peers.truncate(max_peers);
+    }
+    if peers.is_empty() {
+        return w.finish();
+    }
+    let keys: Vec<String> = peers
+        .iter()
+        .map(|p| BASE64_STANDARD.encode(p.public_key.as_bytes()))
+        .collect();
+
+    for counter in &COUNTERS {
+        let name = format!("boringtun_peer_{}", counter.name);
+        w.family(&name, "counter", counter.peer_help);
+        for (p, key) in peers.iter().zip(&keys) {
+            w.counter(&name, &[("public_key", key)], (counter.value)(p));
+        }
+    }
+
+    w.family(
+        "boringtun_peer_last_handshake_seconds",
+        "gauge",
+        "Time of the last completed handshake with the peer, since the UNIX epoch",
+    );
+    for (p, key) in peers.iter().zip(&keys) {
+        if let Some(time) = p.last_handshake_time {
+            let name = "boringtun_peer_last_handshake_seconds";
+            w.sample(name, &[("public_key", key)], time.as_secs_f64());
+        }
+    }
+
+    w.family(
+        "boringtun_peer_loss_ratio",
+        "gauge",
+        "Estimated packet loss from the peer over the recent sessions",
+    );
+    for (p, key) in peers.iter().zip(&keys) {
+        w.sample("boringtun_peer_loss_ratio", &[("public_key", key)], p.loss);
+    }
+
+    w.family(
+        "boringtun_peer_rtt_seconds",
+        "gauge",
+        "Round trip time measured during the last handshake initiated by us",
+    );
+    for (p, key) in peers.iter().zip(&keys) {
+        if let Some(rtt) = p.rtt {
+            let rtt = f64::from(rtt) / 1000.0;
+            w.sample("boringtun_peer_rtt_seconds", &[("public_key", key)], rtt);
+        }
+    }
+
+    w.family(
+        "boringtun_peer_dropped_packets",
+        "counter",
+        "Packets to or from the peer dropped by the device, by reason",
+    );
+    for (p, key) in peers.iter().zip(&keys) {
+        // Most peers never drop anything, so only report the reasons that occurred
+        for (reason, count) in p.drops.iter().filter(|(_, count)| *count > 0) {
+            let labels = [("public_key", key.as_str()), ("reason", reason.name())];
+            w.counter("boringtun_peer_dropped_packets", &labels, count);
+        }
+    }
+
+    w.finish()
+}
+
+struct Writer {
+    out: String,
+    openmetrics: bool,
+}
+
+impl Writer {
+    /// Start a metric family. Counter families are named without the `_total` suffix in
+    /// OpenMetrics, and with it in the Prometheus text format.
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let suffix = if kind == "counter" && !self.openmetrics {
+            "_total"
+        } else {
+            ""
+        };
+        let _ = writeln!(self.out, "# HELP {name}{suffix} {help}");
+        let _ = writeln!(self.out, "# TYPE {name}{suffix} {kind}");
+    }
+
+    fn counter(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.sample(&format!("{name}_total"), labels, value);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                // Label values are key encodings and fixed names, they never need escaping
+                let _ = write!(self.out, "{label}=\"{value}\"");
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    fn finish(mut self) -> String {
+        if self.openmetrics {
+            self.out.push_str("# EOF\n");
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_formats() {
+        let mut w = Writer {
+            out: String::new(),
+            openmetrics: false,
+        };
+        w.family("boringtun_rx_bytes", "counter", "Bytes");
+        w.counter("boringtun_rx_bytes", &[("public_key", "a+b=")], 7);
+        w.family("boringtun_peer_loss_ratio", "gauge", "Loss");
+        w.sample("boringtun_peer_loss_ratio", &[("a", "1"), ("b", "2")], 0.5);
+        assert_eq!(
+            w.finish(),
+            "# HELP boringtun_rx_bytes_total Bytes\n\
+             # TYPE boringtun_rx_bytes_total counter\n\
+             boringtun_rx_bytes_total{public_key=\"a+b=\"} 7\n\
+             # HELP boringtun_peer_loss_ratio Loss\n\
+             # TYPE boringtun_peer_loss_ratio gauge\n\
+             boringtun_peer_loss_ratio{a=\"1\",b=\"2\"} 0.5\n"
+        );
+
+        let mut w = Writer {
+            out: String::new(),
+            openmetrics: true,
+        };
+        w.family("boringtun_rx_bytes", "counter", "Bytes");
+        w.counter("boringtun_rx_bytes", &[], 7);
+        assert_eq!(
+            w.finish(),
+            "# HELP boringtun_rx_bytes Bytes\n\
+             # TYPE boringtun_rx_bytes counter\n\
+             boringtun_rx_bytes_total 7\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn retired_peers_keep_counting() {
+        let sample = PeerSample {
+            public_key: x25519::PublicKey::from([1u8; 32]),
+            rx_bytes: 10,
+            tx_bytes: 20,
+            rx_packets: 1,
+            tx_packets: 2,
+            handshakes: 3,
+            last_handshake_time: None,
+            loss: 0.0,
+            rtt: None,
+            forwarded_packets: 4,
+            drops: DropStats::default(),
+        };
+        let retired = RetiredCounters::default();
+        retired.retire_peer(&sample);
+        retired.retire_peer(&sample);
+        assert_eq!(retired.get().peers, [20, 40, 2, 4, 6, 8]);
+    }
+}
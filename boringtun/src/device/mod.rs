@@ -1,13 +1,33 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+pub mod acl;
 pub mod allowed_ips;
 pub mod api;
+mod batch;
+pub mod capture;
 mod dev_lock;
 pub mod drop_privileges;
+mod events;
+pub mod http;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+pub mod icmp;
 #[cfg(test)]
 mod integration_tests;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod offload;
 pub mod peer;
+pub mod pmtu;
+pub mod poller;
+pub mod recorder;
+mod reload;
+mod save;
+pub mod stats;
+pub mod systemd;
 
 #[cfg(any(
     target_os = "macos",
@@ -31,16 +51,19 @@ pub mod tun;
 #[path = "tun_linux.rs"]
 pub mod tun;
 
+#[cfg(target_os = "linux")]
+mod uring;
+
 #[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
 #[path = "tun_bsd.rs"]
 pub mod tun;
 
 use std::{
     collections::HashMap,
-    io::{self, Write as _},
-    mem::MaybeUninit,
+    io::{self, BufRead as _},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    os::unix::io::AsRawFd,
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
     sync::{
         Arc,
         atomic::{AtomicUsize, Ordering},
@@ -49,23 +72,41 @@ use std::{
     time::Duration,
 };
 
+use acl::{AclHits, AclUpdate, Direction};
 use aead::rand_core::{OsRng, RngCore};
 use allowed_ips::AllowedIps;
+use api::ApiAccess;
+use capture::{Capture, CaptureOptions, CaptureSink, CaptureStats};
+use icmp::{IcmpError, IcmpLimiter, Unreachable};
+use metrics::{PeerSample, RetiredCounters};
 use parking_lot::Mutex;
 use peer::{AllowedIP, Peer};
-use poll::{EventPoll, EventRef, WaitResult};
-use socket2::{Domain, Protocol, Type};
+use pmtu::PmtuUpdate;
+use poll::{EventPoll, EventRef, ReadKind, Received, WaitResult};
+use recorder::{FlightEvent, FlightRecord};
+use socket2::{Domain, Protocol, SockAddr, Type};
 use tun::TunSocket;
 
+pub use poller::DevicePoller;
+
 use crate::{
     noise::{
         Packet, Tunn, TunnResult, errors::WireGuardError, handshake::parse_handshake_anon,
         rate_limiter::RateLimiter,
     },
+    uapi::{
+        client::{self, SetRequest},
+        config::NetworkConfig,
+        event::Event,
+    },
     x25519,
 };
 
+use batch::SendBatch;
 use dev_lock::{Lock, LockReadGuard};
+use events::EventSubscribers;
+use offload::Gro;
+use stats::{DropCounters, DropReason, DropStats};
 
 const HANDSHAKE_RATE_LIMIT: u64 = 100; // The number of handshakes per second we can tolerate before using cookies
 
@@ -107,6 +148,13 @@ pub enum Error {
     DropPrivileges(String),
     #[error("API socket error: {0}")]
     ApiSocket(io::Error),
+    #[error("HTTP listener error: {0}")]
+    Http(io::Error),
+    #[cfg(target_os = "linux")]
+    #[error("Netlink error: {0}")]
+    Netlink(io::Error),
+    #[error("Configuration error: {0}")]
+    Config(String),
 }
 
 // What the event loop should do after a handler returns
@@ -124,14 +172,55 @@ pub struct DeviceHandle {
     threads: Vec<JoinHandle<()>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DeviceConfig {
     pub n_threads: usize,
     pub use_connected_socket: bool,
     #[cfg(target_os = "linux")]
     pub use_multi_queue: bool,
+    /// Open the tunnel interface with TUN offloads, so TCP traffic is read and written as
+    /// super-packets of up to 64KB that are segmented and coalesced in userspace
+    #[cfg(target_os = "linux")]
+    pub use_tun_offload: bool,
+    /// Wait for events with io_uring instead of epoll, and receive from the UDP sockets and the
+    /// tunnel interface with multishot receives. Falls back to epoll if io_uring is unavailable.
+    #[cfg(target_os = "linux")]
+    pub use_io_uring: bool,
     #[cfg(target_os = "linux")]
     pub uapi_fd: i32,
+    /// Reply with ICMP "fragmentation needed" or ICMPv6 "packet too big" to packets from the
+    /// tunnel interface that don't fit the path MTU of their peer, unless the peer overrides it
+    pub icmp_too_big: bool,
+    /// Clamp the MSS of TCP SYNs to and from peers to the MTU of the interface and the path
+    /// MTU of the peer, unless the peer overrides it
+    pub clamp_mss: bool,
+    /// Reply with ICMP "destination unreachable" with this code to packets from the tunnel
+    /// interface without a peer to send them to, or whose peer has no endpoint
+    pub icmp_unreachable: Option<Unreachable>,
+    /// Hub mode: packets from a peer to the allowed IPs of another peer are encapsulated for
+    /// that peer right away, instead of going through the tunnel interface and the routing of
+    /// the host. The firewall rules of both peers still apply.
+    pub hub_mode: bool,
+    /// Restrict which local users may connect to the api socket, when `None` any process
+    /// with permission to open the socket has full access
+    pub api_access: Option<ApiAccess>,
+    /// Serve the HTTP/JSON management API in addition to the api socket
+    #[cfg(feature = "http-api")]
+    pub http_api: Option<http_api::HttpApiConfig>,
+    /// Serve Prometheus metrics
+    pub metrics: Option<metrics::MetricsConfig>,
+    /// A WireGuard configuration file to apply when the device starts, and again on SIGHUP
+    pub config_file: Option<PathBuf>,
+    /// Write the running configuration to this file after every change and on shutdown
+    pub save_config: Option<PathBuf>,
+    /// Listening sockets passed by systemd socket activation, to accept api connections on
+    /// instead of creating the api socket
+    pub api_listen_fds: Vec<i32>,
+    /// Report the status of the device to systemd, and ping its watchdog when enabled
+    pub systemd_notify: bool,
+    /// Exit on SIGINT and SIGTERM, and apply the configuration file again on SIGHUP. The
+    /// signals are blocked on the thread that creates the device, and on the threads it starts.
+    pub handle_signals: bool,
 }
 
 impl Default for DeviceConfig {
@@ -142,11 +231,35 @@ impl Default for DeviceConfig {
             #[cfg(target_os = "linux")]
             use_multi_queue: true,
             #[cfg(target_os = "linux")]
+            use_tun_offload: false,
+            #[cfg(target_os = "linux")]
+            use_io_uring: false,
+            #[cfg(target_os = "linux")]
             uapi_fd: -1,
+            icmp_too_big: false,
+            clamp_mss: false,
+            icmp_unreachable: None,
+            hub_mode: false,
+            api_access: None,
+            #[cfg(feature = "http-api")]
+            http_api: None,
+            metrics: None,
+            config_file: None,
+            save_config: None,
+            api_listen_fds: Vec::new(),
+            systemd_notify: false,
+            handle_signals: true,
         }
     }
 }
 
+/// Sockets bound to a new listen port, not yet in use by the device
+struct ListenSockets {
+    udp4: socket2::Socket,
+    udp6: socket2::Socket,
+    port: u16,
+}
+
 pub struct Device {
     key_pair: Option<(x25519::StaticSecret, x25519::PublicKey)>,
     queue: Arc<EventPoll<Handler>>,
@@ -157,6 +270,8 @@ pub struct Device {
     iface: Arc<TunSocket>,
     udp4: Option<socket2::Socket>,
     udp6: Option<socket2::Socket>,
+    /// The events receiving on `udp4` and `udp6`, registered on clones of them
+    listen_events: Vec<RawFd>,
 
     yield_notice: Option<EventRef>,
     exit_notice: Option<EventRef>,
@@ -174,6 +289,28 @@ pub struct Device {
 
     rate_limiter: Option<Arc<RateLimiter>>,
 
+    events: EventSubscribers,
+
+    drops: DropCounters,
+    /// Counters of removed peers and replaced rate limiters, for the device wide metrics
+    retired: RetiredCounters,
+    /// Limits the ICMP errors written to the tunnel interface
+    icmp_limiter: IcmpLimiter,
+    /// The packet capture started over the api socket, if any
+    capture: Option<Capture>,
+    /// Bound HTTP listeners, served on threads of their own once the device is started
+    http_servers: Vec<http::HttpServer>,
+
+    /// The network settings applied from the configuration file, kept for saving
+    network: NetworkConfig,
+    /// Policy routing rules added for default routes, removed on shutdown
+    #[cfg(target_os = "linux")]
+    routing_rules: Vec<netlink::Rule>,
+    /// Routes for the allowed IPs of the peers, kept in sync once the network settings are
+    /// applied
+    #[cfg(target_os = "linux")]
+    routes: Option<Vec<netlink::Route>>,
+
     #[cfg(target_os = "linux")]
     uapi_fd: i32,
 }
@@ -182,15 +319,135 @@ struct ThreadData {
     iface: Arc<TunSocket>,
     src_buf: [u8; MAX_UDP_SIZE],
     dst_buf: [u8; MAX_UDP_SIZE],
+    /// Holds super-packets read from the interface when TUN offloads are enabled
+    offload_buf: Vec<u8>,
+    /// Decapsulated packets to coalesce, set when TUN offloads are enabled
+    gro: Option<Gro>,
+    /// Encapsulated packets to send on the network
+    send_batch: SendBatch<Arc<Mutex<Peer>>>,
+    /// Holds packets forwarded between peers in hub mode, allocated on first use
+    forward_buf: Vec<u8>,
+    /// Data the io_uring event loop received for the running handler, which then must not
+    /// read from its socket or interface
+    received: Option<std::vec::IntoIter<Received>>,
+}
+
+impl ThreadData {
+    fn new(iface: Arc<TunSocket>) -> ThreadData {
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut thread_local = ThreadData {
+            src_buf: [0u8; MAX_UDP_SIZE],
+            dst_buf: [0u8; MAX_UDP_SIZE],
+            offload_buf: Vec::new(),
+            gro: None,
+            send_batch: SendBatch::default(),
+            forward_buf: Vec::new(),
+            received: None,
+            iface,
+        };
+
+        #[cfg(target_os = "linux")]
+        if thread_local.iface.vnet_hdr() {
+            thread_local.offload_buf = vec![0u8; offload::MAX_OFFLOAD_SIZE];
+            thread_local.gro = Some(Gro::default());
+        }
+
+        thread_local
+    }
+
+    /// Run the handler of a triggered event, and return what the event loop should do next
+    fn dispatch(
+        &mut self,
+        event: WaitResult<'_, Handler>,
+        device: &mut LockReadGuard<Device>,
+    ) -> Action {
+        #[cfg(not(target_os = "linux"))]
+        let uapi_fd = -1;
+        #[cfg(target_os = "linux")]
+        let uapi_fd = device.uapi_fd;
+
+        match event {
+            WaitResult::Ok(mut handler) => {
+                self.received = handler.take_received().map(Vec::into_iter);
+                let action = (*handler)(device, self);
+                self.received = None;
+                action
+            }
+            WaitResult::EoF(handler) => {
+                if uapi_fd >= 0 && uapi_fd == handler.fd() {
+                    return Action::Exit;
+                }
+                handler.cancel();
+                Action::Continue
+            }
+            WaitResult::Error(e) => {
+                tracing::error!(message = "Poll error", error = ?e);
+                Action::Continue
+            }
+        }
+    }
+
+    /// Write the packets queued for coalescing to the interface, and return the number of
+    /// packets that could not be written
+    #[cfg(target_os = "linux")]
+    fn flush_tunnel(&mut self) -> usize {
+        match self.gro {
+            Some(ref mut gro) => gro.flush(|buf| self.iface.write_with_vnet_hdr(buf)),
+            None => 0,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn flush_tunnel(&mut self) -> usize {
+        0
+    }
+
+    /// Receive the next datagrams from a UDP socket into `src_buf`, returning the length, the
+    /// source and the size of the coalesced datagrams
+    fn recv_from(&mut self, udp: &socket2::Socket) -> io::Result<(usize, SockAddr, usize)> {
+        let Some(ref mut received) = self.received else {
+            return batch::recv_from(udp, &mut self.src_buf);
+        };
+        let received = received.next().ok_or(io::ErrorKind::WouldBlock)?;
+        // Safety: the event guard outlives the handler
+        let data = unsafe { received.data() };
+        let len = data.len().min(self.src_buf.len());
+        self.src_buf[..len].copy_from_slice(&data[..len]);
+        let addr = received.addr.ok_or(io::ErrorKind::InvalidData)?;
+        Ok((len, addr, received.segment))
+    }
+
+    /// Read the next packet from the interface, into `offload_buf` with TUN offloads or into
+    /// `src_buf` otherwise, and return its length
+    fn read_iface(
+        &mut self,
+        iface: &TunSocket,
+        vnet_hdr: bool,
+        mtu: usize,
+    ) -> Result<usize, Error> {
+        let buf = if vnet_hdr {
+            &mut self.offload_buf[..]
+        } else {
+            &mut self.src_buf[..mtu]
+        };
+        let Some(ref mut received) = self.received else {
+            return iface.read(buf).map(|src| src.len());
+        };
+        let received = received
+            .next()
+            .ok_or_else(|| Error::IfaceRead(io::ErrorKind::WouldBlock.into()))?;
+        // Safety: the event guard outlives the handler
+        let data = unsafe { received.data() };
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
 }
 
 impl DeviceHandle {
     pub fn new(name: &str, config: DeviceConfig) -> Result<DeviceHandle, Error> {
         let n_threads = config.n_threads;
-        let mut wg_interface = Device::new(name, config)?;
-        wg_interface.open_listen_socket(0)?; // Start listening on a random port
-
-        let interface_lock = Arc::new(Lock::new(wg_interface));
+        let interface_lock = Device::start(name, config)?;
 
         let mut threads = Vec::new();
 
@@ -213,52 +470,101 @@ impl DeviceHandle {
         }
     }
 
+    /// Apply a configuration change, with the same semantics as a `set=1` request on the api
+    /// socket
+    pub fn set(&self, request: &SetRequest) -> Result<(), client::Error> {
+        let mut buf = Vec::new();
+        request.write_to(&mut buf)?;
+        let response = api::api_request(&buf, &mut self.device.read());
+        client::parse_response(&mut response.lines(), |_| Ok(()))
+    }
+
+    /// The packets dropped by the device since it started, by reason
+    pub fn drop_stats(&self) -> DropStats {
+        self.device.read().drops.stats()
+    }
+
+    /// The packets to or from a peer dropped since it was added, or `None` for an unknown peer
+    pub fn peer_drop_stats(&self, public_key: &x25519::PublicKey) -> Option<DropStats> {
+        let device = self.device.read();
+        let peer = device.peers.get(public_key)?;
+        Some(peer.lock().drop_stats())
+    }
+
+    /// The packets matched by the firewall rules of a peer, or `None` for an unknown peer
+    pub fn peer_acl_hits(&self, public_key: &x25519::PublicKey) -> Option<AclHits> {
+        let device = self.device.read();
+        let peer = device.peers.get(public_key)?;
+        Some(peer.lock().acl_hits())
+    }
+
+    /// The recent handshake events of a peer, oldest first, or `None` for an unknown peer
+    pub fn peer_flight_record(&self, public_key: &x25519::PublicKey) -> Option<Vec<FlightRecord>> {
+        let device = self.device.read();
+        let peer = device.peers.get(public_key)?;
+        Some(peer.lock().flight_record())
+    }
+
+    /// Start capturing tunnel traffic to the sink, replacing the running capture if any. The
+    /// same as the `capture` key of a `set=1` request on the api socket.
+    pub fn start_capture(&self, sink: CaptureSink, options: CaptureOptions) -> Result<(), Error> {
+        self.device
+            .read()
+            .try_writeable(Device::trigger_yield, |device| {
+                device.cancel_yield();
+                device.start_capture(sink, options)
+            })
+            .unwrap_or_else(|| Err(io::Error::other("The device is shutting down")))?;
+        Ok(())
+    }
+
+    /// Stop the running capture, if any
+    pub fn stop_capture(&self) {
+        self.device
+            .read()
+            .try_writeable(Device::trigger_yield, |device| {
+                device.cancel_yield();
+                device.capture = None;
+            });
+    }
+
+    /// The progress of the running capture, or `None` when not capturing
+    pub fn capture_stats(&self) -> Option<CaptureStats> {
+        self.device.read().capture.as_ref().map(Capture::stats)
+    }
+
     pub fn clean(&mut self) {
-        for path in &self.device.read().cleanup_paths {
-            // attempt to remove any file we created in the work dir
-            let _ = std::fs::remove_file(path);
-        }
+        self.device.read().clean();
     }
 
     fn event_loop(_i: usize, device: &Lock<Device>) {
         #[cfg(target_os = "linux")]
-        let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: {
-                let device_read = device.read();
-                if _i == 0 || !device_read.config.use_multi_queue {
-                    // For the first thread use the original iface
-                    Arc::clone(&device_read.iface)
+        let iface = {
+            let device_read = device.read();
+            if _i == 0 || !device_read.config.use_multi_queue {
+                // For the first thread use the original iface
+                Arc::clone(&device_read.iface)
+            } else {
+                // For for the rest create a new iface queue
+                let name = device_read.iface.name().unwrap();
+                let iface_local = if device_read.iface.vnet_hdr() {
+                    TunSocket::new_with_offload(&name)
                 } else {
-                    // For for the rest create a new iface queue
-                    let iface_local = Arc::new(
-                        TunSocket::new(&device_read.iface.name().unwrap())
-                            .unwrap()
-                            .set_non_blocking()
-                            .unwrap(),
-                    );
-
-                    device_read
-                        .register_iface_handler(Arc::clone(&iface_local))
-                        .ok();
-
-                    iface_local
-                }
-            },
-        };
+                    TunSocket::new(&name)
+                };
+                let iface_local = Arc::new(iface_local.unwrap().set_non_blocking().unwrap());
 
-        #[cfg(not(target_os = "linux"))]
-        let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
-            dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: Arc::clone(&device.read().iface),
-        };
+                device_read
+                    .register_iface_handler(Arc::clone(&iface_local))
+                    .ok();
 
+                iface_local
+            }
+        };
         #[cfg(not(target_os = "linux"))]
-        let uapi_fd = -1;
-        #[cfg(target_os = "linux")]
-        let uapi_fd = device.read().uapi_fd;
+        let iface = Arc::clone(&device.read().iface);
+
+        let mut thread_local = ThreadData::new(iface);
 
         loop {
             // The event loop keeps a read lock on the device, because we assume write access is rarely needed
@@ -266,26 +572,13 @@ impl DeviceHandle {
             let queue = Arc::clone(&device_lock.queue);
 
             loop {
-                match queue.wait() {
-                    WaitResult::Ok(handler) => {
-                        let action = (*handler)(&mut device_lock, &mut thread_local);
-                        match action {
-                            Action::Continue => {}
-                            Action::Yield => break,
-                            Action::Exit => {
-                                device_lock.trigger_exit();
-                                return;
-                            }
-                        }
-                    }
-                    WaitResult::EoF(handler) => {
-                        if uapi_fd >= 0 && uapi_fd == handler.fd() {
-                            device_lock.trigger_exit();
-                            return;
-                        }
-                        handler.cancel();
+                match thread_local.dispatch(queue.wait(), &mut device_lock) {
+                    Action::Continue => {}
+                    Action::Yield => break,
+                    Action::Exit => {
+                        device_lock.trigger_exit();
+                        return;
                     }
-                    WaitResult::Error(e) => tracing::error!(message = "Poll error", error = ?e),
                 }
             }
         }
@@ -295,11 +588,56 @@ impl DeviceHandle {
 impl Drop for DeviceHandle {
     fn drop(&mut self) {
         self.device.read().trigger_exit();
+        // Keep the final state, such as the endpoints peers roamed to
+        self.device.read().save_config();
         self.clean();
     }
 }
 
 impl Device {
+    /// Create the device, listen on a random port until configured otherwise, and apply the
+    /// configuration file
+    fn start(name: &str, config: DeviceConfig) -> Result<Arc<Lock<Device>>, Error> {
+        let mut wg_interface = Device::new(name, config)?;
+        wg_interface.open_listen_socket(0)?; // Start listening on a random port
+
+        let http_servers = std::mem::take(&mut wg_interface.http_servers);
+        let interface_lock = Arc::new(Lock::new(wg_interface));
+
+        let config_file = interface_lock.read().config.config_file.clone();
+        if let Some(path) = config_file {
+            let config = reload::read_config(&path)?;
+            interface_lock
+                .read()
+                .try_writeable(Device::trigger_yield, |device| {
+                    device.cancel_yield();
+                    device.apply_config(&config)?;
+                    device.network = config.network.clone();
+                    #[cfg(target_os = "linux")]
+                    device.setup_network()?;
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(Error::Config("The device is shutting down".to_owned())))?;
+        }
+
+        for server in http_servers {
+            server
+                .spawn(Arc::downgrade(&interface_lock))
+                .map_err(Error::Http)?;
+        }
+
+        Ok(interface_lock)
+    }
+
+    fn clean(&self) {
+        for path in &self.cleanup_paths {
+            // attempt to remove any file we created in the work dir
+            let _ = std::fs::remove_file(path);
+        }
+        #[cfg(target_os = "linux")]
+        self.remove_routing_rules();
+    }
+
     fn next_index(&mut self) -> u32 {
         self.next_index.next()
     }
@@ -310,12 +648,16 @@ impl Device {
             {
                 let p = peer.lock();
                 p.shutdown_endpoint(); // close open udp socket and free the closure
+                self.retired.retire_peer(&PeerSample::new(pub_key, &p));
                 self.peers_by_idx.remove(&p.index());
+                p.span().in_scope(|| tracing::info!("Peer removed"));
             }
             self.peers_by_ip
                 .remove(&|p: &Arc<Mutex<Peer>>| Arc::ptr_eq(&peer, p));
 
-            tracing::info!("Peer removed");
+            self.events.emit(Event::PeerRemoved {
+                public_key: *pub_key,
+            });
         }
     }
 
@@ -324,11 +666,13 @@ impl Device {
         &mut self,
         pub_key: x25519::PublicKey,
         remove: bool,
-        _replace_ips: bool,
+        replace_ips: bool,
         endpoint: Option<SocketAddr>,
         allowed_ips: &[AllowedIP],
         keepalive: Option<u16>,
         preshared_key: Option<[u8; 32]>,
+        pmtu: &PmtuUpdate,
+        acl: &AclUpdate,
     ) {
         if remove {
             // Completely remove a peer
@@ -336,13 +680,39 @@ impl Device {
             return;
         }
 
-        // Update an existing peer
-        // We already have a peer, we need to merge the existing config into the newly created one
-        assert!(
-            !self.peers.contains_key(&pub_key),
-            "Modifying existing peers is not yet supported. Remove and add again instead."
-        );
+        // Update an existing peer in place, so its sessions are kept
+        if let Some(peer) = self.peers.get(&pub_key).cloned() {
+            let mut p = peer.lock();
+            if let Some(endpoint) = endpoint {
+                p.set_endpoint(endpoint);
+            }
+            let _span = p.enter_span();
+            if let Some(keepalive) = keepalive {
+                p.tunnel.set_persistent_keepalive(keepalive);
+            }
+            if let Some(key) = preshared_key {
+                // An all zero key removes the preshared key
+                p.set_preshared_key((key != [0u8; 32]).then_some(key));
+            }
+            p.set_pmtu(pmtu);
+            p.set_acl(acl);
+            if replace_ips {
+                p.clear_allowed_ips();
+                self.peers_by_ip
+                    .remove(&|p: &Arc<Mutex<Peer>>| Arc::ptr_eq(&peer, p));
+            }
+            for &ip in allowed_ips {
+                p.add_allowed_ip(ip);
+                self.peers_by_ip
+                    .insert(ip.addr, ip.cidr.into(), Arc::clone(&peer));
+            }
 
+            tracing::info!("Peer updated");
+            return;
+        }
+
+        // An all zero key is the same as no preshared key
+        let preshared_key = preshared_key.filter(|key| *key != [0u8; 32]);
         let next_index = self.next_index();
         let Some(device_key_pair) = self.key_pair.as_ref() else {
             tracing::error!("Private key must be set first");
@@ -358,7 +728,17 @@ impl Device {
             None,
         );
 
-        let peer = Peer::new(tunn, next_index, endpoint, allowed_ips, preshared_key);
+        let mut peer = Peer::new(
+            tunn,
+            pub_key,
+            next_index,
+            endpoint,
+            allowed_ips,
+            preshared_key,
+        );
+        peer.set_pmtu(pmtu);
+        peer.set_acl(acl);
+        peer.span().in_scope(|| tracing::info!("Peer added"));
 
         let peer = Arc::new(Mutex::new(peer));
         self.peers.insert(pub_key, Arc::clone(&peer));
@@ -369,14 +749,33 @@ impl Device {
                 .insert(*addr, (*cidr).into(), Arc::clone(&peer));
         }
 
-        tracing::info!("Peer added");
+        self.events.emit(Event::PeerAdded {
+            public_key: pub_key,
+        });
     }
 
     pub fn new(name: &str, config: DeviceConfig) -> Result<Device, Error> {
+        #[cfg(target_os = "linux")]
+        let poll = match config.use_io_uring {
+            true => EventPoll::<Handler>::new_with_io_uring().or_else(|e| {
+                tracing::warn!(message = "Falling back to epoll", error = ?e);
+                EventPoll::<Handler>::new()
+            })?,
+            false => EventPoll::<Handler>::new()?,
+        };
+        #[cfg(not(target_os = "linux"))]
         let poll = EventPoll::<Handler>::new()?;
 
         // Create a tunnel device
-        let iface = Arc::new(TunSocket::new(name)?.set_non_blocking()?);
+        #[cfg(target_os = "linux")]
+        let iface = if config.use_tun_offload {
+            TunSocket::new_with_offload(name)?
+        } else {
+            TunSocket::new(name)?
+        };
+        #[cfg(not(target_os = "linux"))]
+        let iface = TunSocket::new(name)?;
+        let iface = Arc::new(iface.set_non_blocking()?);
         let mtu = iface.mtu()?;
 
         #[cfg(not(target_os = "linux"))]
@@ -399,21 +798,45 @@ impl Device {
             peers_by_ip: AllowedIps::new(),
             udp4: Option::default(),
             udp6: Option::default(),
+            listen_events: Vec::new(),
             cleanup_paths: Vec::default(),
             mtu: AtomicUsize::new(mtu),
             rate_limiter: None,
+            events: EventSubscribers::default(),
+            drops: DropCounters::default(),
+            retired: RetiredCounters::default(),
+            icmp_limiter: IcmpLimiter::default(),
+            capture: None,
+            http_servers: Vec::new(),
+            network: NetworkConfig::default(),
+            #[cfg(target_os = "linux")]
+            routing_rules: Vec::new(),
+            #[cfg(target_os = "linux")]
+            routes: None,
             #[cfg(target_os = "linux")]
             uapi_fd,
         };
 
         if uapi_fd >= 0 {
             device.register_api_fd(uapi_fd)?;
+        } else if !device.config.api_listen_fds.is_empty() {
+            device.register_api_listeners(&device.config.api_listen_fds.clone())?;
         } else {
             device.register_api_handler()?;
         }
+        #[cfg(feature = "http-api")]
+        if let Some(http_api) = device.config.http_api.clone() {
+            device.register_http_api(&http_api)?;
+        }
+        if let Some(metrics) = device.config.metrics.clone() {
+            device.register_metrics(&metrics)?;
+        }
         device.register_iface_handler(Arc::clone(&device.iface))?;
         device.register_notifiers()?;
         device.register_timers()?;
+        if device.config.systemd_notify {
+            device.register_systemd_notifier()?;
+        }
 
         #[cfg(target_os = "macos")]
         {
@@ -429,25 +852,14 @@ impl Device {
         Ok(device)
     }
 
-    fn open_listen_socket(&mut self, mut port: u16) -> Result<(), Error> {
-        // Binds the network facing interfaces
-        // First close any existing open socket, and remove them from the event loop
-        if let Some(s) = self.udp4.take() {
-            unsafe {
-                // This is safe because the event loop is not running yet
-                self.queue.clear_event_by_fd(s.as_raw_fd());
-            }
-        }
-
-        if let Some(s) = self.udp6.take() {
-            unsafe { self.queue.clear_event_by_fd(s.as_raw_fd()) };
-        }
-
-        for peer in self.peers.values() {
-            peer.lock().shutdown_endpoint();
-        }
+    fn open_listen_socket(&mut self, port: u16) -> Result<(), Error> {
+        let sockets = self.bind_listen_sockets(port)?;
+        self.install_listen_sockets(sockets)
+    }
 
-        // Then open new sockets and bind to the port
+    /// Bind the network facing sockets to the port, or to a random port for 0, without using
+    /// them yet
+    fn bind_listen_sockets(&self, mut port: u16) -> Result<ListenSockets, Error> {
         let udp_sock4 = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
         udp_sock4.set_reuse_address(true)?;
         udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
@@ -463,12 +875,50 @@ impl Device {
         udp_sock6.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
         udp_sock6.set_nonblocking(true)?;
 
-        self.register_udp_handler(udp_sock4.try_clone().unwrap())?;
-        self.register_udp_handler(udp_sock6.try_clone().unwrap())?;
-        self.udp4 = Some(udp_sock4);
-        self.udp6 = Some(udp_sock6);
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(mark) = self.fwmark {
+            udp_sock4.set_mark(mark)?;
+            udp_sock6.set_mark(mark)?;
+        }
+
+        Ok(ListenSockets {
+            udp4: udp_sock4,
+            udp6: udp_sock6,
+            port,
+        })
+    }
 
-        self.listen_port = port;
+    /// Start receiving on the sockets, and only then close the previous ones
+    fn install_listen_sockets(&mut self, sockets: ListenSockets) -> Result<(), Error> {
+        let mut events = Vec::with_capacity(2);
+        for sock in [&sockets.udp4, &sockets.udp6] {
+            match sock
+                .try_clone()
+                .map_err(Error::from)
+                .and_then(|sock| self.register_udp_handler(sock))
+            {
+                Ok(fd) => events.push(fd),
+                Err(e) => {
+                    for fd in events {
+                        // This is safe because no handler runs while the device is changed
+                        unsafe { self.queue.clear_event_by_fd(fd) };
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        // The handlers own clones of the previous sockets, so removing them closes the sockets
+        for fd in std::mem::replace(&mut self.listen_events, events) {
+            unsafe { self.queue.clear_event_by_fd(fd) };
+        }
+        for peer in self.peers.values() {
+            peer.lock().shutdown_endpoint();
+        }
+
+        self.udp4 = Some(sockets.udp4);
+        self.udp6 = Some(sockets.udp6);
+        self.listen_port = sockets.port;
 
         Ok(())
     }
@@ -493,14 +943,27 @@ impl Device {
             );
         }
 
+        if let Some(ref old) = self.rate_limiter {
+            self.retired.retire_rate_limiter(old);
+        }
         self.key_pair = key_pair;
         self.rate_limiter = Some(rate_limiter);
+        self.events.emit(Event::KeyRotated { public_key });
     }
 
     #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
     fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
+        if let Err(e) = self.mark_sockets(mark) {
+            // Put the previous mark back on the sockets that were already changed
+            let _ = self.mark_sockets(self.fwmark.unwrap_or(0));
+            return Err(e);
+        }
         self.fwmark = Some(mark);
+        Ok(())
+    }
 
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn mark_sockets(&self, mark: u32) -> Result<(), Error> {
         // First set fwmark on listeners
         if let Some(ref sock) = self.udp4 {
             sock.set_mark(mark)?;
@@ -521,6 +984,13 @@ impl Device {
     }
 
     fn clear_peers(&mut self) {
+        for (public_key, peer) in &self.peers {
+            self.retired
+                .retire_peer(&PeerSample::new(public_key, &peer.lock()));
+            self.events.emit(Event::PeerRemoved {
+                public_key: *public_key,
+            });
+        }
         self.peers.clear();
         self.peers_by_idx.clear();
         self.peers_by_ip.clear();
@@ -543,11 +1013,12 @@ impl Device {
 
     fn register_timers(&self) -> Result<(), Error> {
         self.queue.new_periodic_event(
-            // Reset the rate limiter every second give or take
+            // Reset the rate limiters every second give or take
             Box::new(|d, _| {
                 if let Some(r) = d.rate_limiter.as_ref() {
                     r.reset_count();
                 }
+                d.icmp_limiter.reset();
                 Action::Continue
             }),
             Duration::from_secs(1),
@@ -563,27 +1034,36 @@ impl Device {
                 };
 
                 // Go over each peer and invoke the timer function
-                for peer in peer_map.values() {
+                for (public_key, peer) in peer_map {
                     let mut p = peer.lock();
+                    let _span = p.enter_span();
+                    #[cfg(target_os = "linux")]
+                    p.update_learned_mtu();
                     let Some(endpoint_addr) = p.endpoint().addr else {
                         continue;
                     };
 
+                    let was_expired = p.tunnel.is_expired();
                     match p.update_timers(&mut t.dst_buf[..]) {
                         TunnResult::Done => {}
                         TunnResult::Err(WireGuardError::ConnectionExpired) => {
                             p.shutdown_endpoint(); // close open udp socket
+                            if !was_expired {
+                                d.events.emit(Event::SessionExpired {
+                                    public_key: *public_key,
+                                });
+                            }
                         }
                         TunnResult::Err(e) => tracing::error!(message = "Timer error", error = ?e),
                         TunnResult::WriteToNetwork(packet) => {
-                            match endpoint_addr {
-                                SocketAddr::V4(_) => {
-                                    udp4.send_to(packet, &endpoint_addr.into()).ok()
-                                }
-                                SocketAddr::V6(_) => {
-                                    udp6.send_to(packet, &endpoint_addr.into()).ok()
-                                }
+                            d.capture_outer(&p, packet, Some(endpoint_addr), Direction::Out);
+                            let sent = match endpoint_addr {
+                                SocketAddr::V4(_) => udp4.send_to(packet, &endpoint_addr.into()),
+                                SocketAddr::V6(_) => udp6.send_to(packet, &endpoint_addr.into()),
                             };
+                            if sent.is_err() {
+                                d.drop_peer_packet(&p, DropReason::SendError);
+                            }
                         }
                         _ => panic!("Unexpected result from update_timers"),
                     }
@@ -613,9 +1093,26 @@ impl Device {
             .stop_notification(self.yield_notice.as_ref().unwrap());
     }
 
-    fn register_udp_handler(&self, udp: socket2::Socket) -> Result<(), Error> {
-        self.queue.new_event(
-            udp.as_raw_fd(),
+    /// Count a dropped packet for both the device and the peer
+    fn drop_peer_packet(&self, peer: &Peer, reason: DropReason) {
+        self.drops.drop(reason);
+        peer.count_drop(reason);
+    }
+
+    fn peer_decapsulation_error(&self, peer: &Peer, e: &WireGuardError) {
+        self.drops.decapsulation_error(e);
+        peer.count_drop(DropReason::DecapsulationError);
+        peer.record(FlightEvent::DecapsulationError(
+            stats::wireguard_error_name(e),
+        ));
+    }
+
+    /// Receive on a listen socket, and return the fd of its event
+    fn register_udp_handler(&self, udp: socket2::Socket) -> Result<RawFd, Error> {
+        batch::enable_gro(&udp);
+        let fd = udp.as_raw_fd();
+        self.queue.new_read_event(
+            fd,
             Box::new(move |d, t| {
                 // Handler that handles anonymous packets over UDP
                 let mut iter = MAX_ITR;
@@ -625,106 +1122,166 @@ impl Device {
 
                 // Loop while we have packets on the anonymous connection
 
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf = unsafe { &mut *(&raw mut t.src_buf[..] as *mut [MaybeUninit<u8>]) };
-                while let Ok((packet_len, addr)) = udp.recv_from(src_buf) {
-                    let packet = &t.src_buf[..packet_len];
-                    // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
-                    let parsed_packet = match rate_limiter.verify_packet(
-                        Some(addr.as_socket().unwrap().ip()),
-                        packet,
-                        &mut t.dst_buf,
-                    ) {
-                        Ok(packet) => packet,
-                        Err(TunnResult::WriteToNetwork(cookie)) => {
-                            let _: Result<_, _> = udp.send_to(cookie, &addr);
-                            continue;
-                        }
-                        Err(_) => continue,
-                    };
-
-                    let peer = match &parsed_packet {
-                        Packet::HandshakeInit(p) => {
-                            parse_handshake_anon(private_key, public_key, p)
-                                .ok()
-                                .and_then(|hh| {
-                                    d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
-                                })
-                        }
-                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                    };
-
-                    let Some(peer) = peer else { continue };
-                    let mut flush = false;
-                    let mut packet_to_network = None;
-                    let mut packet_to_tunnel = None;
-                    let mut packet_to_tunnel_v6 = false;
+                // Datagrams coalesced by the kernel are handled one at a time
+                while let Ok((len, addr, segment)) = t.recv_from(&udp) {
+                    for packet in t.src_buf[..len].chunks(segment) {
+                        // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
+                        let parsed_packet = match rate_limiter.verify_packet(
+                            Some(addr.as_socket().unwrap().ip()),
+                            packet,
+                            &mut t.dst_buf,
+                        ) {
+                            Ok(packet) => packet,
+                            Err(TunnResult::WriteToNetwork(cookie)) => {
+                                if udp.send_to(cookie, &addr).is_err() {
+                                    d.drops.drop(DropReason::SendError);
+                                }
+                                continue;
+                            }
+                            Err(TunnResult::Err(e)) => {
+                                d.drops.decapsulation_error(&e);
+                                continue;
+                            }
+                            Err(_) => continue,
+                        };
+
+                        let peer = match &parsed_packet {
+                            Packet::HandshakeInit(p) => {
+                                parse_handshake_anon(private_key, public_key, p)
+                                    .ok()
+                                    .and_then(|hh| {
+                                        d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
+                                    })
+                            }
+                            Packet::HandshakeResponse(p) => {
+                                d.peers_by_idx.get(&(p.receiver_idx >> 8))
+                            }
+                            Packet::PacketCookieReply(p) => {
+                                d.peers_by_idx.get(&(p.receiver_idx >> 8))
+                            }
+                            Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
+                        };
 
-                    {
-                        let mut p = peer.lock();
+                        let Some(peer) = peer else {
+                            d.drops.drop(DropReason::UnknownPeer);
+                            continue;
+                        };
+                        let mut flush = false;
+                        let mut packet_to_network = None;
+                        let mut packet_to_tunnel = None;
+                        let mut packet_to_tunnel_v6 = false;
 
-                        // We found a peer, use it to decapsulate the message.
-                        match p
-                            .tunnel
-                            .handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
                         {
-                            TunnResult::Done => {}
-                            TunnResult::Err(_) => continue,
-                            TunnResult::WriteToNetwork(packet) => {
-                                flush = true;
-                                packet_to_network = Some(packet);
-                            }
-                            TunnResult::WriteToTunnelV4(packet, addr) => {
-                                if p.is_allowed_ip(addr) {
-                                    packet_to_tunnel = Some(packet);
+                            let mut p = peer.lock();
+                            let _span = p.enter_span();
+                            let handshakes = p.tunnel.handshakes();
+                            d.capture_outer(&p, packet, addr.as_socket(), Direction::In);
+                            p.record_received(packet);
+
+                            // We found a peer, use it to decapsulate the message.
+                            match p
+                                .tunnel
+                                .handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
+                            {
+                                TunnResult::Done => {}
+                                TunnResult::Err(e) => {
+                                    d.peer_decapsulation_error(&p, &e);
+                                    continue;
                                 }
-                            }
-                            TunnResult::WriteToTunnelV6(packet, addr) => {
-                                if p.is_allowed_ip(addr) {
-                                    packet_to_tunnel = Some(packet);
-                                    packet_to_tunnel_v6 = true;
+                                TunnResult::WriteToNetwork(packet) => {
+                                    d.capture_outer(&p, packet, addr.as_socket(), Direction::Out);
+                                    p.record_sent(packet, None);
+                                    flush = true;
+                                    packet_to_network = Some(packet);
+                                }
+                                TunnResult::WriteToTunnelV4(packet, addr) => {
+                                    if d.accept_received(&p, packet, addr.into()) {
+                                        packet_to_tunnel = Some(packet);
+                                    }
+                                }
+                                TunnResult::WriteToTunnelV6(packet, addr) => {
+                                    if d.accept_received(&p, packet, addr.into()) {
+                                        packet_to_tunnel = Some(packet);
+                                        packet_to_tunnel_v6 = true;
+                                    }
                                 }
                             }
+
+                            if p.tunnel.handshakes() != handshakes {
+                                p.record(FlightEvent::HandshakeCompleted);
+                                d.events.emit(Event::HandshakeCompleted {
+                                    public_key: *p.public_key(),
+                                });
+                            }
                         }
-                    }
 
-                    if let Some(packet) = packet_to_network {
-                        let _: Result<_, _> = udp.send_to(packet, &addr);
-                    } else if let Some(packet) = packet_to_tunnel {
-                        if packet_to_tunnel_v6 {
-                            let _ = t.iface.write6(packet);
-                        } else {
-                            let _ = t.iface.write4(packet);
+                        if let Some(packet) = packet_to_network {
+                            if udp.send_to(packet, &addr).is_err() {
+                                d.drop_peer_packet(&peer.lock(), DropReason::SendError);
+                            }
+                        } else if let Some(packet) = packet_to_tunnel
+                            && !d.forward_to_peer(
+                                peer,
+                                packet,
+                                &mut t.forward_buf,
+                                &mut t.send_batch,
+                            )
+                        {
+                            if let Some(ref mut gro) = t.gro {
+                                gro.push(packet);
+                            } else {
+                                let written = if packet_to_tunnel_v6 {
+                                    t.iface.write6(packet)
+                                } else {
+                                    t.iface.write4(packet)
+                                };
+                                if written == 0 {
+                                    d.drop_peer_packet(&peer.lock(), DropReason::TunWriteError);
+                                }
+                            }
                         }
-                    }
 
-                    if flush {
-                        // Flush pending queue
-                        while let Some(packet) = {
-                            let mut p = peer.lock();
-                            match p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..]) {
-                                TunnResult::WriteToNetwork(packet) => Some(packet),
-                                _ => None,
+                        if flush {
+                            // Flush pending queue
+                            while let Some(packet) = {
+                                let mut p = peer.lock();
+                                match p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..]) {
+                                    TunnResult::WriteToNetwork(packet) => {
+                                        d.capture_outer(
+                                            &p,
+                                            packet,
+                                            addr.as_socket(),
+                                            Direction::Out,
+                                        );
+                                        Some(packet)
+                                    }
+                                    _ => None,
+                                }
+                            } {
+                                if udp.send_to(packet, &addr).is_err() {
+                                    d.drop_peer_packet(&peer.lock(), DropReason::SendError);
+                                }
                             }
-                        } {
-                            let _: Result<_, _> = udp.send_to(packet, &addr);
                         }
-                    }
 
-                    // This packet was OK, that means we want to create a connected socket for this peer
-                    let addr = addr.as_socket().unwrap();
-                    let ip_addr = addr.ip();
-                    let p = peer.lock();
-                    let endpoint_changed = p.set_endpoint(addr);
-                    if d.config.use_connected_socket
-                        && (endpoint_changed || p.endpoint().conn.is_none())
-                        && let Ok(sock) = p.connect_endpoint(d.listen_port, d.fwmark)
-                    {
-                        d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
-                            .unwrap();
+                        // This packet was OK, that means we want to create a connected socket for this peer
+                        let addr = addr.as_socket().unwrap();
+                        let ip_addr = addr.ip();
+                        let mut p = peer.lock();
+                        let endpoint_changed = p.set_endpoint(addr);
+                        if endpoint_changed {
+                            d.events.emit(Event::EndpointRoamed {
+                                public_key: *p.public_key(),
+                                endpoint: addr,
+                            });
+                        }
+                        if d.config.use_connected_socket
+                            && (endpoint_changed || p.endpoint().conn.is_none())
+                            && let Ok(sock) = p.connect_endpoint(d.listen_port, d.fwmark)
+                        {
+                            d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
+                                .unwrap();
+                        }
                     }
 
                     iter -= 1;
@@ -732,10 +1289,15 @@ impl Device {
                         break;
                     }
                 }
+                for _ in 0..t.flush_tunnel() {
+                    d.drops.drop(DropReason::TunWriteError);
+                }
+                d.flush_sends(&mut t.send_batch);
                 Action::Continue
             }),
+            ReadKind::Datagram,
         )?;
-        Ok(())
+        Ok(fd)
     }
 
     fn register_conn_handler(
@@ -744,72 +1306,109 @@ impl Device {
         udp: socket2::Socket,
         peer_addr: IpAddr,
     ) -> Result<(), Error> {
-        self.queue.new_event(
+        batch::enable_gro(&udp);
+        self.queue.new_read_event(
             udp.as_raw_fd(),
-            Box::new(move |_, t| {
+            Box::new(move |d, t| {
                 // The conn_handler handles packet received from a connected UDP socket, associated
                 // with a known peer, this saves us the hustle of finding the right peer. If another
                 // peer gets the same ip, it will be ignored until the socket does not expire.
-                let iface = &t.iface;
                 let mut iter = MAX_ITR;
 
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf = unsafe { &mut *(&raw mut t.src_buf[..] as *mut [MaybeUninit<u8>]) };
-
-                while let Ok(read_bytes) = udp.recv(src_buf) {
-                    let mut flush = false;
-                    let mut packet_to_network = None;
-                    let mut packet_to_tunnel = None;
-                    let mut packet_to_tunnel_v6 = false;
+                // Datagrams coalesced by the kernel are handled one at a time
+                while let Ok((len, _, segment)) = t.recv_from(&udp) {
+                    for packet in t.src_buf[..len].chunks(segment) {
+                        let mut flush = false;
+                        let mut packet_to_network = None;
+                        let mut packet_to_tunnel = None;
+                        let mut packet_to_tunnel_v6 = false;
 
-                    {
-                        let mut p = peer.lock();
-                        match p.tunnel.decapsulate(
-                            Some(peer_addr),
-                            &t.src_buf[..read_bytes],
-                            &mut t.dst_buf[..],
-                        ) {
-                            TunnResult::Done => {}
-                            TunnResult::Err(e) => eprintln!("Decapsulate error {e:?}"),
-                            TunnResult::WriteToNetwork(packet) => {
-                                flush = true;
-                                packet_to_network = Some(packet);
-                            }
-                            TunnResult::WriteToTunnelV4(packet, addr) => {
-                                if p.is_allowed_ip(addr) {
-                                    packet_to_tunnel = Some(packet);
+                        {
+                            let mut p = peer.lock();
+                            let _span = p.enter_span();
+                            let handshakes = p.tunnel.handshakes();
+                            let endpoint = p.endpoint().addr;
+                            d.capture_outer(&p, packet, endpoint, Direction::In);
+                            p.record_received(packet);
+                            match p
+                                .tunnel
+                                .decapsulate(Some(peer_addr), packet, &mut t.dst_buf[..])
+                            {
+                                TunnResult::Done => {}
+                                TunnResult::Err(e) => {
+                                    d.peer_decapsulation_error(&p, &e);
+                                    tracing::debug!(message = "Decapsulate error", error = ?e);
                                 }
-                            }
-                            TunnResult::WriteToTunnelV6(packet, addr) => {
-                                if p.is_allowed_ip(addr) {
-                                    packet_to_tunnel = Some(packet);
-                                    packet_to_tunnel_v6 = true;
+                                TunnResult::WriteToNetwork(packet) => {
+                                    d.capture_outer(&p, packet, endpoint, Direction::Out);
+                                    p.record_sent(packet, None);
+                                    flush = true;
+                                    packet_to_network = Some(packet);
+                                }
+                                TunnResult::WriteToTunnelV4(packet, addr) => {
+                                    if d.accept_received(&p, packet, addr.into()) {
+                                        packet_to_tunnel = Some(packet);
+                                    }
+                                }
+                                TunnResult::WriteToTunnelV6(packet, addr) => {
+                                    if d.accept_received(&p, packet, addr.into()) {
+                                        packet_to_tunnel = Some(packet);
+                                        packet_to_tunnel_v6 = true;
+                                    }
                                 }
                             }
+
+                            if p.tunnel.handshakes() != handshakes {
+                                p.record(FlightEvent::HandshakeCompleted);
+                                d.events.emit(Event::HandshakeCompleted {
+                                    public_key: *p.public_key(),
+                                });
+                            }
                         }
-                    }
 
-                    if let Some(packet) = packet_to_network {
-                        let _: Result<_, _> = udp.send(packet);
-                    } else if let Some(packet) = packet_to_tunnel {
-                        if packet_to_tunnel_v6 {
-                            let _ = iface.write6(packet);
-                        } else {
-                            let _ = iface.write4(packet);
+                        if let Some(packet) = packet_to_network {
+                            if udp.send(packet).is_err() {
+                                d.drop_peer_packet(&peer.lock(), DropReason::SendError);
+                            }
+                        } else if let Some(packet) = packet_to_tunnel
+                            && !d.forward_to_peer(
+                                &peer,
+                                packet,
+                                &mut t.forward_buf,
+                                &mut t.send_batch,
+                            )
+                        {
+                            if let Some(ref mut gro) = t.gro {
+                                gro.push(packet);
+                            } else {
+                                let written = if packet_to_tunnel_v6 {
+                                    t.iface.write6(packet)
+                                } else {
+                                    t.iface.write4(packet)
+                                };
+                                if written == 0 {
+                                    d.drop_peer_packet(&peer.lock(), DropReason::TunWriteError);
+                                }
+                            }
                         }
-                    }
 
-                    if flush {
-                        // Flush pending queue
-                        while let Some(packet) = {
-                            let mut p = peer.lock();
-                            match p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..]) {
-                                TunnResult::WriteToNetwork(packet) => Some(packet),
-                                _ => None,
+                        if flush {
+                            // Flush pending queue
+                            while let Some(packet) = {
+                                let mut p = peer.lock();
+                                match p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..]) {
+                                    TunnResult::WriteToNetwork(packet) => {
+                                        let endpoint = p.endpoint().addr;
+                                        d.capture_outer(&p, packet, endpoint, Direction::Out);
+                                        Some(packet)
+                                    }
+                                    _ => None,
+                                }
+                            } {
+                                if udp.send(packet).is_err() {
+                                    d.drop_peer_packet(&peer.lock(), DropReason::SendError);
+                                }
                             }
-                        } {
-                            let _: Result<_, _> = udp.send(packet);
                         }
                     }
 
@@ -818,19 +1417,33 @@ impl Device {
                         break;
                     }
                 }
+                let dropped = t.flush_tunnel();
+                if dropped > 0 {
+                    let p = peer.lock();
+                    for _ in 0..dropped {
+                        d.drop_peer_packet(&p, DropReason::TunWriteError);
+                    }
+                }
+                d.flush_sends(&mut t.send_batch);
                 Action::Continue
             }),
+            ReadKind::Datagram,
         )?;
         Ok(())
     }
 
     fn register_iface_handler(&self, iface: Arc<TunSocket>) -> Result<(), Error> {
-        self.queue.new_event(
+        #[cfg(target_os = "linux")]
+        let vnet_hdr = iface.vnet_hdr();
+        #[cfg(not(target_os = "linux"))]
+        let vnet_hdr = false;
+
+        self.queue.new_read_event(
             iface.as_raw_fd(),
             Box::new(move |d, t| {
                 // The iface_handler handles packets received from the WireGuard virtual network
                 // interface. The flow is as follows:
-                // * Read a packet
+                // * Read a packet, and split it into segments when TUN offloads are enabled
                 // * Determine peer based on packet destination ip
                 // * Encapsulate the packet for the given peer
                 // * Send encapsulated packet to the peer's endpoint
@@ -839,10 +1452,30 @@ impl Device {
                 let udp4 = d.udp4.as_ref().expect("Not connected");
                 let udp6 = d.udp6.as_ref().expect("Not connected");
 
-                let peers = &d.peers_by_ip;
                 for _ in 0..MAX_ITR {
-                    let src = match iface.read(&mut t.src_buf[..mtu]) {
-                        Ok(src) => src,
+                    let read = t.read_iface(&iface, vnet_hdr, mtu).map(|len| {
+                        if vnet_hdr {
+                            let src = &t.offload_buf[..len];
+                            let segmented = offload::segment(src, &mut t.src_buf, |packet| {
+                                d.send_to_peer(
+                                    packet,
+                                    &mut t.dst_buf,
+                                    &mut t.send_batch,
+                                    udp4,
+                                    udp6,
+                                );
+                            });
+                            if segmented.is_none() {
+                                d.drops.drop(DropReason::InvalidTunnelPacket);
+                            }
+                        } else {
+                            let src = &t.src_buf[..len];
+                            d.send_to_peer(src, &mut t.dst_buf, &mut t.send_batch, udp4, udp6);
+                        }
+                    });
+
+                    match read {
+                        Ok(()) => {}
                         Err(Error::IfaceRead(e)) => {
                             let ek = e.kind();
                             if ek == io::ErrorKind::Interrupted || ek == io::ErrorKind::WouldBlock {
@@ -855,43 +1488,235 @@ impl Device {
                             eprintln!("Unexpected error on tun interface: {e:?}");
                             return Action::Exit;
                         }
-                    };
-
-                    let Some(dst_addr) = Tunn::dst_address(src) else {
-                        continue;
-                    };
-
-                    let mut peer = match peers.find(dst_addr) {
-                        Some(peer) => peer.lock(),
-                        None => continue,
-                    };
-
-                    match peer.tunnel.encapsulate(src, &mut t.dst_buf[..]) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => {
-                            tracing::error!(message = "Encapsulate error", error = ?e);
-                        }
-                        TunnResult::WriteToNetwork(packet) => {
-                            let mut endpoint = peer.endpoint_mut();
-                            if let Some(conn) = endpoint.conn.as_mut() {
-                                // Prefer to send using the connected socket
-                                let _: Result<_, _> = conn.write(packet);
-                            } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp4.send_to(packet, &addr.into());
-                            } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp6.send_to(packet, &addr.into());
-                            } else {
-                                tracing::error!("No endpoint");
-                            }
-                        }
-                        _ => panic!("Unexpected result from encapsulate"),
                     }
                 }
+                d.flush_sends(&mut t.send_batch);
                 Action::Continue
             }),
+            ReadKind::Packet,
         )?;
         Ok(())
     }
+
+    /// Encapsulate a packet read from the interface for the peer its destination is routed to,
+    /// and queue it to be sent to the peer's endpoint
+    fn send_to_peer(
+        &self,
+        src: &[u8],
+        dst_buf: &mut [u8],
+        batch: &mut SendBatch<Arc<Mutex<Peer>>>,
+        udp4: &socket2::Socket,
+        udp6: &socket2::Socket,
+    ) {
+        let Some(dst_addr) = Tunn::dst_address(src) else {
+            self.drops.drop(DropReason::InvalidTunnelPacket);
+            return;
+        };
+
+        let Some(peer_ref) = self.peers_by_ip.find(dst_addr) else {
+            self.reply_unreachable(src);
+            self.drops.drop(DropReason::NoRoute);
+            return;
+        };
+
+        // Flushing locks the peers that packets could not be sent for
+        if batch.is_full() {
+            self.flush_sends(batch);
+        }
+
+        let mut peer = peer_ref.lock();
+        let _span = peer.enter_span();
+
+        if !peer.acl_allows(src, Direction::Out) {
+            self.drop_peer_packet(&peer, DropReason::AclDenied);
+            return;
+        }
+
+        // Packets from the interface fit its MTU, but not necessarily the path to the peer
+        let mtu = self.peer_mtu(&peer);
+        let pmtu = peer.pmtu();
+        let clamped;
+        let src =
+            if pmtu.clamp_mss.enabled(self.config.clamp_mss) && pmtu::needs_mss_clamp(src, mtu) {
+                let mut syn = src.to_vec();
+                pmtu::clamp_mss(&mut syn, mtu);
+                clamped = syn;
+                &clamped[..]
+            } else {
+                src
+            };
+        if src.len() > mtu
+            && pmtu.icmp_too_big.enabled(self.config.icmp_too_big)
+            && self.reply_icmp(src, IcmpError::TooBig(mtu))
+        {
+            self.drop_peer_packet(&peer, DropReason::PacketTooBig);
+            return;
+        }
+
+        self.capture_inner(&peer, src, Direction::Out);
+        match peer.tunnel.encapsulate(src, dst_buf) {
+            TunnResult::Done => {}
+            TunnResult::Err(e) => {
+                self.drop_peer_packet(&peer, DropReason::EncapsulationError);
+                tracing::error!(message = "Encapsulate error", error = ?e);
+            }
+            TunnResult::WriteToNetwork(packet) => {
+                // Packets are sent from the listen sockets rather than the connected sockets, so
+                // packets for many peers can be sent in one go
+                let socket = match peer.endpoint().addr {
+                    Some(addr @ SocketAddr::V4(_)) => Some((udp4, addr)),
+                    Some(addr @ SocketAddr::V6(_)) => Some((udp6, addr)),
+                    None => None,
+                };
+                match socket {
+                    Some((socket, addr)) => {
+                        self.capture_outer(&peer, packet, Some(addr), Direction::Out);
+                        peer.record_sent(packet, None);
+                        batch.push(socket, addr, packet, Arc::clone(peer_ref));
+                    }
+                    None => {
+                        tracing::error!("No endpoint");
+                        self.reply_unreachable(src);
+                        self.drop_peer_packet(&peer, DropReason::NoEndpoint);
+                    }
+                }
+            }
+            _ => panic!("Unexpected result from encapsulate"),
+        }
+    }
+
+    /// Answer a packet from the interface with an ICMP error, unless the error is not allowed for
+    /// the packet. Returns whether it was answered, even when the error was held back because
+    /// too many were sent to its source recently.
+    fn reply_icmp(&self, packet: &[u8], error: IcmpError) -> bool {
+        let mut reply = [0u8; icmp::MAX_ICMP_LEN];
+        let Some(len) = icmp::icmp_error(packet, error, &mut reply) else {
+            return false;
+        };
+        let Some(src) = icmp::src_address(packet) else {
+            return false;
+        };
+        if !self.icmp_limiter.allow(src) {
+            return true;
+        }
+        let written = match src {
+            IpAddr::V4(_) => self.iface.write4(&reply[..len]),
+            IpAddr::V6(_) => self.iface.write6(&reply[..len]),
+        };
+        if written == 0 {
+            self.drops.drop(DropReason::TunWriteError);
+        }
+        true
+    }
+
+    /// Answer a packet without a peer or endpoint with "destination unreachable", if enabled
+    fn reply_unreachable(&self, packet: &[u8]) {
+        if let Some(code) = self.config.icmp_unreachable {
+            self.reply_icmp(packet, IcmpError::Unreachable(code));
+        }
+    }
+
+    /// The largest packet that can be sent to a peer: the MTU of the interface, or the path MTU
+    /// of the peer when it is known and lower
+    fn peer_mtu(&self, peer: &Peer) -> usize {
+        let mtu = self.mtu.load(Ordering::Relaxed);
+        peer.inner_mtu().map_or(mtu, |inner| inner.min(mtu))
+    }
+
+    /// Check a packet received from a peer before it is written to the interface, and clamp
+    /// its MSS. Returns whether the packet may be written.
+    fn accept_received(&self, peer: &Peer, packet: &mut [u8], src: IpAddr) -> bool {
+        if !peer.is_allowed_ip(src) {
+            self.drop_peer_packet(peer, DropReason::DisallowedSourceIp);
+            return false;
+        }
+        if !peer.acl_allows(packet, Direction::In) {
+            self.drop_peer_packet(peer, DropReason::AclDenied);
+            return false;
+        }
+        if peer.pmtu().clamp_mss.enabled(self.config.clamp_mss) {
+            pmtu::clamp_mss(packet, self.peer_mtu(peer));
+        }
+        self.capture_inner(peer, packet, Direction::In);
+        true
+    }
+
+    /// Record a decrypted packet sent to or received from a peer, when capturing
+    fn capture_inner(&self, peer: &Peer, packet: &[u8], direction: Direction) {
+        if let Some(capture) = &self.capture {
+            capture.inner(peer.public_key(), packet, direction);
+        }
+    }
+
+    /// Record an encrypted datagram sent to or received from a peer, when capturing
+    fn capture_outer(
+        &self,
+        peer: &Peer,
+        datagram: &[u8],
+        endpoint: Option<SocketAddr>,
+        direction: Direction,
+    ) {
+        if let Some(capture) = &self.capture
+            && let Some(endpoint) = endpoint
+        {
+            capture.outer(peer.public_key(), datagram, endpoint, direction);
+        }
+    }
+
+    /// Start capturing to the sink, replacing the running capture if any
+    fn start_capture(&mut self, sink: CaptureSink, options: CaptureOptions) -> io::Result<()> {
+        self.capture = None;
+        self.capture = Some(Capture::start(sink, options, self.listen_port)?);
+        Ok(())
+    }
+
+    /// In hub mode, send a packet received from a peer on to the peer its destination is routed
+    /// to, without writing it to the interface. Returns whether the packet was forwarded.
+    fn forward_to_peer(
+        &self,
+        from: &Arc<Mutex<Peer>>,
+        packet: &[u8],
+        buf: &mut Vec<u8>,
+        batch: &mut SendBatch<Arc<Mutex<Peer>>>,
+    ) -> bool {
+        if !self.config.hub_mode {
+            return false;
+        }
+        let Some(dst_addr) = Tunn::dst_address(packet) else {
+            return false;
+        };
+        // Packets for the device itself, and for every host, go to the interface
+        if dst_addr.is_multicast()
+            || dst_addr == IpAddr::V4(Ipv4Addr::BROADCAST)
+            || self.network.addresses.iter().any(|ip| ip.addr == dst_addr)
+        {
+            return false;
+        }
+        let (Some(udp4), Some(udp6)) = (self.udp4.as_ref(), self.udp6.as_ref()) else {
+            return false;
+        };
+        match self.peers_by_ip.find(dst_addr) {
+            Some(to) if !Arc::ptr_eq(to, from) => {}
+            _ => return false,
+        }
+
+        if buf.is_empty() {
+            buf.resize(MAX_UDP_SIZE, 0);
+        }
+        from.lock().count_forwarded();
+        self.send_to_peer(packet, buf, batch, udp4, udp6);
+        true
+    }
+
+    /// Send the packets queued by [`Device::send_to_peer`]
+    fn flush_sends(&self, batch: &mut SendBatch<Arc<Mutex<Peer>>>) {
+        batch.flush(|peer, lost| {
+            let peer = peer.lock();
+            for _ in 0..lost {
+                self.drop_peer_packet(&peer, DropReason::SendError);
+            }
+        });
+    }
 }
 
 /// A basic linear-feedback shift register implemented as xorshift, used to
@@ -901,7 +1726,7 @@ impl Device {
 /// ensure it requires a non-trivial amount of processing power and/or samples
 /// to guess other peers' indices. Anything more ambitious than this is wasted
 /// with only 24 bits of space.
-struct IndexLfsr {
+pub(crate) struct IndexLfsr {
     initial: u32,
     lfsr: u32,
     mask: u32,
@@ -921,7 +1746,7 @@ impl IndexLfsr {
     }
 
     /// Generate the next value in the pseudorandom sequence
-    fn next(&mut self) -> u32 {
+    pub(crate) fn next(&mut self) -> u32 {
         // 24-bit polynomial for randomness. This is arbitrarily chosen to
         // inject bitflips into the value.
         const LFSR_POLY: u32 = 0xd8_0000; // 24-bit polynomial
@@ -0,0 +1,578 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Configuring the tunnel interface over rtnetlink, so no `ip` commands are needed after
+//! [`super::tun::TunSocket::new`].
+//!
+//! [`Netlink`] sets addresses, the MTU and the link state, and adds routes and policy routing
+//! rules. When a configuration file is applied at startup, its [`NetworkConfig`] is applied the
+//! way `wg-quick up` does:
+//!
+//! - every `Address` is added to the interface, the `MTU` is set and the link is brought up;
+//! - a route is added for every allowed IP of every peer, most specific first, unless the
+//!   table already has a route to the same network;
+//! - with `Table = auto`, the default, routes go to the main table, except default routes,
+//!   which go to a table named after the fwmark (51820 if none is set) together with a rule
+//!   that sends all traffic without the fwmark there, and a rule that still lets more specific
+//!   routes of the main table win;
+//! - with `Table = <id>` all routes go to that table, and with `Table = off` none are added.
+//!
+//! The routes then follow the peers: when peers are added, changed or removed over the api socket,
+//! the HTTP API or a configuration reload, routes are added for new allowed IPs and the routes
+//! the device added for allowed IPs that are gone are removed. Routes that already existed are
+//! never changed. The other network settings are only applied at startup.
+//!
+//! Addresses and routes disappear with the interface. The policy routing rules are removed when
+//! the device shuts down.
+
+use std::{
+    ffi::CString,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::{
+    AF_INET, AF_INET6, AF_NETLINK, IFA_ADDRESS, IFA_LOCAL, IFF_UP, IFLA_MTU, NETLINK_ROUTE,
+    NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REPLACE, NLM_F_REQUEST, NLMSG_ERROR, RT_SCOPE_LINK,
+    RT_SCOPE_UNIVERSE, RT_TABLE_MAIN, RT_TABLE_UNSPEC, RTA_DST, RTA_OIF, RTA_TABLE, RTM_DELROUTE,
+    RTM_DELRULE, RTM_NEWADDR, RTM_NEWLINK, RTM_NEWROUTE, RTM_NEWRULE, RTN_UNICAST, RTPROT_BOOT,
+    SOCK_CLOEXEC, SOCK_RAW, c_int,
+};
+
+use super::{Device, Error, peer::AllowedIP};
+use crate::uapi::config::RouteTable;
+
+/// The table for default routes when the device has no fwmark, as used by `wg-quick`
+const DEFAULT_TABLE: u32 = 51820;
+
+// Definitions from linux/fib_rules.h that libc does not provide
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 2;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+
+const NLMSG_HDRLEN: usize = 16;
+
+/// A policy routing rule that looks up routes in a table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub ipv6: bool,
+    pub table: u32,
+    /// Only match packets that do not carry this fwmark
+    pub not_fwmark: Option<u32>,
+    /// Ignore routes of the table with a prefix length up to this one
+    pub suppress_prefix_length: Option<u32>,
+}
+
+/// A route for an allowed IP of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub network: AllowedIP,
+    pub table: u32,
+    /// Whether the device added the route, or left one that was already there alone
+    pub added: bool,
+}
+
+/// A route netlink socket
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+/// The index of the named network interface
+pub fn link_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+impl Netlink {
+    pub fn new() -> io::Result<Netlink> {
+        match unsafe { libc::socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Netlink {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                seq: 0,
+            }),
+        }
+    }
+
+    pub fn set_mtu(&mut self, index: u32, mtu: u32) -> io::Result<()> {
+        let mut msg = link_message(index, 0, 0);
+        attr(&mut msg, IFLA_MTU, &mtu.to_ne_bytes());
+        self.request(RTM_NEWLINK, 0, &msg)
+    }
+
+    /// Bring the link up or down
+    pub fn set_up(&mut self, index: u32, up: bool) -> io::Result<()> {
+        let flags = if up { IFF_UP as u32 } else { 0 };
+        self.request(RTM_NEWLINK, 0, &link_message(index, flags, IFF_UP as u32))
+    }
+
+    /// Add an address to the interface, with the prefix length of the attached network. Adding
+    /// an address again is not an error.
+    pub fn add_address(&mut self, index: u32, address: &AllowedIP) -> io::Result<()> {
+        // struct ifaddrmsg
+        let mut msg = vec![family(address.addr), address.cidr, 0, RT_SCOPE_UNIVERSE];
+        msg.extend(index.to_ne_bytes());
+        attr(&mut msg, IFA_LOCAL, &ip_bytes(address.addr));
+        attr(&mut msg, IFA_ADDRESS, &ip_bytes(address.addr));
+        self.request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE, &msg)
+    }
+
+    /// Route a network through the interface. Fails with `EEXIST` when the table already has a
+    /// route to the same network.
+    pub fn add_route(&mut self, index: u32, destination: &AllowedIP, table: u32) -> io::Result<()> {
+        let msg = route_message(index, destination, table);
+        self.request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &msg)
+    }
+
+    /// Remove a route added by [`Netlink::add_route`]
+    pub fn delete_route(
+        &mut self,
+        index: u32,
+        destination: &AllowedIP,
+        table: u32,
+    ) -> io::Result<()> {
+        let msg = route_message(index, destination, table);
+        self.request(RTM_DELROUTE, 0, &msg)
+    }
+
+    pub fn add_rule(&mut self, rule: &Rule) -> io::Result<()> {
+        self.request(RTM_NEWRULE, NLM_F_CREATE, &rule_message(rule))
+    }
+
+    pub fn delete_rule(&mut self, rule: &Rule) -> io::Result<()> {
+        self.request(RTM_DELRULE, 0, &rule_message(rule))
+    }
+
+    /// Send a request and wait for the kernel to acknowledge it
+    fn request(&mut self, kind: u16, flags: c_int, payload: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let len = NLMSG_HDRLEN + payload.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend(u32::try_from(len).unwrap().to_ne_bytes());
+        msg.extend(kind.to_ne_bytes());
+        let flags = u16::try_from(flags | NLM_F_REQUEST | NLM_F_ACK).unwrap();
+        msg.extend(flags.to_ne_bytes());
+        msg.extend(self.seq.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(payload);
+
+        let fd = self.fd.as_raw_fd();
+        if unsafe { libc::send(fd, msg.as_ptr().cast(), msg.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), 0) } {
+                -1 => return Err(io::Error::last_os_error()),
+                n => n.cast_unsigned(),
+            };
+            if let Some(result) = find_ack(&buf[..n], self.seq) {
+                return result;
+            }
+        }
+    }
+}
+
+/// Look for the acknowledgement of the request with the sequence number in a batch of messages
+fn find_ack(mut buf: &[u8], seq: u32) -> Option<io::Result<()>> {
+    let u32_at = |buf: &[u8], at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        if i32::from(kind) == NLMSG_ERROR && u32_at(buf, 8) == seq && len >= NLMSG_HDRLEN + 4 {
+            let errno = u32_at(buf, NLMSG_HDRLEN).cast_signed();
+            return Some(match errno {
+                0 => Ok(()),
+                errno => Err(io::Error::from_raw_os_error(-errno)),
+            });
+        }
+        buf = &buf[len.next_multiple_of(4).min(buf.len())..];
+    }
+    None
+}
+
+/// struct ifinfomsg
+fn link_message(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut msg = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    msg.extend(index.to_ne_bytes());
+    msg.extend(flags.to_ne_bytes());
+    msg.extend(change.to_ne_bytes());
+    msg
+}
+
+/// struct rtmsg and its attributes
+fn route_message(index: u32, destination: &AllowedIP, table: u32) -> Vec<u8> {
+    let mut msg = vec![
+        family(destination.addr),
+        destination.cidr,
+        0,
+        0,
+        short_table(table),
+        RTPROT_BOOT,
+        RT_SCOPE_LINK,
+        RTN_UNICAST,
+    ];
+    msg.extend(0u32.to_ne_bytes());
+    attr(
+        &mut msg,
+        RTA_DST,
+        &ip_bytes(network_address(destination.addr, destination.cidr)),
+    );
+    attr(&mut msg, RTA_TABLE, &table.to_ne_bytes());
+    attr(&mut msg, RTA_OIF, &index.to_ne_bytes());
+    msg
+}
+
+/// struct fib_rule_hdr and its attributes
+fn rule_message(rule: &Rule) -> Vec<u8> {
+    let family = (if rule.ipv6 { AF_INET6 } else { AF_INET }) as u8;
+    let table = short_table(rule.table);
+    let mut msg = vec![family, 0, 0, 0, table, 0, 0, FR_ACT_TO_TBL];
+    let flags = if rule.not_fwmark.is_some() {
+        FIB_RULE_INVERT
+    } else {
+        0
+    };
+    msg.extend(flags.to_ne_bytes());
+    attr(&mut msg, FRA_TABLE, &rule.table.to_ne_bytes());
+    if let Some(fwmark) = rule.not_fwmark {
+        attr(&mut msg, FRA_FWMARK, &fwmark.to_ne_bytes());
+    }
+    if let Some(len) = rule.suppress_prefix_length {
+        attr(&mut msg, FRA_SUPPRESS_PREFIXLEN, &len.to_ne_bytes());
+    }
+    msg
+}
+
+/// Append a route attribute, padded to a multiple of four bytes
+fn attr(msg: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = u16::try_from(4 + data.len()).unwrap();
+    msg.extend(len.to_ne_bytes());
+    msg.extend(kind.to_ne_bytes());
+    msg.extend(data);
+    msg.resize(msg.len().next_multiple_of(4), 0);
+}
+
+/// Tables above 255 only fit in the table attribute
+fn short_table(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(RT_TABLE_UNSPEC)
+}
+
+fn family(addr: IpAddr) -> u8 {
+    (if addr.is_ipv4() { AF_INET } else { AF_INET6 }) as u8
+}
+
+fn ip_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// The address with the host bits cleared, which the kernel requires for route destinations
+fn network_address(addr: IpAddr, cidr: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::from(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(cidr)).unwrap_or(0);
+            IpAddr::from(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+impl Device {
+    /// Apply the network settings of the configuration file to the interface, as `wg-quick up`
+    /// does, with routes for the allowed IPs of the peers
+    pub(super) fn setup_network(&mut self) -> Result<(), Error> {
+        let index = link_index(&self.iface.name()?).map_err(Error::Netlink)?;
+        let mut netlink = Netlink::new().map_err(Error::Netlink)?;
+
+        for address in &self.network.addresses {
+            netlink
+                .add_address(index, address)
+                .map_err(Error::Netlink)?;
+        }
+        if let Some(mtu) = self.network.mtu {
+            netlink.set_mtu(index, mtu).map_err(Error::Netlink)?;
+            self.mtu
+                .store(mtu as usize, std::sync::atomic::Ordering::Relaxed);
+        }
+        netlink.set_up(index, true).map_err(Error::Netlink)?;
+
+        self.routes = Some(Vec::new());
+        self.sync_routes()
+    }
+
+    /// Add routes for the allowed IPs of the peers that have none, and remove the routes added
+    /// for allowed IPs that no peer has anymore. Does nothing unless the network settings of a
+    /// configuration file were applied.
+    pub(super) fn sync_routes(&mut self) -> Result<(), Error> {
+        let Some(mut routes) = self.routes.take() else {
+            return Ok(());
+        };
+        let result = self.update_routes(&mut routes);
+        self.routes = Some(routes);
+        result
+    }
+
+    fn update_routes(&mut self, routes: &mut Vec<Route>) -> Result<(), Error> {
+        let route_table = self.network.table.unwrap_or(RouteTable::Auto);
+        if route_table == RouteTable::Off {
+            return Ok(());
+        }
+
+        // Like wg-quick, add the most specific routes first
+        let mut wanted: Vec<AllowedIP> = self
+            .peers
+            .values()
+            .flat_map(|peer| peer.lock().allowed_ips().collect::<Vec<_>>())
+            .map(|(addr, cidr)| AllowedIP {
+                addr: network_address(addr, cidr),
+                cidr,
+            })
+            .collect();
+        wanted.sort_unstable_by_key(|ip| (std::cmp::Reverse(ip.cidr), ip.addr));
+        wanted.dedup();
+
+        let stale = routes.iter().any(|r| !wanted.contains(&r.network));
+        let missing = wanted
+            .iter()
+            .any(|ip| !routes.iter().any(|r| r.network == *ip));
+        if !stale && !missing {
+            return Ok(());
+        }
+
+        let index = link_index(&self.iface.name()?).map_err(Error::Netlink)?;
+        let mut netlink = Netlink::new().map_err(Error::Netlink)?;
+
+        let mut result = Ok(());
+        routes.retain(|route| {
+            if wanted.contains(&route.network) {
+                return true;
+            }
+            if !route.added {
+                return false;
+            }
+            match netlink.delete_route(index, &route.network, route.table) {
+                Ok(()) => false,
+                // Already removed by someone else
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => false,
+                Err(e) => {
+                    result = Err(Error::Netlink(e));
+                    true
+                }
+            }
+        });
+        result?;
+
+        for network in wanted {
+            if routes.iter().any(|r| r.network == network) {
+                continue;
+            }
+            let table = match route_table {
+                RouteTable::Id(table) => table,
+                _ if network.cidr == 0 => {
+                    self.add_default_route_rules(&mut netlink, network.addr.is_ipv6())?
+                }
+                _ => u32::from(RT_TABLE_MAIN),
+            };
+            let added = match netlink.add_route(index, &network, table) {
+                Ok(()) => true,
+                // Like wg-quick, leave networks that are already routed alone
+                Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                    tracing::warn!(
+                        message = "Not adding a route, the network already has one",
+                        network = %format_args!("{}/{}", network.addr, network.cidr),
+                        table
+                    );
+                    false
+                }
+                Err(e) => return Err(Error::Netlink(e)),
+            };
+            routes.push(Route {
+                network,
+                table,
+                added,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Send traffic that does not come from the device itself to a separate table, so a
+    /// default route through the tunnel does not capture the encapsulated packets. Returns
+    /// the table.
+    fn add_default_route_rules(&mut self, netlink: &mut Netlink, ipv6: bool) -> Result<u32, Error> {
+        let table = match self.fwmark {
+            Some(fwmark) if fwmark != 0 => fwmark,
+            _ => {
+                self.set_fwmark(DEFAULT_TABLE)?;
+                DEFAULT_TABLE
+            }
+        };
+
+        let rules = [
+            Rule {
+                ipv6,
+                table,
+                not_fwmark: Some(table),
+                suppress_prefix_length: None,
+            },
+            Rule {
+                ipv6,
+                table: u32::from(RT_TABLE_MAIN),
+                not_fwmark: None,
+                suppress_prefix_length: Some(0),
+            },
+        ];
+        for rule in rules {
+            if self.routing_rules.contains(&rule) {
+                continue;
+            }
+            // Rules can be added more than once, so remove any left behind by a crash first
+            while netlink.delete_rule(&rule).is_ok() {}
+            netlink.add_rule(&rule).map_err(Error::Netlink)?;
+            self.routing_rules.push(rule);
+        }
+
+        // Reverse path filtering must take the fwmark into account, or replies are dropped
+        if !ipv6 && let Err(e) = fs::write("/proc/sys/net/ipv4/conf/all/src_valid_mark", "1") {
+            tracing::warn!(message = "Failed to enable src_valid_mark", error = %e);
+        }
+
+        Ok(table)
+    }
+
+    /// Remove the policy routing rules added by [`Device::setup_network`]
+    pub(super) fn remove_routing_rules(&self) {
+        let Ok(mut netlink) = Netlink::new() else {
+            return;
+        };
+        for rule in &self.routing_rules {
+            let _ = netlink.delete_rule(rule);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::device::{DeviceConfig, DeviceHandle, api::api_request};
+
+    #[test]
+    fn encodes_messages() {
+        let mut msg = Vec::new();
+        attr(&mut msg, 1, &[1, 2, 3]);
+        assert_eq!(msg.len(), 8);
+        assert_eq!(&msg[..2], &7u16.to_ne_bytes());
+
+        let rule = rule_message(&Rule {
+            ipv6: false,
+            table: DEFAULT_TABLE,
+            not_fwmark: Some(DEFAULT_TABLE),
+            suppress_prefix_length: None,
+        });
+        // The table does not fit in the header, so it is only in the attribute
+        assert_eq!(rule[4], RT_TABLE_UNSPEC);
+        assert_eq!(&rule[8..12], &FIB_RULE_INVERT.to_ne_bytes());
+        assert_eq!(rule.len(), 12 + 8 + 8);
+
+        assert_eq!(
+            network_address("10.1.2.3".parse().unwrap(), 16),
+            "10.1.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            network_address("fd00::1".parse().unwrap(), 0),
+            "::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn finds_ack() {
+        let ack = |seq: u32, errno: i32| {
+            let mut msg = 36u32.to_ne_bytes().to_vec();
+            msg.extend(u16::try_from(NLMSG_ERROR).unwrap().to_ne_bytes());
+            msg.extend(0u16.to_ne_bytes());
+            msg.extend(seq.to_ne_bytes());
+            msg.extend(0u32.to_ne_bytes());
+            msg.extend(errno.to_ne_bytes());
+            msg.extend([0u8; 16]);
+            msg
+        };
+
+        assert!(find_ack(&ack(1, 0), 2).is_none());
+        assert!(matches!(find_ack(&ack(2, 0), 2), Some(Ok(()))));
+        let mut batch = ack(1, 0);
+        batch.extend(ack(3, -libc::EEXIST));
+        let e = find_ack(&batch, 3).unwrap().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+    }
+
+    fn ip(args: &[&str]) -> String {
+        let output = Command::new("ip").args(args).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn routes_follow_the_peers() {
+        // A network that is already routed elsewhere
+        ip(&["route", "del", "10.99.1.0/24", "dev", "lo"]);
+        ip(&["route", "add", "10.99.1.0/24", "dev", "lo"]);
+
+        let path =
+            std::env::temp_dir().join(format!("boringtun-routes-{}.conf", std::process::id()));
+        fs::write(
+            &path,
+            "[Interface]\n\
+             PrivateKey = YFjESZYbWRxQVnHsOVYM3zM6rr7f6yyKA7cUp+ElWHk=\n\
+             Address = 10.99.0.1/24\n\
+             \n\
+             [Peer]\n\
+             PublicKey = GNnzFVwRLzi/WFcJ4/hwZjJCd0Q6tqYdNQbTyzZKPUo=\n\
+             AllowedIPs = 10.99.1.0/24, 10.99.2.0/24\n",
+        )
+        .unwrap();
+        let config = DeviceConfig {
+            n_threads: 1,
+            config_file: Some(path.clone()),
+            ..DeviceConfig::default()
+        };
+        let device = DeviceHandle::new("utun96", config).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(ip(&["route", "show", "10.99.1.0/24"]).contains("dev lo"));
+        assert!(ip(&["route", "show", "10.99.2.0/24"]).contains("dev utun96"));
+
+        // Replace the peer with one for another network
+        let set = "set=1\n\
+                   public_key=18d9f3155c112f38bf585709e3f87066324277443ab6a61d3506d3cb364a3d4a\n\
+                   remove=true\n\
+                   public_key=0202020202020202020202020202020202020202020202020202020202020202\n\
+                   allowed_ip=10.99.3.0/24\n\n";
+        api_request(set.as_bytes(), &mut device.device.read());
+
+        assert!(ip(&["route", "show", "10.99.1.0/24"]).contains("dev lo"));
+        assert!(ip(&["route", "show", "10.99.2.0/24"]).is_empty());
+        assert!(ip(&["route", "show", "10.99.3.0/24"]).contains("dev utun96"));
+
+        drop(device);
+        ip(&["route", "del", "10.99.1.0/24", "dev", "lo"]);
+    }
+}
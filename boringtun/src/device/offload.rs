@@ -0,0 +1,551 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Segmentation and receive offloads for a tunnel interface opened with `IFF_VNET_HDR` on
+//! Linux, see [`super::DeviceConfig::use_tun_offload`].
+//!
+//! Every packet read from or written to such an interface starts with a `virtio_net_hdr`. With
+//! TSO enabled, the kernel hands over TCP super-packets of up to 64KB, which [`segment`] splits
+//! into packets that fit the MTU before they are encapsulated. In the other direction, [`Gro`]
+//! coalesces consecutive segments of a TCP flow that arrive in the same batch into a single
+//! super-packet, so the kernel stack handles one large packet instead of many small ones.
+
+/// The size of `struct virtio_net_hdr`
+pub(crate) const VNET_HDR_LEN: usize = 10;
+/// The largest packet read from the interface, including its header
+pub(crate) const MAX_OFFLOAD_SIZE: usize = VNET_HDR_LEN + MAX_IP_SIZE;
+
+const MAX_IP_SIZE: usize = 65535;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPPROTO_TCP: u8 = 6;
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+/// The offset of the checksum in the TCP header
+const TCP_CSUM_OFFSET: usize = 16;
+
+/// `struct virtio_net_hdr`, in native byte order as used by TUN devices
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    fn decode(buf: &[u8]) -> Option<VirtioNetHdr> {
+        let buf = buf.get(..VNET_HDR_LEN)?;
+        let u16_at = |at: usize| u16::from_ne_bytes([buf[at], buf[at + 1]]);
+        Some(VirtioNetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+    }
+}
+
+/// Add up the 16 bit big endian words of the data, for the internet checksum
+pub(crate) fn sum_words(data: &[u8], mut sum: u64) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u64::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+    sum
+}
+
+pub(crate) fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The sum of the TCP pseudo header of an IPv4 or IPv6 packet
+pub(crate) fn pseudo_header_sum(packet: &[u8], ipv4: bool, tcp_len: usize) -> u64 {
+    let addresses = if ipv4 {
+        &packet[12..20]
+    } else {
+        &packet[8..40]
+    };
+    sum_words(addresses, u64::from(IPPROTO_TCP) + tcp_len as u64)
+}
+
+pub(crate) fn set_u16(buf: &mut [u8], at: usize, val: u16) {
+    buf[at..at + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+fn set_ipv4_checksum(packet: &mut [u8], ip_len: usize) {
+    set_u16(packet, 10, 0);
+    let sum = sum_words(&packet[..ip_len], 0);
+    set_u16(packet, 10, !fold(sum));
+}
+
+/// Split a packet read from the interface, starting with its header, into packets that fit the
+/// MTU and call `f` with each of them. Returns `None` for a malformed packet.
+pub(crate) fn segment(packet: &[u8], scratch: &mut [u8], mut f: impl FnMut(&[u8])) -> Option<()> {
+    let hdr = VirtioNetHdr::decode(packet)?;
+    let packet = &packet[VNET_HDR_LEN..];
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 => f(packet),
+        VIRTIO_NET_HDR_GSO_NONE => {
+            // The checksum field holds the sum of the pseudo header, complete it
+            let out = scratch.get_mut(..packet.len())?;
+            out.copy_from_slice(packet);
+            let start = usize::from(hdr.csum_start);
+            let at = start + usize::from(hdr.csum_offset);
+            if at + 2 > out.len() {
+                return None;
+            }
+            let sum = sum_words(&out[start..], 0);
+            set_u16(out, at, !fold(sum));
+            f(out);
+        }
+        gso_type @ (VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6) => {
+            segment_tcp(
+                packet,
+                &hdr,
+                gso_type == VIRTIO_NET_HDR_GSO_TCPV4,
+                scratch,
+                f,
+            )?;
+        }
+        _ => return None,
+    }
+
+    Some(())
+}
+
+fn segment_tcp(
+    packet: &[u8],
+    hdr: &VirtioNetHdr,
+    ipv4: bool,
+    scratch: &mut [u8],
+    mut f: impl FnMut(&[u8]),
+) -> Option<()> {
+    let version = packet.first()? >> 4;
+    let ip_len = usize::from(hdr.csum_start);
+    if version != if ipv4 { 4 } else { 6 } || ip_len < if ipv4 { 20 } else { 40 } {
+        return None;
+    }
+    // The header length in the virtio header is only a hint, so use the one in the packet
+    let tcp_len = usize::from(packet.get(ip_len + 12)? >> 4) * 4;
+    let hdr_len = ip_len + tcp_len;
+    let gso_size = usize::from(hdr.gso_size);
+    if tcp_len < 20 || packet.len() < hdr_len || gso_size == 0 {
+        return None;
+    }
+
+    let payload = &packet[hdr_len..];
+    let seq = u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into().unwrap());
+    let ip_id = u16::from_be_bytes([packet[4], packet[5]]);
+    let segments = payload.len().div_ceil(gso_size);
+
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let total = hdr_len + chunk.len();
+        let out = scratch.get_mut(..total)?;
+        out[..hdr_len].copy_from_slice(&packet[..hdr_len]);
+        out[hdr_len..].copy_from_slice(chunk);
+
+        if ipv4 {
+            set_u16(out, 2, total as u16);
+            set_u16(out, 4, ip_id.wrapping_add(i as u16));
+            set_ipv4_checksum(out, ip_len);
+        } else {
+            set_u16(out, 4, (total - 40) as u16);
+        }
+
+        let tcp = &mut out[ip_len..];
+        let offset = (i * gso_size) as u32;
+        tcp[4..8].copy_from_slice(&seq.wrapping_add(offset).to_be_bytes());
+        // FIN and PSH belong to the last segment, CWR to the first
+        if i + 1 < segments {
+            tcp[13] &= !(TCP_FIN | TCP_PSH);
+        }
+        if i > 0 {
+            tcp[13] &= !TCP_CWR;
+        }
+        set_u16(tcp, TCP_CSUM_OFFSET, 0);
+        let pseudo = pseudo_header_sum(out, ipv4, total - ip_len);
+        let sum = sum_words(&out[ip_len..], pseudo);
+        set_u16(&mut out[ip_len..], TCP_CSUM_OFFSET, !fold(sum));
+
+        f(out);
+    }
+
+    Some(())
+}
+
+/// The headers of a TCP segment that could be coalesced with others
+struct TcpSegment {
+    ipv4: bool,
+    ip_len: usize,
+    tcp_len: usize,
+    seq: u32,
+    payload_len: usize,
+    flags: u8,
+}
+
+impl TcpSegment {
+    /// Only plain data segments without IP options or extension headers are coalesced
+    fn parse(packet: &[u8]) -> Option<TcpSegment> {
+        let (ipv4, ip_len, ip_total) = match packet.first()? >> 4 {
+            4 => {
+                let fragmented = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3fff;
+                if packet[0] != 0x45 || *packet.get(9)? != IPPROTO_TCP || fragmented != 0 {
+                    return None;
+                }
+                (
+                    true,
+                    20,
+                    usize::from(u16::from_be_bytes([packet[2], packet[3]])),
+                )
+            }
+            6 => {
+                if *packet.get(6)? != IPPROTO_TCP || packet.len() < 40 {
+                    return None;
+                }
+                let payload_len = u16::from_be_bytes([packet[4], packet[5]]);
+                (false, 40, 40 + usize::from(payload_len))
+            }
+            _ => return None,
+        };
+
+        let tcp_len = usize::from(packet.get(ip_len + 12)? >> 4) * 4;
+        let flags = *packet.get(ip_len + 13)?;
+        let hdr_len = ip_len + tcp_len;
+        if ip_total != packet.len()
+            || tcp_len < 20
+            || hdr_len >= packet.len()
+            || flags & !(TCP_ACK | TCP_PSH) != 0
+            || flags & TCP_ACK == 0
+        {
+            return None;
+        }
+
+        Some(TcpSegment {
+            ipv4,
+            ip_len,
+            tcp_len,
+            seq: u32::from_be_bytes(packet[ip_len + 4..ip_len + 8].try_into().unwrap()),
+            payload_len: packet.len() - hdr_len,
+            flags,
+        })
+    }
+}
+
+/// The state of a super-packet that segments are being appended to
+struct Flow {
+    ipv4: bool,
+    ip_len: usize,
+    tcp_len: usize,
+    gso_size: usize,
+    segments: usize,
+    next_seq: u32,
+    /// No more segments can be appended after a short segment or one with PSH set
+    closed: bool,
+}
+
+struct Item {
+    /// Room for the virtio header, followed by the packet
+    buf: Vec<u8>,
+    /// Set for TCP packets that segments can be appended to
+    flow: Option<Flow>,
+}
+
+impl Item {
+    fn packet(&self) -> &[u8] {
+        &self.buf[VNET_HDR_LEN..]
+    }
+
+    fn can_append(&self, packet: &[u8], segment: &TcpSegment) -> bool {
+        let Some(ref flow) = self.flow else {
+            return false;
+        };
+        if flow.closed
+            || flow.ipv4 != segment.ipv4
+            || flow.ip_len != segment.ip_len
+            || flow.tcp_len != segment.tcp_len
+            || flow.next_seq != segment.seq
+            || segment.payload_len > flow.gso_size
+            || self.buf.len() - VNET_HDR_LEN + segment.payload_len > MAX_IP_SIZE
+        {
+            return false;
+        }
+
+        let current = self.packet();
+        // The addresses, TOS and TTL, or traffic class, flow label and hop limit must match
+        let same_ip = if flow.ipv4 {
+            current[1] == packet[1] && current[8] == packet[8] && current[12..20] == packet[12..20]
+        } else {
+            current[..4] == packet[..4] && current[7..40] == packet[7..40]
+        };
+        // So must the ports, acknowledgement, flags other than PSH, window and options
+        let (a, b) = (&current[flow.ip_len..], &packet[flow.ip_len..]);
+        let same_tcp = a[..4] == b[..4]
+            && a[8..13] == b[8..13]
+            && a[13] & !TCP_PSH == b[13] & !TCP_PSH
+            && a[14..16] == b[14..16]
+            && a[20..flow.tcp_len] == b[20..flow.tcp_len];
+
+        same_ip && same_tcp
+    }
+
+    fn append(&mut self, packet: &[u8], segment: &TcpSegment) {
+        let flow = self.flow.as_mut().unwrap();
+        self.buf
+            .extend_from_slice(&packet[flow.ip_len + flow.tcp_len..]);
+        flow.segments += 1;
+        flow.next_seq = flow.next_seq.wrapping_add(segment.payload_len as u32);
+        if segment.payload_len < flow.gso_size || segment.flags & TCP_PSH != 0 {
+            flow.closed = true;
+        }
+        if segment.flags & TCP_PSH != 0 {
+            self.buf[VNET_HDR_LEN + flow.ip_len + 13] |= TCP_PSH;
+        }
+    }
+
+    /// Set the headers of a coalesced packet, and the virtio header that describes it
+    fn finish(&mut self) {
+        let hdr = match self.flow {
+            Some(ref flow) if flow.segments > 1 => {
+                let packet = &mut self.buf[VNET_HDR_LEN..];
+                let total = packet.len();
+                if flow.ipv4 {
+                    set_u16(packet, 2, total as u16);
+                    set_ipv4_checksum(packet, flow.ip_len);
+                } else {
+                    set_u16(packet, 4, (total - 40) as u16);
+                }
+                // The kernel completes the checksum, starting from the pseudo header sum
+                let sum = pseudo_header_sum(packet, flow.ipv4, total - flow.ip_len);
+                set_u16(packet, flow.ip_len + TCP_CSUM_OFFSET, fold(sum));
+
+                VirtioNetHdr {
+                    flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                    gso_type: if flow.ipv4 {
+                        VIRTIO_NET_HDR_GSO_TCPV4
+                    } else {
+                        VIRTIO_NET_HDR_GSO_TCPV6
+                    },
+                    hdr_len: (flow.ip_len + flow.tcp_len) as u16,
+                    gso_size: flow.gso_size as u16,
+                    csum_start: flow.ip_len as u16,
+                    csum_offset: TCP_CSUM_OFFSET as u16,
+                }
+            }
+            _ => VirtioNetHdr::default(),
+        };
+        hdr.encode(&mut self.buf);
+    }
+
+    fn segments(&self) -> usize {
+        self.flow.as_ref().map_or(1, |flow| flow.segments)
+    }
+}
+
+/// Packets waiting to be written to the interface, with TCP segments of the same flow coalesced
+#[derive(Default)]
+pub(crate) struct Gro {
+    /// Items are reused between batches to keep their buffers
+    items: Vec<Item>,
+    len: usize,
+}
+
+impl Gro {
+    /// Queue a decapsulated packet
+    pub(crate) fn push(&mut self, packet: &[u8]) {
+        let segment = TcpSegment::parse(packet);
+        if let Some(ref segment) = segment
+            && let Some(item) = self.items[..self.len]
+                .iter_mut()
+                .rev()
+                .find(|item| item.can_append(packet, segment))
+        {
+            item.append(packet, segment);
+            return;
+        }
+
+        if self.len == self.items.len() {
+            self.items.push(Item {
+                buf: Vec::new(),
+                flow: None,
+            });
+        }
+        let item = &mut self.items[self.len];
+        self.len += 1;
+        item.buf.clear();
+        item.buf.resize(VNET_HDR_LEN, 0);
+        item.buf.extend_from_slice(packet);
+        item.flow = segment.map(|segment| Flow {
+            ipv4: segment.ipv4,
+            ip_len: segment.ip_len,
+            tcp_len: segment.tcp_len,
+            gso_size: segment.payload_len,
+            segments: 1,
+            next_seq: segment.seq.wrapping_add(segment.payload_len as u32),
+            closed: segment.flags & TCP_PSH != 0,
+        });
+    }
+
+    /// Write all queued packets, each starting with its virtio header, and return the number of
+    /// original packets that could not be written
+    pub(crate) fn flush(&mut self, mut write: impl FnMut(&[u8]) -> bool) -> usize {
+        let mut dropped = 0;
+        for item in &mut self.items[..self.len] {
+            item.finish();
+            if !write(&item.buf) {
+                dropped += item.segments();
+            }
+        }
+        self.len = 0;
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TCP/IPv4 packet with a valid checksum
+    fn tcp4(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let total = 40 + payload.len();
+        let mut packet = vec![0u8; total];
+        packet[0] = 0x45;
+        set_u16(&mut packet, 2, total as u16);
+        set_u16(&mut packet, 4, 7);
+        packet[8] = 64;
+        packet[9] = IPPROTO_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        set_ipv4_checksum(&mut packet, 20);
+        set_u16(&mut packet, 20, 1234);
+        set_u16(&mut packet, 22, 80);
+        packet[24..28].copy_from_slice(&seq.to_be_bytes());
+        packet[28..32].copy_from_slice(&99u32.to_be_bytes());
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        set_u16(&mut packet, 34, 512);
+        packet[40..].copy_from_slice(payload);
+        let sum = sum_words(&packet[20..], pseudo_header_sum(&packet, true, total - 20));
+        set_u16(&mut packet, 36, !fold(sum));
+        packet
+    }
+
+    fn valid_checksums(packet: &[u8]) -> bool {
+        fold(sum_words(&packet[..20], 0)) == 0xffff
+            && fold(sum_words(
+                &packet[20..],
+                pseudo_header_sum(packet, true, packet.len() - 20),
+            )) == 0xffff
+    }
+
+    #[test]
+    fn segments_tcp() {
+        let payload: Vec<u8> = (0..250u8).collect();
+        let mut packet = vec![0u8; VNET_HDR_LEN];
+        VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: 40,
+            gso_size: 100,
+            csum_start: 20,
+            csum_offset: 16,
+        }
+        .encode(&mut packet);
+        packet.extend(tcp4(1000, TCP_ACK | TCP_PSH, &payload));
+
+        let mut segments = Vec::new();
+        let mut scratch = vec![0u8; MAX_OFFLOAD_SIZE];
+        segment(&packet, &mut scratch, |s| segments.push(s.to_vec())).unwrap();
+
+        assert_eq!(segments.len(), 3);
+        for (i, s) in segments.iter().enumerate() {
+            assert!(valid_checksums(s));
+            let len = if i == 2 { 50 } else { 100 };
+            assert_eq!(s.len(), 40 + len);
+            assert_eq!(&s[40..], &payload[i * 100..i * 100 + len]);
+            assert_eq!(u16::from_be_bytes([s[4], s[5]]), 7 + i as u16);
+            let seq = u32::from_be_bytes(s[24..28].try_into().unwrap());
+            assert_eq!(seq, 1000 + 100 * i as u32);
+            // Only the last segment keeps PSH
+            assert_eq!(s[33] & TCP_PSH != 0, i == 2);
+        }
+
+        let mut plain = vec![0u8; VNET_HDR_LEN];
+        plain.extend(tcp4(1, TCP_ACK, b"x"));
+        let mut count = 0;
+        segment(&plain, &mut scratch, |_| count += 1).unwrap();
+        assert_eq!(count, 1);
+        assert!(segment(&plain[..5], &mut scratch, |_| {}).is_none());
+    }
+
+    #[test]
+    fn coalesces_tcp() {
+        let mut gro = Gro::default();
+        gro.push(&tcp4(1000, TCP_ACK, &[1; 100]));
+        gro.push(&tcp4(1100, TCP_ACK, &[2; 100]));
+        // Not a data segment, written on its own
+        gro.push(&tcp4(5000, TCP_ACK | 0x02, &[]));
+        gro.push(&tcp4(1200, TCP_ACK | TCP_PSH, &[3; 50]));
+        // After PSH the flow is closed
+        gro.push(&tcp4(1250, TCP_ACK, &[4; 50]));
+
+        let mut written = Vec::new();
+        let dropped = gro.flush(|buf| {
+            written.push(buf.to_vec());
+            true
+        });
+        assert_eq!(dropped, 0);
+        assert_eq!(written.len(), 3);
+
+        let hdr = VirtioNetHdr::decode(&written[0]).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 100);
+        assert_eq!(hdr.hdr_len, 40);
+        let packet = &written[0][VNET_HDR_LEN..];
+        assert_eq!(packet.len(), 40 + 250);
+        assert_eq!(packet[33] & TCP_PSH, TCP_PSH);
+        assert_eq!(fold(sum_words(&packet[..20], 0)), 0xffff);
+
+        // Segmenting the coalesced packet again restores the original segments
+        let mut scratch = vec![0u8; MAX_OFFLOAD_SIZE];
+        let mut segments = Vec::new();
+        segment(&written[0], &mut scratch, |s| segments.push(s.to_vec())).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert!(segments.iter().all(|s| valid_checksums(s)));
+
+        for buf in &written[1..] {
+            assert_eq!(VirtioNetHdr::decode(buf), Some(VirtioNetHdr::default()));
+        }
+
+        gro.push(&tcp4(1, TCP_ACK, &[0; 10]));
+        gro.push(&tcp4(11, TCP_ACK, &[0; 10]));
+        assert_eq!(gro.flush(|_| false), 2);
+    }
+}
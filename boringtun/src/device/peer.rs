@@ -4,14 +4,24 @@
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6},
     str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+use base64::prelude::*;
 use parking_lot::RwLock;
 use socket2::{Domain, Protocol, Type};
+use tracing::{Span, span::EnteredSpan};
 
 use crate::{
-    device::{AllowedIps, Error},
-    noise::{Tunn, TunnResult},
+    device::{
+        AllowedIps, Error,
+        acl::{Acl, AclFilter, AclHits, AclUpdate, Direction},
+        pmtu::{self, PeerPmtu, PmtuUpdate},
+        recorder::{FlightEvent, FlightRecord, FlightRecorder},
+        stats::{DropCounts, DropReason, DropStats},
+    },
+    noise::{TimerReason, Tunn, TunnResult, errors::WireGuardError},
+    x25519,
 };
 
 #[derive(Default, Debug)]
@@ -23,11 +33,22 @@ pub struct Endpoint {
 pub struct Peer {
     /// The associated tunnel struct
     pub(crate) tunnel: Tunn,
+    public_key: x25519::PublicKey,
     /// The index the tunnel uses
     index: u32,
     endpoint: RwLock<Endpoint>,
     allowed_ips: AllowedIps<()>,
     preshared_key: Option<[u8; 32]>,
+    drops: DropCounts,
+    /// Packets from this peer forwarded to other peers in hub mode
+    forwarded: AtomicU64,
+    pmtu: PeerPmtu,
+    /// The path MTU to the endpoint, as last learned from the kernel
+    learned_mtu: Option<u16>,
+    acl: AclFilter,
+    recorder: FlightRecorder,
+    /// The span of everything logged about this peer, replaced when the endpoint changes
+    span: Span,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -57,6 +78,7 @@ impl FromStr for AllowedIP {
 impl Peer {
     pub fn new(
         tunnel: Tunn,
+        public_key: x25519::PublicKey,
         index: u32,
         endpoint: Option<SocketAddr>,
         allowed_ips: &[AllowedIP],
@@ -64,6 +86,7 @@ impl Peer {
     ) -> Peer {
         Peer {
             tunnel,
+            public_key,
             index,
             endpoint: RwLock::new(Endpoint {
                 addr: endpoint,
@@ -71,21 +94,34 @@ impl Peer {
             }),
             allowed_ips: allowed_ips.iter().map(|ip| (ip, ())).collect(),
             preshared_key,
+            drops: DropCounts::default(),
+            forwarded: AtomicU64::new(0),
+            pmtu: PeerPmtu::default(),
+            learned_mtu: None,
+            acl: AclFilter::default(),
+            recorder: FlightRecorder::default(),
+            span: peer_span(&public_key, endpoint),
         }
     }
 
     pub fn update_timers<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
-        self.tunnel.update_timers(dst)
+        let result = self.tunnel.update_timers(dst);
+        let reason = self.tunnel.timer_reason();
+        match (&result, reason) {
+            (TunnResult::WriteToNetwork(packet), _) => self.record_sent(packet, reason),
+            // Once expired, every call fails without a reason until a new handshake starts
+            (TunnResult::Err(WireGuardError::ConnectionExpired), Some(reason)) => {
+                self.record(FlightEvent::SessionExpired(reason));
+            }
+            _ => {}
+        }
+        result
     }
 
     pub fn endpoint(&self) -> parking_lot::RwLockReadGuard<'_, Endpoint> {
         self.endpoint.read()
     }
 
-    pub(crate) fn endpoint_mut(&self) -> parking_lot::RwLockWriteGuard<'_, Endpoint> {
-        self.endpoint.write()
-    }
-
     pub fn shutdown_endpoint(&self) {
         if let Some(conn) = self.endpoint.write().conn.take() {
             tracing::info!("Disconnecting from endpoint");
@@ -93,7 +129,7 @@ impl Peer {
         }
     }
 
-    pub fn set_endpoint(&self, addr: SocketAddr) -> bool {
+    pub fn set_endpoint(&mut self, addr: SocketAddr) -> bool {
         let mut endpoint = self.endpoint.write();
         if endpoint.addr != Some(addr) {
             // We only need to update the endpoint if it differs from the current one
@@ -102,6 +138,8 @@ impl Peer {
             }
 
             endpoint.addr = Some(addr);
+            self.record(FlightEvent::EndpointChanged(addr));
+            self.span = peer_span(&self.public_key, Some(addr));
             true
         } else {
             false
@@ -175,7 +213,157 @@ impl Peer {
         self.preshared_key.as_ref()
     }
 
+    pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.preshared_key = preshared_key;
+        self.tunnel.set_preshared_key(preshared_key);
+    }
+
+    pub(crate) fn clear_allowed_ips(&mut self) {
+        self.allowed_ips.clear();
+    }
+
+    pub(crate) fn add_allowed_ip(&mut self, AllowedIP { addr, cidr }: AllowedIP) {
+        self.allowed_ips.insert(addr, cidr.into(), ());
+    }
+
+    pub fn pmtu(&self) -> PeerPmtu {
+        self.pmtu
+    }
+
+    pub(crate) fn set_pmtu(&mut self, update: &PmtuUpdate) {
+        self.pmtu.apply(update);
+    }
+
+    /// The outer MTU towards the endpoint, either configured or learned from the kernel
+    pub fn path_mtu(&self) -> Option<u16> {
+        self.pmtu.mtu.or(self.learned_mtu)
+    }
+
+    /// The largest packet that fits the path to the endpoint, if its MTU is known
+    pub(crate) fn inner_mtu(&self) -> Option<usize> {
+        let mtu = self.path_mtu()?;
+        let ipv6 = self.endpoint().addr.is_some_and(|addr| addr.is_ipv6());
+        Some(pmtu::inner_mtu(mtu, ipv6))
+    }
+
+    /// Read the path MTU the kernel keeps for the connected socket of the endpoint
+    #[cfg(target_os = "linux")]
+    pub(crate) fn update_learned_mtu(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        let endpoint = self.endpoint.read();
+        let Some(ref conn) = endpoint.conn else {
+            self.learned_mtu = None;
+            return;
+        };
+        let (level, name) = match endpoint.addr {
+            Some(SocketAddr::V6(_)) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+            _ => (libc::IPPROTO_IP, libc::IP_MTU),
+        };
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let learned = match unsafe {
+            libc::getsockopt(
+                conn.as_raw_fd(),
+                level,
+                name,
+                (&raw mut mtu).cast(),
+                &raw mut len,
+            )
+        } {
+            0 => u16::try_from(mtu).ok(),
+            _ => None,
+        };
+        self.learned_mtu = learned;
+    }
+
+    /// The firewall rules for the traffic to and from this peer
+    pub fn acl(&self) -> &Acl {
+        self.acl.acl()
+    }
+
+    /// The packets matched by every rule, and by the policy
+    pub fn acl_hits(&self) -> AclHits {
+        self.acl.hits()
+    }
+
+    pub(crate) fn set_acl(&mut self, update: &AclUpdate) {
+        self.acl.apply(update);
+    }
+
+    /// Do the rules of the peer let a packet through in the direction
+    pub(crate) fn acl_allows(&self, packet: &[u8], direction: Direction) -> bool {
+        self.acl.allows(packet, direction)
+    }
+
+    /// The packets to or from this peer that were dropped, by reason
+    pub fn drop_stats(&self) -> DropStats {
+        self.drops.stats()
+    }
+
+    pub(crate) fn count_drop(&self, reason: DropReason) {
+        self.drops.add(reason);
+    }
+
+    /// The packets from this peer that were forwarded to other peers in hub mode
+    pub fn forwarded_packets(&self) -> u64 {
+        self.forwarded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_forwarded(&self) {
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The recent handshake events of this peer, oldest first
+    pub fn flight_record(&self) -> Vec<FlightRecord> {
+        self.recorder.records()
+    }
+
+    pub(crate) fn record(&self, event: FlightEvent) {
+        self.recorder.record(event);
+    }
+
+    /// Record a message sent to the peer, if it is a handshake message
+    pub(crate) fn record_sent(&self, message: &[u8], reason: Option<TimerReason>) {
+        if let Some(event) = FlightEvent::sent(message, reason) {
+            self.recorder.record(event);
+        }
+    }
+
+    /// Record a message received from the peer, if it is a handshake message
+    pub(crate) fn record_received(&self, message: &[u8]) {
+        if let Some(event) = FlightEvent::received(message) {
+            self.recorder.record(event);
+        }
+    }
+
+    /// The span of this peer, naming it and its endpoint in the logs
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Enter the span of this peer while handling its traffic and timers. Nothing is done when
+    /// the span is filtered out, as it is below the info level, so the data path doesn't pay
+    /// for it. The span is cloned, as the peer is changed while it is entered.
+    pub fn enter_span(&self) -> Option<EnteredSpan> {
+        (!self.span.is_disabled()).then(|| self.span.clone().entered())
+    }
+
     pub fn index(&self) -> u32 {
         self.index
     }
+
+    pub fn public_key(&self) -> &x25519::PublicKey {
+        &self.public_key
+    }
+}
+
+/// A span naming the peer by the start of its base64 public key, as shown by `wg`
+fn peer_span(public_key: &x25519::PublicKey, endpoint: Option<SocketAddr>) -> Span {
+    let key = BASE64_STANDARD.encode(public_key.as_bytes());
+    let key = &key[..8];
+    match endpoint {
+        Some(endpoint) => tracing::info_span!("peer", %key, %endpoint),
+        None => tracing::info_span!("peer", %key),
+    }
 }
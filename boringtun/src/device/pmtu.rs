@@ -0,0 +1,317 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Path MTU handling, see [`super::DeviceConfig::icmp_too_big`] and
+//! [`super::DeviceConfig::clamp_mss`].
+//!
+//! Every peer has an outer MTU, the largest datagram that reaches its endpoint. It is either set
+//! with the `mtu` key of the peer, or learned on Linux from the path MTU the kernel keeps for
+//! the connected socket of the peer. Packets from the tunnel interface that don't fit are
+//! answered with an ICMP "fragmentation needed" or ICMPv6 "packet too big" message written back
+//! to the interface by [`super::icmp`], so the sender lowers its path MTU instead of the packet
+//! vanishing. The MSS option of TCP SYNs is clamped in both directions, so connections start
+//! out with segments that fit.
+
+use std::{fmt, str::FromStr};
+
+use super::icmp::{IPV4_HEADER_LEN, IPV6_HEADER_LEN};
+use super::offload::{fold, set_u16, sum_words};
+
+/// The header and AEAD tag of a WireGuard data message, and the UDP header
+const WG_OVERHEAD: usize = 16 + 16 + 8;
+const TCP_HEADER_LEN: usize = 20;
+/// The offset of the checksum in the TCP header
+const TCP_CSUM_OFFSET: usize = 16;
+
+const IPPROTO_TCP: u8 = 6;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+/// A per-peer setting that follows the device unless it is turned on or off
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Toggle {
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+impl Toggle {
+    /// Is the setting enabled, given the setting of the device
+    #[must_use]
+    pub fn enabled(self, device: bool) -> bool {
+        match self {
+            Toggle::Auto => device,
+            Toggle::On => true,
+            Toggle::Off => false,
+        }
+    }
+}
+
+impl FromStr for Toggle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Toggle::Auto),
+            "on" | "true" => Ok(Toggle::On),
+            "off" | "false" => Ok(Toggle::Off),
+            _ => Err(format!("Invalid setting: {s}, expected on, off or auto")),
+        }
+    }
+}
+
+impl fmt::Display for Toggle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Toggle::Auto => write!(f, "auto"),
+            Toggle::On => write!(f, "on"),
+            Toggle::Off => write!(f, "off"),
+        }
+    }
+}
+
+/// The path MTU settings of a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerPmtu {
+    /// The outer MTU towards the endpoint, instead of the one learned from the kernel
+    pub mtu: Option<u16>,
+    /// Reply with ICMP to packets for the peer that don't fit the path
+    pub icmp_too_big: Toggle,
+    /// Clamp the MSS of TCP SYNs to and from the peer to the path
+    pub clamp_mss: Toggle,
+}
+
+impl PeerPmtu {
+    pub fn apply(&mut self, update: &PmtuUpdate) {
+        if let Some(mtu) = update.mtu {
+            self.mtu = (mtu != 0).then_some(mtu);
+        }
+        if let Some(icmp_too_big) = update.icmp_too_big {
+            self.icmp_too_big = icmp_too_big;
+        }
+        if let Some(clamp_mss) = update.clamp_mss {
+            self.clamp_mss = clamp_mss;
+        }
+    }
+}
+
+/// Changes to the path MTU settings of a peer, sent as the `mtu`, `icmp_too_big` and
+/// `clamp_mss` keys of a `set=1` transaction. Settings left as `None` are not changed, and an
+/// MTU of 0 removes the override.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PmtuUpdate {
+    pub mtu: Option<u16>,
+    pub icmp_too_big: Option<Toggle>,
+    pub clamp_mss: Option<Toggle>,
+}
+
+impl PmtuUpdate {
+    /// The changes that turn the `current` settings into the `wanted` ones. Unchanged settings
+    /// are left out, so peers without any stay compatible with other implementations.
+    #[must_use]
+    pub fn between(current: &PeerPmtu, wanted: &PeerPmtu) -> PmtuUpdate {
+        PmtuUpdate {
+            mtu: (current.mtu != wanted.mtu).then(|| wanted.mtu.unwrap_or(0)),
+            icmp_too_big: (current.icmp_too_big != wanted.icmp_too_big)
+                .then_some(wanted.icmp_too_big),
+            clamp_mss: (current.clamp_mss != wanted.clamp_mss).then_some(wanted.clamp_mss),
+        }
+    }
+}
+
+/// The largest packet that fits a WireGuard datagram of the outer MTU
+#[must_use]
+pub fn inner_mtu(outer: u16, ipv6_endpoint: bool) -> usize {
+    let ip_header = if ipv6_endpoint {
+        IPV6_HEADER_LEN
+    } else {
+        IPV4_HEADER_LEN
+    };
+    usize::from(outer).saturating_sub(ip_header + WG_OVERHEAD)
+}
+
+/// The MSS option of a TCP SYN
+struct MssOption {
+    ipv4: bool,
+    /// The offset of the TCP header
+    tcp: usize,
+    /// The offset of the option value
+    at: usize,
+}
+
+fn mss_option(packet: &[u8]) -> Option<MssOption> {
+    let (ipv4, tcp) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = usize::from(packet[0] & 0xf) * 4;
+            let fragment_offset = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x1fff;
+            // Only the first fragment has the TCP header
+            if ihl < IPV4_HEADER_LEN || packet.get(9) != Some(&IPPROTO_TCP) || fragment_offset != 0
+            {
+                return None;
+            }
+            (true, ihl)
+        }
+        6 if packet.get(6) == Some(&IPPROTO_TCP) => (false, IPV6_HEADER_LEN),
+        _ => return None,
+    };
+
+    let header = packet.get(tcp..)?;
+    if header.len() < TCP_HEADER_LEN || header[13] & TCP_SYN == 0 {
+        return None;
+    }
+    let options = header.get(TCP_HEADER_LEN..usize::from(header[12] >> 4) * 4)?;
+
+    let mut i = 0;
+    while let Some(&kind) = options.get(i) {
+        match kind {
+            TCP_OPT_END => return None,
+            TCP_OPT_NOP => i += 1,
+            _ => {
+                let len = usize::from(*options.get(i + 1)?);
+                if kind == TCP_OPT_MSS && len == 4 && i + len <= options.len() {
+                    let at = tcp + TCP_HEADER_LEN + i + 2;
+                    return Some(MssOption { ipv4, tcp, at });
+                }
+                if len < 2 {
+                    return None;
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+/// The largest MSS of a TCP connection over a path with the MTU
+fn max_mss(mtu: usize, ipv4: bool) -> u16 {
+    let ip_header = if ipv4 {
+        IPV4_HEADER_LEN
+    } else {
+        IPV6_HEADER_LEN
+    };
+    u16::try_from(mtu.saturating_sub(ip_header + TCP_HEADER_LEN)).unwrap_or(u16::MAX)
+}
+
+/// Is the packet a TCP SYN with an MSS too large for the MTU
+pub(crate) fn needs_mss_clamp(packet: &[u8], mtu: usize) -> bool {
+    mss_option(packet).is_some_and(|opt| {
+        u16::from_be_bytes([packet[opt.at], packet[opt.at + 1]]) > max_mss(mtu, opt.ipv4)
+    })
+}
+
+/// Lower the MSS of a TCP SYN to fit the MTU, and update the TCP checksum. Returns whether the
+/// packet changed.
+pub(crate) fn clamp_mss(packet: &mut [u8], mtu: usize) -> bool {
+    if !needs_mss_clamp(packet, mtu) {
+        return false;
+    }
+    let Some(MssOption { ipv4, tcp, at }) = mss_option(packet) else {
+        return false;
+    };
+
+    // Update the checksum incrementally over the 16 bit words of the TCP header that hold the
+    // option value, which starts at an odd offset after a single NOP
+    let words = tcp + ((at - tcp) & !1)..tcp + ((at - tcp + 3) & !1);
+    let old = sum_words(&packet[words.clone()], 0);
+    set_u16(packet, at, max_mss(mtu, ipv4));
+    let new = sum_words(&packet[words.clone()], 0);
+
+    let csum_at = tcp + TCP_CSUM_OFFSET;
+    let csum = u16::from_be_bytes([packet[csum_at], packet[csum_at + 1]]);
+    let n = (words.len() / 2) as u64;
+    let sum = u64::from(!csum) + n * 0xffff - old + new;
+    set_u16(packet, csum_at, !fold(sum));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::offload::pseudo_header_sum;
+    use super::*;
+
+    /// A TCP SYN with the MSS option after a NOP, so the value starts at an odd offset
+    fn syn4(mss: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 48];
+        packet[0] = 0x45;
+        set_u16(&mut packet, 2, 48);
+        packet[6] = 0x40;
+        packet[8] = 64;
+        packet[9] = IPPROTO_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        let sum = sum_words(&packet[..20], 0);
+        set_u16(&mut packet, 10, !fold(sum));
+
+        set_u16(&mut packet, 20, 1234);
+        set_u16(&mut packet, 22, 80);
+        packet[32] = 7 << 4;
+        packet[33] = TCP_SYN;
+        set_u16(&mut packet, 34, 64240);
+        packet[40] = TCP_OPT_NOP;
+        packet[41..45].copy_from_slice(&[TCP_OPT_MSS, 4, 0, 0]);
+        set_u16(&mut packet, 43, mss);
+        packet[45..48].copy_from_slice(&[TCP_OPT_NOP, TCP_OPT_NOP, TCP_OPT_END]);
+        let sum = sum_words(&packet[20..], pseudo_header_sum(&packet, true, 28));
+        set_u16(&mut packet, 36, !fold(sum));
+        packet
+    }
+
+    fn valid_tcp_checksum(packet: &[u8]) -> bool {
+        let sum = pseudo_header_sum(packet, true, packet.len() - 20);
+        fold(sum_words(&packet[20..], sum)) == 0xffff
+    }
+
+    #[test]
+    fn clamps_mss() {
+        let mut packet = syn4(1460);
+        assert!(clamp_mss(&mut packet, 1420));
+        assert_eq!(u16::from_be_bytes([packet[43], packet[44]]), 1380);
+        assert!(valid_tcp_checksum(&packet));
+
+        // An MSS that already fits is left alone
+        let mut packet = syn4(1200);
+        assert!(!needs_mss_clamp(&packet, 1420));
+        assert!(!clamp_mss(&mut packet, 1420));
+
+        // As are packets other than SYNs
+        let mut packet = syn4(1460);
+        packet[33] = 0x10;
+        assert!(!clamp_mss(&mut packet, 1420));
+    }
+
+    #[test]
+    fn inner_mtus() {
+        assert_eq!(inner_mtu(1500, false), 1440);
+        assert_eq!(inner_mtu(1500, true), 1420);
+        assert_eq!(inner_mtu(40, true), 0);
+    }
+
+    #[test]
+    fn updates_between_settings() {
+        let current = PeerPmtu::default();
+        assert_eq!(
+            PmtuUpdate::between(&current, &current),
+            PmtuUpdate::default()
+        );
+
+        let wanted = PeerPmtu {
+            mtu: Some(1400),
+            clamp_mss: Toggle::On,
+            ..PeerPmtu::default()
+        };
+        let update = PmtuUpdate::between(&current, &wanted);
+        assert_eq!(update.mtu, Some(1400));
+        assert_eq!(update.icmp_too_big, None);
+        assert_eq!(update.clamp_mss, Some(Toggle::On));
+
+        let mut applied = current;
+        applied.apply(&update);
+        assert_eq!(applied, wanted);
+        applied.apply(&PmtuUpdate::between(&wanted, &current));
+        assert_eq!(applied, current);
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Driving a [`Device`] from an event loop the embedder owns, such as GLib's or a game
+//! engine's, instead of the threads of [`super::DeviceHandle`].
+//!
+//! The embedder waits for the fds of [`DevicePoller::fds`] to become readable, with the timeout
+//! of [`DevicePoller::timeout`], then calls [`DevicePoller::process_ready`] for each readable fd
+//! and [`DevicePoller::process_timers`]. The same handlers run as with `DeviceHandle`, on the
+//! calling thread. Only the HTTP API and metrics endpoints, when configured, are served on
+//! threads of their own. Signals are left to the embedder, and nothing is blocked on its thread.
+//! [`DevicePoller::reload`] applies the configuration file again, like SIGHUP does.
+
+use std::{io::BufRead, os::unix::io::RawFd, sync::Arc, time::Duration};
+
+use super::{Action, Device, DeviceConfig, Error, ThreadData, dev_lock::Lock, stats::DropStats};
+use crate::{
+    uapi::client::{self, SetRequest},
+    x25519,
+};
+
+pub struct DevicePoller {
+    device: Arc<Lock<Device>>,
+    thread_local: ThreadData,
+    exited: bool,
+}
+
+impl DevicePoller {
+    /// Create the device like [`super::DeviceHandle::new`], without starting any threads.
+    /// `n_threads`, `use_io_uring` and `handle_signals` of the configuration are ignored.
+    pub fn new(name: &str, config: DeviceConfig) -> Result<DevicePoller, Error> {
+        let config = DeviceConfig {
+            #[cfg(target_os = "linux")]
+            use_io_uring: false,
+            handle_signals: false,
+            ..config
+        };
+        let device = Device::start(name, config)?;
+        let thread_local = ThreadData::new(Arc::clone(&device.read().iface));
+
+        Ok(DevicePoller {
+            device,
+            thread_local,
+            exited: false,
+        })
+    }
+
+    /// The fds to wait on for reading. The set changes as sockets are opened and closed, so
+    /// fetch it again after processing.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.device.read().queue.fds()
+    }
+
+    /// The longest time to wait before calling [`DevicePoller::process_timers`]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.device.read().queue.timeout()
+    }
+
+    /// Run the handler of a readable fd. Returns false once the device exited, because of a
+    /// signal or through the api, after which it should be dropped.
+    pub fn process_ready(&mut self, fd: RawFd) -> bool {
+        if self.exited {
+            return false;
+        }
+
+        let mut device = self.device.read();
+        let queue = Arc::clone(&device.queue);
+        let Some(event) = queue.triggered(fd) else {
+            return true;
+        };
+
+        match self.thread_local.dispatch(event, &mut device) {
+            // There are no other threads to yield to
+            Action::Continue | Action::Yield => true,
+            Action::Exit => {
+                device.trigger_exit();
+                self.exited = true;
+                false
+            }
+        }
+    }
+
+    /// Run the handlers of the timers that expired. Returns false once the device exited.
+    pub fn process_timers(&mut self) -> bool {
+        let due = self.device.read().queue.due_timers();
+        due.into_iter().all(|fd| self.process_ready(fd))
+    }
+
+    /// Read the configuration file, if the device has one, and apply what changed
+    pub fn reload(&self) {
+        let mut device = self.device.read();
+        if let Some(path) = device.config.config_file.clone() {
+            Device::reload_config(&mut device, &path);
+        }
+    }
+
+    /// See [`super::DeviceHandle::set`]
+    pub fn set(&self, request: &SetRequest) -> Result<(), client::Error> {
+        let mut buf = Vec::new();
+        request.write_to(&mut buf)?;
+        let response = super::api::api_request(&buf, &mut self.device.read());
+        client::parse_response(&mut response.lines(), |_| Ok(()))
+    }
+
+    /// See [`super::DeviceHandle::drop_stats`]
+    pub fn drop_stats(&self) -> DropStats {
+        self.device.read().drops.stats()
+    }
+
+    /// See [`super::DeviceHandle::peer_drop_stats`]
+    pub fn peer_drop_stats(&self, public_key: &x25519::PublicKey) -> Option<DropStats> {
+        let device = self.device.read();
+        let peer = device.peers.get(public_key)?;
+        Some(peer.lock().drop_stats())
+    }
+}
+
+impl Drop for DevicePoller {
+    fn drop(&mut self) {
+        let device = self.device.read();
+        // Keep the final state, such as the endpoints peers roamed to
+        device.save_config();
+        device.clean();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, UdpSocket},
+        os::unix::net::UnixStream,
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::device::api::api_request;
+
+    /// Wait for the fds of the poller and process them until `done`
+    fn run_until(poller: &mut DevicePoller, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline);
+            let mut fds: Vec<libc::pollfd> = poller
+                .fds()
+                .into_iter()
+                .map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            let timeout = poller
+                .timeout()
+                .map_or(100, |t| t.as_millis().min(100) as i32);
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+            for fd in fds.iter().filter(|fd| fd.revents & libc::POLLIN != 0) {
+                assert!(poller.process_ready(fd.fd));
+            }
+            assert!(poller.process_timers());
+        }
+    }
+
+    fn blocked_signals() -> Vec<i32> {
+        let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &raw mut set) };
+        [libc::SIGINT, libc::SIGTERM, libc::SIGHUP]
+            .into_iter()
+            .filter(|&signal| unsafe { libc::sigismember(&raw const set, signal) } == 1)
+            .collect()
+    }
+
+    #[test]
+    #[ignore]
+    fn drives_a_device() {
+        let mut poller = DevicePoller::new("utun97", DeviceConfig::default()).unwrap();
+        assert!(blocked_signals().is_empty());
+
+        // The api socket is served by process_ready
+        let mut api = UnixStream::connect("/var/run/wireguard/utun97.sock").unwrap();
+        api.write_all(b"get=1\n\n").unwrap();
+        api.set_nonblocking(true).unwrap();
+        let mut response = String::new();
+        run_until(&mut poller, || {
+            let _ = api.read_to_string(&mut response);
+            response.ends_with("errno=0\n\n")
+        });
+
+        // The timers of process_timers start a handshake for the keepalive
+        let endpoint = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        endpoint.set_nonblocking(true).unwrap();
+        let set = format!(
+            "set=1\n\
+             private_key={}\n\
+             public_key={}\n\
+             endpoint={}\n\
+             persistent_keepalive_interval=1\n\n",
+            "a8dbc8b5e5e5b5e5d5c5b5a5958575655545352515050f0e0d0c0b0a09080748",
+            "01".repeat(32),
+            endpoint.local_addr().unwrap(),
+        );
+        let response = api_request(set.as_bytes(), &mut poller.device.read());
+        assert_eq!(response, b"errno=0\n\n");
+        let mut buf = [0u8; 256];
+        run_until(&mut poller, || {
+            // A handshake initiation
+            endpoint.recv(&mut buf).ok() == Some(148) && buf[0] == 1
+        });
+
+        assert!(blocked_signals().is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A flight recorder of the recent handshake events of every peer.
+//!
+//! Every peer keeps its last [`CAPACITY`] handshake messages, session expiries, endpoint changes
+//! and decapsulation errors in memory, so an intermittent problem can be looked into after the
+//! fact without running with debug logs. Data packets are not recorded, which keeps the
+//! recorder cheap enough to be always on.
+//!
+//! The records are reported by get requests with `flight_record=true`, as one line per record,
+//! oldest first:
+//!
+//! ```text
+//! flight_record=1760000000.123456 handshake_init_sent reason=rekey_timeout
+//! flight_record=1760000000.234567 endpoint_changed endpoint=192.0.2.1:51820
+//! flight_record=1760000000.345678 decapsulation_error error=invalid_mac
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use super::stats::WIREGUARD_ERRORS;
+use crate::noise::TimerReason;
+
+/// The number of records kept for every peer
+pub const CAPACITY: usize = 64;
+
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESP: u8 = 2;
+const COOKIE_REPLY: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlightEvent {
+    /// A handshake initiation was sent, by the timers for the reason, or for queued data
+    HandshakeInitSent(Option<TimerReason>),
+    HandshakeInitReceived,
+    HandshakeResponseSent,
+    HandshakeResponseReceived,
+    CookieSent,
+    CookieReceived,
+    /// A handshake completed and a new session is in use
+    HandshakeCompleted,
+    /// The connection expired, for the reason
+    SessionExpired(TimerReason),
+    EndpointChanged(SocketAddr),
+    /// A packet from the peer failed decapsulation, with the snake case name of the error
+    DecapsulationError(&'static str),
+}
+
+impl FlightEvent {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlightEvent::HandshakeInitSent(_) => "handshake_init_sent",
+            FlightEvent::HandshakeInitReceived => "handshake_init_received",
+            FlightEvent::HandshakeResponseSent => "handshake_response_sent",
+            FlightEvent::HandshakeResponseReceived => "handshake_response_received",
+            FlightEvent::CookieSent => "cookie_sent",
+            FlightEvent::CookieReceived => "cookie_received",
+            FlightEvent::HandshakeCompleted => "handshake_completed",
+            FlightEvent::SessionExpired(_) => "session_expired",
+            FlightEvent::EndpointChanged(_) => "endpoint_changed",
+            FlightEvent::DecapsulationError(_) => "decapsulation_error",
+        }
+    }
+
+    /// The event for a WireGuard message sent to the peer, if it is a handshake message
+    pub(crate) fn sent(message: &[u8], reason: Option<TimerReason>) -> Option<FlightEvent> {
+        match *message.first()? {
+            HANDSHAKE_INIT => Some(FlightEvent::HandshakeInitSent(reason)),
+            HANDSHAKE_RESP => Some(FlightEvent::HandshakeResponseSent),
+            COOKIE_REPLY => Some(FlightEvent::CookieSent),
+            _ => None,
+        }
+    }
+
+    /// The event for a WireGuard message received from the peer, if it is a handshake message
+    pub(crate) fn received(message: &[u8]) -> Option<FlightEvent> {
+        match *message.first()? {
+            HANDSHAKE_INIT => Some(FlightEvent::HandshakeInitReceived),
+            HANDSHAKE_RESP => Some(FlightEvent::HandshakeResponseReceived),
+            COOKIE_REPLY => Some(FlightEvent::CookieReceived),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FlightEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        match self {
+            FlightEvent::HandshakeInitSent(Some(reason)) | FlightEvent::SessionExpired(reason) => {
+                write!(f, " reason={}", reason.name())
+            }
+            FlightEvent::EndpointChanged(endpoint) => write!(f, " endpoint={endpoint}"),
+            FlightEvent::DecapsulationError(error) => write!(f, " error={error}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for FlightEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_ascii_whitespace();
+        let name = fields.next().ok_or("Missing event")?;
+        let mut reason = None;
+        let mut endpoint = None;
+        let mut error = None;
+        for pair in fields {
+            let (key, val) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid event field: {pair}"))?;
+            match key {
+                "reason" => {
+                    reason = Some(
+                        TimerReason::from_name(val)
+                            .ok_or_else(|| format!("Invalid reason: {val}"))?,
+                    );
+                }
+                "endpoint" => {
+                    endpoint = Some(
+                        val.parse::<SocketAddr>()
+                            .map_err(|_| format!("Invalid endpoint: {val}"))?,
+                    );
+                }
+                "error" => {
+                    error = Some(
+                        *WIREGUARD_ERRORS
+                            .iter()
+                            .find(|e| **e == val)
+                            .ok_or_else(|| format!("Invalid error: {val}"))?,
+                    );
+                }
+                // Ignore unknown fields, so events can be extended later
+                _ => {}
+            }
+        }
+
+        Ok(match name {
+            "handshake_init_sent" => FlightEvent::HandshakeInitSent(reason),
+            "handshake_init_received" => FlightEvent::HandshakeInitReceived,
+            "handshake_response_sent" => FlightEvent::HandshakeResponseSent,
+            "handshake_response_received" => FlightEvent::HandshakeResponseReceived,
+            "cookie_sent" => FlightEvent::CookieSent,
+            "cookie_received" => FlightEvent::CookieReceived,
+            "handshake_completed" => FlightEvent::HandshakeCompleted,
+            "session_expired" => FlightEvent::SessionExpired(reason.ok_or("Missing reason")?),
+            "endpoint_changed" => FlightEvent::EndpointChanged(endpoint.ok_or("Missing endpoint")?),
+            "decapsulation_error" => FlightEvent::DecapsulationError(error.ok_or("Missing error")?),
+            _ => return Err(format!("Unknown event: {name}")),
+        })
+    }
+}
+
+/// An event and when it happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlightRecord {
+    pub time: SystemTime,
+    pub event: FlightEvent,
+}
+
+impl fmt::Display for FlightRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:06} {}",
+            time.as_secs(),
+            time.subsec_micros(),
+            self.event
+        )
+    }
+}
+
+impl FromStr for FlightRecord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (time, event) = s.split_once(' ').ok_or("Missing event")?;
+        let (secs, micros) = time.split_once('.').unwrap_or((time, "0"));
+        let invalid = || format!("Invalid time: {time}");
+        let secs = secs.parse::<u64>().map_err(|_| invalid())?;
+        let micros = micros.parse::<u32>().map_err(|_| invalid())?;
+        Ok(FlightRecord {
+            time: UNIX_EPOCH + Duration::new(secs, micros.saturating_mul(1000)),
+            event: event.parse()?,
+        })
+    }
+}
+
+/// The recent events of a peer
+#[derive(Default)]
+pub(crate) struct FlightRecorder {
+    records: Mutex<VecDeque<FlightRecord>>,
+}
+
+impl FlightRecorder {
+    pub(crate) fn record(&self, event: FlightEvent) {
+        let mut records = self.records.lock();
+        if records.len() == CAPACITY {
+            records.pop_front();
+        }
+        records.push_back(FlightRecord {
+            time: SystemTime::now(),
+            event,
+        });
+    }
+
+    /// The recorded events, oldest first
+    pub(crate) fn records(&self) -> Vec<FlightRecord> {
+        self.records.lock().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        for event in [
+            FlightEvent::HandshakeInitSent(None),
+            FlightEvent::HandshakeInitSent(Some(TimerReason::RekeyTimeout)),
+            FlightEvent::HandshakeResponseReceived,
+            FlightEvent::SessionExpired(TimerReason::RekeyAttemptTime),
+            FlightEvent::EndpointChanged("[2001:db8::1]:51820".parse().unwrap()),
+            FlightEvent::DecapsulationError("invalid_mac"),
+        ] {
+            let record = FlightRecord {
+                time: UNIX_EPOCH + Duration::from_micros(1_760_000_000_123_456),
+                event,
+            };
+            let line = record.to_string();
+            assert!(line.starts_with("1760000000.123456 "));
+            assert_eq!(line.parse::<FlightRecord>(), Ok(record));
+        }
+        assert!(
+            "1 decapsulation_error error=nope"
+                .parse::<FlightRecord>()
+                .is_err()
+        );
+        assert!("1 session_expired".parse::<FlightRecord>().is_err());
+    }
+
+    #[test]
+    fn keeps_the_latest_records() {
+        let recorder = FlightRecorder::default();
+        recorder.record(FlightEvent::HandshakeCompleted);
+        for _ in 0..CAPACITY {
+            recorder.record(FlightEvent::CookieReceived);
+        }
+        let records = recorder.records();
+        assert_eq!(records.len(), CAPACITY);
+        assert!(
+            records
+                .iter()
+                .all(|r| r.event == FlightEvent::CookieReceived)
+        );
+    }
+
+    #[test]
+    fn classifies_handshake_messages() {
+        let reason = Some(TimerReason::NoReply);
+        assert_eq!(
+            FlightEvent::sent(&[1, 0, 0, 0], reason),
+            Some(FlightEvent::HandshakeInitSent(reason))
+        );
+        assert_eq!(
+            FlightEvent::received(&[3, 0, 0, 0]),
+            Some(FlightEvent::CookieReceived)
+        );
+        assert_eq!(FlightEvent::sent(&[4, 0, 0, 0], None), None);
+        assert_eq!(FlightEvent::received(&[]), None);
+    }
+}
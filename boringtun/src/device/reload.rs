@@ -0,0 +1,308 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Applying a configuration file to a running device, see [`super::DeviceConfig::config_file`].
+//!
+//! Only the difference between the file and the device is applied: peers that are unchanged
+//! keep their sessions, changed peers are updated in place, and peers missing from the file are
+//! removed. A file that fails to parse, or whose listen port or fwmark can't be applied, leaves
+//! the device untouched.
+
+use std::{fs::read_to_string, path::Path};
+
+use super::{
+    AllowedIps, Device, Error, acl::AclUpdate, dev_lock::LockReadGuard, peer::Peer,
+    pmtu::PmtuUpdate,
+};
+use crate::{
+    uapi::config::{Config, PeerConfig},
+    x25519,
+};
+
+/// Read and parse a configuration file
+pub(super) fn read_config(path: &Path) -> Result<Config, Error> {
+    let contents =
+        read_to_string(path).map_err(|e| Error::Config(format!("{}: {e}", path.display())))?;
+    contents
+        .parse()
+        .map_err(|e| Error::Config(format!("{}: {e}", path.display())))
+}
+
+impl Device {
+    /// Read the configuration file again and apply it, in response to SIGHUP
+    pub(super) fn reload_config(d: &mut LockReadGuard<Device>, path: &Path) {
+        let config = match read_config(path) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!(message = "Failed to reload configuration", error = %e);
+                return;
+            }
+        };
+
+        let result = d.try_writeable(Device::trigger_yield, |device| {
+            device.cancel_yield();
+            device.apply_config(&config)?;
+            #[cfg(target_os = "linux")]
+            if let Err(e) = device.sync_routes() {
+                tracing::warn!(message = "Failed to update routes", error = ?e);
+            }
+            Ok::<_, Error>(())
+        });
+        match result {
+            Some(Ok(())) => {
+                tracing::info!(message = "Reloaded configuration", path = %path.display())
+            }
+            Some(Err(e)) => tracing::error!(message = "Failed to reload configuration", error = %e),
+            None => tracing::error!("Failed to reload configuration, the device is shutting down"),
+        }
+    }
+
+    /// Make the device match the configuration, changing as little as possible
+    pub(super) fn apply_config(&mut self, config: &Config) -> Result<(), Error> {
+        // The steps that can fail come first, and leave the device as it was when they do
+        let sockets = match config.listen_port {
+            Some(port) if port != self.listen_port => Some(self.bind_listen_sockets(port)?),
+            _ => None,
+        };
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        let previous_fwmark = self.fwmark;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(fwmark) = config.fwmark
+            && Some(fwmark) != self.fwmark
+        {
+            if let Some(ref sockets) = sockets {
+                sockets.udp4.set_mark(fwmark)?;
+                sockets.udp6.set_mark(fwmark)?;
+            }
+            self.set_fwmark(fwmark)?;
+        }
+
+        if let Some(sockets) = sockets
+            && let Err(e) = self.install_listen_sockets(sockets)
+        {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            if self.fwmark != previous_fwmark {
+                let _ = self.set_fwmark(previous_fwmark.unwrap_or(0));
+                self.fwmark = previous_fwmark;
+            }
+            return Err(e);
+        }
+
+        if let Some(ref private_key) = config.private_key {
+            // Does nothing when the key is unchanged
+            self.set_key(private_key);
+        }
+
+        let removed: Vec<x25519::PublicKey> = self
+            .peers
+            .keys()
+            .filter(|k| !config.peers.iter().any(|p| &p.public_key == *k))
+            .copied()
+            .collect();
+        for public_key in &removed {
+            self.remove_peer(public_key);
+        }
+
+        for peer in &config.peers {
+            let existing = self.peers.get(&peer.public_key);
+            if existing.is_some_and(|p| !peer_changed(&p.lock(), peer)) {
+                continue;
+            }
+            let (pmtu, acl) = existing
+                .map(|p| {
+                    let p = p.lock();
+                    (p.pmtu(), p.acl().clone())
+                })
+                .unwrap_or_default();
+
+            // Settings missing from the file are turned off on existing peers
+            let reset = existing.is_some();
+            self.update_peer(
+                peer.public_key,
+                false,
+                true,
+                peer.endpoint,
+                &peer.allowed_ips,
+                peer.persistent_keepalive_interval.or(reset.then_some(0)),
+                peer.preshared_key.or(reset.then_some([0u8; 32])),
+                &PmtuUpdate::between(&pmtu, &peer.pmtu),
+                &AclUpdate::between(&acl, &peer.acl),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Does the running peer differ from its configuration
+fn peer_changed(current: &Peer, config: &PeerConfig) -> bool {
+    // Compare the allowed IPs the way the peer stores them, with the host bits cleared
+    let wanted: AllowedIps<()> = config.allowed_ips.iter().map(|ip| (ip, ())).collect();
+    let mut wanted: Vec<_> = wanted.iter().map(|((), ip, cidr)| (ip, cidr)).collect();
+    let mut allowed_ips: Vec<_> = current.allowed_ips().collect();
+    wanted.sort_unstable();
+    allowed_ips.sort_unstable();
+
+    // A configured endpoint is only applied again if the peer has roamed away from it
+    let endpoint_changed = config
+        .endpoint
+        .is_some_and(|addr| current.endpoint().addr != Some(addr));
+    let keepalive = config.persistent_keepalive_interval.filter(|&i| i > 0);
+
+    allowed_ips != wanted
+        || endpoint_changed
+        || current.persistent_keepalive() != keepalive
+        || current.preshared_key() != config.preshared_key.as_ref()
+        || current.pmtu() != config.pmtu
+        || current.acl() != &config.acl
+}
+
+#[cfg(test)]
+mod tests {
+    use aead::rand_core::OsRng;
+
+    use base64::prelude::*;
+
+    use super::*;
+    use crate::{
+        device::{DeviceConfig, DeviceHandle},
+        noise::Tunn,
+    };
+
+    #[test]
+    fn detects_changed_peers() {
+        let public_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let config = PeerConfig {
+            public_key,
+            preshared_key: None,
+            endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+            persistent_keepalive_interval: Some(25),
+            // Host bits are cleared by the device, so this matches 10.0.0.0/24
+            allowed_ips: vec!["10.0.0.1/24".parse().unwrap()],
+            pmtu: Default::default(),
+            acl: Default::default(),
+        };
+        let tunnel = Tunn::new(
+            x25519::StaticSecret::random_from_rng(OsRng),
+            public_key,
+            None,
+            Some(25),
+            1,
+            None,
+        );
+        let peer = Peer::new(
+            tunnel,
+            public_key,
+            1,
+            config.endpoint,
+            &["10.0.0.0/24".parse().unwrap()],
+            None,
+        );
+
+        assert!(!peer_changed(&peer, &config));
+        assert!(peer_changed(
+            &peer,
+            &PeerConfig {
+                persistent_keepalive_interval: None,
+                ..config.clone()
+            }
+        ));
+        assert!(peer_changed(
+            &peer,
+            &PeerConfig {
+                allowed_ips: vec!["10.0.0.0/24".parse().unwrap(), "fd00::/64".parse().unwrap()],
+                ..config.clone()
+            }
+        ));
+        assert!(peer_changed(
+            &peer,
+            &PeerConfig {
+                preshared_key: Some([1u8; 32]),
+                ..config.clone()
+            }
+        ));
+        assert!(peer_changed(
+            &peer,
+            &PeerConfig {
+                pmtu: crate::device::pmtu::PeerPmtu {
+                    mtu: Some(1400),
+                    ..Default::default()
+                },
+                ..config.clone()
+            }
+        ));
+        assert!(peer_changed(
+            &peer,
+            &PeerConfig {
+                acl: crate::device::acl::Acl {
+                    policy: crate::device::acl::Action::Deny,
+                    rules: Vec::new(),
+                },
+                ..config.clone()
+            }
+        ));
+        // Without a configured endpoint, the one the peer roamed to is kept
+        assert!(!peer_changed(
+            &peer,
+            &PeerConfig {
+                endpoint: None,
+                ..config
+            }
+        ));
+    }
+
+    #[test]
+    #[ignore]
+    /// Creating the tunnel interface requires privileges
+    fn reload_with_a_busy_port_changes_nothing() {
+        let config = DeviceConfig {
+            n_threads: 1,
+            ..DeviceConfig::default()
+        };
+        let device = DeviceHandle::new("utun95", config).unwrap();
+        let private_key = x25519::StaticSecret::random_from_rng(OsRng);
+        let peer_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let config_with_port = |port: u16| -> Config {
+            format!(
+                "[Interface]\nPrivateKey = {}\nListenPort = {port}\n\n\
+                 [Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.2/32\n",
+                BASE64_STANDARD.encode(private_key.to_bytes()),
+                BASE64_STANDARD.encode(peer_key.as_bytes()),
+            )
+            .parse()
+            .unwrap()
+        };
+        let apply = |config: &Config| {
+            device
+                .device
+                .read()
+                .try_writeable(Device::trigger_yield, |device| {
+                    device.cancel_yield();
+                    device.apply_config(config)
+                })
+                .unwrap()
+        };
+
+        // Taken without SO_REUSEADDR, so the device can't share it
+        let busy = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let port = device.device.read().listen_port;
+        assert!(apply(&config_with_port(busy_port)).is_err());
+        {
+            let d = device.device.read();
+            assert_eq!(d.listen_port, port);
+            assert!(d.udp4.is_some() && d.udp6.is_some());
+            assert!(d.key_pair.is_none());
+            assert!(d.peers.is_empty());
+        }
+
+        // A successful reload closes the sockets of the previous port
+        drop(busy);
+        apply(&config_with_port(busy_port)).unwrap();
+        assert_eq!(device.device.read().listen_port, busy_port);
+        assert_eq!(device.device.read().peers.len(), 1);
+        std::net::UdpSocket::bind(("0.0.0.0", port)).unwrap();
+        std::net::UdpSocket::bind(("::", port)).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Writing the running configuration to a file, see [`super::DeviceConfig::save_config`].
+//!
+//! The file is written in the WireGuard configuration format after every successful `set=1`
+//! transaction and when the device shuts down, so peers added at runtime survive a restart.
+//! It is replaced atomically and only when its contents would change. The `Address`, `MTU` and
+//! `Table` keys applied at startup are kept, other keys of `wg-quick` are not preserved.
+
+use std::{
+    fs::{OpenOptions, read_to_string, remove_file, rename},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use super::{Device, peer::AllowedIP};
+use crate::uapi::config::{Config, PeerConfig};
+
+impl Device {
+    /// The running configuration, with peers and allowed IPs in a stable order
+    pub(super) fn running_config(&self) -> Config {
+        let mut peers: Vec<PeerConfig> = self
+            .peers
+            .values()
+            .map(|peer| {
+                let peer = peer.lock();
+                let mut allowed_ips: Vec<AllowedIP> = peer
+                    .allowed_ips()
+                    .map(|(addr, cidr)| AllowedIP { addr, cidr })
+                    .collect();
+                allowed_ips.sort_unstable_by_key(|ip| (ip.addr, ip.cidr));
+                PeerConfig {
+                    public_key: *peer.public_key(),
+                    preshared_key: peer.preshared_key().copied(),
+                    endpoint: peer.endpoint().addr,
+                    persistent_keepalive_interval: peer.persistent_keepalive(),
+                    allowed_ips,
+                    pmtu: peer.pmtu(),
+                    acl: peer.acl().clone(),
+                }
+            })
+            .collect();
+        peers.sort_unstable_by_key(|peer| peer.public_key.to_bytes());
+
+        Config {
+            private_key: self.key_pair.as_ref().map(|(private, _)| private.clone()),
+            listen_port: Some(self.listen_port),
+            fwmark: self.fwmark,
+            network: self.network.clone(),
+            peers,
+        }
+    }
+
+    /// Write the running configuration to the save file, if one is configured
+    pub(super) fn save_config(&self) {
+        let Some(ref path) = self.config.save_config else {
+            return;
+        };
+        match write_config(path, &self.running_config().to_string()) {
+            Ok(true) => tracing::info!(message = "Saved configuration", path = %path.display()),
+            Ok(false) => {}
+            Err(e) => tracing::error!(
+                message = "Failed to save configuration",
+                path = %path.display(),
+                error = %e
+            ),
+        }
+    }
+}
+
+/// Replace the file with the contents, unless it already holds them. The contents are written
+/// to a temporary file next to it first, so readers never see a partial file. Returns whether
+/// the file was written.
+fn write_config(path: &Path, contents: &str) -> io::Result<bool> {
+    if read_to_string(path).is_ok_and(|current| current == contents) {
+        return Ok(false);
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // A leftover from an interrupted write could have other permissions
+    let _ = remove_file(&tmp);
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| rename(&tmp, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp);
+    }
+    result.map(|()| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::metadata, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn writes_only_changes() {
+        let path = std::env::temp_dir().join(format!("boringtun-save-{}.conf", std::process::id()));
+        let _ = remove_file(&path);
+
+        assert!(write_config(&path, "[Interface]\n\n").unwrap());
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!write_config(&path, "[Interface]\n\n").unwrap());
+        assert!(write_config(&path, "[Interface]\nListenPort = 1\n\n").unwrap());
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            "[Interface]\nListenPort = 1\n\n"
+        );
+
+        remove_file(&path).unwrap();
+    }
+}
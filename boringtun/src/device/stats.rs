@@ -0,0 +1,236 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Counters for packets that are dropped on the data path.
+//!
+//! Every drop is counted once for the device, and once more for the peer it belongs to when
+//! the peer is known. The counters can be read through [`super::DeviceHandle::drop_stats`],
+//! the metrics endpoint, and the `drops=true` extension key of a UAPI get request.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::noise::errors::WireGuardError;
+
+/// Why the device dropped a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// A packet from the tunnel interface without a readable destination address
+    InvalidTunnelPacket,
+    /// No peer has an allowed IP that matches the destination of a packet from the tunnel
+    NoRoute,
+    /// The peer has no known endpoint to send the packet to
+    NoEndpoint,
+    /// The packet could not be encapsulated for the peer
+    EncapsulationError,
+    /// A packet from the network that does not belong to any peer
+    UnknownPeer,
+    /// A packet from the network failed verification or decapsulation
+    DecapsulationError,
+    /// The source of a decapsulated packet is not an allowed IP of the peer that sent it
+    DisallowedSourceIp,
+    /// Sending a packet to the network failed
+    SendError,
+    /// Writing a decapsulated packet to the tunnel interface failed
+    TunWriteError,
+    /// A packet from the tunnel interface does not fit the path MTU of the peer, and was
+    /// answered with ICMP
+    PacketTooBig,
+    /// The firewall rules of the peer deny the packet
+    AclDenied,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 11] = [
+        DropReason::InvalidTunnelPacket,
+        DropReason::NoRoute,
+        DropReason::NoEndpoint,
+        DropReason::EncapsulationError,
+        DropReason::UnknownPeer,
+        DropReason::DecapsulationError,
+        DropReason::DisallowedSourceIp,
+        DropReason::SendError,
+        DropReason::TunWriteError,
+        DropReason::PacketTooBig,
+        DropReason::AclDenied,
+    ];
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::InvalidTunnelPacket => "invalid_tunnel_packet",
+            DropReason::NoRoute => "no_route",
+            DropReason::NoEndpoint => "no_endpoint",
+            DropReason::EncapsulationError => "encapsulation_error",
+            DropReason::UnknownPeer => "unknown_peer",
+            DropReason::DecapsulationError => "decapsulation_error",
+            DropReason::DisallowedSourceIp => "disallowed_source_ip",
+            DropReason::SendError => "send_error",
+            DropReason::TunWriteError => "tun_write_error",
+            DropReason::PacketTooBig => "packet_too_big",
+            DropReason::AclDenied => "acl_denied",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<DropReason> {
+        DropReason::ALL.into_iter().find(|r| r.name() == name)
+    }
+}
+
+/// A point in time copy of drop counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DropStats {
+    counts: [u64; DropReason::ALL.len()],
+}
+
+impl DropStats {
+    #[must_use]
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    pub fn set(&mut self, reason: DropReason, count: u64) {
+        self.counts[reason as usize] = count;
+    }
+
+    /// The total number of dropped packets
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The counts of every reason, including those that are zero
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        DropReason::ALL.into_iter().zip(self.counts)
+    }
+}
+
+/// Drop counters by reason
+#[derive(Default)]
+pub struct DropCounts {
+    counts: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl DropCounts {
+    pub(crate) fn add(&self, reason: DropReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> DropStats {
+        DropStats {
+            counts: self.counts.each_ref().map(|c| c.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The variants of [`WireGuardError`], in the order they are counted
+pub(crate) const WIREGUARD_ERRORS: [&str; 16] = [
+    "destination_buffer_too_small",
+    "incorrect_packet_length",
+    "unexpected_packet",
+    "wrong_packet_type",
+    "wrong_index",
+    "wrong_key",
+    "wrong_tai64n_timestamp",
+    "invalid_mac",
+    "invalid_aead_tag",
+    "invalid_counter",
+    "duplicate_counter",
+    "invalid_packet",
+    "no_current_session",
+    "lock_failed",
+    "connection_expired",
+    "under_load",
+];
+
+fn wireguard_error_index(e: &WireGuardError) -> usize {
+    match e {
+        WireGuardError::DestinationBufferTooSmall => 0,
+        WireGuardError::IncorrectPacketLength => 1,
+        WireGuardError::UnexpectedPacket => 2,
+        WireGuardError::WrongPacketType => 3,
+        WireGuardError::WrongIndex => 4,
+        WireGuardError::WrongKey => 5,
+        WireGuardError::WrongTai64nTimestamp => 6,
+        WireGuardError::InvalidMac => 7,
+        WireGuardError::InvalidAeadTag => 8,
+        WireGuardError::InvalidCounter => 9,
+        WireGuardError::DuplicateCounter => 10,
+        WireGuardError::InvalidPacket => 11,
+        WireGuardError::NoCurrentSession => 12,
+        WireGuardError::LockFailed => 13,
+        WireGuardError::ConnectionExpired => 14,
+        WireGuardError::UnderLoad => 15,
+    }
+}
+
+/// The snake case name of a [`WireGuardError`]
+pub(crate) fn wireguard_error_name(e: &WireGuardError) -> &'static str {
+    WIREGUARD_ERRORS[wireguard_error_index(e)]
+}
+
+/// The device wide drop counters, with decapsulation errors also broken down by error
+#[derive(Default)]
+pub struct DropCounters {
+    reasons: DropCounts,
+    decapsulation_errors: [AtomicU64; WIREGUARD_ERRORS.len()],
+}
+
+impl DropCounters {
+    pub(crate) fn drop(&self, reason: DropReason) {
+        self.reasons.add(reason);
+    }
+
+    /// Count a packet from the network that failed verification or decapsulation
+    pub(crate) fn decapsulation_error(&self, e: &WireGuardError) {
+        self.reasons.add(DropReason::DecapsulationError);
+        self.decapsulation_errors[wireguard_error_index(e)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> DropStats {
+        self.reasons.stats()
+    }
+
+    /// The number of decapsulation errors of every kind, by snake case error name
+    pub fn decapsulation_errors(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        WIREGUARD_ERRORS
+            .iter()
+            .zip(&self.decapsulation_errors)
+            .map(|(name, count)| (*name, count.load(Ordering::Relaxed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_reason_and_error() {
+        let counters = DropCounters::default();
+        counters.drop(DropReason::NoRoute);
+        counters.drop(DropReason::NoRoute);
+        counters.decapsulation_error(&WireGuardError::InvalidMac);
+        counters.decapsulation_error(&WireGuardError::UnderLoad);
+
+        let stats = counters.stats();
+        assert_eq!(stats.get(DropReason::NoRoute), 2);
+        assert_eq!(stats.get(DropReason::DecapsulationError), 2);
+        assert_eq!(stats.get(DropReason::DisallowedSourceIp), 0);
+        assert_eq!(stats.total(), 4);
+        let errors: Vec<_> = counters
+            .decapsulation_errors()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        assert_eq!(errors, [("invalid_mac", 1), ("under_load", 1)]);
+    }
+
+    #[test]
+    fn reason_names_round_trip() {
+        for reason in DropReason::ALL {
+            assert_eq!(DropReason::from_name(reason.name()), Some(reason));
+        }
+        assert_eq!(DropReason::from_name("bogus"), None);
+    }
+}
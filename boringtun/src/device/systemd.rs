@@ -0,0 +1,174 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! systemd integration: readiness, status and watchdog notifications for `Type=notify`
+//! services, and api sockets passed by socket activation.
+//!
+//! Everything here is a no-op outside of systemd, when the environment variables it sets are
+//! missing or meant for another process.
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, io::RawFd, net::UnixDatagram},
+    process,
+    time::Duration,
+};
+
+use super::{Action, Device, Error};
+
+/// The first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How often the status is reported when the watchdog is disabled
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Peers with a handshake this recent have a usable session
+const SESSION_TIMEOUT: Duration = Duration::from_mins(3);
+
+/// Whether the service manager expects notifications from this process
+#[must_use]
+pub fn notify_enabled() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Send a notification such as `READY=1` or `STATUS=...` to the service manager. Returns
+/// `false` when not running under systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        [b'/', ..] => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+        _ => return Err(io::ErrorKind::InvalidInput.into()),
+    }
+    Ok(())
+}
+
+/// The watchdog timeout of the service, if it is enabled for this process
+#[must_use]
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse() != Ok(own_pid)
+    {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// The sockets passed to this process by socket activation, marked close-on-exec. Must be
+/// called before forking, as they are passed to the pid systemd started.
+#[must_use]
+pub fn listen_fds() -> Vec<RawFd> {
+    let fds = parse_listen_fds(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        process::id(),
+    );
+    for &fd in &fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    fds
+}
+
+fn parse_listen_fds(count: Option<&str>, pid: Option<&str>, own_pid: u32) -> Vec<RawFd> {
+    if pid.and_then(|p| p.parse().ok()) != Some(own_pid) {
+        return Vec::new();
+    }
+    let count = count.and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)).collect()
+}
+
+impl Device {
+    /// Ping the watchdog, if enabled, and report the number of peers to the service manager
+    pub(super) fn register_systemd_notifier(&self) -> Result<(), Error> {
+        let watchdog = watchdog_interval();
+        // Ping twice per timeout, as systemd recommends
+        let period = watchdog.map_or(STATUS_INTERVAL, |w| (w / 2).min(STATUS_INTERVAL));
+
+        self.queue.new_periodic_event(
+            Box::new(move |d, _| {
+                let connected = d
+                    .peers
+                    .values()
+                    .filter(|p| {
+                        p.lock()
+                            .time_since_last_handshake()
+                            .is_some_and(|t| t < SESSION_TIMEOUT)
+                    })
+                    .count();
+                let mut state = format!("STATUS={} peers, {connected} connected", d.peers.len());
+                if watchdog.is_some() {
+                    state.push_str("\nWATCHDOG=1");
+                }
+                if let Err(e) = notify(&state) {
+                    tracing::warn!(message = "Failed to notify the service manager", error = ?e);
+                }
+                Action::Continue
+            }),
+            period,
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_socket_activation() {
+        assert_eq!(parse_listen_fds(Some("2"), Some("42"), 42), vec![3, 4]);
+        assert!(parse_listen_fds(Some("2"), Some("41"), 42).is_empty());
+        assert!(parse_listen_fds(Some("2"), None, 42).is_empty());
+        assert!(parse_listen_fds(None, Some("42"), 42).is_empty());
+    }
+
+    #[test]
+    fn parses_watchdog() {
+        let interval = Some(Duration::from_secs(30));
+        assert_eq!(parse_watchdog(Some("30000000"), None, 42), interval);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("42"), 42), interval);
+        assert_eq!(parse_watchdog(Some("30000000"), Some("41"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[test]
+    fn sends_notifications() {
+        let dir = std::env::temp_dir().join(format!("boringtun-notify-{}", process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let socket = UnixDatagram::bind(&dir).unwrap();
+        notify_to(dir.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&dir).unwrap();
+
+        assert!(notify_to(OsStr::new("relative"), "READY=1").is_err());
+    }
+}
@@ -8,9 +8,13 @@ use std::{
 
 use libc::*;
 
-use super::Error;
+use super::{Error, offload::VNET_HDR_LEN};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const TUN_F_CSUM: c_ulong = 0x01;
+const TUN_F_TSO4: c_ulong = 0x02;
+const TUN_F_TSO6: c_ulong = 0x04;
 
 #[repr(C)]
 union IfrIfru {
@@ -44,6 +48,8 @@ pub struct ifreq {
 pub struct TunSocket {
     fd: RawFd,
     name: String,
+    /// Packets start with a `virtio_net_hdr`, see [`TunSocket::new_with_offload`]
+    vnet_hdr: bool,
 }
 
 impl Drop for TunSocket {
@@ -60,6 +66,25 @@ impl AsRawFd for TunSocket {
 
 impl TunSocket {
     fn write(&self, buf: &[u8]) -> usize {
+        if self.vnet_hdr {
+            // A packet that needs no offloads
+            let hdr = [0u8; VNET_HDR_LEN];
+            let iov = [
+                iovec {
+                    iov_base: hdr.as_ptr() as _,
+                    iov_len: hdr.len(),
+                },
+                iovec {
+                    iov_base: buf.as_ptr() as _,
+                    iov_len: buf.len(),
+                },
+            ];
+            return match unsafe { writev(self.fd, iov.as_ptr(), iov.len() as _) } {
+                -1 => 0,
+                n => (n as usize).saturating_sub(VNET_HDR_LEN),
+            };
+        }
+
         match unsafe { write(self.fd, buf.as_ptr() as _, buf.len() as _) } {
             -1 => 0,
             n => n as usize,
@@ -67,15 +92,32 @@ impl TunSocket {
     }
 
     pub fn new(name: &str) -> Result<TunSocket, Error> {
+        Self::open(name, false)
+    }
+
+    /// Open the interface with `IFF_VNET_HDR`, and let the kernel pass TCP super-packets of up
+    /// to 64KB and packets with partial checksums in both directions. Every packet read starts
+    /// with a `virtio_net_hdr`, and [`TunSocket::read`] must be given a large enough buffer.
+    pub fn new_with_offload(name: &str) -> Result<TunSocket, Error> {
+        Self::open(name, true)
+    }
+
+    fn open(name: &str, offload: bool) -> Result<TunSocket, Error> {
         // If the provided name appears to be a FD, use that.
         let provided_fd = name.parse::<i32>();
         if let Ok(fd) = provided_fd {
             return Ok(TunSocket {
                 fd,
                 name: name.to_string(),
+                vnet_hdr: false,
             });
         }
 
+        let mut flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
+
         let fd = match unsafe { open(c"/dev/net/tun".as_ptr(), O_RDWR) } {
             -1 => return Err(Error::Socket(io::Error::last_os_error())),
             fd => fd,
@@ -84,7 +126,7 @@ impl TunSocket {
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
+                ifru_flags: flags as _,
             },
         };
 
@@ -98,8 +140,32 @@ impl TunSocket {
             return Err(Error::IOCtl(io::Error::last_os_error()));
         }
 
+        if offload {
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+            if unsafe { ioctl(fd, TUNSETOFFLOAD as _, offloads) } < 0 {
+                return Err(Error::IOCtl(io::Error::last_os_error()));
+            }
+        }
+
         let name = name.to_string();
-        Ok(TunSocket { fd, name })
+        Ok(TunSocket {
+            fd,
+            name,
+            vnet_hdr: offload,
+        })
+    }
+
+    /// Whether packets start with a `virtio_net_hdr`
+    pub fn vnet_hdr(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    /// Write a packet that starts with its `virtio_net_hdr`
+    pub(crate) fn write_with_vnet_hdr(&self, buf: &[u8]) -> bool {
+        match unsafe { write(self.fd, buf.as_ptr() as _, buf.len() as _) } {
+            -1 => false,
+            n => n as usize == buf.len(),
+        }
     }
 
     pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
@@ -0,0 +1,1112 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The io_uring backend of [`super::poll::EventPoll`], see
+//! [`super::DeviceConfig::use_io_uring`].
+//!
+//! Events are armed with oneshot polls, like `EPOLLONESHOT`, except for the UDP sockets and the
+//! tunnel interface, which get multishot receives into a ring of buffers registered with the
+//! kernel. The data then arrives with the completions, and the handlers don't need to read it
+//! themselves. Polls and receives are re-armed by queueing them in the submission ring, and they
+//! are submitted together by the next thread that waits for completions, in the same syscall.
+//! Submissions that don't fit in the ring wait in a backlog, as queueing them must not block:
+//! it happens with the state locked, also while reaping the completions that make room.
+//! Where the kernel lacks multishot receives or buffer rings, those fall back to polls.
+
+use std::{
+    collections::VecDeque,
+    io,
+    os::unix::io::RawFd,
+    ptr,
+    sync::atomic::{AtomicU16, AtomicU32, Ordering},
+};
+
+use libc::*;
+use parking_lot::Mutex;
+use socket2::SockAddr;
+
+const IORING_OFF_SQ_RING: off_t = 0;
+const IORING_OFF_CQ_RING: off_t = 0x800_0000;
+const IORING_OFF_SQES: off_t = 0x1000_0000;
+const IORING_SETUP_CQSIZE: u32 = 1 << 3;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_ENTER_GETEVENTS: c_uint = 1 << 0;
+const IORING_REGISTER_PBUF_RING: c_uint = 22;
+
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_RECVMSG: u8 = 10;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_READ_MULTISHOT: u8 = 49;
+const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+const IORING_CQE_F_MORE: u32 = 1 << 1;
+const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+const SQ_ENTRIES: u32 = 256;
+const CQ_ENTRIES: u32 = 4096;
+/// The number of receive buffers, a power of two
+const BUF_ENTRIES: u16 = 128;
+/// Room for the largest datagram or TUN packet, with the headers of multishot `recvmsg`
+const BUF_SIZE: usize = 69632;
+const BUF_GROUP: u16 = 0;
+/// Reserved in each buffer for the source address of a datagram
+const NAME_LEN: usize = size_of::<sockaddr_storage>();
+/// Reserved in each buffer for control messages, with the `UDP_GRO` segment size
+const CONTROL_LEN: usize = 64;
+/// `struct io_uring_recvmsg_out`
+const RECVMSG_OUT_LEN: usize = 16;
+/// Received buffers handed to a handler at once, fewer than it handles per call
+const MAX_COMPLETIONS: usize = 64;
+/// Completions of cancellations are not for any event
+const CANCEL_KEY: u64 = 0;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// `struct io_uring_sqe`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_group: u16,
+    personality: u16,
+    file_index: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// `struct io_uring_cqe`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// `struct io_uring_buf_reg`
+#[repr(C)]
+struct BufReg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+/// `struct io_uring_buf`
+#[repr(C)]
+struct Buf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    resv: u16,
+}
+
+/// A memory mapping, unmapped on drop
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, offset: off_t, len: usize) -> io::Result<Mmap> {
+        let (flags, fd) = match fd {
+            -1 => (MAP_PRIVATE | MAP_ANONYMOUS, -1),
+            fd => (MAP_SHARED | MAP_POPULATE, fd),
+        };
+        match unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        } {
+            MAP_FAILED => Err(io::Error::last_os_error()),
+            ptr => Ok(Mmap {
+                ptr: ptr.cast(),
+                len,
+            }),
+        }
+    }
+
+    /// A pointer at the offset into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + size_of::<T>() <= self.len);
+        unsafe { self.ptr.add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// The submission and completion rings shared with the kernel. Submissions and completions must
+/// each be serialized by the caller.
+struct Ring {
+    fd: RawFd,
+    _sq_ring: Mmap,
+    _cq_ring: Option<Mmap>,
+    sqes: Mmap,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let mut params = Params {
+            flags: IORING_SETUP_CQSIZE,
+            cq_entries: CQ_ENTRIES,
+            ..Default::default()
+        };
+        let fd = match unsafe { syscall(SYS_io_uring_setup, SQ_ENTRIES, &raw mut params) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => fd as RawFd,
+        };
+
+        // Close the ring if mapping it fails
+        let close_on_error = |e| {
+            unsafe { close(fd) };
+            e
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let single_mmap = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq_ring = match single_mmap {
+            true => Mmap::new(fd, IORING_OFF_SQ_RING, sq_len.max(cq_len)),
+            false => Mmap::new(fd, IORING_OFF_SQ_RING, sq_len),
+        }
+        .map_err(close_on_error)?;
+        let cq_ring = match single_mmap {
+            true => None,
+            false => Some(Mmap::new(fd, IORING_OFF_CQ_RING, cq_len).map_err(close_on_error)?),
+        };
+        let sqes = Mmap::new(
+            fd,
+            IORING_OFF_SQES,
+            params.sq_entries as usize * size_of::<Sqe>(),
+        )
+        .map_err(close_on_error)?;
+
+        let cq = cq_ring.as_ref().unwrap_or(&sq_ring);
+        Ok(Ring {
+            fd,
+            sq_head: sq_ring.at(params.sq_off.head),
+            sq_tail: sq_ring.at(params.sq_off.tail),
+            sq_mask: unsafe { *sq_ring.at::<u32>(params.sq_off.ring_mask) },
+            sq_entries: params.sq_entries,
+            sq_array: sq_ring.at(params.sq_off.array),
+            cq_head: cq.at(params.cq_off.head),
+            cq_tail: cq.at(params.cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(params.cq_off.ring_mask) },
+            cqes: cq.at(params.cq_off.cqes),
+            _sq_ring: sq_ring,
+            _cq_ring: cq_ring,
+            sqes,
+        })
+    }
+
+    /// Queue a submission, returns false if the submission ring is full
+    fn push(&self, sqe: Sqe) -> bool {
+        let (head, tail) = unsafe {
+            (
+                (*self.sq_head).load(Ordering::Acquire),
+                (*self.sq_tail).load(Ordering::Relaxed),
+            )
+        };
+        if tail.wrapping_sub(head) == self.sq_entries {
+            return false;
+        }
+        let index = tail & self.sq_mask;
+        unsafe {
+            *self.sqes.at::<Sqe>(index * size_of::<Sqe>() as u32) = sqe;
+            *self.sq_array.add(index as usize) = index;
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        true
+    }
+
+    /// The number of submissions in the ring that the kernel hasn't consumed yet
+    fn pending(&self) -> u32 {
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            tail.wrapping_sub((*self.sq_head).load(Ordering::Acquire))
+        }
+    }
+
+    /// Submit queued submissions, and wait for at least `min_complete` completions
+    fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<()> {
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        loop {
+            let ret = unsafe {
+                syscall(
+                    SYS_io_uring_enter,
+                    self.fd,
+                    to_submit,
+                    min_complete,
+                    flags,
+                    ptr::null::<c_void>(),
+                    0usize,
+                )
+            };
+            if ret >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => {}
+                // The completion ring is full, the caller must reap completions first
+                _ if e.raw_os_error() == Some(EBUSY) => return Ok(()),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<Cqe> {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            if head == (*self.cq_tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = *self.cqes.add((head & self.cq_mask) as usize);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(cqe)
+        }
+    }
+}
+
+/// Buffers registered with the kernel, which picks one for every multishot receive
+struct BufRing {
+    ring: Mmap,
+    bufs: Mmap,
+    tail: u16,
+}
+
+impl BufRing {
+    fn new(ring: &Ring) -> io::Result<BufRing> {
+        let entries = usize::from(BUF_ENTRIES);
+        let mut bufs = BufRing {
+            ring: Mmap::new(-1, 0, entries * size_of::<Buf>())?,
+            bufs: Mmap::new(-1, 0, entries * BUF_SIZE)?,
+            tail: 0,
+        };
+
+        let reg = BufReg {
+            ring_addr: bufs.ring.ptr as u64,
+            ring_entries: u32::from(BUF_ENTRIES),
+            bgid: BUF_GROUP,
+            flags: 0,
+            resv: [0; 3],
+        };
+        let ret = unsafe {
+            syscall(
+                SYS_io_uring_register,
+                ring.fd,
+                IORING_REGISTER_PBUF_RING,
+                &raw const reg,
+                1,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for bid in 0..BUF_ENTRIES {
+            bufs.recycle(bid);
+        }
+        bufs.publish();
+        Ok(bufs)
+    }
+
+    fn buf(&self, bid: u16) -> *const u8 {
+        unsafe { self.bufs.ptr.add(usize::from(bid) * BUF_SIZE) }
+    }
+
+    /// Give a buffer back to the kernel, once published
+    fn recycle(&mut self, bid: u16) {
+        let index = u32::from(self.tail & (BUF_ENTRIES - 1)) * size_of::<Buf>() as u32;
+        unsafe {
+            let buf = self.ring.at::<Buf>(index);
+            (*buf).addr = self.buf(bid) as u64;
+            (*buf).len = BUF_SIZE as u32;
+            (*buf).bid = bid;
+        }
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        // The tail overlaps the reserved field of the first buffer
+        let tail = self.ring.at::<AtomicU16>(14);
+        unsafe { (*tail).store(self.tail, Ordering::Release) };
+    }
+}
+
+/// What data a read event receives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadKind {
+    /// Datagrams, with their source address, from a UDP socket
+    Datagram,
+    /// Packets read from a tunnel interface
+    Packet,
+}
+
+/// Data received for the handler of a read event, borrowed from the buffer ring until the
+/// event guard is dropped
+#[derive(Clone)]
+pub struct Received {
+    ptr: *const u8,
+    len: usize,
+    /// The source of a datagram
+    pub addr: Option<SockAddr>,
+    /// The size of the datagrams coalesced with `UDP_GRO`, or the length
+    pub segment: usize,
+}
+
+impl Received {
+    /// The received bytes
+    ///
+    /// # Safety
+    ///
+    /// The event guard that returned this must still be alive.
+    pub unsafe fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// A completed receive, holding one of the buffers
+#[derive(Clone, Copy)]
+pub(super) struct Completion {
+    bid: u16,
+    len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Arm {
+    /// A oneshot poll for the events
+    Poll(u32),
+    /// A multishot receive
+    Recv(ReadKind),
+}
+
+struct Slot {
+    key: u64,
+    fd: RawFd,
+    arm: Arm,
+    /// Notifiers are handed to every waiting thread in turn, so they are never busy
+    notifier: bool,
+    /// The poll events reported by the last completion
+    revents: u32,
+    /// Received data not handed to a handler yet
+    pending: Vec<Completion>,
+    /// A thread is running the handler
+    busy: bool,
+    /// In the ready queue
+    queued: bool,
+    /// The multishot receive is still active
+    armed: bool,
+    /// Ran out of buffers, rearm when some are recycled
+    starved: bool,
+    /// Whether a multishot receive completed with data, to fall back to polling if not
+    received: bool,
+    msghdr: Box<msghdr>,
+}
+
+#[derive(Default)]
+struct State {
+    slots: Vec<Option<Slot>>,
+    ready: VecDeque<u64>,
+}
+
+impl State {
+    fn slot(&mut self, key: u64) -> Option<&mut Slot> {
+        self.slots
+            .get_mut(key_fd(key) as usize)
+            .and_then(Option::as_mut)
+            .filter(|slot| slot.key == key)
+    }
+
+    fn queue(&mut self, key: u64) {
+        if let Some(slot) = self.slot(key)
+            && !slot.queued
+            && (!slot.busy || slot.notifier)
+        {
+            slot.queued = true;
+            self.ready.push_back(key);
+        }
+    }
+}
+
+fn key_fd(key: u64) -> RawFd {
+    key as u32 as RawFd
+}
+
+/// An event handed to a waiting thread
+pub(super) struct Ready {
+    pub(super) key: u64,
+    pub(super) fd: RawFd,
+    pub(super) revents: u32,
+    pub(super) completions: Option<Vec<Completion>>,
+}
+
+pub(super) struct Uring {
+    ring: Ring,
+    /// Serializes submissions, and holds those that did not fit in the submission ring
+    sq: Mutex<VecDeque<Sqe>>,
+    /// Held by the thread reaping completions
+    cq: Mutex<()>,
+    state: Mutex<State>,
+    bufs: Option<Mutex<BufRing>>,
+    generation: AtomicU32,
+}
+
+// The ring memory is only accessed under the locks above
+unsafe impl Send for Uring {}
+unsafe impl Sync for Uring {}
+unsafe impl Send for Received {}
+
+impl Uring {
+    pub(super) fn new() -> io::Result<Uring> {
+        let ring = Ring::new()?;
+        let bufs = match BufRing::new(&ring) {
+            Ok(bufs) => Some(Mutex::new(bufs)),
+            Err(e) => {
+                tracing::warn!(message = "No io_uring buffer rings, polling instead", error = ?e);
+                None
+            }
+        };
+        Ok(Uring {
+            ring,
+            sq: Mutex::new(VecDeque::new()),
+            cq: Mutex::new(()),
+            state: Mutex::new(State::default()),
+            bufs,
+            generation: AtomicU32::new(1),
+        })
+    }
+
+    /// Add an event for the fd and arm it, replacing any previous one. With `kind`, data is
+    /// received with multishot receives where supported.
+    pub(super) fn add(
+        &self,
+        fd: RawFd,
+        events: u32,
+        notifier: bool,
+        kind: Option<ReadKind>,
+    ) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let key = (u64::from(generation) << 32) | u64::from(fd as u32);
+        let arm = match kind {
+            Some(kind) if self.bufs.is_some() => Arm::Recv(kind),
+            _ => Arm::Poll(events),
+        };
+
+        let mut msghdr: Box<msghdr> = Box::new(unsafe { std::mem::zeroed() });
+        msghdr.msg_namelen = NAME_LEN as _;
+        msghdr.msg_controllen = CONTROL_LEN as _;
+        let slot = Slot {
+            key,
+            fd,
+            arm,
+            notifier,
+            revents: 0,
+            pending: Vec::new(),
+            busy: false,
+            queued: false,
+            armed: false,
+            starved: false,
+            received: false,
+            msghdr,
+        };
+
+        let mut state = self.state.lock();
+        let index = fd as usize;
+        if state.slots.len() <= index {
+            state.slots.resize_with(index + 1, || None);
+        }
+        if let Some(old) = state.slots[index].take() {
+            self.cancel(old);
+        }
+        state.slots[index] = Some(slot);
+        self.arm(state.slots[index].as_mut().unwrap());
+        key
+    }
+
+    /// Remove the event, if it is still registered
+    pub(super) fn remove(&self, fd: RawFd) {
+        let mut state = self.state.lock();
+        if let Some(slot) = state.slots.get_mut(fd as usize).and_then(Option::take) {
+            self.cancel(slot);
+        }
+    }
+
+    fn cancel(&self, slot: Slot) {
+        self.recycle(&slot.pending);
+        self.submit(Sqe {
+            opcode: IORING_OP_ASYNC_CANCEL,
+            addr: slot.key,
+            user_data: CANCEL_KEY,
+            ..Default::default()
+        });
+    }
+
+    fn arm(&self, slot: &mut Slot) {
+        let mut sqe = Sqe {
+            fd: slot.fd,
+            user_data: slot.key,
+            ..Default::default()
+        };
+        match slot.arm {
+            Arm::Poll(events) => {
+                sqe.opcode = IORING_OP_POLL_ADD;
+                sqe.op_flags = events;
+            }
+            Arm::Recv(ReadKind::Datagram) => {
+                sqe.opcode = IORING_OP_RECVMSG;
+                sqe.addr = &raw const *slot.msghdr as u64;
+                sqe.len = 1;
+                sqe.ioprio = IORING_RECV_MULTISHOT;
+                sqe.flags = IOSQE_BUFFER_SELECT;
+                sqe.buf_group = BUF_GROUP;
+                slot.armed = true;
+            }
+            Arm::Recv(ReadKind::Packet) => {
+                sqe.opcode = IORING_OP_READ_MULTISHOT;
+                sqe.flags = IOSQE_BUFFER_SELECT;
+                sqe.buf_group = BUF_GROUP;
+                slot.armed = true;
+            }
+        }
+        self.submit(sqe);
+    }
+
+    /// Queue a submission, to be submitted with the next wait
+    fn submit(&self, sqe: Sqe) {
+        let mut backlog = self.sq.lock();
+        // Behind the backlog, to keep the order
+        if !backlog.is_empty() || !self.ring.push(sqe) {
+            backlog.push_back(sqe);
+        }
+    }
+
+    /// Submit queued submissions, and wait for at least `min_complete` completions. Returns
+    /// early when the completion ring is full, so completions can be reaped first.
+    fn flush(&self, min_complete: u32) -> io::Result<()> {
+        let mut backlog = self.sq.lock();
+        loop {
+            while let Some(&sqe) = backlog.front()
+                && self.ring.push(sqe)
+            {
+                backlog.pop_front();
+            }
+            let queued = self.ring.pending();
+            if backlog.is_empty() {
+                // Other threads can queue submissions while this one waits
+                drop(backlog);
+                return match (queued, min_complete) {
+                    (0, 0) => Ok(()),
+                    _ => self.ring.enter(queued, min_complete),
+                };
+            }
+            self.ring.enter(queued, 0)?;
+            if self.ring.pending() == queued {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait for an event to be ready, and mark it as handled by the calling thread
+    pub(super) fn wait(&self) -> io::Result<Ready> {
+        loop {
+            if let Some(ready) = self.pop_ready() {
+                return Ok(ready);
+            }
+
+            let cq = match self.cq.try_lock() {
+                Some(cq) => cq,
+                None => {
+                    // Another thread waits for completions, it may need our submissions
+                    self.flush(0)?;
+                    self.cq.lock()
+                }
+            };
+            if self.reap() {
+                continue;
+            }
+            self.flush(1)?;
+            self.reap();
+            drop(cq);
+        }
+    }
+
+    fn pop_ready(&self) -> Option<Ready> {
+        let mut state = self.state.lock();
+        while let Some(key) = state.ready.pop_front() {
+            let Some(slot) = state.slot(key) else {
+                continue;
+            };
+            slot.queued = false;
+            slot.busy = !slot.notifier;
+            let completions = match slot.arm {
+                Arm::Recv(_) => {
+                    let n = slot.pending.len().min(MAX_COMPLETIONS);
+                    Some(slot.pending.drain(..n).collect())
+                }
+                Arm::Poll(_) => None,
+            };
+            return Some(Ready {
+                key,
+                fd: slot.fd,
+                revents: slot.revents,
+                completions,
+            });
+        }
+        None
+    }
+
+    /// Process all completions, returns true if any events became ready
+    fn reap(&self) -> bool {
+        let mut state = self.state.lock();
+        let mut ready = false;
+        while let Some(cqe) = self.ring.pop() {
+            ready |= self.complete(&mut state, cqe);
+        }
+        ready || !state.ready.is_empty()
+    }
+
+    /// Process a completion, returns true if its event became ready
+    fn complete(&self, state: &mut State, cqe: Cqe) -> bool {
+        let buffer = (cqe.flags & IORING_CQE_F_BUFFER != 0)
+            .then_some((cqe.flags >> IORING_CQE_BUFFER_SHIFT) as u16);
+        let Some(slot) = state.slot(cqe.user_data) else {
+            // A cancellation, or an event that was removed
+            if let Some(bid) = buffer {
+                self.recycle(&[Completion { bid, len: 0 }]);
+            }
+            return false;
+        };
+
+        match slot.arm {
+            Arm::Poll(_) if cqe.res == -ECANCELED => return false,
+            // A failed poll reports the error as a negative result
+            Arm::Poll(_) if cqe.res < 0 => slot.revents = POLLERR as u32,
+            Arm::Poll(_) => slot.revents = cqe.res as u32,
+            Arm::Recv(kind) => {
+                if let Some(bid) = buffer {
+                    let len = cqe.res.max(0) as usize;
+                    slot.pending.push(Completion { bid, len });
+                    slot.received = true;
+                }
+                if cqe.flags & IORING_CQE_F_MORE == 0 {
+                    slot.armed = false;
+                    match -cqe.res {
+                        ECANCELED => return false,
+                        ENOBUFS => slot.starved = true,
+                        EINVAL | EOPNOTSUPP | ENOTSUP if !slot.received => {
+                            tracing::warn!(
+                                message = "No io_uring multishot receives, polling instead",
+                                ?kind
+                            );
+                            slot.arm = Arm::Poll(POLLIN as u32);
+                            self.recycle(&std::mem::take(&mut slot.pending));
+                            self.arm(slot);
+                            return false;
+                        }
+                        _ if !slot.busy => self.arm(slot),
+                        // Rearmed once the handler is done
+                        _ => {}
+                    }
+                }
+                if slot.pending.is_empty() {
+                    return false;
+                }
+            }
+        }
+        state.queue(cqe.user_data);
+        true
+    }
+
+    /// The handler of an event returned by [`Uring::wait`] is done, rearm it
+    pub(super) fn release(&self, key: u64, events: u32, completions: &[Completion]) {
+        self.recycle(completions);
+
+        let mut state = self.state.lock();
+        let Some(slot) = state.slot(key) else {
+            return;
+        };
+        slot.busy = false;
+        match slot.arm {
+            Arm::Poll(_) => {
+                slot.arm = Arm::Poll(events);
+                self.arm(slot);
+            }
+            Arm::Recv(_) => {
+                if !slot.armed && !slot.starved {
+                    self.arm(slot);
+                }
+                if !slot.pending.is_empty() {
+                    state.queue(key);
+                }
+            }
+        }
+
+        if !completions.is_empty() {
+            // Receives that ran out of buffers can go on
+            for slot in state.slots.iter_mut().flatten() {
+                if slot.starved && !slot.busy {
+                    slot.starved = false;
+                    self.arm(slot);
+                }
+            }
+        }
+    }
+
+    fn recycle(&self, completions: &[Completion]) {
+        if completions.is_empty() {
+            return;
+        }
+        let Some(ref bufs) = self.bufs else {
+            return;
+        };
+        let mut bufs = bufs.lock();
+        for completion in completions {
+            bufs.recycle(completion.bid);
+        }
+        bufs.publish();
+    }
+
+    /// The data in the buffers of the completions
+    pub(super) fn received(&self, kind: ReadKind, completions: &[Completion]) -> Vec<Received> {
+        let Some(ref bufs) = self.bufs else {
+            return Vec::new();
+        };
+        let bufs = bufs.lock();
+        completions
+            .iter()
+            .filter_map(|completion| {
+                let buf = bufs.buf(completion.bid);
+                match kind {
+                    ReadKind::Packet => Some(Received {
+                        ptr: buf,
+                        len: completion.len,
+                        addr: None,
+                        segment: completion.len,
+                    }),
+                    ReadKind::Datagram => unsafe { parse_recvmsg(buf, completion.len) },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parse the `struct io_uring_recvmsg_out` layout of a multishot `recvmsg` buffer
+///
+/// # Safety
+///
+/// The buffer must hold `len` bytes written by the kernel.
+unsafe fn parse_recvmsg(buf: *const u8, len: usize) -> Option<Received> {
+    let payload_start = RECVMSG_OUT_LEN + NAME_LEN + CONTROL_LEN;
+    if len < payload_start {
+        return None;
+    }
+    let field = |i: usize| unsafe { ptr::read_unaligned(buf.add(i * 4).cast::<u32>()) } as usize;
+    let (namelen, controllen, payloadlen, flags) = (field(0), field(1), field(2), field(3));
+    if flags & MSG_TRUNC as usize != 0 || namelen > NAME_LEN || payloadlen != len - payload_start {
+        return None;
+    }
+
+    let (_, addr) = unsafe {
+        SockAddr::try_init(|storage, addr_len| {
+            ptr::copy_nonoverlapping(buf.add(RECVMSG_OUT_LEN), storage.cast(), namelen);
+            *addr_len = namelen as _;
+            Ok(())
+        })
+    }
+    .ok()?;
+
+    // Find the segment size in the control messages, like for recvmsg
+    let mut msg: msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_control = unsafe { buf.add(RECVMSG_OUT_LEN + NAME_LEN) } as *mut _;
+    msg.msg_controllen = controllen.min(CONTROL_LEN) as _;
+    let segment = super::batch::gro_segment(&msg).unwrap_or(payloadlen);
+
+    Some(Received {
+        ptr: unsafe { buf.add(payload_start) },
+        len: payloadlen,
+        addr: Some(addr),
+        segment: segment.max(1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn receives_datagrams() {
+        let Ok(uring) = Uring::new() else {
+            // io_uring may be disabled
+            return;
+        };
+        if uring.bufs.is_none() {
+            return;
+        }
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let key = uring.add(
+            receiver.as_raw_fd(),
+            POLLIN as u32,
+            false,
+            Some(ReadKind::Datagram),
+        );
+        sender
+            .send_to(b"hello", receiver.local_addr().unwrap())
+            .unwrap();
+        sender
+            .send_to(b"world!", receiver.local_addr().unwrap())
+            .unwrap();
+
+        let mut data = Vec::new();
+        while data.len() < 2 {
+            let ready = uring.wait().unwrap();
+            assert_eq!(ready.key, key);
+            let completions = ready.completions.unwrap();
+            for received in uring.received(ReadKind::Datagram, &completions) {
+                assert_eq!(
+                    received.addr.as_ref().and_then(SockAddr::as_socket),
+                    Some(sender.local_addr().unwrap())
+                );
+                data.push(unsafe { received.data() }.to_vec());
+            }
+            uring.release(key, POLLIN as u32, &completions);
+        }
+        assert_eq!(data, [b"hello".to_vec(), b"world!".to_vec()]);
+
+        uring.remove(receiver.as_raw_fd());
+    }
+
+    /// A UDP socket receiving on the uring, and one sending to it
+    fn sockets() -> (UdpSocket, UdpSocket) {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        (receiver, sender)
+    }
+
+    #[test]
+    fn submissions_wait_for_room_in_the_ring() {
+        let Ok(uring) = Uring::new() else {
+            return;
+        };
+        let nop = Sqe {
+            user_data: CANCEL_KEY,
+            ..Default::default()
+        };
+
+        // Like rearming while reaping, which must not wait for the kernel
+        let state = uring.state.lock();
+        for _ in 0..3 * SQ_ENTRIES {
+            uring.submit(nop);
+        }
+        assert_eq!(uring.sq.lock().len(), 2 * SQ_ENTRIES as usize);
+        drop(state);
+
+        uring.flush(0).unwrap();
+        assert!(uring.sq.lock().is_empty());
+        assert_eq!(uring.ring.pending(), 0);
+        let mut completed = 0;
+        while uring.ring.pop().is_some() {
+            completed += 1;
+        }
+        assert_eq!(completed, 3 * SQ_ENTRIES);
+    }
+
+    #[test]
+    fn rearms_after_running_out_of_buffers() {
+        let Ok(uring) = Uring::new() else {
+            return;
+        };
+        if uring.bufs.is_none() {
+            return;
+        }
+        let (receiver, sender) = sockets();
+        let count = usize::from(BUF_ENTRIES) + 32;
+        for i in 0..count {
+            sender.send(&i.to_ne_bytes()).unwrap();
+        }
+
+        let key = uring.add(
+            receiver.as_raw_fd(),
+            POLLIN as u32,
+            false,
+            Some(ReadKind::Datagram),
+        );
+        let mut received = 0;
+        let mut starved = false;
+        while received < count {
+            let ready = uring.wait().unwrap();
+            assert_eq!(ready.key, key);
+            starved |= uring.state.lock().slot(key).unwrap().starved;
+            let completions = ready.completions.unwrap();
+            received += uring.received(ReadKind::Datagram, &completions).len();
+            uring.release(key, POLLIN as u32, &completions);
+        }
+        assert!(starved);
+        assert_eq!(received, count);
+
+        uring.remove(receiver.as_raw_fd());
+    }
+
+    #[test]
+    fn remove_cancels_the_receive() {
+        let Ok(uring) = Uring::new() else {
+            return;
+        };
+        let (removed, to_removed) = sockets();
+        let (receiver, sender) = sockets();
+        let kind = Some(ReadKind::Datagram);
+        uring.add(removed.as_raw_fd(), POLLIN as u32, false, kind);
+        uring.remove(removed.as_raw_fd());
+        let key = uring.add(receiver.as_raw_fd(), POLLIN as u32, false, kind);
+
+        for _ in 0..2 {
+            sender.send(b"hello").unwrap();
+            let ready = uring.wait().unwrap();
+            assert_eq!(ready.key, key);
+            uring.release(key, POLLIN as u32, &ready.completions.unwrap_or_default());
+            // Sent once the cancellation was submitted with the first wait
+            to_removed.send(b"hello").unwrap();
+        }
+
+        // Left for the socket, not taken by a receive
+        let mut buf = [0u8; 16];
+        assert_eq!(removed.recv(&mut buf).unwrap(), 5);
+        assert_eq!(removed.recv(&mut buf).unwrap(), 5);
+        assert!(uring.state.lock().slot(key).is_some());
+
+        uring.remove(receiver.as_raw_fd());
+    }
+
+    #[test]
+    fn falls_back_to_polling() {
+        let Ok(mut uring) = Uring::new() else {
+            return;
+        };
+        let (receiver, sender) = sockets();
+        let kind = Some(ReadKind::Datagram);
+
+        // Without buffer rings
+        let bufs = uring.bufs.take();
+        let key = uring.add(receiver.as_raw_fd(), POLLIN as u32, false, kind);
+        sender.send(b"hello").unwrap();
+        let ready = uring.wait().unwrap();
+        assert_eq!((ready.key, ready.revents), (key, POLLIN as u32));
+        assert!(ready.completions.is_none());
+        assert_eq!(receiver.recv(&mut [0u8; 16]).unwrap(), 5);
+        uring.remove(receiver.as_raw_fd());
+        uring.bufs = bufs;
+        if uring.bufs.is_none() {
+            return;
+        }
+
+        // Without multishot receives, which fail before receiving anything. The receive is
+        // cancelled, as the kernel would end it.
+        let key = uring.add(receiver.as_raw_fd(), POLLIN as u32, false, kind);
+        uring.submit(Sqe {
+            opcode: IORING_OP_ASYNC_CANCEL,
+            addr: key,
+            user_data: CANCEL_KEY,
+            ..Default::default()
+        });
+        let unsupported = Cqe {
+            user_data: key,
+            res: -EINVAL,
+            flags: 0,
+        };
+        assert!(!uring.complete(&mut uring.state.lock(), unsupported));
+        assert!(uring.state.lock().slot(key).unwrap().arm == Arm::Poll(POLLIN as u32));
+        uring.flush(0).unwrap();
+        sender.send(b"hello").unwrap();
+        let ready = uring.wait().unwrap();
+        assert_eq!((ready.key, ready.revents), (key, POLLIN as u32));
+        assert!(ready.completions.is_none());
+
+        uring.remove(receiver.as_raw_fd());
+    }
+}
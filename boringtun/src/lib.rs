@@ -5,6 +5,9 @@
 //!
 //! <code>git clone https://github.com/cloudflare/boringtun.git</code>
 
+#[cfg(feature = "tokio")]
+pub mod async_tunnel;
+
 #[cfg(feature = "device")]
 pub mod device;
 
@@ -17,7 +20,10 @@ pub mod noise;
 #[cfg(not(feature = "mock-instant"))]
 pub(crate) mod sleepyinstant;
 
-pub(crate) mod serialization;
+pub mod serialization;
+
+#[cfg(feature = "device")]
+pub mod uapi;
 
 /// Re-export of the x25519 types
 pub mod x25519 {
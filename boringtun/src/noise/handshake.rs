@@ -498,6 +498,11 @@ impl Handshake {
         self.params.set_static_private(private_key, public_key);
     }
 
+    /// Change the preshared key, which takes effect with the next handshake
+    pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.params.preshared_key = preshared_key;
+    }
+
     pub(super) fn receive_handshake_initialization<'a>(
         &mut self,
         packet: &HandshakeInit,
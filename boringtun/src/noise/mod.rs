@@ -8,6 +8,8 @@ pub mod rate_limiter;
 mod session;
 mod timers;
 
+pub use timers::TimerReason;
+
 use std::{
     collections::VecDeque,
     convert::{TryFrom, TryInto},
@@ -74,6 +76,11 @@ pub struct Tunn {
     timers: timers::Timers,
     pub(crate) tx_bytes: usize,
     pub(crate) rx_bytes: usize,
+    /// Data packets sent and received, keepalives excluded
+    tx_packets: u64,
+    rx_packets: u64,
+    /// Number of handshakes completed over the lifetime of the tunnel
+    handshakes: u64,
     rate_limiter: Arc<RateLimiter>,
 }
 
@@ -221,6 +228,9 @@ impl Tunn {
             current: Default::default(),
             tx_bytes: Default::default(),
             rx_bytes: Default::default(),
+            tx_packets: Default::default(),
+            rx_packets: Default::default(),
+            handshakes: Default::default(),
 
             packet_queue: VecDeque::with_capacity(MAX_QUEUE_DEPTH),
             timers: Timers::new(persistent_keepalive, rate_limiter.is_none()),
@@ -249,6 +259,18 @@ impl Tunn {
         }
     }
 
+    /// The rate limiter that verifies datagrams before [`Tunn::handle_verified_packet`]
+    #[cfg(feature = "tokio")]
+    pub(crate) fn rate_limiter(&self) -> Arc<RateLimiter> {
+        Arc::clone(&self.rate_limiter)
+    }
+
+    /// Update the preshared key. Existing sessions are kept, the new key is used from the next
+    /// handshake on.
+    pub fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.handshake.set_preshared_key(preshared_key);
+    }
+
     /// Encapsulate a single packet from the tunnel interface.
     /// Returns TunnResult.
     ///
@@ -264,6 +286,7 @@ impl Tunn {
             // Exclude Keepalive packets from timer update.
             if !src.is_empty() {
                 self.timer_tick(TimerName::TimeLastDataPacketSent);
+                self.tx_packets += 1;
             }
             self.tx_bytes += src.len();
             return TunnResult::WriteToNetwork(packet);
@@ -342,6 +365,7 @@ impl Tunn {
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeLastPacketSent);
         self.timer_tick_session_established(false, index); // New session established, we are not the initiator
+        self.handshakes += 1;
 
         tracing::debug!(message = "Sending handshake_response", local_idx = index);
 
@@ -370,6 +394,7 @@ impl Tunn {
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick_session_established(true, index); // New session established, we are the initiator
         self.set_current_session(l_idx);
+        self.handshakes += 1;
 
         tracing::debug!("Sending keepalive");
 
@@ -507,6 +532,7 @@ impl Tunn {
 
         self.timer_tick(TimerName::TimeLastDataPacketReceived);
         self.rx_bytes += computed_len;
+        self.rx_packets += 1;
 
         match src_ip_address {
             IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&mut packet[..computed_len], addr),
@@ -593,6 +619,16 @@ impl Tunn {
         (time, tx_bytes, rx_bytes, loss, rtt)
     }
 
+    /// Number of handshakes completed, as initiator or responder, since the tunnel was created
+    pub fn handshakes(&self) -> u64 {
+        self.handshakes
+    }
+
+    /// Data packets sent and received, keepalives excluded
+    pub fn packets(&self) -> (u64, u64) {
+        (self.tx_packets, self.rx_packets)
+    }
+
     pub fn last_handshake_time(&self) -> Option<Duration> {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -735,6 +771,8 @@ mod tests {
         let keepalive = parse_handshake_resp(&mut my_tun, &resp);
         let packet = Tunn::parse_incoming_packet(&keepalive).unwrap();
         assert!(matches!(packet, Packet::PacketData(_)));
+        assert_eq!(my_tun.handshakes(), 1);
+        assert_eq!(their_tun.handshakes(), 1);
     }
 
     #[test]
@@ -806,5 +844,7 @@ mod tests {
             unreachable!();
         };
         assert_eq!(sent_packet_buf, recv_packet_buf);
+        assert_eq!(my_tun.packets(), (1, 0));
+        assert_eq!(their_tun.packets(), (0, 1));
     }
 }
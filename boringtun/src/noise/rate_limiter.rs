@@ -34,6 +34,7 @@ type Cookie = [u8; COOKIE_SIZE];
 /// There are two places where WireGuard requires "randomness" for cookies
 /// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
 /// * A secret value that changes every two minutes
+///
 /// Because the main goal of the cookie is simply for a party to prove ownership of an IP address
 /// we can relax the randomness definition a bit, in order to avoid locking, because using less
 /// resources is the main goal of any DoS prevention mechanism.
@@ -54,6 +55,10 @@ pub struct RateLimiter {
     count: AtomicU64,
     /// The time last reset was performed on this rate limiter
     last_reset: Mutex<Instant>,
+    /// Handshake messages that arrived while under load
+    under_load: AtomicU64,
+    /// Cookie replies sent in response to handshakes without a valid mac2
+    cookie_replies: AtomicU64,
 }
 
 impl RateLimiter {
@@ -71,6 +76,8 @@ impl RateLimiter {
             limit,
             count: AtomicU64::new(0),
             last_reset: Mutex::new(Instant::now()),
+            under_load: AtomicU64::new(0),
+            cookie_replies: AtomicU64::new(0),
         }
     }
 
@@ -115,7 +122,21 @@ impl RateLimiter {
     }
 
     fn is_under_load(&self) -> bool {
-        self.count.fetch_add(1, Ordering::SeqCst) >= self.limit
+        let under_load = self.count.fetch_add(1, Ordering::SeqCst) >= self.limit;
+        if under_load {
+            self.under_load.fetch_add(1, Ordering::Relaxed);
+        }
+        under_load
+    }
+
+    /// The number of handshake messages that arrived while the rate limit was exceeded
+    pub fn under_load_count(&self) -> u64 {
+        self.under_load.load(Ordering::Relaxed)
+    }
+
+    /// The number of cookie replies sent to handshakes that arrived while under load
+    pub fn cookie_reply_count(&self) -> u64 {
+        self.cookie_replies.load(Ordering::Relaxed)
     }
 
     pub(crate) fn format_cookie_reply<'a>(
@@ -189,6 +210,7 @@ impl RateLimiter {
                     let cookie_packet = self
                         .format_cookie_reply(sender_idx, cookie, mac1, dst)
                         .map_err(TunnResult::Err)?;
+                    self.cookie_replies.fetch_add(1, Ordering::Relaxed);
                     return Err(TunnResult::WriteToNetwork(cookie_packet));
                 }
             }
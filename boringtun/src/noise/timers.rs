@@ -46,6 +46,51 @@ pub enum TimerName {
     Top,
 }
 
+/// Why [`Tunn::update_timers`] started a handshake or expired the connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerReason {
+    /// The last handshake initiation got no response in time, and is retried
+    RekeyTimeout,
+    /// The session is old enough to be replaced as data is sent
+    RekeyAfterTime,
+    /// The session is about to be rejected as data is received
+    RejectAfterTime,
+    /// Data was sent but nothing came back
+    NoReply,
+    /// No handshake completed for long enough that all keys were discarded
+    KeysExpired,
+    /// Handshake initiations were retried for too long without a response
+    RekeyAttemptTime,
+}
+
+impl TimerReason {
+    pub const ALL: [TimerReason; 6] = [
+        TimerReason::RekeyTimeout,
+        TimerReason::RekeyAfterTime,
+        TimerReason::RejectAfterTime,
+        TimerReason::NoReply,
+        TimerReason::KeysExpired,
+        TimerReason::RekeyAttemptTime,
+    ];
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimerReason::RekeyTimeout => "rekey_timeout",
+            TimerReason::RekeyAfterTime => "rekey_after_time",
+            TimerReason::RejectAfterTime => "reject_after_time",
+            TimerReason::NoReply => "no_reply",
+            TimerReason::KeysExpired => "keys_expired",
+            TimerReason::RekeyAttemptTime => "rekey_attempt_time",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<TimerReason> {
+        TimerReason::ALL.into_iter().find(|r| r.name() == name)
+    }
+}
+
 use self::TimerName::{
     TimeCookieReceived, TimeCurrent, TimeLastDataPacketReceived, TimeLastDataPacketSent,
     TimeLastHandshakeStarted, TimeLastPacketReceived, TimeLastPacketSent, TimePersistentKeepalive,
@@ -67,6 +112,8 @@ pub struct Timers {
     persistent_keepalive: u16,
     /// Should this timer call reset rr function (if not a shared rr instance)
     pub(super) should_reset_rr: bool,
+    /// Why the last call to `update_timers` started a handshake or expired the connection
+    reason: Option<TimerReason>,
 }
 
 impl Timers {
@@ -80,6 +127,7 @@ impl Timers {
             want_handshake: Default::default(),
             persistent_keepalive: persistent_keepalive.unwrap_or_default(),
             should_reset_rr: reset_rr,
+            reason: None,
         }
     }
 
@@ -172,6 +220,7 @@ impl Tunn {
     pub fn update_timers<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
         let mut handshake_initiation_required = false;
         let mut keepalive_required = false;
+        self.timers.reason = None;
 
         let time = Instant::now();
 
@@ -210,6 +259,7 @@ impl Tunn {
         // (REJECT_AFTER_TIME * 3) ms if no new keys have been exchanged.
         if now.checked_sub(session_established).unwrap() >= REJECT_AFTER_TIME * 3 {
             tracing::error!("CONNECTION_EXPIRED(REJECT_AFTER_TIME * 3)");
+            self.timers.reason = Some(TimerReason::KeysExpired);
             self.handshake.set_expired();
             self.clear_all();
             return TunnResult::Err(WireGuardError::ConnectionExpired);
@@ -223,6 +273,7 @@ impl Tunn {
                 // up to be sent. If a packet is explicitly queued up to be sent, then
                 // this timer is reset.
                 tracing::error!("CONNECTION_EXPIRED(REKEY_ATTEMPT_TIME)");
+                self.timers.reason = Some(TimerReason::RekeyAttemptTime);
                 self.handshake.set_expired();
                 self.clear_all();
                 return TunnResult::Err(WireGuardError::ConnectionExpired);
@@ -235,6 +286,7 @@ impl Tunn {
                 // if a response has not been received, where jitter is some random
                 // value between 0 and 333 ms.
                 tracing::warn!("HANDSHAKE(REKEY_TIMEOUT)");
+                self.timers.reason = Some(TimerReason::RekeyTimeout);
                 handshake_initiation_required = true;
             }
         } else {
@@ -248,6 +300,7 @@ impl Tunn {
                     && now.checked_sub(session_established).unwrap() >= REKEY_AFTER_TIME
                 {
                     tracing::debug!("HANDSHAKE(REKEY_AFTER_TIME (on send))");
+                    self.timers.reason = Some(TimerReason::RekeyAfterTime);
                     handshake_initiation_required = true;
                 }
 
@@ -264,6 +317,7 @@ impl Tunn {
                         REKEY_TIMEOUT \
                         (on receive))"
                     );
+                    self.timers.reason = Some(TimerReason::RejectAfterTime);
                     handshake_initiation_required = true;
                 }
             }
@@ -277,6 +331,7 @@ impl Tunn {
                 && mem::replace(&mut self.timers.want_handshake, false)
             {
                 tracing::warn!("HANDSHAKE(KEEPALIVE + REKEY_TIMEOUT)");
+                self.timers.reason = Some(TimerReason::NoReply);
                 handshake_initiation_required = true;
             }
 
@@ -316,6 +371,12 @@ impl Tunn {
         TunnResult::Done
     }
 
+    /// Why the last call to [`Tunn::update_timers`] started a handshake or expired the
+    /// connection, if it did
+    pub fn timer_reason(&self) -> Option<TimerReason> {
+        self.timers.reason
+    }
+
     pub fn time_since_last_handshake(&self) -> Option<Duration> {
         let current_session = self.current;
         if self.sessions[current_session % super::N_SESSIONS].is_some() {
@@ -337,4 +398,9 @@ impl Tunn {
 
         if keepalive > 0 { Some(keepalive) } else { None }
     }
+
+    /// Change the persistent keepalive interval in seconds, zero disables it
+    pub fn set_persistent_keepalive(&mut self, interval: u16) {
+        self.timers.persistent_keepalive = interval;
+    }
 }
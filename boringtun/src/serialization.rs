@@ -2,7 +2,7 @@ use std::{fmt, str::FromStr};
 
 use aead::OsRng;
 use base64::prelude::*;
-use x25519_dalek::StaticSecret;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 const KEY_SIZE: usize = 32;
 
@@ -44,12 +44,13 @@ impl FromStr for KeyBytes {
             }
             43 | 44 => {
                 // Try to parse as base64
-                if let Ok(decoded_key) = BASE64_STANDARD.decode(s) {
-                    if decoded_key.len() == internal.len() {
-                        internal[..].copy_from_slice(&decoded_key);
-                    } else {
-                        return Err(KeyBytesError::IllegalCharacter);
-                    }
+                let decoded_key = BASE64_STANDARD
+                    .decode(s)
+                    .map_err(|_| KeyBytesError::IllegalCharacter)?;
+                if decoded_key.len() == internal.len() {
+                    internal[..].copy_from_slice(&decoded_key);
+                } else {
+                    return Err(KeyBytesError::IllegalCharacter);
                 }
             }
             _ => return Err(KeyBytesError::IllegalSize),
@@ -65,6 +66,12 @@ impl KeyBytes {
         Self(key)
     }
 
+    /// The public key that belongs to these bytes, used as a private key
+    #[must_use]
+    pub fn public_key(&self) -> Self {
+        Self(PublicKey::from(&StaticSecret::from(self.0)).to_bytes())
+    }
+
     /// Provide internal bytes as `Vec`.
     /// It is needed mainly to implmenet Equatable and Hashable in Swift.
     pub fn raw_bytes(&self) -> Vec<u8> {
@@ -101,3 +108,18 @@ impl KeyBytes {
         hex
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_base64() {
+        let key = "YFjESZYbWRxQVnHsOVYM3zM6rr7f6yyKA7cUp+ElWHk=";
+        assert_eq!(key.parse::<KeyBytes>().unwrap().to_base64(), key);
+        assert!(matches!(
+            "YFjESZYbWRxQVnHsOVYM3zM6rr7f6yyKA7cUp+ElWH!=".parse::<KeyBytes>(),
+            Err(KeyBytesError::IllegalCharacter)
+        ));
+    }
+}
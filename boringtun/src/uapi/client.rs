@@ -0,0 +1,815 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A client for the userspace WireGuard configuration protocol.
+//!
+//! The client works against boringtun's own API socket (see `device::api`) as well as against
+//! any other implementation of the protocol, such as wireguard-go.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use hex::encode as encode_hex;
+use libc::{EACCES, EADDRINUSE, EBUSY, EINVAL, EIO, ENOENT, EPERM, EPROTO};
+
+use super::{GetRequest, SOCK_DIR, event::Event};
+use crate::{
+    device::{
+        acl::{Acl, AclHits, AclUpdate},
+        peer::AllowedIP,
+        pmtu::{PeerPmtu, PmtuUpdate},
+        recorder::FlightRecord,
+        stats::{DropReason, DropStats},
+    },
+    serialization::KeyBytes,
+    x25519,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("invalid argument")]
+    InvalidArgument,
+    #[error("protocol error")]
+    Protocol,
+    #[error("address in use")]
+    AddressInUse,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("no such peer or interface")]
+    NotFound,
+    #[error("resource busy")]
+    Busy,
+    #[error("remote i/o error")]
+    RemoteIo,
+    #[error("errno={0}")]
+    Errno(i32),
+}
+
+impl Error {
+    /// Map the value of an `errno=` response line to an error, zero means success.
+    /// Implementations disagree on the sign of the value, so both are accepted.
+    #[must_use]
+    pub fn from_errno(errno: i32) -> Option<Error> {
+        Some(match errno.abs() {
+            0 => return None,
+            EINVAL => Error::InvalidArgument,
+            EPROTO => Error::Protocol,
+            EADDRINUSE => Error::AddressInUse,
+            EACCES | EPERM => Error::PermissionDenied,
+            ENOENT => Error::NotFound,
+            EBUSY => Error::Busy,
+            EIO => Error::RemoteIo,
+            errno => Error::Errno(errno),
+        })
+    }
+}
+
+/// Interface configuration and state, as returned by a `get=1` request
+#[derive(Clone, Default)]
+pub struct Interface {
+    pub private_key: Option<x25519::StaticSecret>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    /// Packets dropped by the device, only reported for requests with `drops` set
+    pub drops: DropStats,
+    pub peers: Vec<Peer>,
+}
+
+impl Interface {
+    #[must_use]
+    pub fn public_key(&self) -> Option<x25519::PublicKey> {
+        self.private_key.as_ref().map(x25519::PublicKey::from)
+    }
+}
+
+impl fmt::Debug for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interface")
+            .field("public_key", &self.public_key())
+            .field("listen_port", &self.listen_port)
+            .field("fwmark", &self.fwmark)
+            .field("drops", &self.drops)
+            .field("peers", &self.peers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Peer configuration and state, as returned by a `get=1` request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub public_key: x25519::PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive_interval: Option<u16>,
+    pub allowed_ips: Vec<AllowedIP>,
+    /// Time of the last handshake, since the UNIX epoch
+    pub last_handshake_time: Option<Duration>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub pmtu: PeerPmtu,
+    pub acl: Acl,
+    /// The packets matched by the firewall rules of the peer
+    pub acl_hits: AclHits,
+    /// Packets to or from the peer that were dropped, only reported for requests with `drops` set
+    pub drops: DropStats,
+    /// The recent handshake events of the peer, only reported for requests with `flight_record`
+    /// set
+    pub flight_record: Vec<FlightRecord>,
+}
+
+impl Peer {
+    #[must_use]
+    pub fn new(public_key: x25519::PublicKey) -> Peer {
+        Peer {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            allowed_ips: Vec::new(),
+            last_handshake_time: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            pmtu: PeerPmtu::default(),
+            acl: Acl::default(),
+            acl_hits: AclHits::default(),
+            drops: DropStats::default(),
+            flight_record: Vec::new(),
+        }
+    }
+}
+
+/// A `set=1` transaction. Fields left as `None` are not changed on the remote side.
+#[derive(Clone, Default)]
+pub struct SetRequest {
+    pub private_key: Option<x25519::StaticSecret>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub replace_peers: bool,
+    pub peers: Vec<PeerUpdate>,
+}
+
+/// The peer section of a `set=1` transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerUpdate {
+    pub public_key: x25519::PublicKey,
+    pub remove: bool,
+    pub update_only: bool,
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive_interval: Option<u16>,
+    pub replace_allowed_ips: bool,
+    pub allowed_ips: Vec<AllowedIP>,
+    pub pmtu: PmtuUpdate,
+    pub acl: AclUpdate,
+}
+
+impl PeerUpdate {
+    #[must_use]
+    pub fn new(public_key: x25519::PublicKey) -> PeerUpdate {
+        PeerUpdate {
+            public_key,
+            remove: false,
+            update_only: false,
+            preshared_key: None,
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            replace_allowed_ips: false,
+            allowed_ips: Vec::new(),
+            pmtu: PmtuUpdate::default(),
+            acl: AclUpdate::default(),
+        }
+    }
+
+    /// A peer section that removes the peer
+    #[must_use]
+    pub fn remove(public_key: x25519::PublicKey) -> PeerUpdate {
+        PeerUpdate {
+            remove: true,
+            ..PeerUpdate::new(public_key)
+        }
+    }
+}
+
+impl SetRequest {
+    /// Serialize the request, including the `set=1` header and the terminating empty line
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "set=1")?;
+
+        if let Some(ref key) = self.private_key {
+            writeln!(w, "private_key={}", encode_hex(key.as_bytes()))?;
+        }
+
+        if let Some(port) = self.listen_port {
+            writeln!(w, "listen_port={port}")?;
+        }
+
+        if let Some(fwmark) = self.fwmark {
+            writeln!(w, "fwmark={fwmark}")?;
+        }
+
+        if self.replace_peers {
+            writeln!(w, "replace_peers=true")?;
+        }
+
+        for peer in &self.peers {
+            writeln!(w, "public_key={}", encode_hex(peer.public_key.as_bytes()))?;
+
+            if peer.remove {
+                writeln!(w, "remove=true")?;
+                continue;
+            }
+
+            if peer.update_only {
+                writeln!(w, "update_only=true")?;
+            }
+
+            if let Some(ref key) = peer.preshared_key {
+                writeln!(w, "preshared_key={}", encode_hex(key))?;
+            }
+
+            if let Some(ref endpoint) = peer.endpoint {
+                writeln!(w, "endpoint={endpoint}")?;
+            }
+
+            if let Some(interval) = peer.persistent_keepalive_interval {
+                writeln!(w, "persistent_keepalive_interval={interval}")?;
+            }
+
+            if peer.replace_allowed_ips {
+                writeln!(w, "replace_allowed_ips=true")?;
+            }
+
+            for AllowedIP { addr, cidr } in &peer.allowed_ips {
+                writeln!(w, "allowed_ip={addr}/{cidr}")?;
+            }
+
+            if let Some(mtu) = peer.pmtu.mtu {
+                writeln!(w, "mtu={mtu}")?;
+            }
+
+            if let Some(toggle) = peer.pmtu.icmp_too_big {
+                writeln!(w, "icmp_too_big={toggle}")?;
+            }
+
+            if let Some(toggle) = peer.pmtu.clamp_mss {
+                writeln!(w, "clamp_mss={toggle}")?;
+            }
+
+            if let Some(policy) = peer.acl.policy {
+                writeln!(w, "acl_policy={policy}")?;
+            }
+
+            if peer.acl.replace_rules {
+                writeln!(w, "replace_acl_rules=true")?;
+            }
+
+            for rule in &peer.acl.rules {
+                writeln!(w, "acl_rule={rule}")?;
+            }
+        }
+
+        writeln!(w)
+    }
+}
+
+enum Transport {
+    /// A socket path, a new connection is made for every request
+    Path(PathBuf),
+    /// An already connected stream that can carry multiple requests, such as the `--uapi-fd` socket
+    Stream(BufReader<UnixStream>),
+}
+
+/// A connection to a UAPI socket
+pub struct Client {
+    transport: Transport,
+}
+
+impl Client {
+    /// Create a client for the socket at the given path
+    pub fn new<P: AsRef<Path>>(path: P) -> Client {
+        Client {
+            transport: Transport::Path(path.as_ref().to_path_buf()),
+        }
+    }
+
+    /// Create a client for the socket of the named interface: /var/run/wireguard/{name}.sock
+    #[must_use]
+    pub fn for_interface(name: &str) -> Client {
+        Client::new(Path::new(SOCK_DIR).join(format!("{name}.sock")))
+    }
+
+    /// Create a client on top of an already connected stream
+    #[must_use]
+    pub fn from_stream(stream: UnixStream) -> Client {
+        Client {
+            transport: Transport::Stream(BufReader::new(stream)),
+        }
+    }
+
+    /// Retrieve the current configuration and state of the interface
+    pub fn get(&mut self) -> Result<Interface, Error> {
+        self.get_with(&GetRequest::default())
+    }
+
+    /// Retrieve a subset of the interface configuration and state, using the get request
+    /// extensions described in [`GetRequest`]. Servers without support for the extensions
+    /// reject requests that use them.
+    pub fn get_with(&mut self, request: &GetRequest) -> Result<Interface, Error> {
+        self.request(|w| request.write_to(w), |lines| parse_get_response(lines))
+    }
+
+    /// Apply a configuration change to the interface
+    pub fn set(&mut self, request: &SetRequest) -> Result<(), Error> {
+        self.request(
+            |w| request.write_to(w),
+            |lines| parse_response(lines, |_| Ok(())),
+        )
+    }
+
+    /// Subscribe to the device event stream, using the `subscribe=1` extension command.
+    /// The returned iterator blocks until the next event arrives, and ends when the server
+    /// closes the connection, for example because the subscriber fell behind.
+    pub fn subscribe(mut self) -> Result<Subscription, Error> {
+        if let Transport::Path(path) = &self.transport {
+            // The events are streamed over the connection that made the request
+            self.transport = Transport::Stream(BufReader::new(UnixStream::connect(path)?));
+        }
+
+        self.request(
+            |w| w.write_all(b"subscribe=1\n\n"),
+            |lines| parse_response(lines, |_| Ok(())),
+        )?;
+
+        match self.transport {
+            Transport::Stream(reader) => Ok(Subscription { reader }),
+            Transport::Path(_) => unreachable!(),
+        }
+    }
+
+    fn request<T>(
+        &mut self,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+        read: impl FnOnce(&mut dyn Iterator<Item = io::Result<String>>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        // Send the request at once, as the server reads what arrived of a get request without
+        // waiting for the rest
+        let mut request = Vec::new();
+        write(&mut request)?;
+        match &mut self.transport {
+            Transport::Path(path) => {
+                let stream = UnixStream::connect(path)?;
+                (&stream).write_all(&request)?;
+                read(&mut BufReader::new(&stream).lines())
+            }
+            Transport::Stream(reader) => {
+                reader.get_ref().write_all(&request)?;
+                read(&mut BufRead::lines(reader))
+            }
+        }
+    }
+}
+
+/// A stream of device events, see [`Client::subscribe`]
+pub struct Subscription {
+    reader: BufReader<UnixStream>,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(
+                line.trim_end()
+                    .parse::<Event>()
+                    .map_err(Error::InvalidResponse),
+            ),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl FromRawFd for Client {
+    unsafe fn from_raw_fd(fd: RawFd) -> Client {
+        Client::from_stream(unsafe { UnixStream::from_raw_fd(fd) })
+    }
+}
+
+impl AsRawFd for Client {
+    /// The fd of the underlying stream, or -1 if the client connects by path
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.transport {
+            Transport::Path(_) => -1,
+            Transport::Stream(reader) => reader.get_ref().as_raw_fd(),
+        }
+    }
+}
+
+/// Split a response line into a key and a value
+fn split_line(line: &str) -> Result<(&str, &str), Error> {
+    line.split_once('=')
+        .ok_or_else(|| Error::InvalidResponse(line.to_owned()))
+}
+
+/// Read response lines up to and including the terminating `errno=` line and the empty line
+/// after it, passing every other line to `f`.
+pub(crate) fn parse_response(
+    lines: &mut dyn Iterator<Item = io::Result<String>>,
+    mut f: impl FnMut(&str) -> Result<(), Error>,
+) -> Result<(), Error> {
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if let Some(errno) = line.strip_prefix("errno=") {
+            let errno = errno
+                .parse::<i32>()
+                .map_err(|_| Error::InvalidResponse(line.clone()))?;
+            // Consume the empty line that terminates the response, if any
+            lines.next().transpose()?;
+            return match Error::from_errno(errno) {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
+        if !line.is_empty() {
+            f(&line)?;
+        }
+    }
+
+    Err(Error::InvalidResponse("missing errno".to_owned()))
+}
+
+fn parse_key(val: &str) -> Result<[u8; 32], Error> {
+    val.parse::<KeyBytes>()
+        .map(|k| k.0)
+        .map_err(|e| Error::InvalidResponse(format!("{e}: {val}")))
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, val: &str) -> Result<T, Error> {
+    val.parse::<T>()
+        .map_err(|_| Error::InvalidResponse(format!("{key}={val}")))
+}
+
+pub(crate) fn parse_get_response(
+    lines: &mut dyn Iterator<Item = io::Result<String>>,
+) -> Result<Interface, Error> {
+    let mut interface = Interface::default();
+    let mut handshake_sec = 0;
+    let mut handshake_nsec = 0;
+
+    let finish_handshake = |peer: Option<&mut Peer>, sec: &mut u64, nsec: &mut u32| {
+        if let Some(peer) = peer
+            && (*sec != 0 || *nsec != 0)
+        {
+            peer.last_handshake_time = Some(Duration::new(*sec, *nsec));
+        }
+        *sec = 0;
+        *nsec = 0;
+    };
+
+    parse_response(lines, |line| {
+        let (key, val) = split_line(line)?;
+
+        if key == "public_key" {
+            finish_handshake(
+                interface.peers.last_mut(),
+                &mut handshake_sec,
+                &mut handshake_nsec,
+            );
+            interface
+                .peers
+                .push(Peer::new(x25519::PublicKey::from(parse_key(val)?)));
+            return Ok(());
+        }
+
+        match interface.peers.last_mut() {
+            None => match key {
+                "private_key" => {
+                    interface.private_key = Some(x25519::StaticSecret::from(parse_key(val)?));
+                }
+                "listen_port" => interface.listen_port = Some(parse_value(key, val)?),
+                "fwmark" => interface.fwmark = Some(parse_value(key, val)?),
+                _ => parse_drop(&mut interface.drops, key, val)?,
+            },
+            Some(peer) => match key {
+                "preshared_key" => {
+                    let psk = parse_key(val)?;
+                    // wireguard-go reports an all-zero key when no preshared key is set
+                    peer.preshared_key = (psk != [0u8; 32]).then_some(psk);
+                }
+                "endpoint" => peer.endpoint = Some(parse_value(key, val)?),
+                "persistent_keepalive_interval" => {
+                    let interval: u16 = parse_value(key, val)?;
+                    peer.persistent_keepalive_interval = (interval != 0).then_some(interval);
+                }
+                "allowed_ip" => peer.allowed_ips.push(parse_value(key, val)?),
+                "last_handshake_time_sec" => handshake_sec = parse_value(key, val)?,
+                "last_handshake_time_nsec" => handshake_nsec = parse_value(key, val)?,
+                "rx_bytes" => peer.rx_bytes = parse_value(key, val)?,
+                "tx_bytes" => peer.tx_bytes = parse_value(key, val)?,
+                "mtu" => peer.pmtu.mtu = Some(parse_value(key, val)?),
+                "icmp_too_big" => peer.pmtu.icmp_too_big = parse_value(key, val)?,
+                "clamp_mss" => peer.pmtu.clamp_mss = parse_value(key, val)?,
+                "acl_policy" => peer.acl.policy = parse_value(key, val)?,
+                "acl_rule" => {
+                    peer.acl.rules.push(parse_value(key, val)?);
+                    peer.acl_hits.rules.push(0);
+                }
+                "acl_rule_hits" => {
+                    if let Some(hits) = peer.acl_hits.rules.last_mut() {
+                        *hits = parse_value(key, val)?;
+                    }
+                }
+                "acl_policy_hits" => peer.acl_hits.policy = parse_value(key, val)?,
+                "flight_record" => peer.flight_record.push(parse_value(key, val)?),
+                _ => parse_drop(&mut peer.drops, key, val)?,
+            },
+        }
+        Ok(())
+    })?;
+
+    finish_handshake(
+        interface.peers.last_mut(),
+        &mut handshake_sec,
+        &mut handshake_nsec,
+    );
+
+    Ok(interface)
+}
+
+/// Parse a `drop_<reason>=<n>` line. Unknown keys are ignored so newer servers can extend the
+/// response.
+fn parse_drop(drops: &mut DropStats, key: &str, val: &str) -> Result<(), Error> {
+    if let Some(reason) = key.strip_prefix("drop_").and_then(DropReason::from_name) {
+        drops.set(reason, parse_value(key, val)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::IpAddr, thread};
+
+    use super::*;
+    use crate::{device::recorder::FlightEvent, noise::TimerReason, uapi::Field};
+
+    /// Serve a single canned response on one end of a socket pair, returning the request
+    fn serve_once(response: &'static str) -> (Client, thread::JoinHandle<String>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut reader = BufReader::new(&server);
+            let mut request = String::new();
+            // Requests are terminated by an empty line
+            while reader.read_line(&mut request).unwrap() > 0 {
+                if request.ends_with("\n\n") {
+                    break;
+                }
+            }
+            (&server).write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (Client::from_stream(client), handle)
+    }
+
+    #[test]
+    fn get_parses_boringtun_response() {
+        let (mut client, server) = serve_once(
+            "private_key=e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a\n\
+             listen_port=12912\n\
+             fwmark=7\n\
+             public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33\n\
+             preshared_key=188515093e952f5f22e865cef3012e72f8b5f0b598ac0309d5dacce3b70fcf52\n\
+             persistent_keepalive_interval=25\n\
+             endpoint=[abcd:23::33]:51820\n\
+             allowed_ip=192.168.4.4/32\n\
+             last_handshake_time_sec=1700000000\n\
+             last_handshake_time_nsec=42\n\
+             rx_bytes=2224\n\
+             tx_bytes=38333\n\
+             public_key=58402e695ba1772b1cc9309755f043251ea77fdcf10fbe63989ceb7e19321376\n\
+             allowed_ip=10.0.0.0/8\n\
+             allowed_ip=fd00::/64\n\
+             rx_bytes=0\n\
+             tx_bytes=0\n\
+             errno=0\n\n",
+        );
+
+        let interface = client.get().unwrap();
+        assert_eq!(server.join().unwrap(), "get=1\n\n");
+
+        assert_eq!(
+            interface.private_key.map(|k| k.to_bytes()),
+            Some(
+                parse_key("e84b5a6d2717c1003a13b431570353dbaca9146cf150c5f8575680feba52027a")
+                    .unwrap()
+            )
+        );
+        assert_eq!(interface.listen_port, Some(12912));
+        assert_eq!(interface.fwmark, Some(7));
+        assert_eq!(interface.peers.len(), 2);
+
+        let peer = &interface.peers[0];
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        assert!(peer.preshared_key.is_some());
+        assert_eq!(peer.endpoint, Some("[abcd:23::33]:51820".parse().unwrap()));
+        assert_eq!(peer.allowed_ips, vec!["192.168.4.4/32".parse().unwrap()]);
+        assert_eq!(
+            peer.last_handshake_time,
+            Some(Duration::new(1_700_000_000, 42))
+        );
+        assert_eq!((peer.rx_bytes, peer.tx_bytes), (2224, 38333));
+
+        let peer = &interface.peers[1];
+        assert_eq!(peer.endpoint, None);
+        assert_eq!(peer.last_handshake_time, None);
+        assert_eq!(
+            peer.allowed_ips,
+            vec![
+                AllowedIP {
+                    addr: IpAddr::from([10, 0, 0, 0]),
+                    cidr: 8
+                },
+                "fd00::/64".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn get_parses_wireguard_go_response() {
+        // wireguard-go always reports a handshake time and the protocol version
+        let (mut client, _server) = serve_once(
+            "public_key=b85996fecc9c7f1fc6d2572a76eda11d59bcd20be8e543b15ce4bd85a8e75a33\n\
+             preshared_key=0000000000000000000000000000000000000000000000000000000000000000\n\
+             protocol_version=1\n\
+             last_handshake_time_sec=0\n\
+             last_handshake_time_nsec=0\n\
+             tx_bytes=0\n\
+             rx_bytes=0\n\
+             persistent_keepalive_interval=0\n\
+             errno=0\n\n",
+        );
+
+        let interface = client.get().unwrap();
+        assert!(interface.private_key.is_none());
+        assert_eq!(interface.peers.len(), 1);
+        assert_eq!(interface.peers[0].preshared_key, None);
+        assert_eq!(interface.peers[0].persistent_keepalive_interval, None);
+        assert_eq!(interface.peers[0].last_handshake_time, None);
+    }
+
+    #[test]
+    fn set_serializes_request_and_maps_errno() {
+        let (mut client, server) = serve_once("errno=22\n\n");
+
+        let peer_key = x25519::PublicKey::from([1u8; 32]);
+        let mut peer = PeerUpdate::new(peer_key);
+        peer.endpoint = Some("127.0.0.1:51820".parse().unwrap());
+        peer.replace_allowed_ips = true;
+        peer.allowed_ips.push("10.0.0.1/32".parse().unwrap());
+
+        let request = SetRequest {
+            listen_port: Some(51820),
+            peers: vec![peer, PeerUpdate::remove(x25519::PublicKey::from([2u8; 32]))],
+            ..SetRequest::default()
+        };
+
+        assert!(matches!(client.set(&request), Err(Error::InvalidArgument)));
+        assert_eq!(
+            server.join().unwrap(),
+            format!(
+                "set=1\n\
+                 listen_port=51820\n\
+                 public_key={}\n\
+                 endpoint=127.0.0.1:51820\n\
+                 replace_allowed_ips=true\n\
+                 allowed_ip=10.0.0.1/32\n\
+                 public_key={}\n\
+                 remove=true\n\n",
+                encode_hex([1u8; 32]),
+                encode_hex([2u8; 32])
+            )
+        );
+    }
+
+    #[test]
+    fn get_with_serializes_extensions() {
+        let (mut client, server) = serve_once(
+            "public_key=0202020202020202020202020202020202020202020202020202020202020202\n\
+             rx_bytes=10\n\
+             drop_send_error=3\n\
+             flight_record=1760000000.000001 handshake_init_sent reason=no_reply\n\
+             errno=0\n\n",
+        );
+
+        let interface = client
+            .get_with(&GetRequest {
+                fields: Some(vec![Field::RxBytes]),
+                cursor: Some(x25519::PublicKey::from([1u8; 32])),
+                limit: Some(1),
+                drops: true,
+                flight_record: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            format!(
+                "get=1\nfields=rx_bytes\ncursor={}\nlimit=1\ndrops=true\nflight_record=true\n\n",
+                encode_hex([1u8; 32])
+            )
+        );
+
+        assert_eq!(interface.private_key.map(|k| k.to_bytes()), None);
+        assert_eq!(interface.peers.len(), 1);
+        assert_eq!(interface.peers[0].rx_bytes, 10);
+        assert_eq!(interface.peers[0].drops.get(DropReason::SendError), 3);
+        assert_eq!(
+            interface.peers[0].flight_record[0].event,
+            FlightEvent::HandshakeInitSent(Some(TimerReason::NoReply))
+        );
+        assert_eq!(interface.drops.total(), 0);
+    }
+
+    #[test]
+    fn stream_transport_carries_multiple_requests() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            (&server)
+                .write_all(b"listen_port=1\nerrno=0\n\nerrno=0\n\n")
+                .unwrap();
+            let mut buf = Vec::new();
+            server.shutdown(std::net::Shutdown::Write).unwrap();
+            (&server).read_to_end(&mut buf).unwrap();
+            buf
+        });
+
+        let mut client = Client::from_stream(client);
+        assert_eq!(client.get().unwrap().listen_port, Some(1));
+        client.set(&SetRequest::default()).unwrap();
+        drop(client);
+
+        assert_eq!(handle.join().unwrap(), b"get=1\n\nset=1\n\n");
+    }
+
+    #[test]
+    fn subscription_yields_events() {
+        let public_key = x25519::PublicKey::from([3u8; 32]);
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut request = String::new();
+            BufReader::new(&server).read_line(&mut request).unwrap();
+            assert_eq!(request, "subscribe=1\n");
+            write!(
+                &server,
+                "errno=0\n\n{}\n{}\n",
+                Event::PeerAdded { public_key },
+                Event::HandshakeCompleted { public_key }
+            )
+            .unwrap();
+        });
+
+        let events: Vec<_> = Client::from_stream(client)
+            .subscribe()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        handle.join().unwrap();
+
+        assert_eq!(
+            events,
+            [
+                Event::PeerAdded { public_key },
+                Event::HandshakeCompleted { public_key }
+            ]
+        );
+    }
+
+    #[test]
+    fn errno_mapping() {
+        assert!(Error::from_errno(0).is_none());
+        assert!(matches!(
+            Error::from_errno(-EINVAL),
+            Some(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            Error::from_errno(EADDRINUSE),
+            Some(Error::AddressInUse)
+        ));
+        assert!(matches!(Error::from_errno(EIO), Some(Error::RemoteIo)));
+        assert!(matches!(Error::from_errno(1000), Some(Error::Errno(1000))));
+    }
+}
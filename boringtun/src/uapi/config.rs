@@ -0,0 +1,666 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The WireGuard configuration file format, as read by `wg setconf` and `wg-quick`.
+//!
+//! ```text
+//! [Interface]
+//! PrivateKey = <base64>
+//! ListenPort = 51820
+//!
+//! [Peer]
+//! PublicKey = <base64>
+//! Endpoint = 192.0.2.1:51820
+//! AllowedIPs = 10.0.0.2/32, fd00::2/128
+//! ```
+//!
+//! Keys and section names are case insensitive, and everything after a `#` is a comment. The
+//! `Address`, `MTU` and `Table` keys of `wg-quick` are read into [`NetworkConfig`], and its
+//! other keys, such as `DNS` or `PostUp`, are accepted and ignored so the same file can be used
+//! with both tools. Peer sections may also set the path MTU settings of boringtun with the
+//! `MTU`, `IcmpTooBig` and `ClampMss` keys, see [`PeerPmtu`].
+//!
+//! [`Config`] also formats back to this format, the way `wg showconf` prints it.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+use super::client::{Interface, PeerUpdate, SetRequest};
+use crate::{
+    device::{
+        acl::{Acl, AclUpdate, Action},
+        peer::AllowedIP,
+        pmtu::{PeerPmtu, PmtuUpdate, Toggle},
+    },
+    serialization::KeyBytes,
+    x25519,
+};
+
+/// Interface keys that only `wg-quick` acts on
+const WG_QUICK_KEYS: [&str; 6] = [
+    "dns",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+/// An error in a configuration file, with the line it was found on
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// The contents of a configuration file
+#[derive(Clone, Default)]
+pub struct Config {
+    pub private_key: Option<x25519::StaticSecret>,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub network: NetworkConfig,
+    pub peers: Vec<PeerConfig>,
+}
+
+/// The `[Interface]` keys that configure the network interface rather than WireGuard, as
+/// `wg-quick` does. They are applied on Linux by `device::netlink`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkConfig {
+    /// Addresses of the interface, with the prefix length of the attached network
+    pub addresses: Vec<AllowedIP>,
+    pub mtu: Option<u32>,
+    pub table: Option<RouteTable>,
+}
+
+/// The routing table that routes for the allowed IPs of peers are added to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteTable {
+    /// No routes are added
+    Off,
+    /// Routes are added to the main table, except default routes, which go to a separate table
+    /// selected by a policy rule on the fwmark
+    Auto,
+    /// Routes are added to the table with this id
+    Id(u32),
+}
+
+impl FromStr for RouteTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(RouteTable::Off),
+            "auto" => Ok(RouteTable::Auto),
+            "main" => Ok(RouteTable::Id(254)),
+            _ => s
+                .parse()
+                .map(RouteTable::Id)
+                .map_err(|_| format!("Invalid table: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for RouteTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteTable::Off => write!(f, "off"),
+            RouteTable::Auto => write!(f, "auto"),
+            RouteTable::Id(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+/// A `[Peer]` section of a configuration file
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: x25519::PublicKey,
+    pub preshared_key: Option<[u8; 32]>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive_interval: Option<u16>,
+    pub allowed_ips: Vec<AllowedIP>,
+    /// The `MTU`, `IcmpTooBig` and `ClampMss` keys, which only boringtun understands
+    pub pmtu: PeerPmtu,
+    /// The `AclPolicy` and `AclRule` keys, which only boringtun understands
+    pub acl: Acl,
+}
+
+impl Config {
+    /// A `set=1` transaction that makes the device match the configuration, replacing all
+    /// existing peers
+    #[must_use]
+    pub fn to_set_request(&self) -> SetRequest {
+        SetRequest {
+            private_key: self.private_key.clone(),
+            listen_port: self.listen_port,
+            fwmark: self.fwmark,
+            replace_peers: true,
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| PeerUpdate {
+                    preshared_key: peer.preshared_key,
+                    endpoint: peer.endpoint,
+                    persistent_keepalive_interval: peer.persistent_keepalive_interval,
+                    replace_allowed_ips: true,
+                    allowed_ips: peer.allowed_ips.clone(),
+                    pmtu: PmtuUpdate::between(&PeerPmtu::default(), &peer.pmtu),
+                    acl: AclUpdate::between(&Acl::default(), &peer.acl),
+                    ..PeerUpdate::new(peer.public_key)
+                })
+                .collect(),
+        }
+    }
+
+    /// A `set=1` transaction that makes the device match the configuration without
+    /// restarting sessions, as done by `wg syncconf`. Peers of the running device that are
+    /// missing from the configuration are removed, and the others are updated in place.
+    #[must_use]
+    pub fn to_sync_request(&self, current: &Interface) -> SetRequest {
+        let removed = current
+            .peers
+            .iter()
+            .filter(|p| !self.peers.iter().any(|c| c.public_key == p.public_key))
+            .map(|p| PeerUpdate::remove(p.public_key));
+        let updated = self.peers.iter().map(|peer| {
+            let current = current
+                .peers
+                .iter()
+                .find(|p| p.public_key == peer.public_key);
+            let pmtu = current.map(|p| p.pmtu).unwrap_or_default();
+            let acl = current.map(|p| p.acl.clone()).unwrap_or_default();
+            PeerUpdate {
+                // Settings missing from the file are turned off
+                preshared_key: Some(peer.preshared_key.unwrap_or_default()),
+                endpoint: peer.endpoint,
+                persistent_keepalive_interval: Some(
+                    peer.persistent_keepalive_interval.unwrap_or(0),
+                ),
+                replace_allowed_ips: true,
+                allowed_ips: peer.allowed_ips.clone(),
+                pmtu: PmtuUpdate::between(&pmtu, &peer.pmtu),
+                acl: AclUpdate::between(&acl, &peer.acl),
+                ..PeerUpdate::new(peer.public_key)
+            }
+        });
+
+        SetRequest {
+            private_key: self.private_key.clone(),
+            listen_port: self.listen_port,
+            fwmark: self.fwmark,
+            replace_peers: false,
+            peers: removed.chain(updated).collect(),
+        }
+    }
+}
+
+/// The configuration of a running device, as printed by `wg showconf`
+impl From<&Interface> for Config {
+    fn from(interface: &Interface) -> Config {
+        Config {
+            private_key: interface.private_key.clone(),
+            listen_port: interface.listen_port,
+            fwmark: interface.fwmark,
+            network: NetworkConfig::default(),
+            peers: interface
+                .peers
+                .iter()
+                .map(|peer| PeerConfig {
+                    public_key: peer.public_key,
+                    preshared_key: peer.preshared_key,
+                    endpoint: peer.endpoint,
+                    persistent_keepalive_interval: peer.persistent_keepalive_interval,
+                    allowed_ips: peer.allowed_ips.clone(),
+                    pmtu: peer.pmtu,
+                    acl: peer.acl.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = |bytes: &[u8]| KeyBytes::from_bytes(bytes).map(|k| k.to_base64());
+
+        writeln!(f, "[Interface]")?;
+        if !self.network.addresses.is_empty() {
+            let addresses: Vec<_> = self
+                .network
+                .addresses
+                .iter()
+                .map(|ip| format!("{}/{}", ip.addr, ip.cidr))
+                .collect();
+            writeln!(f, "Address = {}", addresses.join(", "))?;
+        }
+        if let Some(mtu) = self.network.mtu {
+            writeln!(f, "MTU = {mtu}")?;
+        }
+        if let Some(table) = self.network.table {
+            writeln!(f, "Table = {table}")?;
+        }
+        if let Some(port) = self.listen_port.filter(|&p| p != 0) {
+            writeln!(f, "ListenPort = {port}")?;
+        }
+        if let Some(fwmark) = self.fwmark.filter(|&m| m != 0) {
+            writeln!(f, "FwMark = {fwmark:#x}")?;
+        }
+        if let Some(ref private_key) = self.private_key
+            && let Ok(private_key) = key(private_key.as_bytes())
+        {
+            writeln!(f, "PrivateKey = {private_key}")?;
+        }
+        writeln!(f)?;
+
+        for (i, peer) in self.peers.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "[Peer]")?;
+            if let Ok(public_key) = key(peer.public_key.as_bytes()) {
+                writeln!(f, "PublicKey = {public_key}")?;
+            }
+            if let Some(Ok(preshared_key)) = peer.preshared_key.map(|k| key(&k)) {
+                writeln!(f, "PresharedKey = {preshared_key}")?;
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips: Vec<_> = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|ip| format!("{}/{}", ip.addr, ip.cidr))
+                    .collect();
+                writeln!(f, "AllowedIPs = {}", allowed_ips.join(", "))?;
+            }
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(f, "Endpoint = {endpoint}")?;
+            }
+            if let Some(interval) = peer.persistent_keepalive_interval.filter(|&i| i != 0) {
+                writeln!(f, "PersistentKeepalive = {interval}")?;
+            }
+            if let Some(mtu) = peer.pmtu.mtu {
+                writeln!(f, "MTU = {mtu}")?;
+            }
+            if peer.pmtu.icmp_too_big != Toggle::Auto {
+                writeln!(f, "IcmpTooBig = {}", peer.pmtu.icmp_too_big)?;
+            }
+            if peer.pmtu.clamp_mss != Toggle::Auto {
+                writeln!(f, "ClampMss = {}", peer.pmtu.clamp_mss)?;
+            }
+            if peer.acl.policy != Action::Allow {
+                writeln!(f, "AclPolicy = {}", peer.acl.policy)?;
+            }
+            for rule in &peer.acl.rules {
+                writeln!(f, "AclRule = {rule}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum Section {
+    None,
+    Interface,
+    /// A peer section, with the line it started on
+    Peer(usize),
+}
+
+/// The keys of a peer section, before the section is complete
+#[derive(Default)]
+struct PeerBuilder {
+    public_key: Option<x25519::PublicKey>,
+    preshared_key: Option<[u8; 32]>,
+    endpoint: Option<SocketAddr>,
+    persistent_keepalive_interval: Option<u16>,
+    allowed_ips: Vec<AllowedIP>,
+    pmtu: PeerPmtu,
+    acl: Acl,
+}
+
+impl FromStr for Config {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        let mut section = Section::None;
+        let mut peer = PeerBuilder::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if let Section::Peer(start) = section {
+                    config.peers.push(peer.finish(start)?);
+                    peer = PeerBuilder::default();
+                }
+                section = match name.trim().to_ascii_lowercase().as_str() {
+                    "interface" => Section::Interface,
+                    "peer" => Section::Peer(line_no),
+                    _ => {
+                        return Err(ParseError {
+                            line: line_no,
+                            message: format!("Unknown section: {line}"),
+                        });
+                    }
+                };
+                continue;
+            }
+
+            parse_line(&mut config, &mut peer, &section, line).map_err(|message| ParseError {
+                line: line_no,
+                message,
+            })?;
+        }
+
+        if let Section::Peer(start) = section {
+            config.peers.push(peer.finish(start)?);
+        }
+
+        Ok(config)
+    }
+}
+
+impl PeerBuilder {
+    fn finish(self, line: usize) -> Result<PeerConfig, ParseError> {
+        let public_key = self.public_key.ok_or_else(|| ParseError {
+            line,
+            message: "Peer section without a PublicKey".to_owned(),
+        })?;
+        Ok(PeerConfig {
+            public_key,
+            preshared_key: self.preshared_key,
+            endpoint: self.endpoint,
+            persistent_keepalive_interval: self.persistent_keepalive_interval,
+            allowed_ips: self.allowed_ips,
+            pmtu: self.pmtu,
+            acl: self.acl,
+        })
+    }
+}
+
+fn parse_line(
+    config: &mut Config,
+    peer: &mut PeerBuilder,
+    section: &Section,
+    line: &str,
+) -> Result<(), String> {
+    let (key, val) = line
+        .split_once('=')
+        .ok_or_else(|| format!("Expected a key = value pair: {line}"))?;
+    let (key, val) = (key.trim(), val.trim());
+
+    match (section, key.to_ascii_lowercase().as_str()) {
+        (Section::None, _) => return Err(format!("{key} outside of a section")),
+        (Section::Interface, "privatekey") => {
+            config.private_key = Some(x25519::StaticSecret::from(parse_key(val)?));
+        }
+        (Section::Interface, "listenport") => {
+            config.listen_port = Some(val.parse().map_err(|_| format!("Invalid port: {val}"))?);
+        }
+        (Section::Interface, "fwmark") => config.fwmark = Some(parse_fwmark(val)?),
+        (Section::Interface, "address") => {
+            for ip in val.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                config.network.addresses.push(parse_allowed_ip(ip)?);
+            }
+        }
+        (Section::Interface, "mtu") => {
+            config.network.mtu = Some(val.parse().map_err(|_| format!("Invalid MTU: {val}"))?);
+        }
+        (Section::Interface, "table") => config.network.table = Some(val.parse()?),
+        (Section::Interface, key) if WG_QUICK_KEYS.contains(&key) => {}
+        (Section::Peer(_), "publickey") => {
+            peer.public_key = Some(x25519::PublicKey::from(parse_key(val)?));
+        }
+        (Section::Peer(_), "presharedkey") => peer.preshared_key = Some(parse_key(val)?),
+        (Section::Peer(_), "endpoint") => peer.endpoint = Some(parse_endpoint(val)?),
+        (Section::Peer(_), "persistentkeepalive") => {
+            peer.persistent_keepalive_interval = Some(parse_persistent_keepalive(val)?);
+        }
+        (Section::Peer(_), "allowedips") => {
+            // The key may be repeated, every occurrence adds to the list
+            for ip in val.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                peer.allowed_ips.push(parse_allowed_ip(ip)?);
+            }
+        }
+        (Section::Peer(_), "mtu") => {
+            let mtu = val.parse().map_err(|_| format!("Invalid MTU: {val}"))?;
+            peer.pmtu.mtu = (mtu != 0).then_some(mtu);
+        }
+        (Section::Peer(_), "icmptoobig") => peer.pmtu.icmp_too_big = val.parse()?,
+        (Section::Peer(_), "clampmss") => peer.pmtu.clamp_mss = val.parse()?,
+        (Section::Peer(_), "aclpolicy") => peer.acl.policy = val.parse()?,
+        // The key may be repeated, rules are checked in the order they are listed
+        (Section::Peer(_), "aclrule") => peer.acl.rules.push(val.parse()?),
+        _ => return Err(format!("Unknown key: {key}")),
+    }
+
+    Ok(())
+}
+
+fn parse_key(val: &str) -> Result<[u8; 32], String> {
+    val.parse::<KeyBytes>()
+        .map(|key| key.0)
+        .map_err(|e| format!("{e}: {val}"))
+}
+
+/// A fwmark is `off` or a number, in decimal or in hex with a `0x` prefix
+pub fn parse_fwmark(val: &str) -> Result<u32, String> {
+    let fwmark = match val {
+        "off" => Ok(0),
+        _ => match val.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => val.parse(),
+        },
+    };
+    fwmark.map_err(|_| format!("Invalid fwmark: {val}"))
+}
+
+/// A persistent keepalive interval is `off` or a number of seconds
+pub fn parse_persistent_keepalive(val: &str) -> Result<u16, String> {
+    match val {
+        "off" => Ok(0),
+        _ => val
+            .parse()
+            .map_err(|_| format!("Invalid persistent keepalive interval: {val}")),
+    }
+}
+
+/// An endpoint is an address and port, or a host name and port that is resolved right away
+pub fn parse_endpoint(val: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = val.parse() {
+        return Ok(addr);
+    }
+    val.to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve endpoint {val}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("No addresses found for endpoint {val}"))
+}
+
+/// An allowed IP without a prefix length covers a single address
+pub fn parse_allowed_ip(val: &str) -> Result<AllowedIP, String> {
+    if !val.contains('/')
+        && let Ok(addr) = val.parse::<IpAddr>()
+    {
+        let cidr = if addr.is_ipv4() { 32 } else { 128 };
+        return Ok(AllowedIP { addr, cidr });
+    }
+    val.parse()
+        .map_err(|_| format!("Invalid allowed IP: {val}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uapi::client;
+
+    const KEY_A: &str = "YFjESZYbWRxQVnHsOVYM3zM6rr7f6yyKA7cUp+ElWHk=";
+    const KEY_B: &str = "GNnzFVwRLzi/WFcJ4/hwZjJCd0Q6tqYdNQbTyzZKPUo=";
+
+    #[test]
+    fn parse_config() {
+        let config: Config = format!(
+            "# A comment\n\
+             [Interface]\n\
+             PrivateKey = {KEY_A}\n\
+             ListenPort = 51820 # trailing comment\n\
+             fwmark = 0x10\n\
+             Address = 10.0.0.1/24, fd00::1\n\
+             MTU = 1420\n\
+             Table = off\n\
+             DNS = 10.0.0.53\n\
+             PostUp = iptables -A FORWARD -i %i -j ACCEPT\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {KEY_B}\n\
+             PresharedKey = {KEY_A}\n\
+             Endpoint = [::1]:51821\n\
+             AllowedIPs = 10.0.0.2/32, fd00::2\n\
+             AllowedIPs = 10.1.0.0/16\n\
+             PersistentKeepalive = 25\n\
+             \n\
+             [peer]\n\
+             publickey = {KEY_A}\n"
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            config
+                .private_key
+                .as_ref()
+                .map(x25519::StaticSecret::to_bytes),
+            Some(KEY_A.parse::<KeyBytes>().unwrap().0)
+        );
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.fwmark, Some(16));
+        assert_eq!(
+            config.network,
+            NetworkConfig {
+                addresses: vec![
+                    "10.0.0.1/24".parse().unwrap(),
+                    "fd00::1/128".parse().unwrap()
+                ],
+                mtu: Some(1420),
+                table: Some(RouteTable::Off),
+            }
+        );
+        assert_eq!(config.peers.len(), 2);
+
+        let peer = &config.peers[0];
+        assert_eq!(
+            peer.public_key.as_bytes(),
+            &KEY_B.parse::<KeyBytes>().unwrap().0
+        );
+        assert!(peer.preshared_key.is_some());
+        assert_eq!(peer.endpoint, Some("[::1]:51821".parse().unwrap()));
+        assert_eq!(peer.persistent_keepalive_interval, Some(25));
+        let allowed_ips: Vec<_> = peer.allowed_ips.iter().map(|ip| ip.cidr).collect();
+        assert_eq!(allowed_ips, [32, 128, 16]);
+        assert_eq!(config.peers[1].allowed_ips, []);
+
+        let request = config.to_set_request();
+        assert!(request.replace_peers);
+        assert!(request.peers.iter().all(|p| p.replace_allowed_ips));
+    }
+
+    #[test]
+    fn format_like_showconf() {
+        let text = format!(
+            "[Interface]\n\
+             Address = 10.0.0.1/24, fd00::1/64\n\
+             MTU = 1420\n\
+             Table = 1234\n\
+             ListenPort = 51820\n\
+             FwMark = 0x10\n\
+             PrivateKey = {KEY_A}\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {KEY_B}\n\
+             PresharedKey = {KEY_A}\n\
+             AllowedIPs = 10.0.0.2/32, fd00::2/128\n\
+             Endpoint = [::1]:51821\n\
+             PersistentKeepalive = 25\n\
+             \n\
+             [Peer]\n\
+             PublicKey = {KEY_A}\n\
+             MTU = 1400\n\
+             ClampMss = on\n\
+             AclPolicy = deny\n\
+             AclRule = allow out proto tcp to 10.0.0.0/24 dport 22\n\
+             AclRule = allow in from 10.0.0.0/24\n"
+        );
+        let config: Config = text.parse().unwrap();
+        assert_eq!(config.to_string(), text);
+
+        let empty = Config {
+            listen_port: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(empty.to_string(), "[Interface]\n\n");
+    }
+
+    #[test]
+    fn sync_removes_and_resets_peers() {
+        let config: Config = format!("[Peer]\nPublicKey = {KEY_A}\nAllowedIPs = 10.0.0.2\n")
+            .parse()
+            .unwrap();
+        let key = |k: &str| x25519::PublicKey::from(k.parse::<KeyBytes>().unwrap().0);
+        let mut active = client::Peer::new(key(KEY_A));
+        active.pmtu.mtu = Some(1400);
+        active.acl.rules.push("deny proto udp".parse().unwrap());
+        let current = Interface {
+            peers: vec![active, client::Peer::new(key(KEY_B))],
+            ..Default::default()
+        };
+
+        let request = config.to_sync_request(&current);
+        assert!(!request.replace_peers);
+        assert_eq!(request.peers.len(), 2);
+        assert!(request.peers[0].remove);
+        assert_eq!(request.peers[0].public_key, key(KEY_B));
+        let peer = &request.peers[1];
+        assert_eq!(peer.public_key, key(KEY_A));
+        assert_eq!(peer.preshared_key, Some([0u8; 32]));
+        assert_eq!(peer.persistent_keepalive_interval, Some(0));
+        assert!(peer.replace_allowed_ips);
+        // Path MTU settings are only sent when they change
+        assert_eq!(peer.pmtu.mtu, Some(0));
+        assert_eq!(peer.pmtu.clamp_mss, None);
+        assert!(peer.acl.replace_rules);
+        assert_eq!(peer.acl.rules, []);
+        assert_eq!(peer.acl.policy, None);
+    }
+
+    #[test]
+    fn parse_errors_have_line_numbers() {
+        let line = |s: &str| s.parse::<Config>().err().map(|e| e.line);
+
+        assert_eq!(line("ListenPort = 1\n"), Some(1));
+        assert_eq!(line("[Interface]\n\nListenPort = 70000\n"), Some(3));
+        assert_eq!(line("[Interface]\nBogus = 1\n"), Some(2));
+        assert_eq!(line("[Interface]\n[Tunnel]\n"), Some(2));
+        assert_eq!(line("[Peer]\nAllowedIPs = 10.0.0.1/33\n"), Some(2));
+        assert_eq!(
+            line("[Interface]\n[Peer]\nEndpoint = 127.0.0.1:1\n"),
+            Some(2)
+        );
+        assert_eq!(line("[Peer]\nPublicKey\n"), Some(2));
+        assert_eq!(line("[Interface]\nPrivateKey = abc\n"), Some(2));
+        assert_eq!(line("[Interface]\nTable = nope\n"), Some(2));
+        assert_eq!(line("[Peer]\nAclRule = allow dport 22\n"), Some(2));
+
+        let e = "[Interface]\nFwMark = nope\n"
+            .parse::<Config>()
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "line 2: Invalid fwmark: nope");
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Events streamed to subscribers of the `subscribe=1` extension command.
+//!
+//! After the `errno=0` response, every event is sent as a single line of space separated
+//! `key=value` pairs, the first of which is always `event=<name>`:
+//!
+//! ```text
+//! event=handshake_completed public_key=<hex>
+//! event=endpoint_roamed public_key=<hex> endpoint=<addr>
+//! ```
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use hex::encode as encode_hex;
+
+use crate::{serialization::KeyBytes, x25519};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A handshake with the peer completed and a new session is in use
+    HandshakeCompleted {
+        public_key: x25519::PublicKey,
+    },
+    /// No session could be kept up with the peer, and its connection expired
+    SessionExpired {
+        public_key: x25519::PublicKey,
+    },
+    /// An authenticated packet from the peer arrived from a new endpoint
+    EndpointRoamed {
+        public_key: x25519::PublicKey,
+        endpoint: SocketAddr,
+    },
+    PeerAdded {
+        public_key: x25519::PublicKey,
+    },
+    PeerRemoved {
+        public_key: x25519::PublicKey,
+    },
+    /// The interface private key was changed, carries the new public key
+    KeyRotated {
+        public_key: x25519::PublicKey,
+    },
+}
+
+impl Event {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::HandshakeCompleted { .. } => "handshake_completed",
+            Event::SessionExpired { .. } => "session_expired",
+            Event::EndpointRoamed { .. } => "endpoint_roamed",
+            Event::PeerAdded { .. } => "peer_added",
+            Event::PeerRemoved { .. } => "peer_removed",
+            Event::KeyRotated { .. } => "key_rotated",
+        }
+    }
+
+    #[must_use]
+    pub fn public_key(&self) -> &x25519::PublicKey {
+        match self {
+            Event::HandshakeCompleted { public_key }
+            | Event::SessionExpired { public_key }
+            | Event::EndpointRoamed { public_key, .. }
+            | Event::PeerAdded { public_key }
+            | Event::PeerRemoved { public_key }
+            | Event::KeyRotated { public_key } => public_key,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event={} public_key={}",
+            self.name(),
+            encode_hex(self.public_key().as_bytes())
+        )?;
+        if let Event::EndpointRoamed { endpoint, .. } = self {
+            write!(f, " endpoint={endpoint}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut public_key = None;
+        let mut endpoint = None;
+
+        for pair in s.split_ascii_whitespace() {
+            let (key, val) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid event field: {pair}"))?;
+            match key {
+                "event" => name = Some(val),
+                "public_key" => {
+                    let key = val.parse::<KeyBytes>().map_err(|e| e.to_string())?;
+                    public_key = Some(x25519::PublicKey::from(key.0));
+                }
+                "endpoint" => {
+                    endpoint = Some(
+                        val.parse::<SocketAddr>()
+                            .map_err(|_| format!("Invalid endpoint: {val}"))?,
+                    );
+                }
+                // Ignore unknown fields, so events can be extended later
+                _ => {}
+            }
+        }
+
+        let public_key = public_key.ok_or("Missing public_key")?;
+        Ok(match name.ok_or("Missing event")? {
+            "handshake_completed" => Event::HandshakeCompleted { public_key },
+            "session_expired" => Event::SessionExpired { public_key },
+            "endpoint_roamed" => Event::EndpointRoamed {
+                public_key,
+                endpoint: endpoint.ok_or("Missing endpoint")?,
+            },
+            "peer_added" => Event::PeerAdded { public_key },
+            "peer_removed" => Event::PeerRemoved { public_key },
+            "key_rotated" => Event::KeyRotated { public_key },
+            name => return Err(format!("Unknown event: {name}")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_round_trip() {
+        let public_key = x25519::PublicKey::from([7u8; 32]);
+        let events = [
+            Event::HandshakeCompleted { public_key },
+            Event::SessionExpired { public_key },
+            Event::EndpointRoamed {
+                public_key,
+                endpoint: "[2001:db8::1]:51820".parse().unwrap(),
+            },
+            Event::PeerAdded { public_key },
+            Event::PeerRemoved { public_key },
+            Event::KeyRotated { public_key },
+        ];
+
+        for event in events {
+            assert_eq!(event.to_string().parse::<Event>(), Ok(event));
+        }
+
+        assert_eq!(
+            Event::EndpointRoamed {
+                public_key,
+                endpoint: "10.0.0.1:1".parse().unwrap()
+            }
+            .to_string(),
+            format!(
+                "event=endpoint_roamed public_key={} endpoint=10.0.0.1:1",
+                encode_hex([7u8; 32])
+            )
+        );
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Support for the cross-platform userspace WireGuard configuration protocol, as spoken on
+//! `/var/run/wireguard/<iface>.sock` by boringtun, wireguard-go and other userspace
+//! implementations.
+//!
+//! See <https://www.wireguard.com/xplatform/#configuration-protocol> for the wire format.
+
+pub mod client;
+pub mod config;
+pub mod event;
+
+use std::{
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+use hex::encode as encode_hex;
+
+use crate::{serialization::KeyBytes, x25519};
+
+/// The directory where userspace implementations create their control sockets
+pub const SOCK_DIR: &str = "/var/run/wireguard/";
+
+/// A response field that can be selected with the `fields=` extension key of a get request.
+/// The extension fields, from [`Field::Mtu`] on, are only reported when selected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    PrivateKey,
+    ListenPort,
+    Fwmark,
+    PresharedKey,
+    PersistentKeepaliveInterval,
+    Endpoint,
+    AllowedIp,
+    /// Both `last_handshake_time_sec` and `last_handshake_time_nsec`
+    LastHandshakeTime,
+    RxBytes,
+    TxBytes,
+    /// The path MTU settings of a peer, only reported when set
+    Mtu,
+    IcmpTooBig,
+    ClampMss,
+    /// The firewall policy of a peer, only reported when it denies
+    AclPolicy,
+    /// The firewall rules of a peer, each followed by its `acl_rule_hits` line, and the
+    /// `acl_policy_hits` line
+    AclRule,
+    /// The running packet capture, with its `capture_packets`, `capture_bytes` and
+    /// `capture_lost` counters
+    Capture,
+}
+
+impl Field {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::PrivateKey => "private_key",
+            Field::ListenPort => "listen_port",
+            Field::Fwmark => "fwmark",
+            Field::PresharedKey => "preshared_key",
+            Field::PersistentKeepaliveInterval => "persistent_keepalive_interval",
+            Field::Endpoint => "endpoint",
+            Field::AllowedIp => "allowed_ip",
+            Field::LastHandshakeTime => "last_handshake_time",
+            Field::RxBytes => "rx_bytes",
+            Field::TxBytes => "tx_bytes",
+            Field::Mtu => "mtu",
+            Field::IcmpTooBig => "icmp_too_big",
+            Field::ClampMss => "clamp_mss",
+            Field::AclPolicy => "acl_policy",
+            Field::AclRule => "acl_rule",
+            Field::Capture => "capture",
+        }
+    }
+
+    /// Whether the field is specific to boringtun, and left out of responses to requests that
+    /// don't select it, as `wg` and other tools don't expect it
+    #[must_use]
+    pub fn is_extension(&self) -> bool {
+        matches!(
+            self,
+            Field::Mtu
+                | Field::IcmpTooBig
+                | Field::ClampMss
+                | Field::AclPolicy
+                | Field::AclRule
+                | Field::Capture
+        )
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "private_key" => Field::PrivateKey,
+            "listen_port" => Field::ListenPort,
+            "fwmark" => Field::Fwmark,
+            "preshared_key" => Field::PresharedKey,
+            "persistent_keepalive_interval" => Field::PersistentKeepaliveInterval,
+            "endpoint" => Field::Endpoint,
+            "allowed_ip" => Field::AllowedIp,
+            "last_handshake_time" => Field::LastHandshakeTime,
+            "rx_bytes" => Field::RxBytes,
+            "tx_bytes" => Field::TxBytes,
+            "mtu" => Field::Mtu,
+            "icmp_too_big" => Field::IcmpTooBig,
+            "clamp_mss" => Field::ClampMss,
+            "acl_policy" => Field::AclPolicy,
+            "acl_rule" => Field::AclRule,
+            "capture" => Field::Capture,
+            _ => return Err(format!("Unknown field: {s}")),
+        })
+    }
+}
+
+/// The body of a `get=1` request.
+///
+/// The protocol defines no keys for get requests, boringtun accepts the following extensions:
+/// * `public_key=<hex>` - only report the listed peers, may be repeated
+/// * `fields=<name>[,<name>...]` - only report the listed interface and peer fields. The
+///   extension fields, see [`Field::is_extension`], are only reported when listed.
+/// * `cursor=<hex>` - only report peers whose public key sorts after the given key
+/// * `limit=<n>` - report at most n peers, in public key order
+/// * `drops=true` - also report dropped packet counters, as `drop_<reason>=<n>` lines for the
+///   interface and every peer, and `decapsulation_error_<error>=<n>` lines for the interface.
+///   Counters that are zero are omitted, except for the interface `drop_<reason>` lines.
+/// * `flight_record=true` - also report the recent handshake events of every peer, as
+///   `flight_record=<time> <event>` lines, see [`crate::device::recorder`]
+///
+/// To page through all peers, repeat the request with the last reported public key as the
+/// cursor, until fewer than `limit` peers are reported. A request without any of the keys
+/// produces the same response as a plain `get=1`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GetRequest {
+    pub public_keys: Vec<x25519::PublicKey>,
+    pub fields: Option<Vec<Field>>,
+    pub cursor: Option<x25519::PublicKey>,
+    pub limit: Option<usize>,
+    pub drops: bool,
+    pub flight_record: bool,
+}
+
+impl GetRequest {
+    /// Should the given field be part of the response
+    #[must_use]
+    pub fn wants(&self, field: Field) -> bool {
+        match self.fields {
+            Some(ref fields) => fields.contains(&field),
+            None => !field.is_extension(),
+        }
+    }
+
+    /// Is the response paginated, in which case peers are sorted by public key
+    #[must_use]
+    pub fn is_paged(&self) -> bool {
+        self.cursor.is_some() || self.limit.is_some()
+    }
+
+    /// Read the request body that follows the `get=1` line, up to and including the empty line.
+    /// The empty line is optional: reading also stops at EOF, and when a non-blocking reader
+    /// has nothing more. Returns the errno to respond with if the request is invalid.
+    pub fn read_from<R: BufRead + ?Sized>(reader: &mut R) -> Result<GetRequest, i32> {
+        let mut request = GetRequest::default();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => break, // Tolerate a missing empty line at EOF
+                Ok(_) => {}
+                // Nothing more was sent
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return Err(libc::EIO),
+            }
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, val) = line.split_once('=').ok_or(libc::EPROTO)?;
+            match key {
+                "public_key" => request.public_keys.push(parse_public_key(val)?),
+                "fields" => {
+                    let fields = val
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<Vec<Field>, _>>()
+                        .map_err(|_| libc::EINVAL)?;
+                    request.fields.get_or_insert_with(Vec::new).extend(fields);
+                }
+                "cursor" => request.cursor = Some(parse_public_key(val)?),
+                "limit" => request.limit = Some(val.parse().map_err(|_| libc::EINVAL)?),
+                "drops" => request.drops = val.parse().map_err(|_| libc::EINVAL)?,
+                "flight_record" => {
+                    request.flight_record = val.parse().map_err(|_| libc::EINVAL)?;
+                }
+                _ => return Err(libc::EINVAL),
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Serialize the request, including the `get=1` header and the terminating empty line
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "get=1")?;
+
+        for key in &self.public_keys {
+            writeln!(w, "public_key={}", encode_hex(key.as_bytes()))?;
+        }
+
+        if let Some(ref fields) = self.fields {
+            let names: Vec<_> = fields.iter().map(Field::name).collect();
+            writeln!(w, "fields={}", names.join(","))?;
+        }
+
+        if let Some(ref cursor) = self.cursor {
+            writeln!(w, "cursor={}", encode_hex(cursor.as_bytes()))?;
+        }
+
+        if let Some(limit) = self.limit {
+            writeln!(w, "limit={limit}")?;
+        }
+
+        if self.drops {
+            writeln!(w, "drops=true")?;
+        }
+
+        if self.flight_record {
+            writeln!(w, "flight_record=true")?;
+        }
+
+        writeln!(w)
+    }
+}
+
+fn parse_public_key(val: &str) -> Result<x25519::PublicKey, i32> {
+    val.parse::<KeyBytes>()
+        .map(|key| x25519::PublicKey::from(key.0))
+        .map_err(|_| libc::EINVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+
+    #[test]
+    fn get_request_round_trip() {
+        let request = GetRequest {
+            public_keys: vec![
+                x25519::PublicKey::from([1u8; 32]),
+                x25519::PublicKey::from([2u8; 32]),
+            ],
+            fields: Some(vec![Field::Endpoint, Field::RxBytes, Field::TxBytes]),
+            cursor: Some(x25519::PublicKey::from([0u8; 32])),
+            limit: Some(100),
+            drops: true,
+            flight_record: true,
+        };
+
+        let mut buf = Vec::new();
+        request.write_to(&mut buf).unwrap();
+        let mut reader = BufReader::new(&buf[..]);
+        let line = &mut String::new();
+        reader.read_line(line).unwrap();
+        assert_eq!(line, "get=1\n");
+        assert_eq!(GetRequest::read_from(&mut reader), Ok(request));
+
+        let mut buf = Vec::new();
+        GetRequest::default().write_to(&mut buf).unwrap();
+        assert_eq!(buf, b"get=1\n\n");
+    }
+
+    #[test]
+    fn get_request_rejects_unknown_keys() {
+        let read = |s: &str| GetRequest::read_from(&mut BufReader::new(s.as_bytes()));
+
+        assert_eq!(read("\n"), Ok(GetRequest::default()));
+        assert_eq!(read(""), Ok(GetRequest::default()));
+        assert_eq!(read("fields=rx_bytes,bogus\n\n"), Err(libc::EINVAL));
+        assert_eq!(read("listen_port=1\n\n"), Err(libc::EINVAL));
+        assert_eq!(read("limit\n\n"), Err(libc::EPROTO));
+        assert_eq!(read("drops=1\n\n"), Err(libc::EINVAL));
+        assert!(!read("fields=rx_bytes\n\n").unwrap().wants(Field::TxBytes));
+        assert!(read("\n").unwrap().wants(Field::TxBytes));
+        assert!(!read("\n").unwrap().wants(Field::AclRule));
+        assert!(read("fields=acl_rule\n\n").unwrap().wants(Field::AclRule));
+    }
+
+    /// A non-blocking connection with nothing more to read after the data
+    struct Stalled<'a>(&'a [u8]);
+
+    impl io::Read for Stalled<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = self.0.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn get_request_does_not_wait_for_the_empty_line() {
+        let read = |s: &str| GetRequest::read_from(&mut BufReader::new(Stalled(s.as_bytes())));
+
+        assert_eq!(read(""), Ok(GetRequest::default()));
+        assert!(read("fields=rx_bytes\n").unwrap().wants(Field::RxBytes));
+        assert!(!read("fields=rx_bytes\n").unwrap().wants(Field::TxBytes));
+    }
+}